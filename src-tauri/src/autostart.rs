@@ -0,0 +1,198 @@
+use crate::config;
+use tauri::plugin::TauriPlugin;
+use tauri::{AppHandle, Wry};
+use tauri_plugin_autostart::{AutoLaunchManager, MacosLauncher, ManagerExt};
+
+/// Registers the connector with the OS login mechanism: a LaunchAgent on macOS, the
+/// `Run` registry key on Windows and an XDG autostart entry on Linux
+pub fn plugin() -> TauriPlugin<Wry> {
+    tauri_plugin_autostart::init(MacosLauncher::LaunchAgent, None)
+}
+
+/// The OS login registration, so the toggle logic can run against a fake in tests
+trait Launcher {
+    fn is_enabled(&self) -> Result<bool, String>;
+    fn enable(&self) -> Result<(), String>;
+    fn disable(&self) -> Result<(), String>;
+}
+
+impl Launcher for AutoLaunchManager {
+    fn is_enabled(&self) -> Result<bool, String> {
+        AutoLaunchManager::is_enabled(self).map_err(|e| e.to_string())
+    }
+
+    fn enable(&self) -> Result<(), String> {
+        AutoLaunchManager::enable(self).map_err(|e| e.to_string())
+    }
+
+    fn disable(&self) -> Result<(), String> {
+        AutoLaunchManager::disable(self).map_err(|e| e.to_string())
+    }
+}
+
+/// Whether the connector is currently registered to start at login
+pub fn is_enabled(app: &AppHandle) -> bool {
+    registered(&*app.autolaunch())
+}
+
+/// Register or unregister the connector and remember the choice
+pub fn set_enabled(app: &AppHandle, enabled: bool) -> Result<(), String> {
+    set_and_save(&*app.autolaunch(), enabled, |enabled| {
+        config::update(|c| c.start_at_login = Some(enabled))
+    })
+}
+
+/// Re-apply the saved choice at startup, e.g. after the app was moved and its
+/// registration went stale or was removed by the OS
+pub fn sync(app: &AppHandle) {
+    restore(&*app.autolaunch(), config::get().start_at_login);
+}
+
+fn registered(launcher: &impl Launcher) -> bool {
+    launcher.is_enabled().unwrap_or_else(|e| {
+        log::warn!("Failed to read start-at-login state: {}", e);
+        false
+    })
+}
+
+/// Only a choice the OS accepted is saved, so the tray and the file can't disagree
+fn set_and_save(
+    launcher: &impl Launcher,
+    enabled: bool,
+    save: impl FnOnce(bool) -> Result<(), String>,
+) -> Result<(), String> {
+    apply(launcher, enabled)?;
+    save(enabled)
+}
+
+fn restore(launcher: &impl Launcher, saved: Option<bool>) {
+    let Some(enabled) = saved else {
+        return;
+    };
+    if let Err(e) = apply(launcher, enabled) {
+        log::warn!("Failed to restore start-at-login: {}", e);
+    }
+}
+
+fn apply(launcher: &impl Launcher, enabled: bool) -> Result<(), String> {
+    let result = if enabled { launcher.enable() } else { launcher.disable() };
+    result.map_err(|e| format!("Failed to {} start at login: {}", if enabled { "enable" } else { "disable" }, e))?;
+    log::info!("Start at login {}", if enabled { "enabled" } else { "disabled" });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+
+    /// A login registration that can be made to refuse changes
+    #[derive(Default)]
+    struct FakeLauncher {
+        registered: Cell<bool>,
+        refuse: bool,
+        calls: RefCell<Vec<&'static str>>,
+    }
+
+    impl Launcher for FakeLauncher {
+        fn is_enabled(&self) -> Result<bool, String> {
+            Ok(self.registered.get())
+        }
+
+        fn enable(&self) -> Result<(), String> {
+            self.calls.borrow_mut().push("enable");
+            if self.refuse {
+                return Err("access denied".to_string());
+            }
+            self.registered.set(true);
+            Ok(())
+        }
+
+        fn disable(&self) -> Result<(), String> {
+            self.calls.borrow_mut().push("disable");
+            if self.refuse {
+                return Err("access denied".to_string());
+            }
+            self.registered.set(false);
+            Ok(())
+        }
+    }
+
+    fn remember(saved: &Cell<Option<bool>>) -> impl FnOnce(bool) -> Result<(), String> + '_ {
+        |enabled| {
+            saved.set(Some(enabled));
+            Ok(())
+        }
+    }
+
+    struct BrokenLauncher;
+
+    impl Launcher for BrokenLauncher {
+        fn is_enabled(&self) -> Result<bool, String> {
+            Err("no autostart directory".to_string())
+        }
+
+        fn enable(&self) -> Result<(), String> {
+            unreachable!()
+        }
+
+        fn disable(&self) -> Result<(), String> {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn toggling_registers_and_removes_the_registration() {
+        let launcher = FakeLauncher::default();
+        let saved = Cell::new(None);
+
+        set_and_save(&launcher, true, remember(&saved)).unwrap();
+        assert!(registered(&launcher));
+        assert_eq!(saved.get(), Some(true));
+
+        set_and_save(&launcher, false, remember(&saved)).unwrap();
+        assert!(!registered(&launcher));
+        assert_eq!(saved.get(), Some(false));
+    }
+
+    #[test]
+    fn refused_change_is_not_saved() {
+        let launcher = FakeLauncher {
+            refuse: true,
+            ..FakeLauncher::default()
+        };
+        let saved = Cell::new(None);
+
+        let err = set_and_save(&launcher, true, remember(&saved)).unwrap_err();
+        assert_eq!(err, "Failed to enable start at login: access denied");
+        assert_eq!(saved.get(), None);
+        assert!(!registered(&launcher));
+    }
+
+    #[test]
+    fn saved_choice_is_reapplied_at_startup() {
+        // The OS dropped the registration, e.g. after the app moved
+        let launcher = FakeLauncher::default();
+        restore(&launcher, Some(true));
+        assert!(registered(&launcher));
+
+        // A registration left behind after opting out is removed
+        restore(&launcher, Some(false));
+        assert!(!registered(&launcher));
+        assert_eq!(*launcher.calls.borrow(), ["enable", "disable"]);
+    }
+
+    #[test]
+    fn unset_choice_leaves_the_registration_alone() {
+        let launcher = FakeLauncher::default();
+        launcher.registered.set(true);
+        restore(&launcher, None);
+        assert!(registered(&launcher));
+        assert!(launcher.calls.borrow().is_empty());
+    }
+
+    #[test]
+    fn unreadable_state_shows_as_disabled() {
+        assert!(!registered(&BrokenLauncher));
+    }
+}
@@ -0,0 +1,181 @@
+//! Brother QL raster commands, so QL label printers get the label's exact pixels instead of
+//! a PDF the driver rescales
+//!
+//! Labels arrive as an image already rendered at 300 dpi for the tape: 696 pixels wide for
+//! 62mm tape, 306 for 29mm and so on. Narrower images are centred on the narrowest tape
+//! they fit.
+
+/// Continuous tapes for the QL-700/800 series: (width in mm, printable dots, unprintable
+/// dots on the right of the head)
+const TAPES: &[(u8, u32, u32)] = &[
+    (12, 106, 29),
+    (29, 306, 6),
+    (38, 413, 12),
+    (50, 554, 12),
+    (54, 590, 0),
+    (62, 696, 12),
+];
+
+/// The print head is 720 dots wide, sent as 90 bytes per raster line
+const HEAD_DOTS: u32 = 720;
+const HEAD_BYTES: usize = (HEAD_DOTS / 8) as usize;
+
+/// Null bytes that flush whatever a previous, interrupted job left in the printer
+const INVALIDATE_BYTES: usize = 400;
+
+/// Margin fed before and after a label on continuous tape, in dots
+const FEED_MARGIN_DOTS: u16 = 35;
+
+/// Whether a printer name looks like a Brother QL, which understands QL raster commands
+pub fn is_ql_printer(name: &str) -> bool {
+    let name = name.to_lowercase();
+    name.contains("brother") && name.contains("ql")
+}
+
+/// Convert an image to a QL raster job for the tape matching its width
+pub fn encode_image(image: &[u8]) -> Result<Vec<u8>, String> {
+    let image = image::load_from_memory(image)
+        .map_err(|e| format!("Failed to decode label image: {}", e))?
+        .to_luma_alpha8();
+    let (width, height) = image.dimensions();
+    if height == 0 {
+        return Err("Label image is empty".to_string());
+    }
+    let (width_mm, printable, right_margin) = tape_for_width(width)?;
+    log::debug!("Encoding {}x{} label for {}mm tape", width, height, width_mm);
+
+    // Centre the image in the tape's printable area
+    let left = HEAD_DOTS - right_margin - printable + (printable - width) / 2;
+    let rows: Vec<[u8; HEAD_BYTES]> = (0..height)
+        .map(|y| {
+            raster_row(left, width, |x| {
+                let [luma, alpha] = image.get_pixel(x, y).0;
+                // Transparent pixels are the tape showing through
+                alpha >= 128 && luma < 128
+            })
+        })
+        .collect();
+
+    Ok(encode_rows(width_mm, &rows))
+}
+
+/// The narrowest tape an image of this width fits on
+fn tape_for_width(width: u32) -> Result<(u8, u32, u32), String> {
+    TAPES
+        .iter()
+        .copied()
+        .find(|(_, printable, _)| width <= *printable)
+        .ok_or_else(|| {
+            let widths: Vec<String> = TAPES.iter().map(|(mm, dots, _)| format!("{}mm: {}", mm, dots)).collect();
+            format!(
+                "Label image is {} pixels wide, wider than any QL tape (printable pixels at 300 dpi: {})",
+                width,
+                widths.join(", ")
+            )
+        })
+}
+
+/// Pack one image row into head dots, `left` dots in from the head's left edge
+///
+/// The printer prints each line mirrored, so dot `d` of the head goes out as bit `719 - d`.
+fn raster_row(left: u32, width: u32, black: impl Fn(u32) -> bool) -> [u8; HEAD_BYTES] {
+    let mut row = [0u8; HEAD_BYTES];
+    for x in (0..width).filter(|x| black(*x)) {
+        let bit = (HEAD_DOTS - 1 - (left + x)) as usize;
+        row[bit / 8] |= 0x80 >> (bit % 8);
+    }
+    row
+}
+
+/// Wrap raster lines in the commands for one cut label on continuous tape
+fn encode_rows(width_mm: u8, rows: &[[u8; HEAD_BYTES]]) -> Vec<u8> {
+    let mut job = vec![0u8; INVALIDATE_BYTES];
+    job.extend_from_slice(b"\x1b@"); // Initialize
+    job.extend_from_slice(b"\x1bia\x01"); // Switch to raster mode
+
+    // Print information: media type, width and line count are valid, continuous tape,
+    // no fixed length, first page
+    job.extend_from_slice(b"\x1biz");
+    job.extend_from_slice(&[0x86, 0x0a, width_mm, 0]);
+    job.extend_from_slice(&(rows.len() as u32).to_le_bytes());
+    job.extend_from_slice(&[0, 0]);
+
+    job.extend_from_slice(b"\x1biM\x40"); // Auto cut
+    job.extend_from_slice(b"\x1biA\x01"); // Cut after every label
+    job.extend_from_slice(b"\x1biK\x08"); // Cut at the end of the job
+    job.extend_from_slice(b"\x1bid");
+    job.extend_from_slice(&FEED_MARGIN_DOTS.to_le_bytes());
+
+    for row in rows {
+        job.extend_from_slice(&[b'g', 0x00, HEAD_BYTES as u8]);
+        job.extend_from_slice(row);
+    }
+
+    job.push(0x1a); // Print with feed
+    job
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn images_go_on_the_narrowest_tape_they_fit() {
+        assert_eq!(tape_for_width(1).unwrap().0, 12);
+        assert_eq!(tape_for_width(106).unwrap().0, 12);
+        assert_eq!(tape_for_width(107).unwrap().0, 29);
+        assert_eq!(tape_for_width(696).unwrap().0, 62);
+        let error = tape_for_width(697).unwrap_err();
+        assert!(error.contains("697 pixels wide"), "{}", error);
+        assert!(error.contains("62mm: 696"), "{}", error);
+    }
+
+    #[test]
+    fn rows_are_mirrored_onto_the_head() {
+        // The head's leftmost dot is the last bit of the line
+        let row = raster_row(0, 1, |_| true);
+        assert_eq!(row[HEAD_BYTES - 1], 0x01);
+        assert!(row[..HEAD_BYTES - 1].iter().all(|b| *b == 0));
+
+        // 62mm tape starts 12 dots in: dots 12 and 14 are bits 707 and 705, both in byte 88
+        let (_, printable, right_margin) = tape_for_width(696).unwrap();
+        let left = HEAD_DOTS - right_margin - printable;
+        assert_eq!(left, 12);
+        let row = raster_row(left, 3, |x| x != 1);
+        assert_eq!(row[88], 0x10 | 0x40);
+        assert_eq!(row.iter().filter(|b| **b != 0).count(), 1);
+    }
+
+    #[test]
+    fn small_bitmap_is_wrapped_in_the_job_commands() {
+        let (width_mm, printable, right_margin) = tape_for_width(2).unwrap();
+        let left = HEAD_DOTS - right_margin - printable + (printable - 2) / 2;
+        // A 2x2 checkerboard
+        let rows = [raster_row(left, 2, |x| x == 0), raster_row(left, 2, |x| x == 1)];
+        let job = encode_rows(width_mm, &rows);
+
+        let mut expected = vec![0u8; INVALIDATE_BYTES];
+        expected.extend_from_slice(b"\x1b@\x1bia\x01");
+        expected.extend_from_slice(b"\x1biz\x86\x0a\x0c\x00\x02\x00\x00\x00\x00\x00");
+        expected.extend_from_slice(b"\x1biM\x40\x1biA\x01\x1biK\x08\x1bid\x23\x00");
+        for row in &rows {
+            expected.extend_from_slice(&[b'g', 0x00, 90]);
+            expected.extend_from_slice(row);
+        }
+        expected.push(0x1a);
+        assert_eq!(job, expected);
+
+        // Dots 637 and 638 of the head, bits 82 and 81: 12mm tape is 106 dots ending 29 from the right
+        assert_eq!(left, 637);
+        assert_eq!(rows[0][10], 0x20);
+        assert_eq!(rows[1][10], 0x40);
+    }
+
+    #[test]
+    fn brother_ql_printers_are_recognised_by_name() {
+        assert!(is_ql_printer("Brother_QL-800"));
+        assert!(is_ql_printer("brother ql-700 (USB)"));
+        assert!(!is_ql_printer("Brother_HL-L2350DW"));
+        assert!(!is_ql_printer("Zebra_ZP450"));
+    }
+}
@@ -0,0 +1,239 @@
+use crate::brother_ql;
+use crate::printer;
+use crate::protocol::PrinterCapabilities;
+use crate::tspl;
+use crate::virtual_printer;
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+/// Driver capabilities rarely change, so reuse a query for a minute
+const CAPABILITIES_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Formats the OS renders itself, accepted by every printer
+const RENDERED_FORMATS: &[&str] = &["pdf", "png", "jpg", "gif", "bmp"];
+
+/// Printer command languages sent raw to compatible devices
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+const ZEBRA_FORMATS: &[&str] = &["zpl", "epl"];
+
+static CACHE: LazyLock<Mutex<HashMap<String, (Instant, PrinterCapabilities)>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Get the capabilities of a printer by system name, reusing a recent query when possible
+pub fn printer_capabilities(name: &str) -> PrinterCapabilities {
+    if name == virtual_printer::ID {
+        return virtual_printer::info().capabilities;
+    }
+    if let Some((fetched_at, caps)) = CACHE.lock().unwrap().get(name) {
+        if fetched_at.elapsed() < CAPABILITIES_CACHE_TTL {
+            return caps.clone();
+        }
+    }
+
+    let mut caps = query_capabilities(name);
+    if brother_ql::is_ql_printer(name) {
+        caps.formats.push(printer::BROTHER_RASTER_FORMAT.to_string());
+    }
+    if tspl::is_tsc_printer(name) && !caps.formats.iter().any(|f| f == "tspl") {
+        caps.formats.push("tspl".to_string());
+    }
+    CACHE
+        .lock()
+        .unwrap()
+        .insert(name.to_string(), (Instant::now(), caps.clone()));
+    caps
+}
+
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+fn query_capabilities(name: &str) -> PrinterCapabilities {
+    let output = printer::cups_command("lpoptions").arg("-p").arg(name).arg("-l").output();
+
+    match output {
+        Ok(output) if output.status.success() => parse_lpoptions(&String::from_utf8_lossy(&output.stdout)),
+        Ok(output) => {
+            log::warn!("lpoptions failed for '{}': {}", name, String::from_utf8_lossy(&output.stderr));
+            unknown_capabilities()
+        }
+        Err(e) => {
+            log::warn!("Failed to execute lpoptions: {}", e);
+            unknown_capabilities()
+        }
+    }
+}
+
+/// Capabilities when the driver can't be queried: only what every printer supports
+fn unknown_capabilities() -> PrinterCapabilities {
+    PrinterCapabilities {
+        formats: RENDERED_FORMATS.iter().map(|f| f.to_string()).collect(),
+        dpi: None,
+        color: None,
+        max_copies: None,
+        media_sizes: None,
+    }
+}
+
+/// Parse PPD options from `lpoptions -l`
+///
+/// Each line looks like "PageSize/Media Size: *w288h432 Letter A4", with the default starred.
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+fn parse_lpoptions(output: &str) -> PrinterCapabilities {
+    let mut caps = unknown_capabilities();
+
+    for line in output.lines() {
+        let Some((key, values)) = line.split_once(':') else {
+            continue;
+        };
+        let key = key.split('/').next().unwrap_or_default().trim();
+        let values: Vec<&str> = values.split_whitespace().collect();
+
+        match key {
+            "PageSize" | "media" => {
+                caps.media_sizes = Some(values.iter().map(|v| v.trim_start_matches('*').to_string()).collect());
+            }
+            "Resolution" => {
+                let default = values.iter().find(|v| v.starts_with('*')).or(values.first());
+                caps.dpi = default.and_then(|v| parse_dpi(v.trim_start_matches('*')));
+            }
+            "ColorModel" | "print-color-mode" => {
+                let is_color = values.iter().any(|v| {
+                    let v = v.trim_start_matches('*').to_lowercase();
+                    v.contains("rgb") || v.contains("cmy") || v == "color"
+                });
+                caps.color = Some(is_color);
+            }
+            // Zebra's CUPS driver exposes its own option keys, which tells us it speaks ZPL/EPL
+            k if k.starts_with("ze") => {
+                for format in ZEBRA_FORMATS {
+                    if !caps.formats.iter().any(|f| f == format) {
+                        caps.formats.push(format.to_string());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    caps
+}
+
+/// Parse a resolution value like "203dpi" or "300x300dpi"
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+fn parse_dpi(value: &str) -> Option<u32> {
+    let digits: String = value.chars().take_while(|c| c.is_ascii_digit()).collect();
+    digits.parse().ok()
+}
+
+/// Query the driver through DeviceCapabilities
+#[cfg(target_os = "windows")]
+fn query_capabilities(name: &str) -> PrinterCapabilities {
+    use windows::core::{HSTRING, PCWSTR, PWSTR};
+    use windows::Win32::Storage::Xps::{
+        DeviceCapabilitiesW, DC_COLORDEVICE, DC_COPIES, DC_ENUMRESOLUTIONS, DC_PAPERNAMES,
+    };
+
+    /// Paper names are fixed-width 64 character buffers
+    const PAPER_NAME_LEN: usize = 64;
+
+    let device = HSTRING::from(name);
+    let mut caps = unknown_capabilities();
+
+    unsafe {
+        let query = |capability, output: PWSTR| {
+            DeviceCapabilitiesW(&device, PCWSTR::null(), capability, output, None)
+        };
+
+        caps.color = match query(DC_COLORDEVICE, PWSTR::null()) {
+            1 => Some(true),
+            0 => Some(false),
+            _ => None,
+        };
+
+        let copies = query(DC_COPIES, PWSTR::null());
+        caps.max_copies = (copies > 0).then_some(copies as u32);
+
+        // Resolutions come back as (x, y) pairs of LONGs
+        let count = query(DC_ENUMRESOLUTIONS, PWSTR::null());
+        if count > 0 {
+            let mut buf = vec![0i32; count as usize * 2];
+            if query(DC_ENUMRESOLUTIONS, PWSTR(buf.as_mut_ptr() as *mut u16)) > 0 {
+                caps.dpi = buf.chunks(2).map(|pair| pair[0]).filter(|x| *x > 0).max().map(|x| x as u32);
+            }
+        }
+
+        let count = query(DC_PAPERNAMES, PWSTR::null());
+        if count > 0 {
+            let mut buf = vec![0u16; count as usize * PAPER_NAME_LEN];
+            if query(DC_PAPERNAMES, PWSTR(buf.as_mut_ptr())) > 0 {
+                let names = buf
+                    .chunks(PAPER_NAME_LEN)
+                    .map(|chunk| {
+                        let len = chunk.iter().position(|c| *c == 0).unwrap_or(chunk.len());
+                        String::from_utf16_lossy(&chunk[..len])
+                    })
+                    .filter(|n| !n.is_empty())
+                    .collect();
+                caps.media_sizes = Some(names);
+            }
+        }
+    }
+
+    caps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    const ZEBRA_LPOPTIONS: &str = "\
+PageSize/Media Size: w90h18 w108h36 w144h72 *w288h432 w288h288 Custom.WIDTHxHEIGHT
+Resolution/Resolution: *203dpi 300dpi
+zeMediaTracking/Media Tracking: Continuous *Web Mark
+zePrintRate/Print Rate: *Default 1 2 3 4 5 6
+Darkness/Darkness: -1 *Default 1 2 3
+";
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn zebra_ppd_reports_label_sizes_dpi_and_zpl() {
+        let caps = parse_lpoptions(ZEBRA_LPOPTIONS);
+        assert_eq!(caps.dpi, Some(203));
+        assert_eq!(caps.color, None);
+        assert_eq!(
+            caps.media_sizes.as_deref(),
+            Some(&["w90h18", "w108h36", "w144h72", "w288h432", "w288h288", "Custom.WIDTHxHEIGHT"].map(String::from)[..])
+        );
+        assert_eq!(caps.formats, ["pdf", "png", "jpg", "gif", "bmp", "zpl", "epl"]);
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn color_laser_ppd_reports_color_and_default_resolution() {
+        let caps = parse_lpoptions(
+            "PageSize/Media Size: *Letter Legal A4\n\
+             ColorModel/Color Mode: Gray *RGB\n\
+             Resolution/Output Resolution: 600x600dpi *1200x1200dpi\n",
+        );
+        assert_eq!(caps.color, Some(true));
+        assert_eq!(caps.dpi, Some(1200));
+        assert_eq!(caps.media_sizes.as_deref(), Some(&["Letter", "Legal", "A4"].map(String::from)[..]));
+        assert_eq!(caps.formats, RENDERED_FORMATS);
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn grayscale_ppd_is_not_color() {
+        assert_eq!(parse_lpoptions("ColorModel/Color Mode: *Gray\n").color, Some(false));
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn unlisted_options_stay_unknown() {
+        let caps = parse_lpoptions("Duplex/2-Sided Printing: *None DuplexNoTumble\n");
+        assert_eq!(caps.dpi, None);
+        assert_eq!(caps.color, None);
+        assert_eq!(caps.max_copies, None);
+        assert_eq!(caps.media_sizes, None);
+    }
+}
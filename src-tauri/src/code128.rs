@@ -0,0 +1,77 @@
+//! Code 128 barcodes, drawn by the connector itself for PDF labels
+
+/// Bar and space widths in modules for symbol values 0 to 105, bar first
+const PATTERNS: [&str; 106] = [
+    "212222", "222122", "222221", "121223", "121322", "131222", "122213", "122312", "132212", "221213",
+    "221312", "231212", "112232", "122132", "122231", "113222", "123122", "123221", "223211", "221132",
+    "221231", "213212", "223112", "312131", "311222", "321122", "321221", "312212", "322112", "322211",
+    "212123", "212321", "232121", "111323", "131123", "131321", "112313", "132113", "132311", "211313",
+    "231113", "231311", "112133", "112331", "132131", "113123", "113321", "133121", "313121", "211331",
+    "231131", "213113", "213311", "213131", "311123", "311321", "331121", "312113", "312311", "332111",
+    "314111", "221411", "431111", "111224", "111422", "121124", "121421", "141122", "141221", "112214",
+    "112412", "122114", "122411", "142112", "142211", "241211", "221114", "413111", "241112", "134111",
+    "111242", "121142", "121241", "114212", "124112", "124211", "411212", "421112", "421211", "212141",
+    "214121", "412121", "111143", "111341", "131141", "114113", "114311", "411113", "411311", "113141",
+    "114131", "311141", "411131", "211412", "211214", "211232",
+];
+
+/// Code set B covers printable ASCII, which is all label fields need
+const START_B: usize = 104;
+
+const STOP: &str = "2331112";
+
+/// Widths in modules of alternating bars and spaces, starting with a bar
+///
+/// Fails on characters outside printable ASCII, which code set B can't encode.
+pub fn encode(data: &str) -> Result<Vec<u8>, String> {
+    let values = data
+        .chars()
+        .map(|c| match c {
+            ' '..='~' => Ok(c as usize - ' ' as usize),
+            _ => Err(format!("Barcode can't contain '{}'", c.escape_default())),
+        })
+        .collect::<Result<Vec<usize>, String>>()?;
+    if values.is_empty() {
+        return Err("Barcode is empty".to_string());
+    }
+
+    let weighted: usize = values.iter().enumerate().map(|(i, v)| (i + 1) * v).sum();
+    let checksum = (START_B + weighted) % 103;
+
+    let symbols = std::iter::once(START_B).chain(values).chain(std::iter::once(checksum));
+    Ok(symbols
+        .map(|value| PATTERNS[value])
+        .chain(std::iter::once(STOP))
+        .flat_map(|pattern| pattern.bytes().map(|b| b - b'0'))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn widths(patterns: &[&str]) -> Vec<u8> {
+        patterns.concat().bytes().map(|b| b - b'0').collect()
+    }
+
+    #[test]
+    fn encodes_a_known_code_set_b_symbol() {
+        // Start B, 'A' (33), 'B' (34), 'C' (35), check (104 + 33 + 2*34 + 3*35) % 103 = 1, stop
+        let expected = widths(&["211214", "111323", "131123", "131321", "222122", "2331112"]);
+        assert_eq!(encode("ABC").unwrap(), expected);
+    }
+
+    #[test]
+    fn every_symbol_is_eleven_modules_and_the_stop_thirteen() {
+        let bars = encode("LS-0042").unwrap();
+        // Start, seven characters and the check character, then the stop pattern
+        assert_eq!(bars.iter().map(|&w| w as usize).sum::<usize>(), 9 * 11 + 13);
+        assert_eq!(bars.len(), 9 * 6 + 7);
+    }
+
+    #[test]
+    fn characters_outside_code_set_b_are_refused() {
+        assert!(encode("").is_err());
+        assert!(encode("café").unwrap_err().contains("\\u{e9}"));
+    }
+}
@@ -0,0 +1,418 @@
+use crate::protocol::PrintOptions;
+use crate::serial_scale::SerialScaleConfig;
+use crate::updater::UpdateChannel;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{OnceLock, RwLock};
+use std::time::Duration;
+
+const CONFIG_FILE: &str = "config.json";
+
+/// Editors often save in several writes; wait for them to settle before reloading
+const RELOAD_DELAY: Duration = Duration::from_millis(250);
+
+/// User settings persisted as JSON in the app data directory
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Config {
+    /// Printer pinned as the LimeStack default, overriding the OS default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_printer_id: Option<String>,
+    /// Extra origins allowed to connect, in addition to the built-in LimeStack ones
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_origins: Vec<String>,
+    /// Page opened by the tray's "Open LimeStack"; must be on an allowed origin
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub settings_url: Option<String>,
+    /// Tokens issued to browsers that completed pairing
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub paired_tokens: Vec<PairedToken>,
+    /// Refuse `hello` without a paired token instead of trusting the origin alone
+    #[serde(default)]
+    pub require_pairing: bool,
+    /// Printer pinned as the label printer for `get_default_thermal_printer`, whatever its name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label_printer_id: Option<String>,
+    /// Extra name keywords, matched case-insensitively, that mark a printer as thermal
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub thermal_keywords: Vec<String>,
+    /// Printer id -> name shown to LimeStack in place of the system's display name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub printer_nicknames: BTreeMap<String, String>,
+    /// Printer id -> options used when a print request doesn't set them
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub printer_defaults: BTreeMap<String, PrintOptions>,
+    /// Printer ids whose drivers print one copy whatever the copies option says; these
+    /// print one job per copy
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub ignores_copies: BTreeSet<String>,
+    /// Printer id -> device path or `host:port`; raw jobs for these bypass the spooler
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub raw_devices: BTreeMap<String, String>,
+    /// Directory for label files handed to the spooler; defaults to the system temp dir
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temp_dir: Option<PathBuf>,
+    /// Start-at-login choice from the tray; unset until the user changes it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_at_login: Option<bool>,
+    /// Serial scale to read instead of a USB HID scale
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial_scale: Option<SerialScaleConfig>,
+    /// Release channel the updater follows
+    #[serde(default)]
+    pub update_channel: UpdateChannel,
+    /// Desktop notification when a print fails; on unless set to false
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notify_print_failures: Option<bool>,
+    /// Desktop notification when a print succeeds
+    #[serde(default)]
+    pub notify_print_successes: bool,
+    /// List a `virtual-printer` that saves labels to files, for development without hardware
+    #[serde(default)]
+    pub virtual_printer: bool,
+    /// Check the print system and default printer at startup, warning in the tray on problems
+    #[serde(default)]
+    pub startup_self_test: bool,
+    /// Advertise the connector on the LAN over mDNS; off by default, as the server only
+    /// listens on loopback and peers couldn't connect anyway
+    #[serde(default)]
+    pub advertise_mdns: bool,
+    /// Send ZPL to api.labelary.com to render previews; off by default, as the label's
+    /// contents leave the machine
+    #[serde(default)]
+    pub labelary_previews: bool,
+    /// Seconds a print command may run before the request fails with a timeout
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub print_timeout_secs: Option<u64>,
+    /// Seconds without a message before a connection is closed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_timeout_secs: Option<u64>,
+    /// Prints each connection may submit a minute; 0 turns rate limiting off
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub print_rate_per_minute: Option<u32>,
+    /// Prints a connection may submit at once before the per-minute rate applies
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub print_burst: Option<u32>,
+}
+
+/// A browser paired through the tray, identified by its origin and issued token
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PairedToken {
+    pub origin: String,
+    pub token: String,
+    /// RFC 3339 timestamp of when pairing completed
+    pub paired_at: String,
+}
+
+struct ConfigStore {
+    path: PathBuf,
+    config: RwLock<Config>,
+}
+
+static STORE: OnceLock<ConfigStore> = OnceLock::new();
+
+/// Bumped whenever the settings change, so derived state knows to rebuild
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Load the config from the given directory; call once at startup
+pub fn init(dir: PathBuf) {
+    let path = dir.join(CONFIG_FILE);
+    let config = load(&path);
+    log::info!("Loaded config from {:?}", path);
+
+    if STORE.set(ConfigStore { path, config: RwLock::new(config) }).is_err() {
+        log::warn!("Config already initialized");
+    }
+}
+
+/// Current settings, or defaults if the config hasn't been initialized
+pub fn get() -> Config {
+    STORE
+        .get()
+        .map(|store| store.config.read().unwrap().clone())
+        .unwrap_or_default()
+}
+
+/// Modify the settings and persist them to disk
+pub fn update(f: impl FnOnce(&mut Config)) -> Result<(), String> {
+    let store = STORE.get().ok_or("Config not initialized")?;
+    let mut config = store.config.write().unwrap();
+    f(&mut config);
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+    save(&store.path, &config)
+}
+
+/// Changes every time the settings do, whether from `update` or a reloaded file
+pub fn generation() -> u64 {
+    GENERATION.load(Ordering::SeqCst)
+}
+
+/// Reload `config.json` whenever it's edited by hand, for the life of the app
+///
+/// An edit that doesn't parse is logged and ignored, keeping the last good settings.
+pub fn watch() -> Result<(), String> {
+    watch_store(STORE.get().ok_or("Config not initialized")?)
+}
+
+fn watch_store(store: &'static ConfigStore) -> Result<(), String> {
+    use notify::{RecursiveMode, Watcher};
+
+    // Watch the directory rather than the file, since editors often replace the file on save
+    let dir = store.path.parent().ok_or("Config file has no directory")?;
+    std::fs::create_dir_all(dir).map_err(|e| format!("Failed to create config dir: {}", e))?;
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx).map_err(|e| format!("Failed to watch config: {}", e))?;
+    watcher
+        .watch(dir, RecursiveMode::NonRecursive)
+        .map_err(|e| format!("Failed to watch {:?}: {}", dir, e))?;
+
+    std::thread::spawn(move || {
+        let _watcher = watcher;
+        while let Ok(event) = rx.recv() {
+            let touches_config = match event {
+                Ok(event) => event.paths.iter().any(|p| p.file_name() == Some(std::ffi::OsStr::new(CONFIG_FILE))),
+                Err(e) => {
+                    log::warn!("Config watch error: {}", e);
+                    false
+                }
+            };
+            if !touches_config {
+                continue;
+            }
+
+            std::thread::sleep(RELOAD_DELAY);
+            while rx.try_recv().is_ok() {}
+            reload(store);
+        }
+    });
+
+    log::info!("Watching {:?} for changes", store.path);
+    Ok(())
+}
+
+fn reload(store: &ConfigStore) {
+    let contents = match std::fs::read_to_string(&store.path) {
+        Ok(contents) => contents,
+        // Deleted or mid-replace; the settings in memory stay as they are
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+        Err(e) => {
+            log::warn!("Failed to read config file {:?}: {}", store.path, e);
+            return;
+        }
+    };
+    let reloaded: Config = match serde_json::from_str(&contents) {
+        Ok(config) => config,
+        Err(e) => {
+            log::warn!("Ignoring invalid edit to {:?}, keeping previous settings: {}", store.path, e);
+            return;
+        }
+    };
+
+    let mut config = store.config.write().unwrap();
+    let changed = changed_settings(&config, &reloaded);
+    // Our own saves come back through the watcher too; they change nothing
+    if changed.is_empty() {
+        return;
+    }
+    *config = reloaded;
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+    log::info!("Reloaded config, changed: {}", changed.join(", "));
+}
+
+/// Top-level settings that differ, by their `config.json` names
+fn changed_settings(old: &Config, new: &Config) -> Vec<String> {
+    let (Ok(serde_json::Value::Object(old)), Ok(serde_json::Value::Object(new))) =
+        (serde_json::to_value(old), serde_json::to_value(new))
+    else {
+        return Vec::new();
+    };
+
+    let keys: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    keys.into_iter()
+        .filter(|key| old.get(*key) != new.get(*key))
+        .cloned()
+        .collect()
+}
+
+fn load(path: &Path) -> Config {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+            log::warn!("Invalid config file {:?}, using defaults: {}", path, e);
+            Config::default()
+        }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Config::default(),
+        Err(e) => {
+            log::warn!("Failed to read config file {:?}: {}", path, e);
+            Config::default()
+        }
+    }
+}
+
+fn save(path: &Path, config: &Config) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| format!("Failed to create config dir: {}", e))?;
+    }
+
+    let json = serde_json::to_string_pretty(config).map_err(|e| format!("Failed to serialize config: {}", e))?;
+    std::fs::write(path, json).map_err(|e| format!("Failed to write config: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_round_trip_through_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CONFIG_FILE);
+        let config = Config {
+            default_printer_id: Some("Zebra_ZP450".to_string()),
+            require_pairing: true,
+            ..Config::default()
+        };
+
+        save(&path, &config).unwrap();
+        let loaded = load(&path);
+        assert_eq!(loaded.default_printer_id.as_deref(), Some("Zebra_ZP450"));
+        assert!(loaded.require_pairing);
+        assert!(std::fs::read_to_string(&path).unwrap().contains("\"defaultPrinterId\""));
+    }
+
+    #[test]
+    fn cleared_default_is_left_out_of_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CONFIG_FILE);
+
+        save(&path, &Config::default()).unwrap();
+        assert!(!std::fs::read_to_string(&path).unwrap().contains("defaultPrinterId"));
+        assert_eq!(load(&path).default_printer_id, None);
+    }
+
+    #[test]
+    fn missing_or_invalid_file_loads_defaults() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CONFIG_FILE);
+        assert_eq!(load(&path).default_printer_id, None);
+
+        std::fs::write(&path, "{ not json").unwrap();
+        assert_eq!(load(&path).default_printer_id, None);
+    }
+
+    #[test]
+    fn start_at_login_choice_is_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CONFIG_FILE);
+
+        // Unset until the user picks, and left out of the file
+        save(&path, &Config::default()).unwrap();
+        assert!(!std::fs::read_to_string(&path).unwrap().contains("startAtLogin"));
+        assert_eq!(load(&path).start_at_login, None);
+
+        for choice in [true, false] {
+            let config = Config {
+                start_at_login: Some(choice),
+                ..Config::default()
+            };
+            save(&path, &config).unwrap();
+            assert_eq!(load(&path).start_at_login, Some(choice));
+        }
+    }
+
+    #[test]
+    fn printer_defaults_are_read_from_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CONFIG_FILE);
+        std::fs::write(&path, r#"{ "printerDefaults": { "Zebra_ZP450": { "paperSize": "4x6", "scale": "actual" } } }"#).unwrap();
+
+        let loaded = load(&path);
+        let defaults = &loaded.printer_defaults["Zebra_ZP450"];
+        assert_eq!(defaults.paper_size.as_deref(), Some("4x6"));
+        assert_eq!(defaults.scale.as_deref(), Some("actual"));
+        assert!(!loaded.printer_defaults.contains_key("Office_Laser"));
+    }
+
+    /// A store for a config file in `dir` holding `config`
+    fn store_in(dir: &Path, config: Config) -> ConfigStore {
+        let path = dir.join(CONFIG_FILE);
+        save(&path, &config).unwrap();
+        ConfigStore {
+            path,
+            config: RwLock::new(config),
+        }
+    }
+
+    #[test]
+    fn edited_file_replaces_the_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = store_in(dir.path(), Config::default());
+        let before = generation();
+
+        std::fs::write(&store.path, r#"{ "allowedOrigins": ["https://shop.example.com"], "printRatePerMinute": 30 }"#).unwrap();
+        reload(&store);
+        let config = store.config.read().unwrap();
+        assert_eq!(config.allowed_origins, ["https://shop.example.com"]);
+        assert_eq!(config.print_rate_per_minute, Some(30));
+        assert!(generation() > before);
+    }
+
+    #[test]
+    fn invalid_edit_keeps_the_previous_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            allowed_origins: vec!["https://shop.example.com".to_string()],
+            print_rate_per_minute: Some(30),
+            ..Config::default()
+        };
+        let store = store_in(dir.path(), config);
+
+        for contents in ["{ not json", r#"{ "printRatePerMinute": "fast" }"#, r#"{ "allowedOrigins": "https://a.example" }"#] {
+            std::fs::write(&store.path, contents).unwrap();
+            reload(&store);
+            let config = store.config.read().unwrap();
+            assert_eq!(config.allowed_origins, ["https://shop.example.com"], "{}", contents);
+            assert_eq!(config.print_rate_per_minute, Some(30), "{}", contents);
+        }
+    }
+
+    #[test]
+    fn deleted_file_keeps_the_previous_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = store_in(dir.path(), Config { require_pairing: true, ..Config::default() });
+
+        std::fs::remove_file(&store.path).unwrap();
+        reload(&store);
+        assert!(store.config.read().unwrap().require_pairing);
+    }
+
+    #[test]
+    fn changed_settings_are_named_as_in_the_file() {
+        let old = Config::default();
+        let new = Config {
+            require_pairing: true,
+            print_burst: Some(2),
+            ..Config::default()
+        };
+        assert_eq!(changed_settings(&old, &new), ["printBurst", "requirePairing"]);
+        assert!(changed_settings(&new, &new.clone()).is_empty());
+    }
+
+    #[test]
+    fn watched_file_is_reloaded_when_edited() {
+        let dir = tempfile::tempdir().unwrap();
+        // The watcher thread runs for the rest of the test binary, so the store must too
+        let store: &'static ConfigStore = Box::leak(Box::new(store_in(dir.path(), Config::default())));
+        watch_store(store).unwrap();
+
+        std::fs::write(&store.path, r#"{ "requirePairing": true }"#).unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while !store.config.read().unwrap().require_pairing {
+            assert!(std::time::Instant::now() < deadline, "edit was not picked up");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
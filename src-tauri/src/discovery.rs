@@ -0,0 +1,96 @@
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+
+/// DNS-SD service type the connector advertises
+const SERVICE_TYPE: &str = "_limestack._tcp.local.";
+
+/// An mDNS advertisement of the WebSocket server, withdrawn when dropped
+pub struct Advertisement {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl Advertisement {
+    /// Advertise the server on `port`, with TXT records for the version and TLS support
+    pub fn start(port: u16, tls: bool, version: &str) -> Result<Self, String> {
+        let daemon = ServiceDaemon::new().map_err(|e| format!("Failed to start mDNS: {}", e))?;
+        let info = service_info(port, tls, version)?;
+        let fullname = info.get_fullname().to_string();
+
+        daemon
+            .register(info)
+            .map_err(|e| format!("Failed to register mDNS service: {}", e))?;
+        log::info!("Advertising {} on port {}", fullname, port);
+
+        Ok(Self { daemon, fullname })
+    }
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        if let Err(e) = self.daemon.unregister(&self.fullname) {
+            log::warn!("Failed to withdraw mDNS service: {}", e);
+        }
+        let _ = self.daemon.shutdown();
+    }
+}
+
+fn service_info(port: u16, tls: bool, version: &str) -> Result<ServiceInfo, String> {
+    let host = host_name();
+    let port_text = port.to_string();
+    let properties = [
+        ("version", version),
+        ("port", port_text.as_str()),
+        ("tls", if tls { "true" } else { "false" }),
+    ];
+
+    ServiceInfo::new(SERVICE_TYPE, &host, &format!("{}.local.", host), "", port, &properties[..])
+        .map(ServiceInfo::enable_addr_auto)
+        .map_err(|e| format!("Invalid mDNS service info: {}", e))
+}
+
+/// Machine name used for the instance and host, so several connectors on a LAN stay distinct
+fn host_name() -> String {
+    std::env::var("COMPUTERNAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .ok()
+        .map(|name| dns_label(&name))
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "limestack-connector".to_string())
+}
+
+/// Replace anything but letters, digits and '-' so the name is a valid DNS label
+fn dns_label(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '-' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn service_is_advertised_with_its_type_and_port() {
+        let info = service_info(9632, true, "1.4.0").unwrap();
+        assert_eq!(info.get_type(), SERVICE_TYPE);
+        assert_eq!(info.get_port(), 9632);
+        assert_eq!(info.get_fullname(), format!("{}.{}", host_name(), SERVICE_TYPE));
+        assert_eq!(info.get_hostname(), format!("{}.local.", host_name()));
+    }
+
+    #[test]
+    fn txt_records_carry_the_version_port_and_tls_flag() {
+        let info = service_info(9633, false, "1.4.0").unwrap();
+        assert_eq!(info.get_property_val_str("version"), Some("1.4.0"));
+        assert_eq!(info.get_property_val_str("port"), Some("9633"));
+        assert_eq!(info.get_property_val_str("tls"), Some("false"));
+        assert_eq!(service_info(9632, true, "1.4.0").unwrap().get_property_val_str("tls"), Some("true"));
+    }
+
+    #[test]
+    fn host_names_become_dns_labels() {
+        assert_eq!(dns_label("PACKING-PC-02"), "PACKING-PC-02");
+        assert_eq!(dns_label("Front Desk's iMac.local"), "Front-Desk-s-iMac-local");
+        assert_eq!(dns_label("Café"), "Caf-");
+    }
+}
@@ -0,0 +1,541 @@
+//! Dialog-free PDF printing on Windows through a GDI printer device context.
+//!
+//! Pages are rasterized at the printer's resolution with the built-in `Windows.Data.Pdf`
+//! renderer and drawn with `StretchDIBits`, so printing needs no external executable.
+//! SumatraPDF is still preferred when installed, since it sends vector output.
+
+use crate::printer::{JobOptions, Rotation, Scaling};
+use image::RgbImage;
+use std::path::Path;
+use windows::core::{Error, HSTRING, PCWSTR};
+use windows::Win32::Foundation::HWND;
+use windows::Win32::Graphics::Gdi::{
+    CreateDCW, DeleteDC, GetDeviceCaps, StretchDIBits, BITMAPINFO, BITMAPINFOHEADER, BI_RGB, DEVMODEW, DIB_RGB_COLORS,
+    DM_PAPERLENGTH, DM_PAPERSIZE, DM_PAPERWIDTH, HDC, HORZRES, LOGPIXELSX, LOGPIXELSY, SRCCOPY, VERTRES,
+};
+use windows::Win32::Graphics::Printing::{ClosePrinter, DocumentPropertiesW, OpenPrinterW, PRINTER_HANDLE};
+use windows::Win32::Storage::Xps::{AbortDoc, EndDoc, EndPage, StartDocW, StartPage, DOCINFOW};
+
+/// Document name shown in the Windows print queue
+const DOC_NAME: &str = "LimeStack Label";
+
+/// PDF page sizes are reported in device-independent pixels
+const DIPS_PER_INCH: f32 = 96.0;
+
+/// Printable area of the current page, in device pixels
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PageMetrics {
+    pub dpi_x: u32,
+    pub dpi_y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Where a page image lands on the printable area, in device pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Placement {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+/// The device calls a GDI job makes, so the job flow can run against a fake in tests
+pub trait GdiDevice {
+    fn metrics(&self) -> PageMetrics;
+    fn start_doc(&mut self, doc_name: &str) -> Result<(), String>;
+    fn start_page(&mut self) -> Result<(), String>;
+    fn draw(&mut self, image: &RgbImage, placement: Placement) -> Result<(), String>;
+    fn end_page(&mut self) -> Result<(), String>;
+    fn end_doc(&mut self) -> Result<(), String>;
+    fn abort_doc(&mut self);
+}
+
+/// Print a PDF to the named printer without showing any UI
+pub fn print_pdf(printer_name: &str, path: &Path, options: &JobOptions) -> Result<(), String> {
+    log::info!("Printing {:?} to '{}' via GDI ({} copies)", path, printer_name, options.copies);
+    let custom_paper = options.paper_size.filter(|p| p.is_custom()).map(|p| (p.width_in, p.height_in));
+    let mut device = WinGdiDevice::open(printer_name, custom_paper)?;
+    let mut pages = render_pdf_pages(path, device.metrics().dpi_x)?;
+    if let Some(page_range) = &options.page_range {
+        let mut page_number = 0;
+        pages.retain(|_| {
+            page_number += 1;
+            page_range.contains(page_number)
+        });
+    }
+    print_pages(&mut device, &pages, options)
+}
+
+/// Draw every page once per copy as a single document, aborting it on the first failure
+pub fn print_pages(device: &mut impl GdiDevice, pages: &[RgbImage], options: &JobOptions) -> Result<(), String> {
+    if pages.is_empty() {
+        return Err("The PDF has no pages to print".to_string());
+    }
+
+    let metrics = device.metrics();
+    device.start_doc(DOC_NAME)?;
+
+    for _ in 0..options.copies {
+        for page in pages {
+            let page = rotate(page, options.rotation);
+            let placement = page_placement(page.width(), page.height(), &metrics, options.scaling);
+            let drawn = device
+                .start_page()
+                .and_then(|_| device.draw(&page, placement))
+                .and_then(|_| device.end_page());
+            if let Err(e) = drawn {
+                device.abort_doc();
+                return Err(e);
+            }
+        }
+    }
+
+    device.end_doc()
+}
+
+/// Position a page rendered at `metrics.dpi_x` on the printable area
+///
+/// Pages are fitted and centered by default; actual size and percentages anchor at the
+/// top-left corner like a driver's "no scaling" option.
+pub fn page_placement(width: u32, height: u32, metrics: &PageMetrics, scaling: Option<Scaling>) -> Placement {
+    // Rendering uses the horizontal DPI, so correct the height for non-square printer pixels
+    let natural_width = width as f64;
+    let natural_height = height as f64 * metrics.dpi_y as f64 / metrics.dpi_x.max(1) as f64;
+
+    let (scale, centered) = match scaling.unwrap_or(Scaling::Fit) {
+        Scaling::Fit => {
+            let fit_x = metrics.width as f64 / natural_width.max(1.0);
+            let fit_y = metrics.height as f64 / natural_height.max(1.0);
+            (fit_x.min(fit_y), true)
+        }
+        Scaling::Actual => (1.0, false),
+        Scaling::Percent(percent) => (percent as f64 / 100.0, false),
+    };
+
+    let placed_width = (natural_width * scale).round() as i32;
+    let placed_height = (natural_height * scale).round() as i32;
+    let (x, y) = if centered {
+        ((metrics.width as i32 - placed_width) / 2, (metrics.height as i32 - placed_height) / 2)
+    } else {
+        (0, 0)
+    };
+
+    Placement {
+        x,
+        y,
+        width: placed_width,
+        height: placed_height,
+    }
+}
+
+fn rotate(page: &RgbImage, rotation: Rotation) -> RgbImage {
+    match rotation {
+        Rotation::None => page.clone(),
+        Rotation::Quarter => image::imageops::rotate90(page),
+        Rotation::Half => image::imageops::rotate180(page),
+        Rotation::ThreeQuarter => image::imageops::rotate270(page),
+    }
+}
+
+/// Whether the Windows PDF renderer can be activated (it ships with Windows 8.1 and later)
+pub fn renderer_available() -> bool {
+    use windows::Data::Pdf::PdfPageRenderOptions;
+    use windows::Win32::System::WinRT::{RoInitialize, RO_INIT_MULTITHREADED};
+
+    let _ = unsafe { RoInitialize(RO_INIT_MULTITHREADED) };
+    PdfPageRenderOptions::new().is_ok()
+}
+
+/// Rasterize every page of a PDF with the Windows PDF renderer
+fn render_pdf_pages(path: &Path, dpi: u32) -> Result<Vec<RgbImage>, String> {
+    use windows::Data::Pdf::{PdfDocument, PdfPageRenderOptions};
+    use windows::Storage::StorageFile;
+    use windows::Storage::Streams::{DataReader, InMemoryRandomAccessStream};
+    use windows::Win32::System::WinRT::{RoInitialize, RO_INIT_MULTITHREADED};
+
+    let failed = |e: Error| format!("Failed to render PDF: {}", e);
+
+    // Print jobs run on blocking threads, which may not have joined an apartment yet
+    let _ = unsafe { RoInitialize(RO_INIT_MULTITHREADED) };
+
+    let file = StorageFile::GetFileFromPathAsync(&HSTRING::from(path))
+        .and_then(|op| op.get())
+        .map_err(failed)?;
+    let document = PdfDocument::LoadFromFileAsync(&file).and_then(|op| op.get()).map_err(failed)?;
+    let scale = dpi as f32 / DIPS_PER_INCH;
+
+    (0..document.PageCount().map_err(failed)?)
+        .map(|index| {
+            let page = document.GetPage(index).map_err(failed)?;
+            let size = page.Size().map_err(failed)?;
+
+            let render_options = PdfPageRenderOptions::new().map_err(failed)?;
+            render_options
+                .SetDestinationWidth((size.Width * scale).round() as u32)
+                .map_err(failed)?;
+            render_options
+                .SetDestinationHeight((size.Height * scale).round() as u32)
+                .map_err(failed)?;
+
+            let stream = InMemoryRandomAccessStream::new().map_err(failed)?;
+            page.RenderWithOptionsToStreamAsync(&stream, &render_options)
+                .and_then(|op| op.get())
+                .map_err(failed)?;
+
+            let len = stream.Size().map_err(failed)? as u32;
+            let reader = DataReader::CreateDataReader(&stream.GetInputStreamAt(0).map_err(failed)?).map_err(failed)?;
+            reader.LoadAsync(len).and_then(|op| op.get()).map_err(failed)?;
+            let mut encoded = vec![0u8; len as usize];
+            reader.ReadBytes(&mut encoded).map_err(failed)?;
+
+            image::load_from_memory(&encoded)
+                .map(|page| page.to_rgb8())
+                .map_err(|e| format!("Failed to decode rendered page: {}", e))
+        })
+        .collect()
+}
+
+/// A printer device context
+struct WinGdiDevice {
+    hdc: HDC,
+}
+
+impl WinGdiDevice {
+    /// Open the printer with its driver defaults, or with a custom paper size in inches
+    fn open(printer_name: &str, paper_inches: Option<(f32, f32)>) -> Result<Self, String> {
+        let devmode = paper_inches
+            .map(|(width, height)| custom_paper_devmode(printer_name, width, height))
+            .transpose()?;
+        let devmode_ptr = devmode.as_ref().map(|buffer| buffer.as_ptr() as *const DEVMODEW);
+        let hdc = unsafe { CreateDCW(PCWSTR::null(), &HSTRING::from(printer_name), PCWSTR::null(), devmode_ptr) };
+        if hdc.is_invalid() {
+            return Err(format!("Failed to open printer '{}': {}", printer_name, Error::from_win32()));
+        }
+        Ok(Self { hdc })
+    }
+}
+
+/// The printer's default DEVMODE with a user-defined paper size, merged by the driver
+///
+/// Returned as `u64`s so the buffer is aligned for DEVMODEW, whose driver-private data
+/// makes it larger than the struct.
+fn custom_paper_devmode(printer_name: &str, width_in: f32, height_in: f32) -> Result<Vec<u64>, String> {
+    const DM_OUT_BUFFER: u32 = 2;
+    const DM_IN_BUFFER: u32 = 8;
+    const DMPAPER_USER: i16 = 256;
+
+    let name = HSTRING::from(printer_name);
+    let mut printer = PRINTER_HANDLE::default();
+    unsafe { OpenPrinterW(&name, &mut printer, None) }.map_err(|e| format!("Failed to open printer: {}", e))?;
+
+    let result = unsafe {
+        // First call reports the buffer size needed
+        let size = DocumentPropertiesW(HWND::default(), printer, &name, None, None, 0);
+        if size <= 0 {
+            Err(format!("Failed to read printer settings: {}", Error::from_win32()))
+        } else {
+            let mut buffer = vec![0u64; (size as usize).div_ceil(8)];
+            let devmode = buffer.as_mut_ptr() as *mut DEVMODEW;
+            if DocumentPropertiesW(HWND::default(), printer, &name, Some(devmode), None, DM_OUT_BUFFER) < 0 {
+                Err(format!("Failed to read printer settings: {}", Error::from_win32()))
+            } else {
+                // DEVMODE measures paper in tenths of a millimetre
+                let tenths_mm = |inches: f32| (inches * 254.0).round() as i16;
+                (*devmode).dmFields |= DM_PAPERSIZE | DM_PAPERWIDTH | DM_PAPERLENGTH;
+                (*devmode).Anonymous1.Anonymous1.dmPaperSize = DMPAPER_USER;
+                (*devmode).Anonymous1.Anonymous1.dmPaperWidth = tenths_mm(width_in);
+                (*devmode).Anonymous1.Anonymous1.dmPaperLength = tenths_mm(height_in);
+
+                // Let the driver check the size and update its private settings to match
+                let mode = DM_IN_BUFFER | DM_OUT_BUFFER;
+                if DocumentPropertiesW(HWND::default(), printer, &name, Some(devmode), Some(devmode), mode) < 0 {
+                    Err(format!("Printer rejected the custom paper size: {}", Error::from_win32()))
+                } else {
+                    Ok(buffer)
+                }
+            }
+        }
+    };
+    let _ = unsafe { ClosePrinter(printer) };
+    result
+}
+
+impl Drop for WinGdiDevice {
+    fn drop(&mut self) {
+        let _ = unsafe { DeleteDC(self.hdc) };
+    }
+}
+
+impl GdiDevice for WinGdiDevice {
+    fn metrics(&self) -> PageMetrics {
+        let caps = |index| unsafe { GetDeviceCaps(self.hdc, index) }.max(0) as u32;
+        PageMetrics {
+            dpi_x: caps(LOGPIXELSX),
+            dpi_y: caps(LOGPIXELSY),
+            width: caps(HORZRES),
+            height: caps(VERTRES),
+        }
+    }
+
+    fn start_doc(&mut self, doc_name: &str) -> Result<(), String> {
+        let doc_name = HSTRING::from(doc_name);
+        let doc_info = DOCINFOW {
+            cbSize: std::mem::size_of::<DOCINFOW>() as i32,
+            lpszDocName: PCWSTR(doc_name.as_ptr()),
+            ..Default::default()
+        };
+        if unsafe { StartDocW(self.hdc, &doc_info) } <= 0 {
+            return Err(format!("StartDoc failed: {}", Error::from_win32()));
+        }
+        Ok(())
+    }
+
+    fn start_page(&mut self) -> Result<(), String> {
+        if unsafe { StartPage(self.hdc) } <= 0 {
+            return Err(format!("StartPage failed: {}", Error::from_win32()));
+        }
+        Ok(())
+    }
+
+    fn draw(&mut self, image: &RgbImage, placement: Placement) -> Result<(), String> {
+        let (width, height) = image.dimensions();
+        let bits = to_dib_rows(image);
+        let info = BITMAPINFO {
+            bmiHeader: BITMAPINFOHEADER {
+                biSize: std::mem::size_of::<BITMAPINFOHEADER>() as u32,
+                biWidth: width as i32,
+                // Negative height marks the rows as top-down
+                biHeight: -(height as i32),
+                biPlanes: 1,
+                biBitCount: 24,
+                biCompression: BI_RGB.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let lines = unsafe {
+            StretchDIBits(
+                self.hdc,
+                placement.x,
+                placement.y,
+                placement.width,
+                placement.height,
+                0,
+                0,
+                width as i32,
+                height as i32,
+                Some(bits.as_ptr() as *const _),
+                &info,
+                DIB_RGB_COLORS,
+                SRCCOPY,
+            )
+        };
+        if lines == 0 {
+            return Err(format!("StretchDIBits failed: {}", Error::from_win32()));
+        }
+        Ok(())
+    }
+
+    fn end_page(&mut self) -> Result<(), String> {
+        if unsafe { EndPage(self.hdc) } <= 0 {
+            return Err(format!("EndPage failed: {}", Error::from_win32()));
+        }
+        Ok(())
+    }
+
+    fn end_doc(&mut self) -> Result<(), String> {
+        if unsafe { EndDoc(self.hdc) } <= 0 {
+            return Err(format!("EndDoc failed: {}", Error::from_win32()));
+        }
+        Ok(())
+    }
+
+    fn abort_doc(&mut self) {
+        let _ = unsafe { AbortDoc(self.hdc) };
+    }
+}
+
+/// 24-bit DIB rows are BGR and padded to a multiple of four bytes
+fn to_dib_rows(image: &RgbImage) -> Vec<u8> {
+    let row_len = (image.width() as usize * 3).div_ceil(4) * 4;
+    let mut bits = Vec::with_capacity(row_len * image.height() as usize);
+    for row in image.rows() {
+        let start = bits.len();
+        for pixel in row {
+            let [r, g, b] = pixel.0;
+            bits.extend_from_slice(&[b, g, r]);
+        }
+        bits.resize(start + row_len, 0);
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 4x6 inch label printer at 203 dpi with the whole label printable
+    const LABEL_4X6_203: PageMetrics = PageMetrics {
+        dpi_x: 203,
+        dpi_y: 203,
+        width: 812,
+        height: 1218,
+    };
+
+    fn placed(x: i32, y: i32, width: i32, height: i32) -> Placement {
+        Placement { x, y, width, height }
+    }
+
+    #[test]
+    fn full_size_4x6_page_fills_the_label() {
+        assert_eq!(page_placement(812, 1218, &LABEL_4X6_203, None), placed(0, 0, 812, 1218));
+        assert_eq!(page_placement(812, 1218, &LABEL_4X6_203, Some(Scaling::Actual)), placed(0, 0, 812, 1218));
+    }
+
+    #[test]
+    fn smaller_page_is_fitted_and_centered_on_4x6() {
+        // A 2x2 inch page grows to the label's width and sits in the middle
+        assert_eq!(page_placement(406, 406, &LABEL_4X6_203, None), placed(0, 203, 812, 812));
+        // Letter-sized pages shrink to fit the label's width
+        assert_eq!(
+            page_placement(1726, 2233, &LABEL_4X6_203, Some(Scaling::Fit)),
+            placed(0, 83, 812, 1051)
+        );
+    }
+
+    #[test]
+    fn actual_size_and_percentages_anchor_at_the_corner() {
+        assert_eq!(page_placement(406, 406, &LABEL_4X6_203, Some(Scaling::Actual)), placed(0, 0, 406, 406));
+        assert_eq!(page_placement(812, 1218, &LABEL_4X6_203, Some(Scaling::Percent(50))), placed(0, 0, 406, 609));
+    }
+
+    #[test]
+    fn non_square_printer_pixels_are_corrected() {
+        let metrics = PageMetrics {
+            dpi_x: 203,
+            dpi_y: 406,
+            width: 812,
+            height: 2436,
+        };
+        // Rendered at 203 dpi, so each row covers two device rows
+        assert_eq!(page_placement(812, 1218, &metrics, Some(Scaling::Actual)), placed(0, 0, 812, 2436));
+        assert_eq!(page_placement(812, 1218, &metrics, None), placed(0, 0, 812, 2436));
+    }
+
+    /// Records the calls a job makes, failing the page numbered `fail_on_page` when set
+    #[derive(Default)]
+    struct FakeDevice {
+        calls: Vec<String>,
+        fail_on_page: Option<usize>,
+        pages_started: usize,
+    }
+
+    impl GdiDevice for FakeDevice {
+        fn metrics(&self) -> PageMetrics {
+            LABEL_4X6_203
+        }
+
+        fn start_doc(&mut self, doc_name: &str) -> Result<(), String> {
+            self.calls.push(format!("start_doc {}", doc_name));
+            Ok(())
+        }
+
+        fn start_page(&mut self) -> Result<(), String> {
+            self.pages_started += 1;
+            self.calls.push("start_page".to_string());
+            Ok(())
+        }
+
+        fn draw(&mut self, image: &RgbImage, placement: Placement) -> Result<(), String> {
+            self.calls.push(format!(
+                "draw {}x{} at {},{} as {}x{}",
+                image.width(),
+                image.height(),
+                placement.x,
+                placement.y,
+                placement.width,
+                placement.height
+            ));
+            if self.fail_on_page == Some(self.pages_started) {
+                return Err("Printer out of paper".to_string());
+            }
+            Ok(())
+        }
+
+        fn end_page(&mut self) -> Result<(), String> {
+            self.calls.push("end_page".to_string());
+            Ok(())
+        }
+
+        fn end_doc(&mut self) -> Result<(), String> {
+            self.calls.push("end_doc".to_string());
+            Ok(())
+        }
+
+        fn abort_doc(&mut self) {
+            self.calls.push("abort_doc".to_string());
+        }
+    }
+
+    #[test]
+    fn each_copy_prints_every_page_in_one_document() {
+        let mut device = FakeDevice::default();
+        let pages = [RgbImage::new(812, 1218), RgbImage::new(406, 406)];
+        let options = JobOptions {
+            copies: 2,
+            ..JobOptions::default()
+        };
+
+        print_pages(&mut device, &pages, &options).unwrap();
+        let page_calls = [
+            "start_page",
+            "draw 812x1218 at 0,0 as 812x1218",
+            "end_page",
+            "start_page",
+            "draw 406x406 at 0,203 as 812x812",
+            "end_page",
+        ];
+        let expected: Vec<String> = std::iter::once(format!("start_doc {}", DOC_NAME))
+            .chain(page_calls.iter().chain(page_calls.iter()).map(|c| c.to_string()))
+            .chain(std::iter::once("end_doc".to_string()))
+            .collect();
+        assert_eq!(device.calls, expected);
+    }
+
+    #[test]
+    fn rotated_pages_are_placed_after_rotating() {
+        let mut device = FakeDevice::default();
+        let options = JobOptions {
+            rotation: Rotation::Quarter,
+            ..JobOptions::default()
+        };
+
+        print_pages(&mut device, &[RgbImage::new(1218, 812)], &options).unwrap();
+        assert!(device.calls.contains(&"draw 812x1218 at 0,0 as 812x1218".to_string()), "{:?}", device.calls);
+    }
+
+    #[test]
+    fn failed_page_aborts_the_document() {
+        let mut device = FakeDevice {
+            fail_on_page: Some(2),
+            ..FakeDevice::default()
+        };
+        let pages = [RgbImage::new(812, 1218), RgbImage::new(812, 1218), RgbImage::new(812, 1218)];
+
+        let err = print_pages(&mut device, &pages, &JobOptions::default()).unwrap_err();
+        assert_eq!(err, "Printer out of paper");
+        assert_eq!(device.pages_started, 2);
+        assert_eq!(device.calls.last().map(String::as_str), Some("abort_doc"));
+        assert!(!device.calls.contains(&"end_doc".to_string()));
+    }
+
+    #[test]
+    fn document_without_pages_is_refused() {
+        let mut device = FakeDevice::default();
+        let err = print_pages(&mut device, &[], &JobOptions::default()).unwrap_err();
+        assert_eq!(err, "The PDF has no pages to print");
+        assert!(device.calls.is_empty());
+    }
+}
@@ -0,0 +1,147 @@
+//! Server-only mode, started with `--headless` (or `--no-tray`): no tray, updater or desktop
+//! notifications, and logs go to stdout. For scripting, protocol testing and Linux machines
+//! without a desktop session.
+
+use crate::config;
+use crate::logging;
+use crate::protocol::ShutdownReason;
+use crate::server::{self, ServerShutdown, ServerStatus};
+use std::future::Future;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Flags that select headless mode
+const HEADLESS_FLAGS: &[&str] = &["--headless", "--no-tray"];
+
+/// The bundle identifier from tauri.conf.json, so headless and tray runs share `config.json`
+/// and the TLS certificate
+const APP_IDENTIFIER: &str = "io.limestack.connector";
+
+/// Whether the connector was started with a headless flag
+pub fn requested() -> bool {
+    requested_in(std::env::args().skip(1))
+}
+
+fn requested_in(args: impl IntoIterator<Item = String>) -> bool {
+    args.into_iter().any(|arg| HEADLESS_FLAGS.contains(&arg.as_str()))
+}
+
+/// Run the WebSocket server until Ctrl+C, exiting with status 1 if it can't start
+pub fn run() {
+    logging::init_stdout();
+
+    let data_dir = data_dir();
+    match &data_dir {
+        Some(dir) => {
+            config::init(dir.clone());
+            if let Err(e) = config::watch() {
+                log::warn!("Config changes need a restart: {}", e);
+            }
+        }
+        None => log::warn!("No app data dir, running with default settings"),
+    }
+
+    let runtime = tokio::runtime::Runtime::new().expect("failed to start the async runtime");
+    let (status_tx, status_rx) = watch::channel(ServerStatus::default());
+
+    runtime.block_on(serve_until(data_dir, status_tx, async {
+        let _ = tokio::signal::ctrl_c().await;
+    }));
+    // Don't let a hung print command hold up exit
+    runtime.shutdown_timeout(Duration::from_secs(1));
+
+    if let Some(error) = &status_rx.borrow().error {
+        log::error!("Server failed to start: {}", error);
+        std::process::exit(1);
+    }
+}
+
+/// Run the server until `stop` resolves, then shut it down as quitting the tray app would
+async fn serve_until(data_dir: Option<PathBuf>, status: watch::Sender<ServerStatus>, stop: impl Future<Output = ()>) {
+    let (shutdown, shutdown_rx, stopped_tx) = ServerShutdown::new();
+    let server = tokio::spawn(async move {
+        server::start_server(data_dir, status, shutdown_rx).await;
+        drop(stopped_tx);
+    });
+    log::info!("LimeStack Connector started in headless mode");
+
+    tokio::select! {
+        // Returns by itself only when the server couldn't start
+        _ = server => {}
+        _ = stop => shutdown.shutdown(ShutdownReason::Quit).await,
+    }
+}
+
+/// The directory the tray app uses, overridable with `LIMESTACK_DATA_DIR`
+fn data_dir() -> Option<PathBuf> {
+    match std::env::var_os("LIMESTACK_DATA_DIR") {
+        Some(dir) => Some(PathBuf::from(dir)),
+        None => dirs::data_dir().map(|dir| dir.join(APP_IDENTIFIER)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::net::TcpStream;
+    use tokio::sync::oneshot;
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::Message;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn headless_flags_are_recognised() {
+        assert!(requested_in(args(&["--headless"])));
+        assert!(requested_in(args(&["--minimized", "--no-tray"])));
+        assert!(!requested_in(args(&[])));
+        assert!(!requested_in(args(&["--minimized"])));
+        assert!(!requested_in(args(&["headless"])));
+    }
+
+    #[tokio::test]
+    async fn headless_server_accepts_a_client() {
+        let (status_tx, mut status) = watch::channel(ServerStatus::default());
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve_until(None, status_tx, async {
+            let _ = stop_rx.await;
+        }));
+
+        let addr = format!("127.0.0.1:{}", server::SERVER_PORT);
+        let stream = loop {
+            if let Ok(stream) = TcpStream::connect(&addr).await {
+                break stream;
+            }
+            if status.borrow_and_update().error.is_some() || server.is_finished() {
+                // Another connector already has the port, so there's nothing of ours to connect to
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        };
+
+        let mut request = format!("ws://{}/", addr).into_client_request().unwrap();
+        request.headers_mut().insert("origin", "https://app.limestack.io".parse().unwrap());
+        let (mut ws, _) = tokio_tungstenite::client_async(request, stream).await.unwrap();
+        let hello = serde_json::json!({ "type": "hello", "version": "1.0", "origin": "https://app.limestack.io" });
+        ws.send(Message::Text(hello.to_string())).await.unwrap();
+        let welcome = loop {
+            match tokio::time::timeout(Duration::from_secs(5), ws.next()).await.expect("no welcome") {
+                Some(Ok(Message::Text(text))) => break serde_json::from_str::<serde_json::Value>(&text).unwrap(),
+                Some(Ok(_)) => continue,
+                other => panic!("connection ended: {:?}", other),
+            }
+        };
+        assert_eq!(welcome["type"], "welcome", "{}", welcome);
+
+        stop_tx.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(10), server)
+            .await
+            .expect("server did not stop")
+            .unwrap();
+        assert_eq!(status.borrow().error, None);
+    }
+}
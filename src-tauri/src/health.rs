@@ -0,0 +1,161 @@
+//! Plain HTTP `GET /health` on the WebSocket port, for monitoring tools that don't speak
+//! the protocol. Served before the WebSocket upgrade and only on the loopback listeners.
+
+use serde::Serialize;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const HEALTH_PATH: &str = "/health";
+
+/// Request headers beyond this are ignored; a health probe sends a handful of lines
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+
+/// How long a probe gets to finish sending its request
+const READ_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthReport {
+    pub status: &'static str,
+    pub connector_version: &'static str,
+    pub uptime_seconds: u64,
+    /// Authenticated WebSocket clients
+    pub connected_clients: usize,
+    pub printer_count: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enumeration_error: Option<String>,
+    /// False while connections are paused from the tray
+    pub accepting_connections: bool,
+    pub listeners: Listeners,
+}
+
+/// Which loopback addresses the server is bound to
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Listeners {
+    pub ipv4: bool,
+    pub ipv6: bool,
+}
+
+/// Whether a plain connection opens with a health request rather than a WebSocket upgrade
+pub async fn is_health_request(stream: &TcpStream) -> bool {
+    let request_line = format!("GET {}", HEALTH_PATH);
+    let mut start = [0u8; 16];
+    match stream.peek(&mut start).await {
+        Ok(n) if n > request_line.len() => {
+            start.starts_with(request_line.as_bytes()) && matches!(start[request_line.len()], b' ' | b'?')
+        }
+        _ => false,
+    }
+}
+
+/// Read the rest of the request and answer it with the report as JSON
+pub async fn respond(mut stream: TcpStream, report: HealthReport) {
+    // Drain the headers first, since closing with unread input resets the connection
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    let drained = tokio::time::timeout(READ_TIMEOUT, async {
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_BYTES {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => request.extend_from_slice(&buf[..n]),
+            }
+        }
+    })
+    .await;
+    if drained.is_err() {
+        log::debug!("Health probe didn't finish its request in time");
+        return;
+    }
+
+    let body = serde_json::to_string(&report).unwrap();
+    if let Err(e) = write_json(&mut stream, &body).await {
+        log::debug!("Failed to answer health probe: {}", e);
+    }
+}
+
+async fn write_json(stream: &mut (impl AsyncWrite + Unpin), body: &str) -> std::io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// The server's end of a connection whose client has already sent `request`
+    async fn connection_with(request: &'static str) -> (TcpStream, tokio::task::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = Vec::new();
+            let _ = stream.read_to_end(&mut response).await;
+            response
+        });
+        let (stream, _) = listener.accept().await.unwrap();
+        // Wait for the request to arrive, as the accept loop would before peeking
+        let mut start = [0u8; 1];
+        stream.peek(&mut start).await.unwrap();
+        (stream, client)
+    }
+
+    fn report() -> HealthReport {
+        HealthReport {
+            status: "ok",
+            connector_version: "1.2.3",
+            uptime_seconds: 42,
+            connected_clients: 2,
+            printer_count: 3,
+            enumeration_error: None,
+            accepting_connections: true,
+            listeners: Listeners { ipv4: true, ipv6: false },
+        }
+    }
+
+    #[tokio::test]
+    async fn health_requests_are_told_apart_from_upgrades() {
+        for (request, expected) in [
+            ("GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n", true),
+            ("GET /health?verbose=1 HTTP/1.1\r\n\r\n", true),
+            ("GET /healthz HTTP/1.1\r\n\r\n", false),
+            ("GET / HTTP/1.1\r\nUpgrade: websocket\r\n\r\n", false),
+        ] {
+            let (stream, _) = connection_with(request).await;
+            assert_eq!(is_health_request(&stream).await, expected, "{:?}", request);
+        }
+    }
+
+    #[tokio::test]
+    async fn health_report_is_served_as_json() {
+        let (stream, client) = connection_with("GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        respond(stream, report()).await;
+
+        let response = String::from_utf8(client.await.unwrap()).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        assert!(head.contains("Content-Type: application/json"), "{}", head);
+        assert!(head.contains(&format!("Content-Length: {}", body.len())), "{}", head);
+
+        let json: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "status": "ok",
+                "connectorVersion": "1.2.3",
+                "uptimeSeconds": 42,
+                "connectedClients": 2,
+                "printerCount": 3,
+                "acceptingConnections": true,
+                "listeners": { "ipv4": true, "ipv6": false },
+            })
+        );
+    }
+}
@@ -0,0 +1,161 @@
+use flate2::{write::ZlibEncoder, Compression};
+use std::io::Write;
+
+/// Points per inch in PDF user space
+const POINTS_PER_INCH: f32 = 72.0;
+
+/// Page size used when the print request doesn't name a paper size (4x6 inches)
+const DEFAULT_PAGE_INCHES: (f32, f32) = (4.0, 6.0);
+
+/// Wrap a PNG or JPEG in a one-page PDF, scaled to fit the page and centered
+///
+/// Drivers handle PDFs far more consistently than bare images. Pixels are stored
+/// losslessly so barcodes stay sharp.
+pub fn image_to_pdf(data: &[u8], page_inches: Option<(f32, f32)>) -> Result<Vec<u8>, String> {
+    let image = image::load_from_memory(data)
+        .map_err(|e| format!("Failed to decode image: {}", e))?
+        .to_rgb8();
+    let (pixel_width, pixel_height) = image.dimensions();
+
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(image.as_raw())
+        .map_err(|e| format!("Failed to compress image: {}", e))?;
+    let pixels = encoder
+        .finish()
+        .map_err(|e| format!("Failed to compress image: {}", e))?;
+
+    let (page_width_in, page_height_in) = page_inches.unwrap_or(DEFAULT_PAGE_INCHES);
+    let page_width = page_width_in * POINTS_PER_INCH;
+    let page_height = page_height_in * POINTS_PER_INCH;
+
+    // Fit inside the page keeping the aspect ratio, then center
+    let scale = (page_width / pixel_width as f32).min(page_height / pixel_height as f32);
+    let draw_width = pixel_width as f32 * scale;
+    let draw_height = pixel_height as f32 * scale;
+    let x = (page_width - draw_width) / 2.0;
+    let y = (page_height - draw_height) / 2.0;
+    let content = format!(
+        "q\n{:.2} 0 0 {:.2} {:.2} {:.2} cm\n/Im0 Do\nQ\n",
+        draw_width, draw_height, x, y
+    );
+
+    let objects: [Vec<u8>; 5] = [
+        b"<< /Type /Catalog /Pages 2 0 R >>".to_vec(),
+        b"<< /Type /Pages /Kids [3 0 R] /Count 1 >>".to_vec(),
+        format!(
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {:.2} {:.2}] /Resources << /XObject << /Im0 5 0 R >> >> /Contents 4 0 R >>",
+            page_width, page_height
+        )
+        .into_bytes(),
+        format!("<< /Length {} >>\nstream\n{}endstream", content.len(), content).into_bytes(),
+        [
+            format!(
+                "<< /Type /XObject /Subtype /Image /Width {} /Height {} /ColorSpace /DeviceRGB /BitsPerComponent 8 /Filter /FlateDecode /Length {} >>\nstream\n",
+                pixel_width,
+                pixel_height,
+                pixels.len()
+            )
+            .into_bytes(),
+            pixels,
+            b"\nendstream".to_vec(),
+        ]
+        .concat(),
+    ];
+
+    let mut pdf = b"%PDF-1.4\n".to_vec();
+    let mut offsets = Vec::with_capacity(objects.len());
+    for (i, object) in objects.iter().enumerate() {
+        offsets.push(pdf.len());
+        pdf.extend_from_slice(format!("{} 0 obj\n", i + 1).as_bytes());
+        pdf.extend_from_slice(object);
+        pdf.extend_from_slice(b"\nendobj\n");
+    }
+
+    let xref_offset = pdf.len();
+    let mut trailer = format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1);
+    for offset in offsets {
+        trailer.push_str(&format!("{:010} 00000 n \n", offset));
+    }
+    trailer.push_str(&format!(
+        "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+        objects.len() + 1,
+        xref_offset
+    ));
+    pdf.extend_from_slice(trailer.as_bytes());
+
+    Ok(pdf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, ImageFormat};
+    use std::io::Cursor;
+
+    fn encoded(width: u32, height: u32, format: ImageFormat) -> Vec<u8> {
+        let mut data = Vec::new();
+        DynamicImage::new_rgb8(width, height)
+            .write_to(&mut Cursor::new(&mut data), format)
+            .unwrap();
+        data
+    }
+
+    fn contains(pdf: &[u8], needle: &str) -> bool {
+        pdf.windows(needle.len()).any(|w| w == needle.as_bytes())
+    }
+
+    /// Check the cross-reference table points at each object, returning how many there are
+    fn check_xref(pdf: &[u8]) -> usize {
+        let tail = std::str::from_utf8(&pdf[pdf.len() - 64..]).unwrap();
+        let start: usize = tail.split("startxref\n").nth(1).unwrap().lines().next().unwrap().parse().unwrap();
+        let table = std::str::from_utf8(&pdf[start..]).unwrap();
+        let mut lines = table.lines();
+        assert_eq!(lines.next(), Some("xref"));
+        let count: usize = lines.next().unwrap().trim_start_matches("0 ").parse().unwrap();
+        assert_eq!(lines.next(), Some("0000000000 65535 f "));
+        for object in 1..count {
+            let offset: usize = lines.next().unwrap()[..10].parse().unwrap();
+            assert!(pdf[offset..].starts_with(format!("{} 0 obj\n", object).as_bytes()), "object {}", object);
+        }
+        count - 1
+    }
+
+    #[test]
+    fn png_becomes_a_single_page_pdf() {
+        let pdf = image_to_pdf(&encoded(200, 100, ImageFormat::Png), None).unwrap();
+        assert!(pdf.starts_with(b"%PDF-1.4\n"));
+        assert!(pdf.ends_with(b"%%EOF\n"));
+        assert_eq!(check_xref(&pdf), 5);
+        assert!(contains(&pdf, "/Count 1"));
+        // 4x6 inches when no paper size is given
+        assert!(contains(&pdf, "/MediaBox [0 0 288.00 432.00]"));
+        assert!(contains(&pdf, "/Width 200 /Height 100"));
+    }
+
+    #[test]
+    fn jpeg_becomes_a_single_page_pdf_of_the_paper_size() {
+        let pdf = image_to_pdf(&encoded(300, 150, ImageFormat::Jpeg), Some((2.0, 1.0))).unwrap();
+        assert_eq!(check_xref(&pdf), 5);
+        assert!(contains(&pdf, "/Count 1"));
+        assert!(contains(&pdf, "/MediaBox [0 0 144.00 72.00]"));
+        assert!(contains(&pdf, "/Width 300 /Height 150"));
+    }
+
+    #[test]
+    fn image_is_scaled_to_fit_and_centered() {
+        // 200x100 on a 288x432pt page: scaled by 1.44 to 288x144, centered vertically
+        let pdf = image_to_pdf(&encoded(200, 100, ImageFormat::Png), None).unwrap();
+        assert!(contains(&pdf, "288.00 0 0 144.00 0.00 144.00 cm"));
+
+        // A tall image on a wide label is centered horizontally instead
+        let pdf = image_to_pdf(&encoded(100, 200, ImageFormat::Png), Some((4.0, 2.0))).unwrap();
+        assert!(contains(&pdf, "72.00 0 0 144.00 108.00 0.00 cm"));
+    }
+
+    #[test]
+    fn data_that_isnt_an_image_is_refused() {
+        let err = image_to_pdf(b"%PDF-1.4 not an image", None).unwrap_err();
+        assert!(err.starts_with("Failed to decode image"), "{}", err);
+    }
+}
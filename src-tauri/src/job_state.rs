@@ -0,0 +1,222 @@
+//! Follow a spooled job after `lp` accepts it, for prints sent with `waitForCompletion`
+
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+use crate::printer;
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+use crate::printer_status;
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+use crate::protocol::PrinterStatus;
+use crate::virtual_printer;
+use std::time::{Duration, Instant};
+
+/// How often the job's state is checked while it's queued
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Where a job is in the spooler
+#[derive(Debug, Clone, PartialEq)]
+pub enum JobState {
+    /// Waiting or printing
+    Queued,
+    Completed,
+    /// Aborted, cancelled or stuck behind a printer error
+    Failed(String),
+    /// The spooler can't report job states here
+    Unknown,
+}
+
+/// How a waited-for job ended
+#[derive(Debug, Clone, PartialEq)]
+pub enum JobOutcome {
+    Completed,
+    Failed(String),
+    /// Still queued when the wait ran out
+    TimedOut,
+    /// The job was accepted but its state can't be followed
+    Unconfirmed,
+}
+
+/// Poll `state` until the job leaves the queue or `timeout` elapses
+pub fn wait_for_job(timeout: Duration, interval: Duration, mut state: impl FnMut() -> JobState) -> JobOutcome {
+    let started = Instant::now();
+    loop {
+        match state() {
+            JobState::Completed => return JobOutcome::Completed,
+            JobState::Failed(reason) => return JobOutcome::Failed(reason),
+            JobState::Unknown => return JobOutcome::Unconfirmed,
+            JobState::Queued => {}
+        }
+
+        let remaining = timeout.saturating_sub(started.elapsed());
+        if remaining.is_zero() {
+            return JobOutcome::TimedOut;
+        }
+        std::thread::sleep(interval.min(remaining));
+    }
+}
+
+/// Wait for a job `print_label` submitted to finish printing
+pub fn wait_for_completion(printer_name: &str, job_id: &str, timeout: Duration) -> JobOutcome {
+    // Its jobs are finished as soon as they're saved
+    if printer_name == virtual_printer::ID {
+        return JobOutcome::Completed;
+    }
+    wait_for_job(timeout, POLL_INTERVAL, || job_state(printer_name, job_id))
+}
+
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+fn job_state(printer_name: &str, job_id: &str) -> JobState {
+    let pending = match lpstat(&["-W", "not-completed", "-o", printer_name]) {
+        Ok(output) => output,
+        Err(e) => {
+            log::warn!("Can't follow job {}: {}", job_id, e);
+            return JobState::Unknown;
+        }
+    };
+    if find_job(&pending, job_id).is_some() {
+        // CUPS keeps the job queued when the printer stops on an error
+        return match printer_status::printer_statuses(&[printer_name.to_string()]).get(printer_name) {
+            Some(PrinterStatus::Paused | PrinterStatus::Error) => {
+                JobState::Failed(format!("{} stopped with the job still queued", printer_name))
+            }
+            _ => JobState::Queued,
+        };
+    }
+
+    match lpstat(&["-l", "-W", "completed", "-o", printer_name]) {
+        Ok(output) => completed_state(&output, job_id),
+        // It has left the queue, which is all lp can tell us without the history
+        Err(_) => JobState::Completed,
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn job_state(_printer_name: &str, _job_id: &str) -> JobState {
+    JobState::Unknown
+}
+
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+fn lpstat(args: &[&str]) -> Result<String, String> {
+    let output = printer::cups_command("lpstat")
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to run lpstat: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("lpstat failed: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The job's line and the indented detail lines `lpstat -l` prints under it
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+fn find_job<'a>(output: &'a str, job_id: &str) -> Option<Vec<&'a str>> {
+    let mut lines = output.lines().skip_while(|line| line.split_whitespace().next() != Some(job_id));
+    let first = lines.next()?;
+    let details = lines.take_while(|line| line.starts_with(char::is_whitespace));
+    Some(std::iter::once(first).chain(details).collect())
+}
+
+/// State of a finished job from its `Alerts:` (job-state-reasons) line
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+fn completed_state(output: &str, job_id: &str) -> JobState {
+    // Servers that don't keep job history forget finished jobs straight away
+    let Some(lines) = find_job(output, job_id) else {
+        return JobState::Completed;
+    };
+    let alerts = lines
+        .iter()
+        .find_map(|line| line.trim().strip_prefix("Alerts:"))
+        .unwrap_or_default()
+        .trim();
+
+    let failed = ["aborted", "canceled", "completed-with-errors", "stopped"]
+        .iter()
+        .any(|reason| alerts.contains(reason));
+    if failed && !alerts.contains("job-completed-successfully") {
+        JobState::Failed(format!("Job {} did not print: {}", job_id, alerts))
+    } else {
+        JobState::Completed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Wait on a fixed sequence of states, repeating the last, counting the polls
+    fn wait_on(states: &[JobState], timeout: Duration) -> (JobOutcome, usize) {
+        let mut polls = 0;
+        let outcome = wait_for_job(timeout, Duration::from_millis(1), || {
+            polls += 1;
+            states[(polls - 1).min(states.len() - 1)].clone()
+        });
+        (outcome, polls)
+    }
+
+    #[test]
+    fn queued_job_is_followed_until_it_completes() {
+        let states = [JobState::Queued, JobState::Queued, JobState::Completed];
+        assert_eq!(wait_on(&states, Duration::from_secs(5)), (JobOutcome::Completed, 3));
+    }
+
+    #[test]
+    fn job_that_fails_in_the_queue_is_not_completed() {
+        let failed = JobState::Failed("Zebra_ZP450 stopped with the job still queued".to_string());
+        let (outcome, polls) = wait_on(&[JobState::Queued, failed], Duration::from_secs(5));
+        assert_eq!(outcome, JobOutcome::Failed("Zebra_ZP450 stopped with the job still queued".to_string()));
+        assert_eq!(polls, 2);
+    }
+
+    #[test]
+    fn job_still_queued_at_the_deadline_times_out() {
+        let started = Instant::now();
+        let (outcome, polls) = wait_on(&[JobState::Queued], Duration::from_millis(30));
+        assert_eq!(outcome, JobOutcome::TimedOut);
+        assert!(polls > 1);
+        assert!(started.elapsed() >= Duration::from_millis(30));
+    }
+
+    #[test]
+    fn unknown_state_is_unconfirmed_without_waiting() {
+        assert_eq!(wait_on(&[JobState::Unknown], Duration::from_secs(5)), (JobOutcome::Unconfirmed, 1));
+    }
+
+    #[test]
+    fn virtual_printer_jobs_complete_at_once() {
+        assert_eq!(
+            wait_for_completion(virtual_printer::ID, "virtual-1-1", Duration::from_secs(5)),
+            JobOutcome::Completed
+        );
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    const COMPLETED: &str = "\
+Zebra_ZP450-41          alice           1024   Tue 14 Oct 2025 09:12:03 AM
+\tStatus: The printer is not responding.
+\tAlerts: job-completed-successfully
+\tqueued for Zebra_ZP450
+Zebra_ZP450-42          alice           1024   Tue 14 Oct 2025 09:13:10 AM
+\tAlerts: job-canceled-at-device aborted-by-system
+\tqueued for Zebra_ZP450
+";
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn job_lines_are_found_with_their_details() {
+        let lines = find_job(COMPLETED, "Zebra_ZP450-42").unwrap();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].contains("aborted-by-system"));
+        assert!(find_job(COMPLETED, "Zebra_ZP450-4").is_none());
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn finished_jobs_are_told_apart_by_their_alerts() {
+        assert_eq!(completed_state(COMPLETED, "Zebra_ZP450-41"), JobState::Completed);
+        assert!(matches!(
+            completed_state(COMPLETED, "Zebra_ZP450-42"),
+            JobState::Failed(reason) if reason.contains("job-canceled-at-device")
+        ));
+        // Forgotten by a server without job history, having left the queue
+        assert_eq!(completed_state(COMPLETED, "Zebra_ZP450-43"), JobState::Completed);
+    }
+}
@@ -0,0 +1,201 @@
+use reqwest::{redirect, Url};
+use std::time::Duration;
+
+/// Largest label the connector will download
+const MAX_LABEL_BYTES: usize = 20 * 1024 * 1024;
+
+/// Longest a label download may take
+const FETCH_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Domains labels may be fetched from, including their subdomains
+const ALLOWED_DOMAINS: &[&str] = &["limestack.io"];
+
+/// Why a label URL couldn't be printed
+#[derive(Debug)]
+pub enum FetchError {
+    /// Not HTTPS, or not on an allowed domain
+    NotAllowed(String),
+    /// The download failed or was too large
+    Failed(String),
+}
+
+impl std::fmt::Display for FetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FetchError::NotAllowed(msg) | FetchError::Failed(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+/// Parse a label URL and check it's HTTPS on an allowed domain
+pub fn check_url(url: &str) -> Result<Url, FetchError> {
+    let url = Url::parse(url).map_err(|e| FetchError::NotAllowed(format!("Invalid URL: {}", e)))?;
+    if url.scheme() != "https" {
+        return Err(FetchError::NotAllowed("Label URLs must use https".to_string()));
+    }
+    if !url.host_str().is_some_and(host_allowed) {
+        return Err(FetchError::NotAllowed(format!(
+            "Labels can't be fetched from {}",
+            url.host_str().unwrap_or_default()
+        )));
+    }
+    Ok(url)
+}
+
+fn host_allowed(host: &str) -> bool {
+    let host = host.to_lowercase();
+    ALLOWED_DOMAINS
+        .iter()
+        .any(|domain| host == *domain || host.ends_with(&format!(".{}", domain)))
+}
+
+/// Download a label, returning its bytes and Content-Type
+///
+/// Redirects are only followed to other allowed URLs, and the body is capped at
+/// `MAX_LABEL_BYTES`.
+pub async fn fetch_label(url: &str) -> Result<(Vec<u8>, Option<String>), FetchError> {
+    let url = check_url(url)?;
+
+    let client = reqwest::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .redirect(redirect::Policy::custom(|attempt| {
+            if attempt.previous().len() >= 5 {
+                attempt.error("Too many redirects")
+            } else if check_url(attempt.url().as_str()).is_ok() {
+                attempt.follow()
+            } else {
+                attempt.stop()
+            }
+        }))
+        .build()
+        .map_err(|e| FetchError::Failed(format!("Failed to set up download: {}", e)))?;
+
+    download(&client, url, MAX_LABEL_BYTES).await
+}
+
+/// Read a response body of at most `max_bytes`, with its Content-Type
+async fn download(client: &reqwest::Client, url: Url, max_bytes: usize) -> Result<(Vec<u8>, Option<String>), FetchError> {
+    let mut response = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| FetchError::Failed(format!("Failed to download label: {}", e)))?;
+
+    if response.status().is_redirection() {
+        return Err(FetchError::NotAllowed("Label URL redirects to a disallowed location".to_string()));
+    }
+    if response.content_length().is_some_and(|len| len > max_bytes as u64) {
+        return Err(too_large(max_bytes));
+    }
+
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.split(';').next().unwrap_or_default().trim().to_lowercase());
+
+    // Content-Length can be missing or wrong, so enforce the limit while reading too
+    let mut body = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|e| FetchError::Failed(format!("Failed to download label: {}", e)))?
+    {
+        if body.len() + chunk.len() > max_bytes {
+            return Err(too_large(max_bytes));
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    log::info!("Downloaded {} bytes of label data", body.len());
+    Ok((body, content_type))
+}
+
+fn too_large(max_bytes: usize) -> FetchError {
+    FetchError::Failed(format!("Label is larger than {} MB", max_bytes / (1024 * 1024)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn limestack_hosts_are_allowed() {
+        for url in [
+            "https://limestack.io/labels/1.pdf",
+            "https://files.limestack.io/labels/1.pdf?sig=abc",
+            "https://eu.files.LimeStack.io/1.pdf",
+        ] {
+            assert!(check_url(url).is_ok(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn other_hosts_are_not_allowed() {
+        for url in [
+            "https://evil-limestack.io/1.pdf",
+            "https://limestack.io.attacker.com/1.pdf",
+            "https://attacker.com/limestack.io",
+            "https://127.0.0.1/1.pdf",
+        ] {
+            assert!(matches!(check_url(url), Err(FetchError::NotAllowed(e)) if e.contains("can't be fetched")), "{}", url);
+        }
+    }
+
+    #[test]
+    fn only_https_is_allowed() {
+        for url in ["http://files.limestack.io/1.pdf", "file:///etc/passwd", "ftp://limestack.io/1.pdf"] {
+            assert!(matches!(check_url(url), Err(FetchError::NotAllowed(e)) if e.contains("https")), "{}", url);
+        }
+        assert!(matches!(check_url("not a url"), Err(FetchError::NotAllowed(_))));
+    }
+
+    /// Serve one HTTP response on a local port and return its URL
+    async fn serve_once(response: Vec<u8>) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/label.pdf", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 4096];
+            let _ = stream.read(&mut request).await;
+            let _ = stream.write_all(&response).await;
+        });
+        url
+    }
+
+    fn http_response(headers: &str, body: &[u8]) -> Vec<u8> {
+        let mut response = format!("HTTP/1.1 200 OK\r\nConnection: close\r\n{}\r\n", headers).into_bytes();
+        response.extend_from_slice(body);
+        response
+    }
+
+    #[tokio::test]
+    async fn label_within_the_limit_is_downloaded() {
+        let url = serve_once(http_response(
+            "Content-Type: application/pdf; charset=binary\r\nContent-Length: 8\r\n",
+            b"%PDF-1.4",
+        ))
+        .await;
+        let (body, content_type) = download(&reqwest::Client::new(), url, 8).await.unwrap();
+        assert_eq!(body, b"%PDF-1.4");
+        assert_eq!(content_type.as_deref(), Some("application/pdf"));
+    }
+
+    #[tokio::test]
+    async fn declared_oversized_label_is_refused() {
+        let url = serve_once(http_response("Content-Length: 9\r\n", b"%PDF-1.4\n")).await;
+        let error = download(&reqwest::Client::new(), url, 8).await.unwrap_err();
+        assert!(matches!(error, FetchError::Failed(e) if e.starts_with("Label is larger than")));
+    }
+
+    #[tokio::test]
+    async fn oversized_label_without_a_length_is_cut_off() {
+        // No Content-Length, so the body runs until the connection closes
+        let url = serve_once(http_response("", &[b'x'; 64 * 1024])).await;
+        let error = download(&reqwest::Client::new(), url, 1024).await.unwrap_err();
+        assert!(matches!(error, FetchError::Failed(e) if e.starts_with("Label is larger than")));
+    }
+}
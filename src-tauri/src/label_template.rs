@@ -0,0 +1,353 @@
+//! Built-in label layouts filled in from fields, so integrations can send data instead of
+//! a rendered label. Each layout renders to ZPL for printers that speak it and to PDF for
+//! everything else, from the same positions.
+
+use crate::capabilities;
+use crate::code128;
+use crate::printer;
+use crate::test_label::{escape_pdf_text, text_pdf};
+use std::collections::BTreeMap;
+
+/// Dots per inch of the ZPL output, the resolution of most Zebra desktop printers
+const ZPL_DPI: f32 = 203.0;
+
+const POINTS_PER_INCH: f32 = 72.0;
+
+/// Narrow bar width, in dots for ZPL (`^BY2`) and as the matching inches for PDF
+const BARCODE_MODULE_DOTS: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Template {
+    /// 4x6 shipping label: `name`, `address` and `barcode`, with optional `from` and `reference`
+    Shipping4x6,
+    /// 2x1 product label: `sku`, with optional `description` and `price`
+    Sku2x1,
+}
+
+impl Template {
+    /// Label size in inches
+    fn size(self) -> (f32, f32) {
+        match self {
+            Template::Shipping4x6 => (4.0, 6.0),
+            Template::Sku2x1 => (2.0, 1.0),
+        }
+    }
+
+    fn required_fields(self) -> &'static [&'static str] {
+        match self {
+            Template::Shipping4x6 => &["name", "address", "barcode"],
+            Template::Sku2x1 => &["sku"],
+        }
+    }
+
+    fn optional_fields(self) -> &'static [&'static str] {
+        match self {
+            Template::Shipping4x6 => &["from", "reference"],
+            Template::Sku2x1 => &["description", "price"],
+        }
+    }
+}
+
+/// Accept a template name: "shipping_4x6" or "sku_2x1"
+pub fn parse_template(name: &str) -> Result<Template, String> {
+    match name.trim().to_lowercase().as_str() {
+        "shipping_4x6" => Ok(Template::Shipping4x6),
+        "sku_2x1" => Ok(Template::Sku2x1),
+        other => Err(format!("Unknown template '{}' (available: shipping_4x6, sku_2x1)", other)),
+    }
+}
+
+/// Something placed on the label, positioned in inches from the top left
+enum Element {
+    /// `size` is the character height in inches
+    Text { x: f32, y: f32, size: f32, text: String },
+    Barcode { x: f32, y: f32, height: f32, data: String },
+    /// A horizontal rule
+    Line { x: f32, y: f32, width: f32 },
+}
+
+/// Render a template for a printer: ZPL if it takes ZPL, otherwise PDF. Returns the format
+/// and the label.
+pub fn render_for_printer(
+    printer_id: &str,
+    template: Template,
+    fields: &BTreeMap<String, String>,
+) -> Result<(&'static str, Vec<u8>), String> {
+    let elements = layout(template, fields)?;
+    let zpl = printer::find_printer(printer_id)
+        .is_some_and(|name| capabilities::printer_capabilities(&name).formats.iter().any(|f| f == "zpl"));
+
+    if zpl {
+        Ok(("zpl", to_zpl(template, &elements)))
+    } else {
+        to_pdf(template, &elements).map(|pdf| ("pdf", pdf))
+    }
+}
+
+/// Check the fields and place them on the template's layout
+fn layout(template: Template, fields: &BTreeMap<String, String>) -> Result<Vec<Element>, String> {
+    let known: Vec<&str> = [template.required_fields(), template.optional_fields()].concat();
+    if let Some(name) = fields.keys().find(|name| !known.contains(&name.as_str())) {
+        return Err(format!("Unknown field '{}' (expected: {})", name, known.join(", ")));
+    }
+    for name in template.required_fields() {
+        if !fields.get(*name).is_some_and(|value| !value.trim().is_empty()) {
+            return Err(format!("Missing field '{}'", name));
+        }
+    }
+
+    let field = |name: &str| fields.get(name).map(|value| value.trim()).filter(|value| !value.is_empty());
+    let mut elements = Vec::new();
+    let mut lines = |text: &str, x: f32, top: f32, size: f32, step: f32, max: usize| {
+        for (i, line) in text.lines().map(str::trim).filter(|l| !l.is_empty()).take(max).enumerate() {
+            elements.push(Element::Text {
+                x,
+                y: top + step * i as f32,
+                size,
+                text: line.to_string(),
+            });
+        }
+    };
+
+    match template {
+        Template::Shipping4x6 => {
+            if let Some(from) = field("from") {
+                lines(from, 0.25, 0.2, 0.12, 0.16, 4);
+            }
+            lines("SHIP TO:", 0.25, 1.15, 0.14, 0.0, 1);
+            lines(field("name").unwrap_or_default(), 0.25, 1.4, 0.25, 0.0, 1);
+            lines(field("address").unwrap_or_default(), 0.25, 1.75, 0.2, 0.26, 5);
+            let barcode = field("barcode").unwrap_or_default();
+            lines(barcode, 0.3, 4.6, 0.16, 0.0, 1);
+            if let Some(reference) = field("reference") {
+                lines(&format!("Ref: {}", reference), 0.25, 5.3, 0.16, 0.0, 1);
+            }
+            elements.push(Element::Line { x: 0.2, y: 1.0, width: 3.6 });
+            elements.push(Element::Line { x: 0.2, y: 3.3, width: 3.6 });
+            elements.push(Element::Barcode {
+                x: 0.3,
+                y: 3.5,
+                height: 1.0,
+                data: barcode.to_string(),
+            });
+        }
+        Template::Sku2x1 => {
+            if let Some(description) = field("description") {
+                lines(description, 0.1, 0.08, 0.11, 0.0, 1);
+            }
+            if let Some(price) = field("price") {
+                lines(price, 1.4, 0.08, 0.13, 0.0, 1);
+            }
+            let sku = field("sku").unwrap_or_default();
+            lines(sku, 0.1, 0.78, 0.11, 0.0, 1);
+            elements.push(Element::Barcode {
+                x: 0.1,
+                y: 0.28,
+                height: 0.45,
+                data: sku.to_string(),
+            });
+        }
+    }
+
+    // Catch barcodes Code 128 can't encode here, not when the printer rejects the label
+    for element in &elements {
+        if let Element::Barcode { data, .. } = element {
+            code128::encode(data)?;
+        }
+    }
+    Ok(elements)
+}
+
+fn to_zpl(template: Template, elements: &[Element]) -> Vec<u8> {
+    let dots = |inches: f32| (inches * ZPL_DPI).round() as u32;
+    let (width, height) = template.size();
+
+    // UTF-8 field data, with `^FH` hex escapes for characters ZPL treats as commands
+    let mut zpl = format!("^XA\n^CI28\n^PW{}\n^LL{}\n", dots(width), dots(height));
+    for element in elements {
+        zpl.push_str(&match element {
+            Element::Text { x, y, size, text } => format!(
+                "^FO{},{}^A0N,{},{}^FH^FD{}^FS\n",
+                dots(*x),
+                dots(*y),
+                dots(*size),
+                dots(*size),
+                escape_zpl(text)
+            ),
+            Element::Barcode { x, y, height, data } => format!(
+                "^FO{},{}^BY{}^BCN,{},N,N,N^FH^FD{}^FS\n",
+                dots(*x),
+                dots(*y),
+                BARCODE_MODULE_DOTS,
+                dots(*height),
+                escape_zpl(data)
+            ),
+            Element::Line { x, y, width } => format!("^FO{},{}^GB{},3,3^FS\n", dots(*x), dots(*y), dots(*width)),
+        });
+    }
+    zpl.push_str("^XZ\n");
+    zpl.into_bytes()
+}
+
+/// Field data with `^`, `~` and the `_` escape character itself hex-escaped, for `^FH`
+fn escape_zpl(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_control())
+        .map(|c| match c {
+            '^' => "_5E".to_string(),
+            '~' => "_7E".to_string(),
+            '_' => "_5F".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+fn to_pdf(template: Template, elements: &[Element]) -> Result<Vec<u8>, String> {
+    let (width, height) = template.size();
+    let points = |inches: f32| inches * POINTS_PER_INCH;
+    // PDF measures up from the bottom of the page
+    let from_top = |inches: f32| points(height - inches);
+    let module = points(BARCODE_MODULE_DOTS as f32 / ZPL_DPI);
+
+    let mut content = String::new();
+    for element in elements {
+        match element {
+            Element::Text { x, y, size, text } => {
+                // Baseline about four fifths of the way down the character cell
+                content.push_str(&format!(
+                    "BT\n/F1 {:.2} Tf\n1 0 0 1 {:.2} {:.2} Tm\n({}) Tj\nET\n",
+                    points(*size),
+                    points(*x),
+                    from_top(y + size * 0.8),
+                    escape_pdf_text(text)
+                ));
+            }
+            Element::Barcode { x, y, height, data } => {
+                let mut bar_x = points(*x);
+                for (i, modules) in code128::encode(data)?.into_iter().enumerate() {
+                    let bar_width = module * modules as f32;
+                    // Even positions are bars, odd ones spaces
+                    if i % 2 == 0 {
+                        content.push_str(&format!(
+                            "{:.2} {:.2} {:.2} {:.2} re\n",
+                            bar_x,
+                            from_top(y + height),
+                            bar_width,
+                            points(*height)
+                        ));
+                    }
+                    bar_x += bar_width;
+                }
+                content.push_str("f\n");
+            }
+            Element::Line { x, y, width } => {
+                content.push_str(&format!("{:.2} {:.2} {:.2} 1.5 re\nf\n", points(*x), from_top(*y), points(*width)));
+            }
+        }
+    }
+
+    Ok(text_pdf(points(width) as u32, points(height) as u32, &content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    fn shipping_fields() -> BTreeMap<String, String> {
+        fields(&[
+            ("name", "Ada Lovelace"),
+            ("address", "12 St James's Square\nLondon SW1Y 4JH\n\nUnited Kingdom"),
+            ("barcode", "1Z999AA10123456784"),
+            ("reference", "ORD-1042"),
+        ])
+    }
+
+    fn zpl(template: Template, fields: &BTreeMap<String, String>) -> String {
+        String::from_utf8(to_zpl(template, &layout(template, fields).unwrap())).unwrap()
+    }
+
+    #[test]
+    fn template_names_are_recognised() {
+        assert_eq!(parse_template("shipping_4x6"), Ok(Template::Shipping4x6));
+        assert_eq!(parse_template(" SKU_2x1 "), Ok(Template::Sku2x1));
+        let error = parse_template("a4_letter").unwrap_err();
+        assert_eq!(error, "Unknown template 'a4_letter' (available: shipping_4x6, sku_2x1)");
+    }
+
+    #[test]
+    fn sku_label_renders_to_zpl() {
+        assert_eq!(
+            zpl(Template::Sku2x1, &fields(&[("sku", "ABC-123")])),
+            "^XA\n^CI28\n^PW406\n^LL203\n\
+             ^FO20,158^A0N,22,22^FH^FDABC-123^FS\n\
+             ^FO20,57^BY2^BCN,91,N,N,N^FH^FDABC-123^FS\n\
+             ^XZ\n"
+        );
+    }
+
+    #[test]
+    fn shipping_label_renders_to_zpl() {
+        let zpl = zpl(Template::Shipping4x6, &shipping_fields());
+        assert!(zpl.starts_with("^XA\n^CI28\n^PW812\n^LL1218\n"), "{}", zpl);
+        assert!(zpl.ends_with("^XZ\n"), "{}", zpl);
+        assert!(zpl.contains("^FDSHIP TO:^FS"), "{}", zpl);
+        assert!(zpl.contains("^A0N,51,51^FH^FDAda Lovelace^FS"), "{}", zpl);
+        // Address lines in order, blank ones skipped
+        let street = zpl.find("^FD12 St James's Square^FS").unwrap();
+        let city = zpl.find("^FDLondon SW1Y 4JH^FS").unwrap();
+        let country = zpl.find("^FDUnited Kingdom^FS").unwrap();
+        assert!(street < city && city < country);
+        assert!(zpl.contains("^BCN,203,N,N,N^FH^FD1Z999AA10123456784^FS"), "{}", zpl);
+        assert!(zpl.contains("^FDRef: ORD-1042^FS"), "{}", zpl);
+        assert_eq!(zpl.matches("^GB731,3,3^FS").count(), 2, "{}", zpl);
+    }
+
+    #[test]
+    fn field_data_cannot_inject_zpl() {
+        let zpl = zpl(Template::Sku2x1, &fields(&[("sku", "A1"), ("description", "^XZ^XA~JR tea_cup")]));
+        assert!(zpl.contains("^FD_5EXZ_5EXA_7EJR tea_5Fcup^FS"), "{}", zpl);
+        assert_eq!(zpl.matches("^XZ").count(), 1, "{}", zpl);
+    }
+
+    #[test]
+    fn shipping_label_renders_to_pdf() {
+        let elements = layout(Template::Shipping4x6, &shipping_fields()).unwrap();
+        let pdf = String::from_utf8(to_pdf(Template::Shipping4x6, &elements).unwrap()).unwrap();
+        assert!(pdf.starts_with("%PDF-1.4\n"));
+        assert!(pdf.contains("/MediaBox [0 0 288 432]"), "{}", pdf);
+        assert!(pdf.contains("(Ada Lovelace) Tj"), "{}", pdf);
+        assert!(pdf.contains("(12 St James's Square) Tj"), "{}", pdf);
+        assert!(pdf.contains("(Ref: ORD-1042) Tj"), "{}", pdf);
+        // Code 128 bars for the barcode, drawn as filled rectangles
+        assert!(pdf.matches(" re\n").count() > 20, "{}", pdf);
+    }
+
+    #[test]
+    fn sku_label_renders_to_pdf() {
+        let elements = layout(Template::Sku2x1, &fields(&[("sku", "ABC-123"), ("price", "$4.99")])).unwrap();
+        let pdf = String::from_utf8(to_pdf(Template::Sku2x1, &elements).unwrap()).unwrap();
+        assert!(pdf.contains("/MediaBox [0 0 144 72]"), "{}", pdf);
+        assert!(pdf.contains("($4.99) Tj"), "{}", pdf);
+        assert!(pdf.contains("(ABC-123) Tj"), "{}", pdf);
+    }
+
+    #[test]
+    fn fields_are_checked_against_the_template() {
+        let error = layout(Template::Sku2x1, &fields(&[("sku", "A1"), ("colour", "red")])).err().unwrap();
+        assert_eq!(error, "Unknown field 'colour' (expected: sku, description, price)");
+        let error = layout(Template::Shipping4x6, &fields(&[("name", "Ada"), ("barcode", "1")])).err().unwrap();
+        assert_eq!(error, "Missing field 'address'");
+        let error = layout(Template::Sku2x1, &fields(&[("sku", "  ")])).err().unwrap();
+        assert_eq!(error, "Missing field 'sku'");
+    }
+
+    #[test]
+    fn barcodes_code_128_cannot_encode_are_refused() {
+        let error = layout(Template::Sku2x1, &fields(&[("sku", "Café")])).err().unwrap();
+        assert!(error.starts_with("Barcode can't contain"), "{}", error);
+    }
+}
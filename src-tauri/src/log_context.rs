@@ -0,0 +1,131 @@
+//! Which connection a log line came from, so lines from simultaneous clients can be told apart
+//!
+//! Each accepted connection runs in a `scope` with its own id. Tasks and blocking work
+//! started from it must use `spawn` and `spawn_blocking` here to keep the id.
+
+use std::cell::Cell;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::task::JoinHandle;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+tokio::task_local! {
+    static CONNECTION: u64;
+}
+
+thread_local! {
+    /// The id for blocking work, which runs outside any task
+    static BLOCKING_CONNECTION: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// A new connection id, unique for this run
+pub fn next_id() -> u64 {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The connection the current task or blocking job belongs to
+pub fn current() -> Option<u64> {
+    CONNECTION
+        .try_with(|id| *id)
+        .ok()
+        .or_else(|| BLOCKING_CONNECTION.with(Cell::get))
+}
+
+/// Prefix for log lines, e.g. "[conn 12] ", or nothing outside a connection
+pub fn prefix() -> String {
+    current().map(|id| format!("[conn {}] ", id)).unwrap_or_default()
+}
+
+/// Run a future with its logs attributed to `id`
+pub async fn scope<F: Future>(id: u64, future: F) -> F::Output {
+    CONNECTION.scope(id, future).await
+}
+
+/// `tokio::spawn`, keeping the current connection id
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    match current() {
+        Some(id) => tokio::spawn(CONNECTION.scope(id, future)),
+        None => tokio::spawn(future),
+    }
+}
+
+/// `tokio::task::spawn_blocking`, keeping the current connection id
+pub fn spawn_blocking<F, R>(f: F) -> JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let id = current();
+    tokio::task::spawn_blocking(move || {
+        // Blocking threads are pooled, so clear the id even if `f` panics
+        struct Reset;
+        impl Drop for Reset {
+            fn drop(&mut self) {
+                BLOCKING_CONNECTION.with(|c| c.set(None));
+            }
+        }
+        let _reset = Reset;
+        BLOCKING_CONNECTION.with(|c| c.set(id));
+        f()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::sync::Barrier;
+
+    #[tokio::test]
+    async fn concurrent_connections_log_with_their_own_ids() {
+        let (first, second) = (next_id(), next_id());
+        assert_ne!(first, second);
+
+        // Both connections are in flight at once, so neither id can leak into the other
+        let barrier = Arc::new(Barrier::new(2));
+        let connection = |id: u64, barrier: Arc<Barrier>| {
+            tokio::spawn(scope(id, async move {
+                let before = prefix();
+                barrier.wait().await;
+                tokio::task::yield_now().await;
+                (before, prefix())
+            }))
+        };
+        let a = connection(first, barrier.clone());
+        let b = connection(second, barrier);
+        let (a, b) = (a.await.unwrap(), b.await.unwrap());
+
+        assert_eq!(a, (format!("[conn {}] ", first), format!("[conn {}] ", first)));
+        assert_eq!(b, (format!("[conn {}] ", second), format!("[conn {}] ", second)));
+    }
+
+    #[tokio::test]
+    async fn spawned_tasks_and_blocking_work_keep_the_id() {
+        let id = next_id();
+        let (task, blocking) = scope(id, async {
+            let task = spawn(async { current() });
+            let blocking = spawn_blocking(current);
+            (task.await.unwrap(), blocking.await.unwrap())
+        })
+        .await;
+        assert_eq!(task, Some(id));
+        assert_eq!(blocking, Some(id));
+    }
+
+    #[tokio::test]
+    async fn work_outside_a_connection_has_no_prefix() {
+        assert_eq!(current(), None);
+        assert_eq!(prefix(), "");
+        assert_eq!(spawn(async { current() }).await.unwrap(), None);
+
+        // Pooled blocking threads forget the id of the connection that last used them
+        let id = next_id();
+        scope(id, async { spawn_blocking(current).await.unwrap() }).await;
+        assert_eq!(spawn_blocking(current).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,214 @@
+use crate::protocol::ServerMessage;
+use log::LevelFilter;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tauri_plugin_log::{fern, Target, TargetKind};
+use tokio::sync::Notify;
+
+/// Records buffered per subscriber before the oldest are dropped
+const MAX_BUFFERED_RECORDS: usize = 500;
+
+/// Targets never streamed, since sending a record over the WebSocket would log again
+const EXCLUDED_TARGETS: &[&str] = &["tungstenite", "tokio_tungstenite"];
+
+static SUBSCRIBERS: Mutex<Vec<Arc<Subscriber>>> = Mutex::new(Vec::new());
+
+struct Subscriber {
+    level: LevelFilter,
+    buffer: Mutex<Buffer>,
+    notify: Notify,
+}
+
+#[derive(Default)]
+struct Buffer {
+    records: VecDeque<LogEntry>,
+    /// Records dropped since the last one delivered
+    dropped: u64,
+}
+
+struct LogEntry {
+    timestamp: String,
+    level: String,
+    target: String,
+    message: String,
+}
+
+/// Log target fanning records out to WebSocket subscribers alongside the log file
+pub fn target() -> Target {
+    Target::new(TargetKind::Dispatch(dispatch()))
+}
+
+/// The same fan-out for loggers built without the Tauri plugin
+pub fn dispatch() -> fern::Dispatch {
+    fern::Dispatch::new().chain(fern::Output::call(publish))
+}
+
+fn publish(record: &log::Record) {
+    publish_to(&SUBSCRIBERS.lock().unwrap(), record);
+}
+
+fn publish_to(subscribers: &[Arc<Subscriber>], record: &log::Record) {
+    if EXCLUDED_TARGETS.iter().any(|t| record.target().starts_with(t)) {
+        return;
+    }
+
+    for subscriber in subscribers.iter().filter(|s| record.level() <= s.level) {
+        let entry = LogEntry {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            level: record.level().to_string(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+        };
+
+        let mut buffer = subscriber.buffer.lock().unwrap();
+        if buffer.records.len() >= MAX_BUFFERED_RECORDS {
+            buffer.records.pop_front();
+            buffer.dropped += 1;
+        }
+        buffer.records.push_back(entry);
+        subscriber.notify.notify_one();
+    }
+}
+
+/// A client's live view of the log, unsubscribed when dropped
+pub struct LogSubscription(Arc<Subscriber>);
+
+/// Start buffering records at `level` or more severe for one client
+pub fn subscribe(level: LevelFilter) -> LogSubscription {
+    let subscriber = Subscriber::new(level);
+    SUBSCRIBERS.lock().unwrap().push(subscriber.clone());
+    LogSubscription(subscriber)
+}
+
+impl Subscriber {
+    fn new(level: LevelFilter) -> Arc<Self> {
+        Arc::new(Subscriber {
+            level,
+            buffer: Mutex::new(Buffer::default()),
+            notify: Notify::new(),
+        })
+    }
+}
+
+impl LogSubscription {
+    /// Wait for the next record, reporting how many were dropped before it
+    pub async fn next(&self) -> ServerMessage {
+        loop {
+            {
+                let mut buffer = self.0.buffer.lock().unwrap();
+                if let Some(entry) = buffer.records.pop_front() {
+                    let dropped = std::mem::take(&mut buffer.dropped);
+                    return ServerMessage::Log {
+                        timestamp: entry.timestamp,
+                        level: entry.level,
+                        target: entry.target,
+                        message: entry.message,
+                        dropped,
+                    };
+                }
+            }
+            self.0.notify.notified().await;
+        }
+    }
+}
+
+impl Drop for LogSubscription {
+    fn drop(&mut self) {
+        SUBSCRIBERS.lock().unwrap().retain(|s| !Arc::ptr_eq(s, &self.0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::FutureExt;
+    use log::Level;
+
+    fn record(subscribers: &[Arc<Subscriber>], level: Level, target: &str, message: &str) {
+        publish_to(
+            subscribers,
+            &log::Record::builder()
+                .level(level)
+                .target(target)
+                .args(format_args!("{}", message))
+                .build(),
+        );
+    }
+
+    /// The next record without waiting, as (level, message, dropped)
+    fn next_now(subscription: &LogSubscription) -> Option<(String, String, u64)> {
+        match subscription.next().now_or_never()? {
+            ServerMessage::Log {
+                level,
+                message,
+                dropped,
+                ..
+            } => Some((level, message, dropped)),
+            other => panic!("expected a log record, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn records_fan_out_to_each_subscriber_at_its_level() {
+        let verbose = Subscriber::new(LevelFilter::Debug);
+        let quiet = Subscriber::new(LevelFilter::Warn);
+        let subscribers = [verbose.clone(), quiet.clone()];
+
+        record(&subscribers, Level::Info, "limestack::printer", "Printed label-1");
+        record(&subscribers, Level::Error, "limestack::printer", "Printer offline");
+        record(&subscribers, Level::Trace, "limestack::printer", "lp argv");
+
+        let verbose = LogSubscription(verbose);
+        assert_eq!(next_now(&verbose), Some(("INFO".to_string(), "Printed label-1".to_string(), 0)));
+        assert_eq!(next_now(&verbose), Some(("ERROR".to_string(), "Printer offline".to_string(), 0)));
+        assert_eq!(next_now(&verbose), None);
+
+        let quiet = LogSubscription(quiet);
+        assert_eq!(next_now(&quiet), Some(("ERROR".to_string(), "Printer offline".to_string(), 0)));
+        assert_eq!(next_now(&quiet), None);
+    }
+
+    #[test]
+    fn websocket_records_are_not_streamed() {
+        let subscriber = Subscriber::new(LevelFilter::Trace);
+        record(&[subscriber.clone()], Level::Debug, "tungstenite::protocol", "Received frame");
+        record(&[subscriber.clone()], Level::Debug, "tokio_tungstenite", "Sending frame");
+        assert_eq!(next_now(&LogSubscription(subscriber)), None);
+    }
+
+    #[test]
+    fn slow_subscriber_drops_the_oldest_records_and_counts_them() {
+        let subscriber = Subscriber::new(LevelFilter::Info);
+        for i in 0..MAX_BUFFERED_RECORDS + 3 {
+            record(&[subscriber.clone()], Level::Info, "limestack", &format!("record {}", i));
+        }
+        assert_eq!(subscriber.buffer.lock().unwrap().records.len(), MAX_BUFFERED_RECORDS);
+
+        let subscription = LogSubscription(subscriber);
+        assert_eq!(next_now(&subscription), Some(("INFO".to_string(), "record 3".to_string(), 3)));
+        // The count is reported once, with the first record after the gap
+        assert_eq!(next_now(&subscription), Some(("INFO".to_string(), "record 4".to_string(), 0)));
+    }
+
+    #[tokio::test]
+    async fn waiting_subscriber_wakes_for_a_new_record() {
+        let subscriber = Subscriber::new(LevelFilter::Info);
+        let subscription = LogSubscription(subscriber.clone());
+        let waiting = tokio::spawn(async move { subscription.next().await });
+        tokio::task::yield_now().await;
+
+        record(&[subscriber], Level::Warn, "limestack", "Scale disconnected");
+        let message = tokio::time::timeout(std::time::Duration::from_secs(1), waiting).await.unwrap().unwrap();
+        assert!(matches!(message, ServerMessage::Log { ref message, .. } if message == "Scale disconnected"));
+    }
+
+    #[test]
+    fn dropped_subscription_is_unsubscribed() {
+        let subscription = subscribe(LevelFilter::Info);
+        let subscriber = subscription.0.clone();
+        assert!(SUBSCRIBERS.lock().unwrap().iter().any(|s| Arc::ptr_eq(s, &subscriber)));
+
+        drop(subscription);
+        assert!(!SUBSCRIBERS.lock().unwrap().iter().any(|s| Arc::ptr_eq(s, &subscriber)));
+    }
+}
@@ -0,0 +1,100 @@
+use crate::log_context;
+use crate::log_stream;
+use std::str::FromStr;
+use tauri::plugin::TauriPlugin;
+use tauri::{AppHandle, Manager, Wry};
+use tauri_plugin_log::{fern, RotationStrategy, Target, TargetKind};
+
+/// Log file name (without extension) inside the platform log directory
+const LOG_FILE_NAME: &str = "limestack-connector";
+
+/// Rotate once the current log file reaches this size
+const MAX_LOG_FILE_BYTES: u128 = 5 * 1024 * 1024;
+
+/// Number of rotated log files kept alongside the current one
+const KEPT_LOG_FILES: usize = 5;
+
+/// Logger writing to a rotating file in the app log directory, plus stderr in debug builds
+/// and any clients subscribed to the log stream
+///
+/// The level defaults to `info` and can be overridden with `LIMESTACK_LOG_LEVEL`.
+pub fn plugin() -> TauriPlugin<Wry> {
+    let mut targets = vec![
+        Target::new(TargetKind::LogDir {
+            file_name: Some(LOG_FILE_NAME.to_string()),
+        }),
+        log_stream::target(),
+    ];
+    if cfg!(debug_assertions) {
+        targets.push(Target::new(TargetKind::Stderr));
+    }
+
+    tauri_plugin_log::Builder::new()
+        .level(log_level())
+        // The plugin's default layout, with the connection id in front of the message
+        .format(|out, message, record| {
+            out.finish(format_args!(
+                "[{}][{}][{}] {}{}",
+                chrono::Local::now().format("%Y-%m-%d][%H:%M:%S"),
+                record.target(),
+                record.level(),
+                log_context::prefix(),
+                message
+            ))
+        })
+        .targets(targets)
+        .max_file_size(MAX_LOG_FILE_BYTES)
+        .rotation_strategy(RotationStrategy::KeepSome(KEPT_LOG_FILES))
+        .build()
+}
+
+/// Logger for headless runs: stdout plus the log stream, with no log file
+pub fn init_stdout() {
+    let stdout = fern::Dispatch::new()
+        .format(|out, message, record| {
+            out.finish(format_args!(
+                "{} {:<5} {}: {}{}",
+                chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+                record.level(),
+                record.target(),
+                log_context::prefix(),
+                message
+            ))
+        })
+        .chain(std::io::stdout());
+
+    let applied = fern::Dispatch::new()
+        .level(log_level())
+        .chain(stdout)
+        .chain(log_stream::dispatch())
+        .apply();
+    if let Err(e) = applied {
+        eprintln!("Failed to set up logging: {}", e);
+    }
+}
+
+fn log_level() -> log::LevelFilter {
+    let Ok(value) = std::env::var("LIMESTACK_LOG_LEVEL") else {
+        return log::LevelFilter::Info;
+    };
+
+    log::LevelFilter::from_str(value.trim()).unwrap_or_else(|_| {
+        eprintln!("Ignoring invalid LIMESTACK_LOG_LEVEL '{}'", value);
+        log::LevelFilter::Info
+    })
+}
+
+/// Reveal the log directory in the platform file manager
+pub fn open_log_folder(app: &AppHandle) {
+    let dir = match app.path().app_log_dir() {
+        Ok(dir) => dir,
+        Err(e) => {
+            log::warn!("Could not resolve log directory: {}", e);
+            return;
+        }
+    };
+
+    if let Err(e) = open::that(&dir) {
+        log::warn!("Failed to open log folder {}: {}", dir.display(), e);
+    }
+}
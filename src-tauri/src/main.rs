@@ -1,75 +1,99 @@
 // Prevents additional console window on Windows in release
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+mod autostart;
+mod brother_ql;
+mod capabilities;
+mod code128;
+mod config;
+mod discovery;
+#[cfg(target_os = "windows")]
+mod gdi_print;
+mod headless;
+mod health;
+mod image_pdf;
+mod job_state;
+mod label_fetch;
+mod label_template;
+mod log_context;
+mod log_stream;
+mod logging;
+mod notifications;
+mod origins;
+mod pairing;
+mod print_dedupe;
+mod print_history;
+mod preview;
 mod printer;
+mod printer_status;
+mod printer_watch;
 mod protocol;
+mod rate_limit;
+mod raw_device;
+mod receipt;
+mod scale;
+mod self_test;
+mod serial_scale;
 mod server;
+#[cfg(target_os = "windows")]
+mod spooler;
+mod stats;
+mod test_label;
+mod tls;
+mod tray;
+mod tspl;
+mod updater;
+mod virtual_printer;
 
 #[cfg(target_os = "macos")]
 use tauri::ActivationPolicy;
-use tauri::{
-    menu::{Menu, MenuItem, PredefinedMenuItem},
-    tray::TrayIconBuilder,
-    Manager,
-};
-use tauri_plugin_updater::UpdaterExt;
+use tauri::Manager;
 
 fn main() {
-    env_logger::init();
+    if headless::requested() {
+        headless::run();
+        return;
+    }
 
     tauri::Builder::default()
+        .plugin(logging::plugin())
         .plugin(tauri_plugin_shell::init())
+        .plugin(tauri_plugin_notification::init())
         .plugin(tauri_plugin_updater::Builder::new().build())
+        .plugin(autostart::plugin())
         .setup(|app| {
             // Hide from dock on macOS - we're a tray-only app
             #[cfg(target_os = "macos")]
             app.set_activation_policy(ActivationPolicy::Accessory);
 
-            // Start WebSocket server
-            let app_handle = app.handle().clone();
-            std::thread::spawn(move || {
-                let rt = tokio::runtime::Runtime::new().unwrap();
-                rt.block_on(server::start_server(app_handle));
-            });
-
-            // Check for updates in background
-            let update_handle = app.handle().clone();
-            tauri::async_runtime::spawn(async move {
-                if let Err(e) = check_for_updates(update_handle).await {
-                    log::warn!("Update check failed: {}", e);
-                }
-            });
+            config::init(app.path().app_data_dir()?);
+            if let Err(e) = config::watch() {
+                log::warn!("Config changes need a restart: {}", e);
+            }
+            autostart::sync(app.handle());
+            notifications::init(app.handle().clone());
 
-            // Create system tray menu
-            let status = MenuItem::with_id(app, "status", "● Running", false, None::<&str>)?;
-            let separator1 = PredefinedMenuItem::separator(app)?;
-            let open_limestack = MenuItem::with_id(app, "open_limestack", "Open LimeStack", true, None::<&str>)?;
-            let separator2 = PredefinedMenuItem::separator(app)?;
-            let quit = MenuItem::with_id(app, "quit", "Quit", true, None::<&str>)?;
+            let data_dir = app.path().app_data_dir().ok();
+            let (status_tx, status_rx) = tokio::sync::watch::channel(server::ServerStatus::default());
+            let (shutdown, shutdown_rx, stopped_tx) = server::ServerShutdown::new();
+            app.manage(shutdown);
 
-            let menu = Menu::with_items(app, &[
-                &status,
-                &separator1,
-                &open_limestack,
-                &separator2,
-                &quit,
-            ])?;
+            start_server_and_tray(
+                || {
+                    std::thread::spawn(move || {
+                        let rt = tokio::runtime::Runtime::new().unwrap();
+                        rt.block_on(server::start_server(data_dir, status_tx, shutdown_rx));
+                        // Don't let a hung print command hold up exit
+                        rt.shutdown_timeout(std::time::Duration::from_secs(1));
+                        drop(stopped_tx);
+                    });
+                },
+                || tray::create_tray(app, status_rx),
+            );
 
-            let _tray = TrayIconBuilder::new()
-                .icon(app.default_window_icon().unwrap().clone())
-                .menu(&menu)
-                .menu_on_left_click(true)
-                .on_menu_event(|app, event| match event.id.as_ref() {
-                    "open_limestack" => {
-                        let _ = open::that("https://app.limestack.io/settings#devices");
-                    }
-                    "quit" => {
-                        app.exit(0);
-                    }
-                    _ => {}
-                })
-                .tooltip("LimeStack Connector")
-                .build(app)?;
+            // Check for updates in background
+            let update_handle = app.handle().clone();
+            tauri::async_runtime::spawn(updater::check_for_updates(update_handle, false));
 
             log::info!("LimeStack Connector started");
             Ok(())
@@ -78,40 +102,46 @@ fn main() {
         .expect("error while running tauri application");
 }
 
-async fn check_for_updates(app: tauri::AppHandle) -> Result<(), Box<dyn std::error::Error>> {
-    log::info!("Checking for updates...");
-
-    let updater = app.updater()?;
-
-    match updater.check().await {
-        Ok(Some(update)) => {
-            log::info!("Update available: {} -> {}", update.current_version, update.version);
-
-            // Download and install the update
-            let mut downloaded = 0;
-            let bytes = update.download(
-                |chunk, total| {
-                    downloaded += chunk;
-                    log::debug!("Downloaded {} of {:?} bytes", downloaded, total);
-                },
-                || {
-                    log::info!("Download completed, preparing to install...");
-                }
-            ).await?;
-
-            log::info!("Installing update...");
-            update.install(bytes)?;
-
-            log::info!("Update installed. Restarting...");
-            app.restart();
-        }
-        Ok(None) => {
-            log::info!("No updates available");
-        }
+/// Start the WebSocket server, then the tray. Some Linux desktops have no tray; the server
+/// doesn't need one, so failing to create it is only logged. Returns whether there's a tray.
+fn start_server_and_tray<E: std::fmt::Display>(start_server: impl FnOnce(), create_tray: impl FnOnce() -> Result<(), E>) -> bool {
+    start_server();
+    match create_tray() {
+        Ok(()) => true,
         Err(e) => {
-            log::warn!("Update check error: {}", e);
+            log::warn!(
+                "Couldn't create the tray icon, running without it (use --headless to skip the tray): {}",
+                e
+            );
+            false
         }
     }
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn server_runs_when_the_tray_cannot_be_created() {
+        let server_started = Cell::new(false);
+        let has_tray = start_server_and_tray(
+            || server_started.set(true),
+            || {
+                assert!(server_started.get(), "tray created before the server started");
+                Err("org.kde.StatusNotifierWatcher not available")
+            },
+        );
+        assert!(server_started.get());
+        assert!(!has_tray);
+    }
+
+    #[test]
+    fn server_and_tray_both_start_on_a_desktop_with_a_tray() {
+        let server_started = Cell::new(false);
+        let has_tray = start_server_and_tray(|| server_started.set(true), || Ok::<(), String>(()));
+        assert!(server_started.get());
+        assert!(has_tray);
+    }
 }
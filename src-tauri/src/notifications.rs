@@ -0,0 +1,139 @@
+use crate::config::{self, Config};
+use crate::protocol::{ErrorCode, ServerMessage};
+use std::sync::OnceLock;
+use tauri::AppHandle;
+use tauri_plugin_notification::NotificationExt;
+
+/// Longest print failure reason shown before it's cut off
+const MAX_REASON_CHARS: usize = 120;
+
+/// App handle for notifications raised away from the UI, e.g. by print jobs
+static APP: OnceLock<AppHandle> = OnceLock::new();
+
+/// Remember the app handle; call once at startup
+pub fn init(app: AppHandle) {
+    let _ = APP.set(app);
+}
+
+/// Show a native desktop notification, logging if the platform refuses
+pub fn notify(app: &AppHandle, title: &str, body: &str) {
+    if let Err(e) = app.notification().builder().title(title).body(body).show() {
+        log::warn!("Failed to show notification: {}", e);
+    }
+}
+
+/// Show a notification from code without an app handle; does nothing when running headless
+pub fn alert(title: &str, body: &str) {
+    if let Some(app) = APP.get() {
+        notify(app, title, body);
+    }
+}
+
+/// Tell the operator how a print went, if their settings ask for it
+pub fn print_outcome(printer: &str, result: &ServerMessage) {
+    let Some(app) = APP.get() else {
+        return;
+    };
+    if let Some((title, body)) = print_notification(printer, result, &config::get()) {
+        notify(app, &title, &body);
+    }
+}
+
+/// Title and body for a finished print: failures notify unless turned off, successes
+/// only when turned on, and cancellations never since the user asked for them
+fn print_notification(printer: &str, result: &ServerMessage, config: &Config) -> Option<(String, String)> {
+    let ServerMessage::PrintResult { success, error, code, .. } = result else {
+        return None;
+    };
+
+    if *success {
+        return config
+            .notify_print_successes
+            .then(|| ("Label printed".to_string(), format!("Sent to {}", printer)));
+    }
+
+    if *code == Some(ErrorCode::Cancelled) || !config.notify_print_failures.unwrap_or(true) {
+        return None;
+    }
+
+    let reason = error.as_deref().unwrap_or("Unknown error");
+    let reason = match reason.char_indices().nth(MAX_REASON_CHARS) {
+        Some((end, _)) => format!("{}…", &reason[..end]),
+        None => reason.to_string(),
+    };
+    Some((format!("Print failed on {}", printer), reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn printed() -> ServerMessage {
+        ServerMessage::PrintResult {
+            request_id: "label-1".to_string(),
+            success: true,
+            job_id: Some("Zebra_ZP450-7".to_string()),
+            message: None,
+            error: None,
+            code: None,
+            copy_results: None,
+            attempts: Some(1),
+            retry_after: None,
+        }
+    }
+
+    fn failed(code: ErrorCode, error: &str) -> ServerMessage {
+        ServerMessage::print_failure("label-1".to_string(), code, error)
+    }
+
+    #[test]
+    fn failures_notify_by_default() {
+        let notification = print_notification("Zebra_ZP450", &failed(ErrorCode::PrinterUnavailable, "Printer is offline"), &Config::default());
+        assert_eq!(
+            notification,
+            Some(("Print failed on Zebra_ZP450".to_string(), "Printer is offline".to_string()))
+        );
+    }
+
+    #[test]
+    fn failures_can_be_silenced() {
+        let config = Config {
+            notify_print_failures: Some(false),
+            ..Config::default()
+        };
+        assert_eq!(print_notification("Zebra_ZP450", &failed(ErrorCode::PrintFailed, "Out of paper"), &config), None);
+    }
+
+    #[test]
+    fn successes_notify_only_when_turned_on() {
+        assert_eq!(print_notification("Zebra_ZP450", &printed(), &Config::default()), None);
+
+        let config = Config {
+            notify_print_successes: true,
+            ..Config::default()
+        };
+        assert_eq!(
+            print_notification("Zebra_ZP450", &printed(), &config),
+            Some(("Label printed".to_string(), "Sent to Zebra_ZP450".to_string()))
+        );
+    }
+
+    #[test]
+    fn cancelled_prints_never_notify() {
+        assert_eq!(print_notification("Zebra_ZP450", &failed(ErrorCode::Cancelled, "Cancelled"), &Config::default()), None);
+    }
+
+    #[test]
+    fn other_messages_never_notify() {
+        let error = ServerMessage::error(ErrorCode::InvalidMessage, "Invalid message format");
+        assert_eq!(print_notification("Zebra_ZP450", &error, &Config::default()), None);
+    }
+
+    #[test]
+    fn long_reasons_are_cut_off() {
+        let reason = "é".repeat(MAX_REASON_CHARS + 10);
+        let (_, body) = print_notification("Zebra_ZP450", &failed(ErrorCode::PrintFailed, &reason), &Config::default()).unwrap();
+        assert_eq!(body.chars().count(), MAX_REASON_CHARS + 1);
+        assert!(body.ends_with('…'));
+    }
+}
@@ -0,0 +1,208 @@
+use crate::config::{self, PairedToken};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::Rng;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a pairing code shown in the tray stays valid
+const PAIRING_CODE_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Written to the app data directory so support can read the code without the tray
+const PAIRING_CODE_FILE: &str = "pairing-code.txt";
+
+/// Wrong guesses allowed before the code is thrown away, so a page can't try all million
+const MAX_CODE_ATTEMPTS: u32 = 5;
+
+struct PendingCode {
+    code: String,
+    issued_at: Instant,
+    file: PathBuf,
+    wrong_attempts: u32,
+}
+
+/// The one pairing code currently on offer, replaced each time pairing starts
+static PENDING_CODE: Mutex<Option<PendingCode>> = Mutex::new(None);
+
+/// Result of checking the token sent in `hello`
+pub enum TokenCheck {
+    /// No token was sent
+    Missing,
+    /// A token previously issued to this origin
+    Paired,
+    /// The current pairing code was used; the browser should keep this new token
+    NewlyPaired(String),
+    /// Unknown token, or an expired or already used pairing code
+    Invalid,
+}
+
+/// Start pairing a browser: create a short-lived code and write it next to the config
+pub fn start_pairing(dir: &Path) -> String {
+    let code = format!("{:06}", rand::thread_rng().gen_range(0..1_000_000));
+    let file = dir.join(PAIRING_CODE_FILE);
+
+    if let Err(e) = std::fs::create_dir_all(dir).and_then(|_| std::fs::write(&file, &code)) {
+        log::warn!("Failed to write pairing code to {:?}: {}", file, e);
+    }
+
+    *PENDING_CODE.lock().unwrap() = Some(PendingCode {
+        code: code.clone(),
+        issued_at: Instant::now(),
+        file,
+        wrong_attempts: 0,
+    });
+    log::info!("Pairing started, code valid for {} minutes", PAIRING_CODE_TTL.as_secs() / 60);
+    code
+}
+
+/// Check a `hello` token against paired tokens and the current pairing code
+///
+/// A valid pairing code is used up and exchanged for a persistent token for the origin.
+pub fn check_token(origin: &str, token: Option<&str>) -> TokenCheck {
+    let Some(token) = token else {
+        return TokenCheck::Missing;
+    };
+
+    let paired = config::get()
+        .paired_tokens
+        .iter()
+        .any(|p| p.origin == origin && constant_time_eq(p.token.as_bytes(), token.as_bytes()));
+    if paired {
+        return TokenCheck::Paired;
+    }
+
+    if !use_code(&mut PENDING_CODE.lock().unwrap(), token) {
+        return TokenCheck::Invalid;
+    }
+
+    let token = URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>());
+    let paired = PairedToken {
+        origin: origin.to_string(),
+        token: token.clone(),
+        paired_at: chrono::Utc::now().to_rfc3339(),
+    };
+    if let Err(e) = config::update(|c| c.paired_tokens.push(paired)) {
+        log::error!("Failed to save paired token: {}", e);
+        return TokenCheck::Invalid;
+    }
+
+    log::info!("Paired new browser for origin {}", origin);
+    TokenCheck::NewlyPaired(token)
+}
+
+/// Use up the pending code if `token` is it; too many wrong guesses discard the code
+///
+/// Only guesses shaped like a code count, so a browser retrying a revoked token can't use
+/// up the code meant for it.
+fn use_code(pending: &mut Option<PendingCode>, token: &str) -> bool {
+    let Some(code) = pending.as_mut() else {
+        return false;
+    };
+
+    let expired = code.issued_at.elapsed() >= PAIRING_CODE_TTL;
+    let matches = !expired && constant_time_eq(code.code.as_bytes(), token.as_bytes());
+    if !matches && !expired && token.len() == code.code.len() {
+        code.wrong_attempts += 1;
+        log::warn!("Wrong pairing code ({} of {} attempts)", code.wrong_attempts, MAX_CODE_ATTEMPTS);
+    }
+
+    if matches || expired || code.wrong_attempts >= MAX_CODE_ATTEMPTS {
+        if !matches && !expired {
+            log::warn!("Too many wrong pairing codes, discarding the code; start pairing again from the tray");
+        }
+        if let Some(discarded) = pending.take() {
+            let _ = std::fs::remove_file(&discarded.file);
+        }
+    }
+    matches
+}
+
+/// Compare without returning early, so response timing doesn't reveal how much of a guess
+/// was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending(code: &str, dir: &Path) -> Option<PendingCode> {
+        let file = dir.join(PAIRING_CODE_FILE);
+        std::fs::write(&file, code).unwrap();
+        Some(PendingCode {
+            code: code.to_string(),
+            issued_at: Instant::now(),
+            file,
+            wrong_attempts: 0,
+        })
+    }
+
+    #[test]
+    fn right_code_is_accepted_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut code = pending("042137", dir.path());
+
+        assert!(use_code(&mut code, "042137"));
+        assert!(code.is_none());
+        assert!(!dir.path().join(PAIRING_CODE_FILE).exists());
+        assert!(!use_code(&mut code, "042137"));
+    }
+
+    #[test]
+    fn wrong_code_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut code = pending("042137", dir.path());
+
+        assert!(!use_code(&mut code, "042138"));
+        assert!(!use_code(&mut code, "42137"));
+        assert!(!use_code(&mut code, ""));
+        assert!(use_code(&mut code, "042137"));
+    }
+
+    #[test]
+    fn code_is_discarded_after_too_many_wrong_guesses() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut code = pending("042137", dir.path());
+
+        for guess in 0..MAX_CODE_ATTEMPTS {
+            assert!(!use_code(&mut code, &format!("{:06}", guess)));
+        }
+        assert!(code.is_none());
+        assert!(!dir.path().join(PAIRING_CODE_FILE).exists());
+        assert!(!use_code(&mut code, "042137"));
+    }
+
+    #[test]
+    fn revoked_tokens_do_not_use_up_the_code() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut code = pending("042137", dir.path());
+
+        for _ in 0..MAX_CODE_ATTEMPTS * 2 {
+            assert!(!use_code(&mut code, "q2VtbW9uLXJldm9rZWQtdG9rZW4tZnJvbS1sYXN0LXdlZWs"));
+        }
+        assert!(use_code(&mut code, "042137"));
+    }
+
+    #[test]
+    fn expired_code_is_rejected_and_discarded() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut code = pending("042137", dir.path());
+        let Some(issued) = Instant::now().checked_sub(PAIRING_CODE_TTL) else {
+            return;
+        };
+        code.as_mut().unwrap().issued_at = issued;
+
+        assert!(!use_code(&mut code, "042137"));
+        assert!(code.is_none());
+    }
+
+    #[test]
+    fn comparison_needs_every_byte_to_match() {
+        assert!(constant_time_eq(b"042137", b"042137"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"042137", b"042138"));
+        assert!(!constant_time_eq(b"042137", b"142137"));
+        assert!(!constant_time_eq(b"042137", b"0421370"));
+    }
+}
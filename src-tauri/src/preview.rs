@@ -0,0 +1,306 @@
+use crate::config;
+use crate::log_context;
+use crate::printer::{PaperSize, Rotation, Scaling};
+use image::{imageops::FilterType, DynamicImage, ImageFormat};
+use std::io::Cursor;
+use std::time::Duration;
+
+/// Longest side of a rendered preview, in pixels
+const MAX_PREVIEW_SIDE: u32 = 1600;
+
+/// Resolution PDFs are rasterized at
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+const PDF_PREVIEW_DPI: u32 = 150;
+
+/// Resolution a label fitted to its paper size is drawn at
+const FIT_PREVIEW_DPI: f64 = 150.0;
+
+/// Labelary renders ZPL the way Zebra printers do; only its API host is contacted
+const LABELARY_URL: &str = "https://api.labelary.com/v1/printers/8dpmm/labels";
+
+const LABELARY_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// A rendered preview as PNG bytes
+pub struct Preview {
+    pub png: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Rasterize a label to a PNG no larger than `MAX_PREVIEW_SIDE`, applying the rotation and
+/// scaling
+///
+/// `paper_size` picks the label size for ZPL (4x6 inches when missing) and the page a `fit`
+/// label is fitted to.
+pub async fn render_preview(
+    format: &str,
+    data: Vec<u8>,
+    rotation: Rotation,
+    scaling: Option<Scaling>,
+    paper_size: Option<PaperSize>,
+) -> Result<Preview, String> {
+    let image = match format {
+        "png" | "jpg" | "jpeg" | "gif" | "bmp" => decode_image(&data)?,
+        "pdf" => {
+            let png = log_context::spawn_blocking(move || render_pdf(&data))
+                .await
+                .map_err(|e| format!("PDF render task failed: {}", e))??;
+            decode_image(&png)?
+        }
+        "zpl" => decode_image(&render_zpl(data, paper_size).await?)?,
+        other => return Err(format!("Previews aren't available for {}", other)),
+    };
+
+    let image = match rotation {
+        Rotation::None => image,
+        Rotation::Quarter => image.rotate90(),
+        Rotation::Half => image.rotate180(),
+        Rotation::ThreeQuarter => image.rotate270(),
+    };
+    let (width, height) = preview_size(image.width(), image.height(), scaling, paper_size);
+    let image = if (width, height) != (image.width(), image.height()) {
+        image.resize_exact(width, height, FilterType::Triangle)
+    } else {
+        image
+    };
+
+    let mut png = Vec::new();
+    image
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(|e| format!("Failed to encode preview: {}", e))?;
+
+    Ok(Preview {
+        png,
+        width: image.width(),
+        height: image.height(),
+    })
+}
+
+/// Size of the scaled image, with its longest side bounded by `MAX_PREVIEW_SIDE`
+fn preview_size(width: u32, height: u32, scaling: Option<Scaling>, paper_size: Option<PaperSize>) -> (u32, u32) {
+    let (width, height) = (width as f64, height as f64);
+    let factor = match (scaling, paper_size) {
+        (Some(Scaling::Percent(percent)), _) => percent as f64 / 100.0,
+        (Some(Scaling::Fit), Some(paper)) => {
+            let page_width = paper.width_in as f64 * FIT_PREVIEW_DPI;
+            let page_height = paper.height_in as f64 * FIT_PREVIEW_DPI;
+            (page_width / width).min(page_height / height)
+        }
+        // Without a page there's nothing to fit to
+        _ => 1.0,
+    };
+    let (width, height) = (width * factor, height * factor);
+
+    // Scaling is applied before the bound, so a large percentage can't blow up the image
+    let bound = (MAX_PREVIEW_SIDE as f64 / width.max(height)).min(1.0);
+    let side = |length: f64| ((length * bound).round() as u32).max(1);
+    (side(width), side(height))
+}
+
+fn decode_image(data: &[u8]) -> Result<DynamicImage, String> {
+    image::load_from_memory(data).map_err(|e| format!("Failed to decode image: {}", e))
+}
+
+/// Render the first page of a PDF with the platform's tools
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+fn render_pdf(data: &[u8]) -> Result<Vec<u8>, String> {
+    use std::process::Command;
+
+    let stem = std::env::temp_dir().join(format!("limestack_preview_{}_{}", std::process::id(), unique_suffix()));
+    let input = stem.with_extension("pdf");
+    let output = stem.with_extension("png");
+    std::fs::write(&input, data).map_err(|e| format!("Failed to write preview input: {}", e))?;
+
+    // poppler's pdftoppm is the better renderer; macOS always has sips
+    let rendered = Command::new("pdftoppm")
+        .args(["-png", "-singlefile", "-f", "1", "-l", "1", "-r"])
+        .arg(PDF_PREVIEW_DPI.to_string())
+        .arg(&input)
+        .arg(&stem)
+        .output();
+    let rendered = match rendered {
+        Ok(out) if out.status.success() => Ok(()),
+        _ if cfg!(target_os = "macos") => Command::new("sips")
+            .args(["-s", "format", "png"])
+            .arg(&input)
+            .arg("--out")
+            .arg(&output)
+            .output()
+            .map_err(|e| format!("Failed to run sips: {}", e))
+            .and_then(|out| {
+                if out.status.success() {
+                    Ok(())
+                } else {
+                    Err(format!("sips failed: {}", String::from_utf8_lossy(&out.stderr)))
+                }
+            }),
+        Ok(out) => Err(format!("pdftoppm failed: {}", String::from_utf8_lossy(&out.stderr))),
+        Err(e) => Err(format!("PDF previews need poppler-utils (pdftoppm): {}", e)),
+    };
+
+    let result = rendered.and_then(|_| std::fs::read(&output).map_err(|e| format!("Failed to read preview: {}", e)));
+    let _ = std::fs::remove_file(&input);
+    let _ = std::fs::remove_file(&output);
+    result
+}
+
+#[cfg(target_os = "windows")]
+fn render_pdf(_data: &[u8]) -> Result<Vec<u8>, String> {
+    Err("PDF previews aren't available on Windows yet".to_string())
+}
+
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+fn unique_suffix() -> u128 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default()
+}
+
+/// Render ZPL through the Labelary API, if `labelaryPreviews` allows sending labels there
+async fn render_zpl(zpl: Vec<u8>, paper_size: Option<PaperSize>) -> Result<Vec<u8>, String> {
+    if !config::get().labelary_previews {
+        return Err(
+            "ZPL previews send the label to api.labelary.com; set labelaryPreviews: true in config.json to allow it"
+                .to_string(),
+        );
+    }
+    let (width, height) = paper_size.map_or((4.0, 6.0), |p| (p.width_in, p.height_in));
+
+    let client = reqwest::Client::builder()
+        .timeout(LABELARY_TIMEOUT)
+        .build()
+        .map_err(|e| format!("Failed to set up preview request: {}", e))?;
+    let response = client
+        .post(format!("{}/{}x{}/0/", LABELARY_URL, width, height))
+        .header(reqwest::header::ACCEPT, "image/png")
+        .body(zpl)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| format!("ZPL preview failed: {}", e))?;
+
+    response
+        .bytes()
+        .await
+        .map(|b| b.to_vec())
+        .map_err(|e| format!("ZPL preview failed: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::printer;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut png = Vec::new();
+        DynamicImage::new_rgb8(width, height)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        png
+    }
+
+    /// A one-page PDF with an empty page of the given size in points
+    #[cfg(target_os = "linux")]
+    fn pdf(width_pt: u32, height_pt: u32) -> Vec<u8> {
+        let objects = [
+            "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+            "<< /Type /Pages /Kids [3 0 R] /Count 1 >>".to_string(),
+            format!("<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] >>", width_pt, height_pt),
+        ];
+        let mut pdf = String::from("%PDF-1.4\n");
+        let mut offsets = Vec::new();
+        for (i, object) in objects.iter().enumerate() {
+            offsets.push(pdf.len());
+            pdf.push_str(&format!("{} 0 obj\n{}\nendobj\n", i + 1, object));
+        }
+        let xref = pdf.len();
+        pdf.push_str(&format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1));
+        for offset in offsets {
+            pdf.push_str(&format!("{:010} 00000 n \n", offset));
+        }
+        pdf.push_str(&format!(
+            "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+            objects.len() + 1,
+            xref
+        ));
+        pdf.into_bytes()
+    }
+
+    #[tokio::test]
+    async fn images_keep_their_size() {
+        let preview = render_preview("png", png(400, 600), Rotation::None, None, None).await.unwrap();
+        assert_eq!((preview.width, preview.height), (400, 600));
+
+        let decoded = decode_image(&preview.png).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (400, 600));
+    }
+
+    #[tokio::test]
+    async fn rotation_is_applied() {
+        let quarter = render_preview("png", png(400, 600), Rotation::Quarter, None, None).await.unwrap();
+        assert_eq!((quarter.width, quarter.height), (600, 400));
+
+        let half = render_preview("png", png(400, 600), Rotation::Half, None, None).await.unwrap();
+        assert_eq!((half.width, half.height), (400, 600));
+    }
+
+    #[tokio::test]
+    async fn large_images_are_bounded() {
+        let preview = render_preview("png", png(3200, 800), Rotation::None, None, None).await.unwrap();
+        assert_eq!((preview.width, preview.height), (MAX_PREVIEW_SIDE, 400));
+
+        let rotated = render_preview("png", png(3200, 800), Rotation::ThreeQuarter, None, None).await.unwrap();
+        assert_eq!((rotated.width, rotated.height), (400, MAX_PREVIEW_SIDE));
+    }
+
+    #[tokio::test]
+    async fn unsupported_formats_and_bad_data_are_rejected() {
+        let err = render_preview("epl", b"N\nP1\n".to_vec(), Rotation::None, None, None).await.err().unwrap();
+        assert!(err.contains("Previews aren't available for epl"), "{}", err);
+
+        let err = render_preview("png", b"not a png".to_vec(), Rotation::None, None, None).await.err().unwrap();
+        assert!(err.starts_with("Failed to decode image"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn percentage_scaling_resizes_the_label() {
+        let half = Some(Scaling::Percent(50));
+        let preview = render_preview("png", png(400, 600), Rotation::None, half, None).await.unwrap();
+        assert_eq!((preview.width, preview.height), (200, 300));
+
+        // Still bounded, however large the percentage
+        let tenfold = Some(Scaling::Percent(1000));
+        let preview = render_preview("png", png(400, 600), Rotation::None, tenfold, None).await.unwrap();
+        assert_eq!((preview.width, preview.height), (1067, MAX_PREVIEW_SIDE));
+    }
+
+    #[test]
+    fn fit_scales_to_the_paper_size() {
+        let paper = printer::parse_paper_size("custom:2x1in").ok();
+        // 300x150 pixels of page; the taller side limits the label
+        assert_eq!(preview_size(400, 600, Some(Scaling::Fit), paper), (100, 150));
+        assert_eq!(preview_size(100, 50, Some(Scaling::Fit), paper), (300, 150));
+        assert_eq!(preview_size(400, 600, Some(Scaling::Actual), paper), (400, 600));
+        assert_eq!(preview_size(400, 600, Some(Scaling::Fit), None), (400, 600));
+    }
+
+    #[tokio::test]
+    async fn zpl_previews_are_off_unless_enabled() {
+        let err = render_preview("zpl", b"^XA^XZ".to_vec(), Rotation::None, None, None).await.err().unwrap();
+        assert!(err.contains("labelaryPreviews"), "{}", err);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn small_pdf_renders_at_preview_dpi() {
+        // A 1x2 inch page
+        let preview = match render_preview("pdf", pdf(72, 144), Rotation::None, None, None).await {
+            Ok(preview) => preview,
+            // Nothing to test without poppler-utils installed
+            Err(e) if e.contains("poppler-utils") => return,
+            Err(e) => panic!("{}", e),
+        };
+        assert_eq!((preview.width, preview.height), (PDF_PREVIEW_DPI, 2 * PDF_PREVIEW_DPI));
+    }
+}
@@ -0,0 +1,216 @@
+use crate::protocol::ServerMessage;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+/// Prints by client and `requestId`, so a print resent after a reconnect is answered with
+/// the first attempt's result instead of printing again
+///
+/// Clients are identified by origin, which survives reconnects. Only successful prints are
+/// remembered; a failed print can be retried under the same id.
+pub struct RecentPrints {
+    ttl: Duration,
+    entries: Mutex<HashMap<(String, String), Entry>>,
+}
+
+enum Entry {
+    /// Still printing; resends wait for the result
+    Running(Vec<oneshot::Sender<ServerMessage>>),
+    Succeeded { finished_at: Instant, response: ServerMessage },
+}
+
+/// What a resent request gets instead of a second print
+pub enum EarlierResult {
+    Finished(ServerMessage),
+    Running(oneshot::Receiver<ServerMessage>),
+}
+
+impl RecentPrints {
+    pub fn new(ttl: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// This client's view, for one connection
+    pub fn for_client(self: &Arc<Self>, client: String) -> ClientPrints {
+        ClientPrints {
+            recent: self.clone(),
+            client,
+        }
+    }
+}
+
+/// One client's recent prints
+#[derive(Clone)]
+pub struct ClientPrints {
+    recent: Arc<RecentPrints>,
+    client: String,
+}
+
+impl ClientPrints {
+    /// The earlier result for a resent request id, or `None` after claiming it for a new print
+    ///
+    /// Every claimed id must be passed to `finish` once its print completes.
+    pub fn earlier_result(&self, request_id: &str) -> Option<EarlierResult> {
+        let mut entries = self.recent.entries.lock().unwrap();
+        let ttl = self.recent.ttl;
+        entries.retain(|_, entry| match entry {
+            Entry::Running(_) => true,
+            Entry::Succeeded { finished_at, .. } => finished_at.elapsed() < ttl,
+        });
+
+        match entries.get_mut(&self.key(request_id)) {
+            Some(Entry::Succeeded { response, .. }) => Some(EarlierResult::Finished(response.clone())),
+            Some(Entry::Running(waiters)) => {
+                let (tx, rx) = oneshot::channel();
+                waiters.push(tx);
+                Some(EarlierResult::Running(rx))
+            }
+            None => {
+                entries.insert(self.key(request_id), Entry::Running(Vec::new()));
+                None
+            }
+        }
+    }
+
+    /// Record a claimed print's result and pass it to any resends waiting on it
+    pub fn finish(&self, request_id: &str, response: &ServerMessage) {
+        let mut entries = self.recent.entries.lock().unwrap();
+        let key = self.key(request_id);
+        let waiters = match entries.remove(&key) {
+            Some(Entry::Running(waiters)) => waiters,
+            _ => Vec::new(),
+        };
+        if succeeded(response) && !self.recent.ttl.is_zero() {
+            entries.insert(
+                key,
+                Entry::Succeeded {
+                    finished_at: Instant::now(),
+                    response: response.clone(),
+                },
+            );
+        }
+        drop(entries);
+
+        for waiter in waiters {
+            let _ = waiter.send(response.clone());
+        }
+    }
+
+    fn key(&self, request_id: &str) -> (String, String) {
+        (self.client.clone(), request_id.to_string())
+    }
+}
+
+fn succeeded(response: &ServerMessage) -> bool {
+    matches!(
+        response,
+        ServerMessage::PrintResult { success: true, .. } | ServerMessage::PrintBatchResult { success: true, .. }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ErrorCode;
+    use std::cell::Cell;
+
+    fn printed(request_id: &str, job_id: &str) -> ServerMessage {
+        ServerMessage::PrintResult {
+            request_id: request_id.to_string(),
+            success: true,
+            job_id: Some(job_id.to_string()),
+            message: None,
+            error: None,
+            code: None,
+            copy_results: None,
+            attempts: None,
+            retry_after: None,
+        }
+    }
+
+    fn json(message: &ServerMessage) -> serde_json::Value {
+        serde_json::to_value(message).unwrap()
+    }
+
+    /// Submit a print the way the server does, with `print` run only for a new request id
+    async fn submit(client: &ClientPrints, request_id: &str, print: impl FnOnce() -> ServerMessage) -> ServerMessage {
+        match client.earlier_result(request_id) {
+            Some(EarlierResult::Finished(response)) => response,
+            Some(EarlierResult::Running(rx)) => rx.await.unwrap(),
+            None => {
+                let response = print();
+                client.finish(request_id, &response);
+                response
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn resent_request_prints_once_with_the_same_result() {
+        let client = RecentPrints::new(Duration::from_secs(60)).for_client("https://app.limestack.io".to_string());
+        let prints = Cell::new(0);
+        let print = || {
+            prints.set(prints.get() + 1);
+            printed("job-1", &format!("Zebra-{}", prints.get()))
+        };
+
+        let first = submit(&client, "job-1", print).await;
+        let resent = submit(&client, "job-1", print).await;
+        assert_eq!(prints.get(), 1);
+        assert_eq!(json(&resent), json(&first));
+    }
+
+    #[tokio::test]
+    async fn resend_while_printing_waits_for_the_result() {
+        let client = RecentPrints::new(Duration::from_secs(60)).for_client("https://app.limestack.io".to_string());
+        assert!(client.earlier_result("job-1").is_none());
+        let Some(EarlierResult::Running(waiting)) = client.earlier_result("job-1") else {
+            panic!("resend didn't wait for the running print");
+        };
+
+        client.finish("job-1", &printed("job-1", "Zebra-1"));
+        assert_eq!(json(&waiting.await.unwrap()), json(&printed("job-1", "Zebra-1")));
+    }
+
+    #[tokio::test]
+    async fn failed_print_can_be_retried() {
+        let client = RecentPrints::new(Duration::from_secs(60)).for_client("https://app.limestack.io".to_string());
+        let failed = ServerMessage::print_failure("job-1".to_string(), ErrorCode::PrintFailed, "Printer busy");
+        submit(&client, "job-1", || failed).await;
+
+        let retried = submit(&client, "job-1", || printed("job-1", "Zebra-2")).await;
+        assert_eq!(json(&retried), json(&printed("job-1", "Zebra-2")));
+    }
+
+    #[tokio::test]
+    async fn request_ids_are_per_client() {
+        let recent = RecentPrints::new(Duration::from_secs(60));
+        let app = recent.for_client("https://app.limestack.io".to_string());
+        let other = recent.for_client("https://other.example".to_string());
+        submit(&app, "job-1", || printed("job-1", "Zebra-1")).await;
+
+        let response = submit(&other, "job-1", || printed("job-1", "Zebra-2")).await;
+        assert_eq!(json(&response), json(&printed("job-1", "Zebra-2")));
+    }
+
+    #[tokio::test]
+    async fn results_are_forgotten_after_the_ttl() {
+        let client = RecentPrints::new(Duration::from_millis(20)).for_client("https://app.limestack.io".to_string());
+        submit(&client, "job-1", || printed("job-1", "Zebra-1")).await;
+        tokio::time::sleep(Duration::from_millis(40)).await;
+
+        let response = submit(&client, "job-1", || printed("job-1", "Zebra-2")).await;
+        assert_eq!(json(&response), json(&printed("job-1", "Zebra-2")));
+    }
+
+    #[tokio::test]
+    async fn zero_ttl_turns_dedupe_off() {
+        let client = RecentPrints::new(Duration::ZERO).for_client("https://app.limestack.io".to_string());
+        submit(&client, "job-1", || printed("job-1", "Zebra-1")).await;
+        assert!(client.earlier_result("job-1").is_none());
+    }
+}
@@ -0,0 +1,110 @@
+use crate::protocol::{PrintRecord, ServerMessage};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Number of recent print jobs kept for support
+const HISTORY_CAPACITY: usize = 100;
+
+/// Recent print jobs across all connections, oldest first
+static HISTORY: Mutex<VecDeque<PrintRecord>> = Mutex::new(VecDeque::new());
+
+/// Record the outcome of a print request, dropping the oldest entry when full
+pub fn record(printer: &str, format: &str, result: &ServerMessage) {
+    if let Some(entry) = print_record(printer, format, result) {
+        push(&mut HISTORY.lock().unwrap(), entry);
+    }
+}
+
+/// Recent print jobs, newest first
+pub fn recent() -> Vec<PrintRecord> {
+    newest_first(&HISTORY.lock().unwrap())
+}
+
+/// The history entry for a print result; other messages aren't prints
+fn print_record(printer: &str, format: &str, result: &ServerMessage) -> Option<PrintRecord> {
+    let ServerMessage::PrintResult {
+        request_id,
+        success,
+        error,
+        ..
+    } = result
+    else {
+        return None;
+    };
+
+    Some(PrintRecord {
+        request_id: request_id.clone(),
+        printer: printer.to_string(),
+        format: format.to_string(),
+        timestamp: chrono::Utc::now().to_rfc3339(),
+        success: *success,
+        error: error.clone(),
+    })
+}
+
+fn push(history: &mut VecDeque<PrintRecord>, entry: PrintRecord) {
+    if history.len() == HISTORY_CAPACITY {
+        history.pop_front();
+    }
+    history.push_back(entry);
+}
+
+fn newest_first(history: &VecDeque<PrintRecord>) -> Vec<PrintRecord> {
+    history.iter().rev().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ErrorCode;
+
+    fn printed(request_id: &str) -> PrintRecord {
+        let result = ServerMessage::print_failure(request_id.to_string(), ErrorCode::PrintFailed, "Printer busy");
+        print_record("Zebra_ZP450", "zpl", &result).unwrap()
+    }
+
+    fn request_ids(records: &[PrintRecord]) -> Vec<&str> {
+        records.iter().map(|r| r.request_id.as_str()).collect()
+    }
+
+    #[test]
+    fn print_results_are_recorded() {
+        let record = printed("job-1");
+        assert_eq!(record.printer, "Zebra_ZP450");
+        assert_eq!(record.format, "zpl");
+        assert!(!record.success);
+        assert_eq!(record.error.as_deref(), Some("Printer busy"));
+        assert!(chrono::DateTime::parse_from_rfc3339(&record.timestamp).is_ok());
+    }
+
+    #[test]
+    fn other_messages_are_not_recorded() {
+        let error = ServerMessage::error(ErrorCode::InvalidMessage, "Invalid message format");
+        assert!(print_record("Zebra_ZP450", "zpl", &error).is_none());
+    }
+
+    #[test]
+    fn history_is_listed_newest_first() {
+        let mut history = VecDeque::new();
+        for id in ["job-1", "job-2", "job-3"] {
+            push(&mut history, printed(id));
+        }
+        assert_eq!(request_ids(&newest_first(&history)), ["job-3", "job-2", "job-1"]);
+    }
+
+    #[test]
+    fn oldest_entries_are_evicted_at_capacity() {
+        let mut history = VecDeque::new();
+        for i in 0..HISTORY_CAPACITY {
+            push(&mut history, printed(&format!("job-{}", i)));
+        }
+        assert_eq!(history.len(), HISTORY_CAPACITY);
+        assert_eq!(history.front().unwrap().request_id, "job-0");
+
+        push(&mut history, printed("job-100"));
+        push(&mut history, printed("job-101"));
+        assert_eq!(history.len(), HISTORY_CAPACITY);
+        assert_eq!(history.front().unwrap().request_id, "job-2");
+        assert_eq!(request_ids(&newest_first(&history))[..2], ["job-101", "job-100"]);
+    }
+}
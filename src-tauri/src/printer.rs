@@ -1,17 +1,39 @@
-use crate::protocol::PrinterInfo;
-use base64::{engine::general_purpose::STANDARD, Engine};
+use crate::brother_ql;
+use crate::capabilities;
+use crate::config;
+use crate::image_pdf;
+use crate::printer_status;
+use crate::protocol::{PrinterInfo, PrinterStatus};
+use crate::raw_device;
+use crate::virtual_printer;
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD, URL_SAFE, URL_SAFE_NO_PAD};
+use base64::Engine;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
 use std::io::Write;
 use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 /// Get list of available printers
 pub fn get_printers() -> Vec<PrinterInfo> {
     let system_printers = printers::get_printers();
 
-    system_printers
+    let mut names: Vec<String> = system_printers.iter().map(|p| p.system_name.clone()).collect();
+    let statuses = printer_status::printer_statuses(&names);
+    let virtual_printer = virtual_printer::enabled();
+    if virtual_printer {
+        names.push(virtual_printer::ID.to_string());
+    }
+    let pinned = pinned_default_printer(&names);
+    let config = config::get();
+
+    let mut printers: Vec<PrinterInfo> = system_printers
         .into_iter()
         .map(|p| {
             // Detect if it's likely a thermal printer based on name
-            let printer_type = if is_thermal_printer(&p.name) {
+            let printer_type = if matches_thermal_keywords(&p.name, &config.thermal_keywords) {
                 "thermal"
             } else {
                 "standard"
@@ -20,28 +42,312 @@ pub fn get_printers() -> Vec<PrinterInfo> {
             // Use system_name for CUPS compatibility, fall back to name
             let printer_id = p.system_name.clone();
 
-            log::debug!("Found printer: name='{}', system_name='{}', is_default={}",
-                p.name, p.system_name, p.is_default);
+            // A pinned printer replaces the OS default
+            let is_default = is_listed_default(pinned.as_deref(), &p.system_name, p.is_default);
+
+            let status = statuses
+                .get(&p.system_name)
+                .copied()
+                .unwrap_or(PrinterStatus::Unknown);
+
+            log::debug!("Found printer: name='{}', system_name='{}', is_default={}, status={:?}",
+                p.name, p.system_name, is_default, status);
 
             PrinterInfo {
+                name: listed_name(&config.printer_nicknames, &printer_id, &p.name),
                 id: printer_id,
-                name: p.name.clone(),
                 printer_type: printer_type.to_string(),
-                status: "ready".to_string(),
-                is_default: p.is_default,
+                status,
+                is_default,
+                capabilities: capabilities::printer_capabilities(&p.system_name),
             }
         })
-        .collect()
+        .collect();
+
+    if virtual_printer {
+        let mut info = virtual_printer::info();
+        info.is_default = pinned.as_deref() == Some(virtual_printer::ID);
+        printers.push(info);
+    }
+    printers
+}
+
+/// Printers plus why enumeration failed, so "no printers" and "couldn't list printers" differ
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrinterList {
+    pub printers: Vec<PrinterInfo>,
+    /// Set when the list is empty because the print system isn't available
+    pub error: Option<String>,
+}
+
+/// Spooler services can start after the connector at login, so empty lists are retried
+/// this long after startup
+const STARTUP_GRACE: Duration = Duration::from_secs(60);
+const STARTUP_RETRIES: u32 = 3;
+const STARTUP_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// List printers, telling an empty list apart from an unavailable print system
+pub fn list_printers() -> PrinterList {
+    cached_list(|| list_from(get_printers()))
+}
+
+/// Like `list_printers`, but retries an empty list while the server is still starting up
+pub fn list_printers_at_startup(uptime: Duration) -> PrinterList {
+    let retries = if uptime < STARTUP_GRACE { STARTUP_RETRIES } else { 0 };
+    cached_list(|| list_from(retry_while_empty(get_printers, retries, STARTUP_RETRY_DELAY)))
+}
+
+/// The last enumeration, reused until it's older than the TTL
+///
+/// The lock is held while enumerating, so concurrent callers wait for one query rather than
+/// each starting their own.
+static LIST_CACHE: Mutex<Option<CachedList>> = Mutex::new(None);
+static LIST_CACHE_TTL_MS: AtomicU64 = AtomicU64::new(2000);
+
+struct CachedList {
+    listed_at: Instant,
+    /// Nicknames and the pinned default shape the list, so a config change makes it stale
+    config_generation: u64,
+    list: PrinterList,
+}
+
+/// How long an enumeration is reused; zero enumerates on every call
+pub fn set_list_cache_ttl(ttl: Duration) {
+    LIST_CACHE_TTL_MS.store(ttl.as_millis().min(u64::MAX as u128) as u64, Ordering::Relaxed);
+}
+
+fn cached_list(enumerate: impl FnOnce() -> PrinterList) -> PrinterList {
+    let ttl = Duration::from_millis(LIST_CACHE_TTL_MS.load(Ordering::Relaxed));
+    cached_list_in(&LIST_CACHE, ttl, config::generation(), enumerate)
+}
+
+fn cached_list_in(
+    cache: &Mutex<Option<CachedList>>,
+    ttl: Duration,
+    config_generation: u64,
+    enumerate: impl FnOnce() -> PrinterList,
+) -> PrinterList {
+    let mut cache = cache.lock().unwrap();
+    let fresh = |c: &&CachedList| c.listed_at.elapsed() < ttl && c.config_generation == config_generation;
+    if let Some(cached) = cache.as_ref().filter(fresh) {
+        return cached.list.clone();
+    }
+
+    let list = enumerate();
+    *cache = Some(CachedList {
+        listed_at: Instant::now(),
+        config_generation,
+        list: list.clone(),
+    });
+    list
+}
+
+fn list_from(printers: Vec<PrinterInfo>) -> PrinterList {
+    let error = if printers.is_empty() { enumeration_error() } else { None };
+    PrinterList { printers, error }
+}
+
+/// Call `enumerate` until it returns something or `retries` run out
+fn retry_while_empty<T>(enumerate: impl Fn() -> Vec<T>, retries: u32, delay: Duration) -> Vec<T> {
+    let mut items = enumerate();
+    for attempt in 1..=retries {
+        if !items.is_empty() {
+            break;
+        }
+        log::info!("No printers found, retrying ({}/{})", attempt, retries);
+        std::thread::sleep(delay);
+        items = enumerate();
+    }
+    items
+}
+
+/// Locale for CUPS commands: English messages, which the output parsing relies on, in UTF-8
+/// so non-ASCII printer names come through intact rather than as '?'
+#[cfg(target_os = "linux")]
+const CUPS_LOCALE: &str = "C.UTF-8";
+#[cfg(target_os = "macos")]
+const CUPS_LOCALE: &str = "en_US.UTF-8";
+
+/// A CUPS command line tool with a predictable locale; arguments are passed as a vector,
+/// never through a shell
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+pub fn cups_command(program: &str) -> Command {
+    let mut cmd = Command::new(program);
+    cmd.env("LC_ALL", CUPS_LOCALE);
+    cmd
+}
+
+/// Why the print system can't be queried, if it can't
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+fn enumeration_error() -> Option<String> {
+    match cups_command("lpstat").arg("-r").output() {
+        Ok(output) if String::from_utf8_lossy(&output.stdout).contains("scheduler is running") => None,
+        Ok(_) => Some("The CUPS scheduler is not running".to_string()),
+        Err(e) => Some(format!("CUPS is not installed: {}", e)),
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn enumeration_error() -> Option<String> {
+    match Command::new("sc").args(["query", "Spooler"]).output() {
+        Ok(output) if String::from_utf8_lossy(&output.stdout).contains("RUNNING") => None,
+        Ok(_) => Some("The Print Spooler service is not running".to_string()),
+        Err(e) => Some(format!("Failed to query the Print Spooler service: {}", e)),
+    }
+}
+
+/// Whether a pinned default printer is still among the printers found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PinState {
+    Present,
+    /// Printers were listed and the pinned one wasn't among them
+    Gone,
+    /// Nothing was listed, which may just mean the spooler isn't ready yet
+    Unknown,
+}
+
+fn pin_state(pinned: &str, available: &[String]) -> PinState {
+    if available.iter().any(|name| name == pinned) {
+        PinState::Present
+    } else if available.is_empty() {
+        PinState::Unknown
+    } else {
+        PinState::Gone
+    }
+}
+
+/// Whether a printer is listed as the default: the pinned one if there is a pin, otherwise
+/// whatever the OS says
+fn is_listed_default(pinned: Option<&str>, system_name: &str, os_default: bool) -> bool {
+    match pinned {
+        Some(id) => id == system_name,
+        None => os_default,
+    }
+}
+
+/// The user-pinned default printer, cleared if it no longer exists
+fn pinned_default_printer(available: &[String]) -> Option<String> {
+    let pinned = config::get().default_printer_id?;
+
+    match pin_state(&pinned, available) {
+        PinState::Present => Some(pinned),
+        PinState::Gone => {
+            log::warn!("Pinned default printer '{}' is gone, falling back to OS default", pinned);
+            if let Err(e) = config::update(|c| c.default_printer_id = None) {
+                log::error!("Failed to clear default printer: {}", e);
+            }
+            None
+        }
+        PinState::Unknown => None,
+    }
+}
+
+/// Pin a printer as the LimeStack default
+pub fn set_default_printer(printer_id: &str) -> Result<(), String> {
+    config::update(|c| c.default_printer_id = Some(printer_id.to_string()))
+}
+
+/// Show a printer under a friendlier name; an empty nickname restores the system name
+///
+/// Nicknames are display-only: the id stays the system name, so `find_printer` is unaffected.
+pub fn set_printer_nickname(printer_id: &str, nickname: &str) -> Result<(), String> {
+    config::update(|c| set_nickname(&mut c.printer_nicknames, printer_id, nickname))
+}
+
+fn set_nickname(nicknames: &mut BTreeMap<String, String>, printer_id: &str, nickname: &str) {
+    let nickname = nickname.trim();
+    if nickname.is_empty() {
+        nicknames.remove(printer_id);
+    } else {
+        nicknames.insert(printer_id.to_string(), nickname.to_string());
+    }
+}
+
+/// The name a printer is listed under: its nickname if it has one, else the system's
+fn listed_name(nicknames: &BTreeMap<String, String>, printer_id: &str, system_name: &str) -> String {
+    nicknames.get(printer_id).cloned().unwrap_or_else(|| system_name.to_string())
 }
 
+/// Name fragments of thermal label and receipt printers; `thermalKeywords` in the config adds more
+const THERMAL_KEYWORDS: &[&str] = &[
+    "rollo", "dymo", "labelwriter", "zebra", "brother ql", "thermal",
+    "label", "4x6", "shipping", "stamps.com", "receipt", "tm-t", "tsp", "tsc",
+    "lp2844", "lp 2844", "zp450", "zp 450", "zp505", "zp 505", "gk420", "gx420", "zd410", "zd420", "zd620",
+    "xprinter", "munbyn", "idprt", "arkscan", "godex", "bixolon", "phomemo", "polono", "beeprt", "jadens",
+];
+
 /// Check if a printer is likely a thermal label printer based on its name
 fn is_thermal_printer(name: &str) -> bool {
-    let thermal_keywords = [
-        "rollo", "dymo", "zebra", "brother ql", "thermal",
-        "label", "4x6", "shipping", "stamps.com"
-    ];
+    matches_thermal_keywords(name, &config::get().thermal_keywords)
+}
+
+fn matches_thermal_keywords(name: &str, extra: &[String]) -> bool {
     let name_lower = name.to_lowercase();
-    thermal_keywords.iter().any(|kw| name_lower.contains(kw))
+    THERMAL_KEYWORDS.iter().any(|kw| name_lower.contains(kw))
+        || extra
+            .iter()
+            .map(|kw| kw.trim().to_lowercase())
+            .any(|kw| !kw.is_empty() && name_lower.contains(&kw))
+}
+
+/// Formats that are sent to the printer untouched instead of being rendered by the OS
+const RAW_FORMATS: &[&str] = &["zpl", "epl", "tspl", "escpos", BROTHER_RASTER_FORMAT];
+
+/// Image labels converted to Brother QL raster commands and sent raw
+pub const BROTHER_RASTER_FORMAT: &str = "brother_raster";
+
+/// Image formats converted to PDF before printing
+const IMAGE_FORMATS: &[&str] = &["png", "jpg", "jpeg", "gif", "bmp"];
+
+/// Every format `print` accepts, rendered formats first
+pub fn supported_formats() -> Vec<String> {
+    std::iter::once("pdf")
+        .chain(IMAGE_FORMATS.iter().copied())
+        .chain(RAW_FORMATS.iter().copied())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Check if a format is a printer command language that must bypass the driver
+pub fn is_raw_format(format: &str) -> bool {
+    RAW_FORMATS.contains(&format.to_lowercase().as_str())
+}
+
+/// ASCII characters allowed in a printer name besides letters and digits
+const PRINTER_NAME_PUNCTUATION: &str = " -_.()@#,+:/\\[]";
+
+/// Reject printer names that could be misread when passed to print commands
+///
+/// Names come from the OS printer list, but they end up on command lines and in shell
+/// verbs, so ASCII outside a conservative character set is refused. Other Unicode is
+/// fine ("Imprimante Bureau — Café"), since arguments are passed as UTF-8 (UTF-16 on
+/// Windows) without a shell, except for control and invisible formatting characters.
+pub fn validate_printer_name(name: &str) -> Result<(), String> {
+    if name.trim().is_empty() {
+        return Err("Printer name is empty".to_string());
+    }
+    if name.starts_with('-') {
+        return Err(format!("Printer name may not start with '-': {}", name));
+    }
+    match name
+        .chars()
+        .find(|c| !printer_name_char_allowed(*c))
+    {
+        Some(c) => Err(format!("Printer name contains unsupported character {:?}: {}", c, name)),
+        None => Ok(()),
+    }
+}
+
+fn printer_name_char_allowed(c: char) -> bool {
+    if c.is_ascii() {
+        return c.is_ascii_alphanumeric() || PRINTER_NAME_PUNCTUATION.contains(c);
+    }
+    // Zero-width and direction marks could make a name display as something else
+    let invisible = matches!(
+        c,
+        '\u{200b}'..='\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2060}'..='\u{2069}' | '\u{feff}'
+    );
+    !c.is_control() && !c.is_whitespace() && !invisible
 }
 
 /// Create a safe ID from printer name
@@ -54,159 +360,2776 @@ fn sanitize_printer_id(name: &str) -> String {
         .to_string()
 }
 
-/// Find a printer by ID (system_name)
-pub fn find_printer(printer_id: &str) -> Option<String> {
-    let printers = printers::get_printers();
-    log::debug!("Looking for printer with id: {}", printer_id);
-    printers
-        .into_iter()
-        .find(|p| p.system_name == printer_id)
-        .map(|p| {
-            log::debug!("Found printer: system_name='{}', name='{}'", p.system_name, p.name);
-            p.system_name // Return system_name for CUPS
-        })
+/// Supported paper sizes: (accepted name, CUPS media, SumatraPDF paper, width and height in inches)
+///
+/// SumatraPDF only knows standard paper names, so label sizes rely on the driver default there.
+const PAPER_SIZES: &[(&str, &str, Option<&str>, f32, f32)] = &[
+    ("4x6", "na_index-4x6_4x6in", None, 4.0, 6.0),
+    ("a4", "iso_a4_210x297mm", Some("A4"), 8.27, 11.69),
+    ("a5", "iso_a5_148x210mm", Some("A5"), 5.83, 8.27),
+    ("a6", "iso_a6_105x148mm", Some("A6"), 4.13, 5.83),
+    ("letter", "na_letter_8.5x11in", Some("letter"), 8.5, 11.0),
+    ("legal", "na_legal_8.5x14in", Some("legal"), 8.5, 14.0),
+];
+
+/// Units a `custom:` paper size can be given in, with their length in inches
+const CUSTOM_SIZE_UNITS: &[(&str, f32)] = &[("in", 1.0), ("mm", 1.0 / 25.4)];
+
+/// Longest side accepted for a custom paper size, in inches
+const MAX_CUSTOM_SIZE_INCHES: f32 = 48.0;
+
+/// A paper size resolved to each platform's media name
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PaperSize {
+    media: Media,
+    pub sumatra_paper: Option<&'static str>,
+    pub width_in: f32,
+    pub height_in: f32,
 }
 
-/// Print a label to the specified printer (supports PDF and PNG)
-pub fn print_label(printer_name: &str, data_base64: &str, format: &str, copies: u32) -> Result<(), String> {
-    log::info!("Printing {} to '{}' ({} copies)", format, printer_name, copies);
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Media {
+    /// An IPP media name
+    Named(&'static str),
+    /// Dimensions as sent, so CUPS gets the same numbers and unit
+    Custom { width: f32, height: f32, unit: &'static str },
+}
 
-    // Decode base64 data
-    let data = STANDARD
-        .decode(data_base64)
-        .map_err(|e| format!("Failed to decode {}: {}", format, e))?;
+impl PaperSize {
+    /// The CUPS `media` value: the IPP name, or `Custom.WxH` with the unit for custom sizes
+    pub fn cups_media(&self) -> String {
+        match self.media {
+            Media::Named(name) => name.to_string(),
+            Media::Custom { width, height, unit } => format!("Custom.{}x{}{}", width, height, unit),
+        }
+    }
 
-    log::debug!("Decoded {}: {} bytes", format, data.len());
+    pub fn is_custom(&self) -> bool {
+        matches!(self.media, Media::Custom { .. })
+    }
+}
 
-    // Determine file extension based on format
-    let extension = match format.to_lowercase().as_str() {
-        "png" => "png",
-        "pdf" => "pdf",
-        "jpg" | "jpeg" => "jpg",
-        _ => "pdf", // Default to PDF
+/// Look up a paper size by name (case-insensitive), or read a `custom:WIDTHxHEIGHTunit` size
+/// such as `custom:2.25x1.25in` or `custom:57x32mm`
+pub fn parse_paper_size(name: &str) -> Result<PaperSize, String> {
+    let name = name.trim().to_lowercase();
+    if let Some(size) = name.strip_prefix("custom:") {
+        return parse_custom_paper_size(size);
+    }
+    PAPER_SIZES
+        .iter()
+        .find(|(n, ..)| *n == name)
+        .map(|(_, cups_media, sumatra_paper, width_in, height_in)| PaperSize {
+            media: Media::Named(cups_media),
+            sumatra_paper: *sumatra_paper,
+            width_in: *width_in,
+            height_in: *height_in,
+        })
+        .ok_or_else(|| {
+            let supported: Vec<&str> = PAPER_SIZES.iter().map(|(n, ..)| *n).collect();
+            format!(
+                "Unsupported paper size '{}' (supported: {}, or custom:WIDTHxHEIGHTin / custom:WIDTHxHEIGHTmm)",
+                name,
+                supported.join(", ")
+            )
+        })
+}
+
+fn parse_custom_paper_size(size: &str) -> Result<PaperSize, String> {
+    let invalid = || format!("Invalid custom paper size '{}' (expected e.g. custom:2.25x1.25in or custom:57x32mm)", size);
+    let (unit, inches_per_unit) = CUSTOM_SIZE_UNITS
+        .iter()
+        .find(|(unit, _)| size.ends_with(unit))
+        .ok_or_else(invalid)?;
+    let (width, height) = size[..size.len() - unit.len()].split_once('x').ok_or_else(invalid)?;
+
+    // Plain decimals only, so "1e3" or "inf" don't slip through `f32` parsing
+    let dimension = |value: &str| -> Result<f32, String> {
+        let value = value.trim();
+        if value.is_empty() || !value.chars().all(|c| c.is_ascii_digit() || c == '.') {
+            return Err(invalid());
+        }
+        let number: f32 = value.parse().map_err(|_| invalid())?;
+        let inches = number * inches_per_unit;
+        if inches <= 0.0 || inches > MAX_CUSTOM_SIZE_INCHES {
+            return Err(format!(
+                "Custom paper size '{}' must be more than 0 and at most {} inches a side",
+                size, MAX_CUSTOM_SIZE_INCHES
+            ));
+        }
+        Ok(number)
     };
+    let (width, height) = (dimension(width)?, dimension(height)?);
+
+    Ok(PaperSize {
+        media: Media::Custom { width, height, unit },
+        // SumatraPDF only takes named paper
+        sumatra_paper: None,
+        width_in: width * inches_per_unit,
+        height_in: height * inches_per_unit,
+    })
+}
 
-    // Write to temp file
-    let temp_dir = std::env::temp_dir();
-    let temp_path = temp_dir.join(format!("limestack_label_{}.{}", std::process::id(), extension));
+/// Clockwise page rotation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Rotation {
+    #[default]
+    None,
+    Quarter,
+    Half,
+    ThreeQuarter,
+}
 
-    log::debug!("Writing to temp file: {:?}", temp_path);
+/// Accept 0, 90, 180 or 270 degrees
+pub fn parse_rotation(degrees: u32) -> Result<Rotation, String> {
+    match degrees {
+        0 => Ok(Rotation::None),
+        90 => Ok(Rotation::Quarter),
+        180 => Ok(Rotation::Half),
+        270 => Ok(Rotation::ThreeQuarter),
+        other => Err(format!("Unsupported rotation {} (supported: 0, 90, 180, 270)", other)),
+    }
+}
 
-    let mut file = std::fs::File::create(&temp_path)
-        .map_err(|e| format!("Failed to create temp file: {}", e))?;
+/// How the document is scaled onto the media
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scaling {
+    /// Shrink or grow the page to fill the media
+    Fit,
+    /// Print at the document's own size
+    Actual,
+    /// Print at a percentage of the document's size
+    Percent(u32),
+}
 
-    file.write_all(&data)
-        .map_err(|e| format!("Failed to write label: {}", e))?;
+/// Scaling for a job that doesn't set `scale`
+///
+/// Thermal labels are already made for the media, and fitting them to a driver's default
+/// page shrinks or distorts them, so they print at actual size. Other printers fit.
+fn default_scaling(printer_name: &str) -> Scaling {
+    if is_thermal_printer(printer_name) {
+        Scaling::Actual
+    } else {
+        Scaling::Fit
+    }
+}
 
-    // Ensure file is flushed and closed before printing
-    drop(file);
+/// `options` with any unset scaling filled in from `default_scaling`
+fn with_default_scaling(options: &JobOptions, printer_name: &str) -> JobOptions {
+    JobOptions {
+        scaling: Some(options.scaling.unwrap_or_else(|| default_scaling(printer_name))),
+        ..options.clone()
+    }
+}
 
-    // Verify file exists
-    if !temp_path.exists() {
-        return Err("Temp file was not created".to_string());
+/// Accept "fit", "actual", or a percentage such as "75%" or "75"
+pub fn parse_scaling(value: &str) -> Result<Scaling, String> {
+    let value = value.trim().to_lowercase();
+    match value.as_str() {
+        "fit" => Ok(Scaling::Fit),
+        "actual" => Ok(Scaling::Actual),
+        _ => match value.trim_end_matches('%').trim().parse::<u32>() {
+            Ok(percent) if (1..=1000).contains(&percent) => Ok(Scaling::Percent(percent)),
+            _ => Err(format!(
+                "Unsupported scale '{}' (expected \"fit\", \"actual\" or a percentage from 1 to 1000)",
+                value
+            )),
+        },
     }
+}
 
-    // Print using OS-specific command
-    let result = print_file(&temp_path, printer_name, copies);
+/// Print quality requested from the driver
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quality {
+    Draft,
+    Normal,
+    High,
+}
 
-    // Clean up temp file
-    let _ = std::fs::remove_file(&temp_path);
+impl Quality {
+    /// IPP `print-quality` enum value
+    fn ipp_value(self) -> u8 {
+        match self {
+            Quality::Draft => 3,
+            Quality::Normal => 4,
+            Quality::High => 5,
+        }
+    }
+}
 
-    result
+/// Accept "draft", "normal" or "high"
+pub fn parse_quality(value: &str) -> Result<Quality, String> {
+    match value.trim().to_lowercase().as_str() {
+        "draft" => Ok(Quality::Draft),
+        "normal" => Ok(Quality::Normal),
+        "high" => Ok(Quality::High),
+        other => Err(format!("Unsupported quality '{}' (expected \"draft\", \"normal\" or \"high\")", other)),
+    }
 }
 
-#[cfg(target_os = "macos")]
-fn print_file(path: &std::path::Path, printer_name: &str, copies: u32) -> Result<(), String> {
-    log::info!("Running: lpr -P '{}' -# {} -o fit-to-page {:?}", printer_name, copies, path);
+/// Darkest setting thermal drivers take; Zebra's CUPS driver uses 0 to 30
+const MAX_DARKNESS: u32 = 30;
 
-    let output = Command::new("lpr")
-        .arg("-P")
-        .arg(printer_name)
-        .arg("-#")
-        .arg(copies.to_string())
-        .arg("-o")
-        .arg("fit-to-page")
-        .arg(path)
-        .output()
-        .map_err(|e| format!("Failed to execute lpr: {}", e))?;
+/// Accept a darkness from 0 to 30
+pub fn parse_darkness(darkness: u32) -> Result<u32, String> {
+    if darkness > MAX_DARKNESS {
+        return Err(format!("Darkness {} is out of range (0 to {})", darkness, MAX_DARKNESS));
+    }
+    Ok(darkness)
+}
 
-    if output.status.success() {
-        log::info!("Print job submitted successfully");
-        Ok(())
-    } else {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        log::error!("lpr failed: {}", stderr);
-        Err(format!("lpr failed: {}", stderr))
+/// Pages to print, as inclusive 1-based ranges in the order given
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageRange(Vec<(u32, u32)>);
+
+impl PageRange {
+    /// Whether the 1-based `page` is in the range
+    pub fn contains(&self, page: u32) -> bool {
+        self.0.iter().any(|&(first, last)| (first..=last).contains(&page))
     }
 }
 
-#[cfg(target_os = "windows")]
-fn print_file(path: &std::path::Path, printer_name: &str, copies: u32) -> Result<(), String> {
-    // Use SumatraPDF for silent printing if available, otherwise use default PDF handler
-    let sumatra_paths = [
-        r"C:\Program Files\SumatraPDF\SumatraPDF.exe",
-        r"C:\Program Files (x86)\SumatraPDF\SumatraPDF.exe",
-    ];
+impl std::fmt::Display for PageRange {
+    /// The "1-3,5" form both CUPS `page-ranges` and SumatraPDF expect
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let parts: Vec<String> = self
+            .0
+            .iter()
+            .map(|&(first, last)| if first == last { first.to_string() } else { format!("{}-{}", first, last) })
+            .collect();
+        f.write_str(&parts.join(","))
+    }
+}
+
+/// Accept comma-separated pages and ascending ranges such as "1", "2-3" or "1,4-6"
+pub fn parse_page_range(value: &str) -> Result<PageRange, String> {
+    let invalid = || format!("Invalid page range '{}' (expected pages like \"1\", \"2-3\" or \"1,4\")", value);
+    let page = |s: &str| s.trim().parse::<u32>().ok().filter(|&n| n > 0).ok_or_else(invalid);
+
+    let ranges = value
+        .split(',')
+        .map(|part| match part.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (page(first)?, page(last)?);
+                if first > last {
+                    return Err(invalid());
+                }
+                Ok((first, last))
+            }
+            None => page(part).map(|n| (n, n)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(PageRange(ranges))
+}
+
+/// Most `rawOptions` one print may pass
+const MAX_RAW_OPTIONS: usize = 16;
+
+/// Characters allowed in a raw option value besides letters and digits
+const RAW_OPTION_VALUE_PUNCTUATION: &str = "._-:,+/@%";
+
+/// Options `rawOptions` may not set: ones the print options already control, and ones that
+/// change the whole job (banner pages, holds, imposition) rather than how the label prints
+const RESERVED_RAW_OPTIONS: &[&str] = &[
+    "copies",
+    "collate",
+    "multiple-document-handling",
+    "page-ranges",
+    "page-set",
+    "media",
+    "PageSize",
+    "PageRegion",
+    "orientation-requested",
+    "landscape",
+    "portrait",
+    "fit-to-page",
+    "scaling",
+    "print-quality",
+    "Darkness",
+    "raw",
+    "document-format",
+    "job-sheets",
+    "job-hold-until",
+    "job-priority",
+    "job-cancel-after",
+    "number-up",
+    "number-up-layout",
+    "outputorder",
+    "page-border",
+    "mirror",
+];
 
-    for sumatra_path in sumatra_paths {
-        if std::path::Path::new(sumatra_path).exists() {
-            let output = Command::new(sumatra_path)
-                .arg("-print-to")
-                .arg(printer_name)
-                .arg("-print-settings")
-                .arg(format!("{}x", copies))
-                .arg("-silent")
-                .arg(path)
-                .output()
-                .map_err(|e| format!("Failed to execute SumatraPDF: {}", e))?;
-
-            if output.status.success() {
-                return Ok(());
+/// Check CUPS `-o` options passed through from `rawOptions`, e.g. "MediaType=Thermal"
+///
+/// lp runs without a shell, but options are still limited to `name` or `name=value` from a
+/// conservative character set so nothing can be read as another flag or a file.
+pub fn parse_raw_options(options: &[String]) -> Result<Vec<String>, String> {
+    if options.len() > MAX_RAW_OPTIONS {
+        return Err(format!("At most {} rawOptions are allowed", MAX_RAW_OPTIONS));
+    }
+    options
+        .iter()
+        .map(|option| {
+            let option = option.trim();
+            let (name, value) = option.split_once('=').unwrap_or((option, ""));
+            let name_ok = name.chars().next().is_some_and(|c| c.is_ascii_alphabetic())
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+            let value_ok = value
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || RAW_OPTION_VALUE_PUNCTUATION.contains(c));
+            if !name_ok || !value_ok {
+                return Err(format!(
+                    "Unsupported raw option '{}'; use name=value with letters, digits and {}",
+                    option, RAW_OPTION_VALUE_PUNCTUATION
+                ));
+            }
+            if RESERVED_RAW_OPTIONS.iter().any(|reserved| reserved.eq_ignore_ascii_case(name)) {
+                return Err(format!(
+                    "Raw option '{}' is not allowed; use the matching print option, if there is one",
+                    name
+                ));
             }
+            Ok(option.to_string())
+        })
+        .collect()
+}
+
+/// Driver settings for a single print job
+#[derive(Debug, Clone)]
+pub struct JobOptions {
+    pub copies: u32,
+    pub paper_size: Option<PaperSize>,
+    pub rotation: Rotation,
+    /// None uses `default_scaling` for the printer
+    pub scaling: Option<Scaling>,
+    /// None prints every page
+    pub page_range: Option<PageRange>,
+    /// None leaves quality to the driver
+    pub quality: Option<Quality>,
+    /// Thermal darkness from 0 to 30; None leaves it to the driver
+    pub darkness: Option<u32>,
+    /// Validated CUPS `-o` options, applied after the ones above; ignored on Windows
+    pub raw_options: Vec<String>,
+}
+
+/// How several copies of a label are printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyStrategy {
+    /// One job carrying the copies option (`lp -n`, SumatraPDF `Nx`)
+    Spooler,
+    /// One job per copy, for drivers that ignore the copies option
+    PerCopyJobs,
+}
+
+/// Choose how to print `copies` copies, and why
+///
+/// An explicit `perCopyJobs` wins, then printers listed under `ignoresCopies` in the config,
+/// then drivers that report they can only print one copy at a time.
+pub fn copy_strategy(printer_name: &str, per_copy_jobs: Option<bool>, copies: u32) -> (CopyStrategy, &'static str) {
+    choose_copy_strategy(
+        per_copy_jobs,
+        copies,
+        config::get().ignores_copies.contains(printer_name),
+        || capabilities::printer_capabilities(printer_name).max_copies,
+    )
+}
+
+/// `copy_strategy` with the printer's `ignoresCopies` listing and driver copy limit given;
+/// the limit is only queried when it decides
+fn choose_copy_strategy(
+    per_copy_jobs: Option<bool>,
+    copies: u32,
+    ignores_copies: bool,
+    max_copies: impl FnOnce() -> Option<u32>,
+) -> (CopyStrategy, &'static str) {
+    match per_copy_jobs {
+        Some(true) => return (CopyStrategy::PerCopyJobs, "perCopyJobs requested"),
+        Some(false) => return (CopyStrategy::Spooler, "perCopyJobs turned off"),
+        None => {}
+    }
+    if copies <= 1 {
+        return (CopyStrategy::Spooler, "single copy");
+    }
+    if ignores_copies {
+        return (CopyStrategy::PerCopyJobs, "printer is listed in ignoresCopies");
+    }
+    if max_copies() == Some(1) {
+        return (CopyStrategy::PerCopyJobs, "driver reports a maximum of one copy");
+    }
+    (CopyStrategy::Spooler, "driver handles copies")
+}
+
+impl Default for JobOptions {
+    fn default() -> Self {
+        Self {
+            copies: 1,
+            paper_size: None,
+            rotation: Rotation::None,
+            scaling: None,
+            page_range: None,
+            quality: None,
+            darkness: None,
+            raw_options: Vec::new(),
         }
     }
+}
 
-    // Fallback: use Windows print verb
-    let path_str = path.to_string_lossy();
-    let output = Command::new("cmd")
-        .args(["/C", "start", "/wait", "", "/min", "mshta"])
-        .arg(format!(
-            "javascript:var ws=new ActiveXObject('WScript.Shell');ws.Run('print /d:\"{}\" \"{}\"',0,true);close();",
-            printer_name, path_str
-        ))
-        .output()
-        .map_err(|e| format!("Failed to print: {}", e))?;
+/// Retries for print failures that look temporary
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Extra attempts after the first one fails
+    pub max_retries: u32,
+    /// Wait before the first retry, doubled for each one after
+    pub initial_backoff: Duration,
+}
 
-    if output.status.success() {
-        Ok(())
-    } else {
-        // Try another fallback
-        Command::new("rundll32")
-            .arg("mshtml.dll,PrintHTML")
-            .arg(path)
-            .output()
-            .map_err(|e| format!("Failed to print: {}", e))?;
-        Ok(())
+/// Error text from lp, CUPS and the spooler that usually clears up on its own
+const TRANSIENT_ERROR_PATTERNS: &[&str] = &[
+    "busy",
+    "temporarily",
+    "try again",
+    "timed out",
+    "connection refused",
+    "unable to connect",
+    "not responding",
+    "device not ready",
+];
+
+/// Whether a print error is worth retrying, e.g. a busy printer or a USB reconnect
+pub fn is_transient_error(error: &str) -> bool {
+    let error = error.to_lowercase();
+    TRANSIENT_ERROR_PATTERNS.iter().any(|pattern| error.contains(pattern))
+}
+
+/// lp and spooler messages meaning the queue itself won't take jobs, with a summary for the user
+///
+/// e.g. `lp: Destination "Zebra" is not accepting jobs.` after `cupsreject`, or
+/// `lp: Too many active jobs.` when the scheduler's job limit is reached.
+const UNAVAILABLE_ERROR_PATTERNS: &[(&str, &str)] = &[
+    ("not accepting jobs", "The printer's queue is not accepting jobs"),
+    ("too many active jobs", "The printer's queue is full"),
+    ("too many jobs", "The printer's queue is full"),
+    ("printer is paused", "The printer is paused"),
+];
+
+/// Why the printer can't take a job right now, if that's what a print error means
+///
+/// These need someone to re-enable the queue or clear a jam, so retrying won't help.
+pub fn printer_unavailable_reason(error: &str) -> Option<String> {
+    let lower = error.to_lowercase();
+    UNAVAILABLE_ERROR_PATTERNS
+        .iter()
+        .find(|(pattern, _)| lower.contains(pattern))
+        .map(|(_, summary)| format!("{} ({})", summary, error.trim()))
+}
+
+/// Run `attempt` until it succeeds, fails with a permanent error, or retries run out
+///
+/// Sleeps between attempts, so call it from a blocking thread. Returns the final result
+/// and how many attempts were made.
+pub fn with_retries<T>(policy: RetryPolicy, mut attempt: impl FnMut() -> Result<T, String>) -> (Result<T, String>, u32) {
+    let mut backoff = policy.initial_backoff;
+    let mut attempts = 1;
+    loop {
+        match attempt() {
+            Err(e) if attempts <= policy.max_retries && is_transient_error(&e) => {
+                log::warn!("Print attempt {} failed, retrying in {:?}: {}", attempts, backoff, e);
+                std::thread::sleep(backoff);
+                backoff *= 2;
+                attempts += 1;
+            }
+            result => return (result, attempts),
+        }
     }
 }
 
-#[cfg(target_os = "linux")]
-fn print_file(path: &std::path::Path, printer_name: &str, copies: u32) -> Result<(), String> {
-    let output = Command::new("lp")
-        .arg("-d")
-        .arg(printer_name)
-        .arg("-n")
-        .arg(copies.to_string())
-        .arg(path)
-        .output()
-        .map_err(|e| format!("Failed to execute lp: {}", e))?;
+/// One printer as `get_printers` lists it, looked up like `find_printer`, with its status
+/// re-read rather than taken from the cached list
+pub fn get_printer(printer_id: &str) -> Option<PrinterInfo> {
+    let name = find_printer(printer_id)?;
+    printer_with_status(&name, list_printers().printers, |name| {
+        printer_status::printer_statuses(&[name.to_string()]).get(name).copied()
+    })
+}
 
-    if output.status.success() {
-        Ok(())
-    } else {
-        Err(format!(
-            "lp failed: {}",
-            String::from_utf8_lossy(&output.stderr)
-        ))
+/// The listed printer for a queue, with its status replaced by a fresh one where there is one
+fn printer_with_status(
+    name: &str,
+    printers: Vec<PrinterInfo>,
+    fresh_status: impl FnOnce(&str) -> Option<PrinterStatus>,
+) -> Option<PrinterInfo> {
+    let mut info = printers.into_iter().find(|p| p.id == name)?;
+    if name != virtual_printer::ID {
+        if let Some(status) = fresh_status(name) {
+            info.status = status;
+        }
+    }
+    Some(info)
+}
+
+/// The printer most likely meant by "the label printer", for UIs that don't want to choose
+///
+/// A printer pinned with `labelPrinterId` wins, then the default printer if it's thermal,
+/// then the first thermal printer listed.
+pub fn default_thermal_printer() -> Option<PrinterInfo> {
+    let pinned = config::get().label_printer_id.and_then(|id| {
+        let name = find_printer(&id);
+        if name.is_none() {
+            log::warn!("Pinned label printer '{}' was not found", id);
+        }
+        name
+    });
+    choose_thermal_printer(list_printers().printers, pinned.as_deref())
+}
+
+/// Pick from a printer list: the pinned id, the default if thermal, then the first thermal one
+fn choose_thermal_printer(printers: Vec<PrinterInfo>, pinned: Option<&str>) -> Option<PrinterInfo> {
+    let is_thermal = |p: &PrinterInfo| p.printer_type == "thermal";
+    let position = pinned
+        .and_then(|id| printers.iter().position(|p| p.id == id))
+        .or_else(|| printers.iter().position(|p| p.is_default && is_thermal(p)))
+        .or_else(|| printers.iter().position(is_thermal))?;
+    printers.into_iter().nth(position)
+}
+
+/// Find a printer by ID, returning its system_name for printing
+///
+/// Exact system_name matches win. Ids cached by the browser can drift from the queue name
+/// on some systems, so fall back to the display name (case-insensitive) and then to the
+/// sanitized form of either name.
+pub fn find_printer(printer_id: &str) -> Option<String> {
+    if printer_id == virtual_printer::ID && virtual_printer::enabled() {
+        return Some(virtual_printer::ID.to_string());
+    }
+
+    let printers = printers::get_printers();
+    log::debug!("Looking for printer with id: {}", printer_id);
+
+    let names: Vec<(&str, &str)> = printers.iter().map(|p| (p.system_name.as_str(), p.name.as_str())).collect();
+    let (system_name, strategy) = match_printer(printer_id, &names)?;
+    match strategy {
+        PrinterMatch::Exact => log::debug!("Found printer: system_name='{}'", system_name),
+        strategy => log::info!("Matched printer id '{}' to '{}' by {:?}", printer_id, system_name, strategy),
+    }
+
+    Some(system_name.to_string()) // Return system_name for CUPS
+}
+
+/// How `find_printer` matched an id to a queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PrinterMatch {
+    Exact,
+    DisplayName,
+    SanitizedId,
+}
+
+/// Pick the queue for `printer_id` from (system_name, display name) pairs
+fn match_printer<'a>(printer_id: &str, printers: &[(&'a str, &str)]) -> Option<(&'a str, PrinterMatch)> {
+    if let Some((system_name, _)) = printers.iter().find(|(system_name, _)| *system_name == printer_id) {
+        return Some((*system_name, PrinterMatch::Exact));
+    }
+    if let Some((system_name, _)) = printers.iter().find(|(_, name)| name.eq_ignore_ascii_case(printer_id)) {
+        return Some((*system_name, PrinterMatch::DisplayName));
+    }
+
+    let wanted = sanitize_printer_id(printer_id);
+    printers
+        .iter()
+        .find(|(system_name, name)| sanitize_printer_id(system_name) == wanted || sanitize_printer_id(name) == wanted)
+        .map(|(system_name, _)| (*system_name, PrinterMatch::SanitizedId))
+}
+
+/// What the connector can see when looking up a printer id, for integrators debugging a
+/// "Printer not found"
+pub struct PrinterDiagnosis {
+    /// The queue the id resolves to, if any
+    pub matched: Option<String>,
+    /// Known ids closest to the requested one, best first
+    pub suggestions: Vec<String>,
+    pub known_ids: Vec<String>,
+    /// Why the print system can't be queried, if it can't
+    pub spooler_error: Option<String>,
+}
+
+const MAX_SUGGESTIONS: usize = 3;
+
+pub fn diagnose_printer(printer_id: &str) -> PrinterDiagnosis {
+    let known_ids: Vec<String> = printers::get_printers().into_iter().map(|p| p.system_name).collect();
+    PrinterDiagnosis {
+        matched: find_printer(printer_id),
+        suggestions: closest_ids(printer_id, &known_ids),
+        known_ids,
+        spooler_error: enumeration_error(),
+    }
+}
+
+/// Known ids containing the wanted id (or contained in it), then those within a few edits,
+/// compared in sanitized form so case and punctuation don't count
+fn closest_ids(wanted: &str, known: &[String]) -> Vec<String> {
+    let wanted = sanitize_printer_id(wanted);
+    let mut scored: Vec<(usize, &String)> = known
+        .iter()
+        .filter_map(|id| {
+            let candidate = sanitize_printer_id(id);
+            let overlaps = !wanted.is_empty()
+                && !candidate.is_empty()
+                && (candidate.contains(&wanted) || wanted.contains(&candidate));
+            let distance = if overlaps { 0 } else { edit_distance(&wanted, &candidate) };
+            // More than half the characters changed isn't a near miss
+            let limit = wanted.chars().count().max(candidate.chars().count()) / 2;
+            (distance <= limit).then_some((distance, id))
+        })
+        .collect();
+    scored.sort();
+    scored.into_iter().take(MAX_SUGGESTIONS).map(|(_, id)| id.clone()).collect()
+}
+
+/// Levenshtein distance in characters
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                diagonal
+            } else {
+                1 + diagonal.min(above).min(row[j])
+            };
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+/// Move settings stored under printer ids that no longer exist to the printer they became
+///
+/// Reinstalling a driver or upgrading the OS can rename a queue, e.g. "Zebra_ZP450" to
+/// "Zebra_ZP450_1". An old id is remapped when exactly one current printer matches it the
+/// way `find_printer` would, by display name or sanitized id.
+pub fn migrate_printer_ids() {
+    let printers = printers::get_printers();
+    // The spooler may not be up yet, and an empty list would make every id look stale
+    if printers.is_empty() {
+        return;
+    }
+    let current: Vec<(String, String)> = printers.into_iter().map(|p| (p.system_name, p.name)).collect();
+
+    let remaps = stale_id_remaps(&config::get(), &current);
+    if remaps.is_empty() {
+        return;
+    }
+
+    for (old_id, new_id) in &remaps {
+        log::info!("Printer '{}' is now '{}', moving its settings", old_id, new_id);
+    }
+    if let Err(e) = config::update(|c| apply_remaps(c, &remaps)) {
+        log::error!("Failed to save migrated printer ids: {}", e);
+    }
+}
+
+/// New ids for the stored ids that aren't among the current printers (system name, display name)
+fn stale_id_remaps(config: &config::Config, current: &[(String, String)]) -> BTreeMap<String, String> {
+    stored_printer_ids(config)
+        .into_iter()
+        .filter(|id| id != virtual_printer::ID && !current.iter().any(|(system_name, _)| system_name == id))
+        .filter_map(|id| remap_target(&id, current).map(|new_id| (id, new_id)))
+        .collect()
+}
+
+/// Every printer id the config refers to
+fn stored_printer_ids(config: &config::Config) -> BTreeSet<String> {
+    config
+        .default_printer_id
+        .iter()
+        .chain(config.label_printer_id.iter())
+        .chain(config.printer_nicknames.keys())
+        .chain(config.printer_defaults.keys())
+        .chain(config.raw_devices.keys())
+        .chain(config.ignores_copies.iter())
+        .cloned()
+        .collect()
+}
+
+/// The one current printer (system name, display name) a stale id matches, if exactly one does
+fn remap_target(old_id: &str, current: &[(String, String)]) -> Option<String> {
+    let wanted = sanitize_printer_id(old_id);
+    let mut matches = current.iter().filter(|(system_name, name)| {
+        name.eq_ignore_ascii_case(old_id)
+            || sanitize_printer_id(name) == wanted
+            || sanitize_printer_id(system_name) == wanted
+    });
+    let (system_name, _) = matches.next()?;
+    matches.next().is_none().then(|| system_name.clone())
+}
+
+fn apply_remaps(config: &mut config::Config, remaps: &BTreeMap<String, String>) {
+    if let Some(new_id) = config.default_printer_id.as_ref().and_then(|id| remaps.get(id)) {
+        config.default_printer_id = Some(new_id.clone());
+    }
+    if let Some(new_id) = config.label_printer_id.as_ref().and_then(|id| remaps.get(id)) {
+        config.label_printer_id = Some(new_id.clone());
+    }
+    rekey(&mut config.printer_nicknames, remaps);
+    rekey(&mut config.printer_defaults, remaps);
+    rekey(&mut config.raw_devices, remaps);
+    for (old_id, new_id) in remaps {
+        if config.ignores_copies.remove(old_id) {
+            config.ignores_copies.insert(new_id.clone());
+        }
+    }
+}
+
+/// Move entries to their new ids, leaving any the new id already has untouched
+fn rekey<T>(map: &mut BTreeMap<String, T>, remaps: &BTreeMap<String, String>) {
+    for (old_id, new_id) in remaps {
+        if map.contains_key(new_id) {
+            continue;
+        }
+        if let Some(value) = map.remove(old_id) {
+            map.insert(new_id.clone(), value);
+        }
+    }
+}
+
+/// Label content as it arrived from the client
+#[derive(Debug, Clone)]
+pub enum LabelData {
+    /// Base64 or a base64 data URI from a JSON `print` message
+    Base64(String),
+    /// Raw bytes from a binary frame
+    Bytes(Vec<u8>),
+}
+
+/// Print a label to the specified printer (supports PDF, PNG, and raw ZPL/EPL/TSPL)
+///
+/// Base64 `data` may be plain or URL-safe, or a `data:<mime>;base64,` URI. An empty or
+/// `auto` format is taken from the URI's MIME type.
+///
+/// Returns the spooler job id when the platform reports one.
+pub fn print_label(printer_name: &str, data: &LabelData, format: &str, options: &JobOptions) -> Result<Option<String>, String> {
+    let label = prepare_label(printer_name, data, format, options)?;
+    log::info!("Printing {} to '{}' ({} copies)", label.format, printer_name, options.copies);
+
+    if printer_name == virtual_printer::ID {
+        return virtual_printer::print(&label, options.copies);
+    }
+
+    // Mapped devices take raw jobs directly, skipping the spooler's latency and filters
+    if label.raw {
+        if let Some(target) = config::get().raw_devices.get(printer_name) {
+            raw_device::send(&raw_device::parse_target(target), &label.data, options.copies)?;
+            return Ok(None);
+        }
+    }
+
+    // Determine file extension based on format
+    let extension = match label.format.as_str() {
+        "pdf" => "pdf",
+        "zpl" => "zpl",
+        "epl" => "epl",
+        "tspl" => "tspl",
+        "escpos" | BROTHER_RASTER_FORMAT => "bin",
+        _ => "pdf", // Default to PDF
+    };
+
+    let temp_path = write_temp_label(&label.data, extension)?;
+    log::debug!("Wrote label to temp file: {:?}", temp_path);
+
+    // Settle scaling here so every platform's print path makes the same choice
+    let options = &with_default_scaling(options, printer_name);
+
+    // Print using OS-specific command; the file is removed when `temp_path` drops, even on panic
+    print_file(&temp_path, printer_name, options, label.raw)
+}
+
+/// A label decoded and converted into what will be sent to the printer
+pub struct PreparedLabel<'a> {
+    pub data: Cow<'a, [u8]>,
+    /// Lowercase format after conversion, e.g. "pdf" for images
+    pub format: String,
+    pub raw: bool,
+}
+
+/// Run every check and conversion `print_label` does, without printing anything
+pub fn prepare_label<'a>(
+    printer_name: &str,
+    data: &'a LabelData,
+    format: &str,
+    options: &JobOptions,
+) -> Result<PreparedLabel<'a>, String> {
+    validate_printer_name(printer_name)?;
+
+    let (data, format): (Cow<[u8]>, String) = match data {
+        LabelData::Base64(encoded) => {
+            let (data, format) = decode_payload(encoded, format)?;
+            (Cow::Owned(data), format)
+        }
+        LabelData::Bytes(bytes) => (Cow::Borrowed(bytes.as_slice()), bytes_format(bytes, format)?),
+    };
+    check_content(&data, &format)?;
+    log::debug!("Decoded {}: {} bytes", format, data.len());
+
+    // Bare images print inconsistently across drivers, so send them as a fitted PDF page
+    let (data, format) = if IMAGE_FORMATS.contains(&format.as_str()) {
+        let page = options.paper_size.map(|p| (p.width_in, p.height_in));
+        (Cow::Owned(image_pdf::image_to_pdf(&data, page)?), "pdf".to_string())
+    } else if format == BROTHER_RASTER_FORMAT {
+        (Cow::Owned(brother_ql::encode_image(&data)?), format)
+    } else {
+        (data, format)
+    };
+
+    // The explicit format always wins, but flag likely mistakes
+    let raw = is_raw_format(&format);
+    if raw && !is_thermal_printer(printer_name) {
+        log::warn!("Sending raw {} to '{}', which doesn't look like a thermal printer", format, printer_name);
+    }
+
+    Ok(PreparedLabel { data, format, raw })
+}
+
+/// Write a label to a uniquely named temp file, readable only by the current user
+fn write_temp_label(data: &[u8], extension: &str) -> Result<tempfile::TempPath, String> {
+    let dir = config::get().temp_dir.unwrap_or_else(std::env::temp_dir);
+    write_temp_label_in(&dir, data, extension)
+}
+
+fn write_temp_label_in(dir: &std::path::Path, data: &[u8], extension: &str) -> Result<tempfile::TempPath, String> {
+    let mut file = tempfile::Builder::new()
+        .prefix("limestack_label_")
+        .suffix(&format!(".{}", extension))
+        .tempfile_in(dir)
+        .map_err(|e| format!("Failed to create temp file in {:?}: {}", dir, e))?;
+
+    file.write_all(data)
+        .map_err(|e| format!("Failed to write label: {}", e))?;
+
+    // Close the handle so the spooler can open the file on every platform
+    Ok(file.into_temp_path())
+}
+
+/// What's wrong with a print payload, told apart so each client bug gets its own error code
+#[derive(Debug, Clone, PartialEq)]
+pub enum PayloadError {
+    /// `data` was empty
+    Empty,
+    /// Not base64 in any of the accepted alphabets
+    InvalidBase64(String),
+    /// Valid base64 that decoded to nothing
+    EmptyDecoded,
+    /// The bytes or data URI type disagree with the declared format
+    FormatMismatch(String),
+    /// Anything else, e.g. a malformed data URI or a format that can't be inferred
+    Invalid(String),
+}
+
+impl std::fmt::Display for PayloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PayloadError::Empty => f.write_str("No label data"),
+            PayloadError::InvalidBase64(e) => write!(f, "Label data is not valid base64: {}", e),
+            PayloadError::EmptyDecoded => f.write_str("Label data decoded to zero bytes"),
+            PayloadError::FormatMismatch(e) | PayloadError::Invalid(e) => f.write_str(e),
+        }
+    }
+}
+
+impl From<PayloadError> for String {
+    fn from(e: PayloadError) -> Self {
+        e.to_string()
+    }
+}
+
+/// Decode label data once, up front, so later steps work on plain bytes
+pub fn decode_label(data: LabelData, format: &str) -> Result<(Vec<u8>, String), PayloadError> {
+    let (data, format) = match data {
+        LabelData::Base64(encoded) => decode_payload(&encoded, format)?,
+        LabelData::Bytes(bytes) => {
+            let format = bytes_format(&bytes, format)?;
+            (bytes, format)
+        }
+    };
+    check_content(&data, &format)?;
+    Ok((data, format))
+}
+
+/// Format for bytes from a binary frame, which have no data URI to infer it from
+fn bytes_format(bytes: &[u8], format: &str) -> Result<String, PayloadError> {
+    if bytes.is_empty() {
+        return Err(PayloadError::Empty);
+    }
+    match format.trim().to_lowercase().as_str() {
+        "" | "auto" => Err(PayloadError::Invalid("Binary print data needs an explicit format".to_string())),
+        format => Ok(format.to_string()),
+    }
+}
+
+/// How far into a PDF the header may start; the spec allows leading junk before `%PDF`
+const PDF_HEADER_SEARCH_BYTES: usize = 1024;
+
+/// Cheap sanity check that the bytes are what the format claims
+fn check_content(data: &[u8], format: &str) -> Result<(), PayloadError> {
+    let head = &data[..data.len().min(PDF_HEADER_SEARCH_BYTES)];
+    if format == "pdf" && !head.windows(4).any(|w| w == b"%PDF") {
+        return Err(PayloadError::FormatMismatch("Format is 'pdf' but the data is not a PDF".to_string()));
+    }
+    Ok(())
+}
+
+/// Decode a print payload and settle its format, checking it against any data URI MIME type
+pub fn decode_payload(data: &str, format: &str) -> Result<(Vec<u8>, String), PayloadError> {
+    if data.trim().is_empty() {
+        return Err(PayloadError::Empty);
+    }
+
+    let format = format.trim().to_lowercase();
+    let (mime, encoded) = match data.strip_prefix("data:") {
+        Some(uri) => {
+            let (header, encoded) = uri
+                .split_once(',')
+                .ok_or_else(|| PayloadError::Invalid("Malformed data URI: missing ','".to_string()))?;
+            let mime = header
+                .strip_suffix(";base64")
+                .ok_or_else(|| PayloadError::Invalid("Data URI must be base64 encoded".to_string()))?;
+            (Some(mime.to_lowercase()), encoded)
+        }
+        None => (None, data),
+    };
+
+    let inferred = mime.as_deref().and_then(format_for_mime);
+    let format = match (format.as_str(), inferred) {
+        ("" | "auto", Some(inferred)) => inferred.to_string(),
+        ("" | "auto", None) => {
+            return Err(PayloadError::Invalid(match mime {
+                Some(mime) => format!("Cannot infer a print format from MIME type '{}'", mime),
+                None => "No format given and data is not a data URI".to_string(),
+            }));
+        }
+        // Brother raster labels arrive as images
+        (BROTHER_RASTER_FORMAT, Some(inferred)) if IMAGE_FORMATS.contains(&inferred) => format,
+        (declared, Some(inferred)) if normalize_format(declared) != inferred => {
+            return Err(PayloadError::FormatMismatch(format!(
+                "Format '{}' does not match data URI type '{}'",
+                declared,
+                mime.unwrap_or_default()
+            )));
+        }
+        _ => format,
+    };
+
+    // Browsers and backends disagree on padding and alphabet, so accept any of them
+    let encoded: String = encoded.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+    if encoded.is_empty() {
+        return Err(PayloadError::Empty);
+    }
+    let data = match STANDARD.decode(&encoded) {
+        Ok(data) => data,
+        Err(e) => [URL_SAFE, STANDARD_NO_PAD, URL_SAFE_NO_PAD]
+            .iter()
+            .find_map(|engine| engine.decode(&encoded).ok())
+            .ok_or_else(|| PayloadError::InvalidBase64(e.to_string()))?,
+    };
+    if data.is_empty() {
+        return Err(PayloadError::EmptyDecoded);
+    }
+
+    Ok((data, format))
+}
+
+/// Print format for a data URI or Content-Type MIME type, if it's one we know
+pub fn format_for_mime(mime: &str) -> Option<&'static str> {
+    match mime {
+        "application/pdf" => Some("pdf"),
+        "image/png" => Some("png"),
+        "image/jpeg" | "image/jpg" => Some("jpg"),
+        "image/gif" => Some("gif"),
+        "image/bmp" => Some("bmp"),
+        "application/vnd.zebra-zpl" | "application/x-zpl" => Some("zpl"),
+        "application/x-epl" => Some("epl"),
+        _ => None,
+    }
+}
+
+fn normalize_format(format: &str) -> &str {
+    match format {
+        "jpeg" => "jpg",
+        other => other,
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+fn print_file(path: &std::path::Path, printer_name: &str, options: &JobOptions, raw: bool) -> Result<Option<String>, String> {
+    let mut cmd = lp_command(path, printer_name, options, raw);
+
+    log::info!("Running: {:?}", cmd);
+
+    let output = cmd
+        .output()
+        .map_err(|e| format!("Failed to execute lp: {}", e))?;
+
+    if output.status.success() {
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let job_id = parse_job_id(&stdout);
+        log::info!("Print job submitted successfully (job: {:?})", job_id);
+        Ok(job_id)
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        log::error!("lp failed: {}", stderr);
+        Err(format!("lp failed: {}", stderr))
+    }
+}
+
+/// Build the lp invocation; used on both platforms so the job id can be parsed consistently
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+fn lp_command(path: &std::path::Path, printer_name: &str, options: &JobOptions, raw: bool) -> Command {
+    let mut cmd = cups_command("lp");
+    cmd.arg("-d")
+        .arg(printer_name)
+        .arg("-n")
+        .arg(options.copies.to_string());
+
+    if raw {
+        // Skip CUPS filters so printer command languages reach the device untouched
+        cmd.arg("-o").arg("raw");
+        if options.quality.is_some() || options.darkness.is_some() {
+            log::info!("Ignoring quality and darkness for a raw job; set them in the label's commands");
+        }
+    } else {
+        if let Some(paper_size) = &options.paper_size {
+            cmd.arg("-o").arg(format!("media={}", paper_size.cups_media()));
+        }
+
+        // IPP orientation-requested: 4 and 5 are the landscape turns, 6 is upside down
+        let orientation = match options.rotation {
+            Rotation::None => None,
+            Rotation::Quarter => Some(4),
+            Rotation::Half => Some(6),
+            Rotation::ThreeQuarter => Some(5),
+        };
+        if let Some(orientation) = orientation {
+            cmd.arg("-o").arg(format!("orientation-requested={}", orientation));
+        }
+
+        match options.scaling {
+            Some(Scaling::Fit) => {
+                cmd.arg("-o").arg("fit-to-page");
+            }
+            Some(Scaling::Actual) => {
+                cmd.arg("-o").arg("scaling=100");
+            }
+            Some(Scaling::Percent(percent)) => {
+                cmd.arg("-o").arg(format!("scaling={}", percent));
+            }
+            None => {}
+        }
+
+        if let Some(page_range) = &options.page_range {
+            cmd.arg("-o").arg(format!("page-ranges={}", page_range));
+        }
+        if let Some(quality) = options.quality {
+            cmd.arg("-o").arg(format!("print-quality={}", quality.ipp_value()));
+        }
+        // The option name thermal PPDs (Zebra, Rollo, DYMO's CUPS drivers) use
+        if let Some(darkness) = options.darkness {
+            cmd.arg("-o").arg(format!("Darkness={}", darkness));
+        }
+    }
+
+    // Last, so they override anything set above
+    for option in &options.raw_options {
+        cmd.arg("-o").arg(option);
+    }
+
+    cmd.arg(path);
+    cmd
+}
+
+/// Extract the job id from lp output, e.g. "request id is Printer-123 (1 file(s))"
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+fn parse_job_id(stdout: &str) -> Option<String> {
+    stdout.lines().find_map(|line| {
+        let (_, rest) = line.split_once("request id is ")?;
+        rest.split_whitespace().next().map(|id| id.to_string())
+    })
+}
+
+/// Why a spooled job couldn't be cancelled
+#[derive(Debug)]
+pub enum CancelError {
+    /// The job already finished printing, or never existed
+    NotFound,
+    Failed(String),
+}
+
+impl std::fmt::Display for CancelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CancelError::NotFound => write!(f, "Job not found; it may have already printed"),
+            CancelError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Cancel a job the spooler has already accepted, by the id `print_label` returned
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+pub fn cancel_job(_printer_name: &str, job_id: &str) -> Result<(), CancelError> {
+    let output = cancel_command(job_id)?
+        .output()
+        .map_err(|e| CancelError::Failed(format!("Failed to run cancel: {}", e)))?;
+    if output.status.success() {
+        return Ok(());
+    }
+    Err(cancel_failure(&String::from_utf8_lossy(&output.stderr)))
+}
+
+/// The `cancel` invocation for a job id, refusing ids that aren't a CUPS job
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+fn cancel_command(job_id: &str) -> Result<Command, CancelError> {
+    // Job ids look like "Printer-123"; anything else could be read as an option or a printer
+    if job_id.is_empty() || job_id.starts_with('-') || !job_id.chars().all(|c| c.is_alphanumeric() || "-_.".contains(c)) {
+        return Err(CancelError::NotFound);
+    }
+
+    let mut cmd = cups_command("cancel");
+    cmd.arg(job_id);
+    Ok(cmd)
+}
+
+/// Tell a job that's already gone from a real failure, by `cancel`'s error output
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+fn cancel_failure(stderr: &str) -> CancelError {
+    if ["does not exist", "not found", "already", "completed"].iter().any(|p| stderr.contains(p)) {
+        CancelError::NotFound
+    } else {
+        CancelError::Failed(format!("cancel failed: {}", stderr.trim()))
+    }
+}
+
+#[cfg(target_os = "windows")]
+pub fn cancel_job(printer_name: &str, job_id: &str) -> Result<(), CancelError> {
+    if printer_name.is_empty() {
+        return Err(CancelError::Failed("Cancelling a spooled job on Windows needs its printer".to_string()));
+    }
+    let job_id = job_id.trim().parse::<u32>().map_err(|_| CancelError::NotFound)?;
+    crate::spooler::cancel_job(printer_name, job_id)
+}
+
+/// Cancel every job queued for a printer, returning how many were removed
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+pub fn clear_queue(printer_name: &str) -> Result<usize, String> {
+    validate_printer_name(printer_name)?;
+    if printer_name == virtual_printer::ID {
+        return Ok(0);
+    }
+
+    // `cancel` doesn't say what it removed, so count the queue first
+    let queued = cups_command("lpstat")
+        .arg("-o")
+        .arg(printer_name)
+        .output()
+        .map_err(|e| format!("Failed to run lpstat: {}", e))?;
+    let count = count_queued_jobs(&String::from_utf8_lossy(&queued.stdout), printer_name);
+
+    let output = cancel_all_command(printer_name)
+        .output()
+        .map_err(|e| format!("Failed to run cancel: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("cancel failed: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(count)
+}
+
+/// `cancel -a`, which removes every job on the named queue and nothing else
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+fn cancel_all_command(printer_name: &str) -> Command {
+    let mut cmd = cups_command("cancel");
+    cmd.arg("-a").arg(printer_name);
+    cmd
+}
+
+/// Jobs for one queue in `lpstat -o` output, whose lines start with "<printer>-<job number>"
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+fn count_queued_jobs(output: &str, printer_name: &str) -> usize {
+    output
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .filter_map(|job_id| job_id.rsplit_once('-'))
+        .filter(|(queue, number)| {
+            *queue == printer_name && !number.is_empty() && number.chars().all(|c| c.is_ascii_digit())
+        })
+        .count()
+}
+
+#[cfg(target_os = "windows")]
+pub fn clear_queue(printer_name: &str) -> Result<usize, String> {
+    validate_printer_name(printer_name)?;
+    if printer_name == virtual_printer::ID {
+        return Ok(0);
+    }
+    crate::spooler::purge_queue(printer_name)
+}
+
+/// Why printing can't work on this machine at all, if it can't; used to explain failures
+pub fn print_backend_problem(raw: bool) -> Option<String> {
+    if let Some(problem) = enumeration_error() {
+        return Some(problem);
+    }
+    if raw {
+        None
+    } else {
+        missing_pdf_backend()
+    }
+}
+
+/// Why PDFs can't be printed, if neither SumatraPDF nor the Windows PDF renderer is available
+#[cfg(target_os = "windows")]
+pub fn missing_pdf_backend() -> Option<String> {
+    pdf_backend_problem(|path| path.exists(), crate::gdi_print::renderer_available)
+}
+
+#[cfg(target_os = "windows")]
+fn pdf_backend_problem(exists: impl Fn(&std::path::Path) -> bool, renderer_available: impl FnOnce() -> bool) -> Option<String> {
+    if sumatra_path(exists).is_some() || renderer_available() {
+        return None;
+    }
+    Some("PDF printing needs SumatraPDF on this version of Windows. Install it from sumatrapdfreader.org".to_string())
+}
+
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+pub fn missing_pdf_backend() -> Option<String> {
+    None
+}
+
+/// Where SumatraPDF is installed, checked with `exists` so detection can run without the filesystem
+#[cfg(target_os = "windows")]
+fn sumatra_path(exists: impl Fn(&std::path::Path) -> bool) -> Option<&'static str> {
+    const SUMATRA_PATHS: &[&str] = &[
+        r"C:\Program Files\SumatraPDF\SumatraPDF.exe",
+        r"C:\Program Files (x86)\SumatraPDF\SumatraPDF.exe",
+    ];
+    SUMATRA_PATHS.iter().copied().find(|path| exists(std::path::Path::new(path)))
+}
+
+/// SumatraPDF's `-print-settings` for a job; `gdi_paper` means the paper size is set through
+/// GDI instead
+#[cfg(target_os = "windows")]
+fn sumatra_print_settings(options: &JobOptions, gdi_paper: bool) -> String {
+    let mut print_settings = format!("{}x", options.copies);
+    match options.paper_size.and_then(|p| p.sumatra_paper) {
+        Some(paper) => print_settings.push_str(&format!(",paper={}", paper)),
+        None if gdi_paper => {}
+        None if options.paper_size.is_some() => {
+            log::info!("Paper size has no SumatraPDF equivalent, using driver default");
+        }
+        None => {}
+    }
+
+    // SumatraPDF only distinguishes portrait and landscape
+    match options.rotation {
+        Rotation::None => {}
+        Rotation::Quarter | Rotation::ThreeQuarter => print_settings.push_str(",landscape"),
+        Rotation::Half => log::info!("SumatraPDF cannot rotate 180 degrees, printing upright"),
+    }
+
+    match options.scaling {
+        Some(Scaling::Fit) => print_settings.push_str(",fit"),
+        Some(Scaling::Actual) => print_settings.push_str(",noscale"),
+        Some(Scaling::Percent(percent)) => {
+            log::info!("SumatraPDF has no percentage scaling, printing {}% at actual size", percent);
+            print_settings.push_str(",noscale");
+        }
+        None => {}
+    }
+
+    if let Some(page_range) = &options.page_range {
+        print_settings.push_str(&format!(",{}", page_range));
+    }
+
+    print_settings
+}
+
+/// Print on Windows
+///
+/// Raw formats use the native spooler and need nothing installed. PDFs (including converted
+/// images) are printed silently with SumatraPDF when it's installed in Program Files, and
+/// otherwise rendered and drawn through GDI.
+#[cfg(target_os = "windows")]
+fn print_file(path: &std::path::Path, printer_name: &str, options: &JobOptions, raw: bool) -> Result<Option<String>, String> {
+    if !options.raw_options.is_empty() {
+        log::info!("Ignoring rawOptions {:?}: they're CUPS options", options.raw_options);
+    }
+    // SumatraPDF and the spooler take these from the driver's own preferences
+    if options.quality.is_some() || options.darkness.is_some() {
+        log::info!("Ignoring quality and darkness: set them in the printer's preferences on Windows");
+    }
+    if raw {
+        let data = std::fs::read(path).map_err(|e| format!("Failed to read label: {}", e))?;
+        crate::spooler::print_raw(printer_name, &data, options.copies)?;
+        return Ok(None);
+    }
+
+    // SumatraPDF can't set a custom size, but the GDI path can through the driver settings
+    let gdi_paper = options.paper_size.is_some_and(|p| p.is_custom()) && crate::gdi_print::renderer_available();
+    let print_settings = sumatra_print_settings(options, gdi_paper);
+
+    // Use SumatraPDF for silent printing if available
+    if let Some(sumatra_path) = sumatra_path(|path| path.exists()).filter(|_| !gdi_paper) {
+        let output = Command::new(sumatra_path)
+            .arg("-print-to")
+            .arg(printer_name)
+            .arg("-print-settings")
+            .arg(&print_settings)
+            .arg("-silent")
+            .arg(path)
+            .output()
+            .map_err(|e| format!("Failed to execute SumatraPDF: {}", e))?;
+
+        if output.status.success() {
+            return Ok(None);
+        }
+    }
+
+    // Without SumatraPDF, rasterize the PDF ourselves and draw it on the printer's device context
+    if gdi_paper {
+        log::info!("Printing via GDI to set the custom paper size");
+    } else {
+        log::info!("SumatraPDF not found, printing via GDI");
+    }
+    crate::gdi_print::print_pdf(printer_name, path, options)?;
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{PrintOptions, PrinterCapabilities};
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    fn lp_args(options: &JobOptions, raw: bool) -> Vec<String> {
+        lp_command(std::path::Path::new("/tmp/label"), "Zebra_ZP450", options, raw)
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn job_id_is_read_from_lp_output() {
+        assert_eq!(
+            parse_job_id("request id is Zebra_ZP450-42 (1 file(s))\n").as_deref(),
+            Some("Zebra_ZP450-42")
+        );
+        assert_eq!(
+            parse_job_id("request id is Rollo_Printer-7 (0 file(s))").as_deref(),
+            Some("Rollo_Printer-7")
+        );
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn job_id_follows_lp_warnings() {
+        let stdout = "lp: Warning - job-sheets option ignored\nrequest id is DYMO_LabelWriter_450-1038 (1 file(s))\n";
+        assert_eq!(parse_job_id(stdout).as_deref(), Some("DYMO_LabelWriter_450-1038"));
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn missing_job_id_is_none() {
+        assert_eq!(parse_job_id(""), None);
+        assert_eq!(parse_job_id("lp: Error - The printer or class does not exist.\n"), None);
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn raw_jobs_skip_cups_filters() {
+        let options = JobOptions {
+            copies: 2,
+            ..JobOptions::default()
+        };
+        assert_eq!(lp_args(&options, true), ["-d", "Zebra_ZP450", "-n", "2", "-o", "raw", "/tmp/label"]);
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn raw_jobs_leave_layout_to_the_label() {
+        let options = JobOptions {
+            rotation: Rotation::Quarter,
+            scaling: Some(Scaling::Fit),
+            darkness: Some(20),
+            ..JobOptions::default()
+        };
+        let args = lp_args(&options, true);
+        assert!(args.contains(&"raw".to_string()));
+        assert!(!args.iter().any(|arg| arg.starts_with("orientation-requested") || arg == "fit-to-page"));
+        assert!(!args.iter().any(|arg| arg.starts_with("Darkness")));
+    }
+
+    #[test]
+    fn safe_raw_options_are_accepted() {
+        let options = names(&["MediaType=Thermal", " zeMediaTracking=Continuous ", "Resolution=203dpi", "cupsMirror"]);
+        assert_eq!(
+            parse_raw_options(&options).unwrap(),
+            ["MediaType=Thermal", "zeMediaTracking=Continuous", "Resolution=203dpi", "cupsMirror"]
+        );
+        assert_eq!(parse_raw_options(&[]).unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn raw_option_injection_is_refused() {
+        for option in [
+            "MediaType=Thermal; rm -rf ~",
+            "MediaType=$(id)",
+            "MediaType=`id`",
+            "MediaType=a|b",
+            "MediaType=a&b",
+            "MediaType=a>b",
+            "MediaType=a b",
+            "MediaType=a\nb",
+            "MediaType='x'",
+            "-d",
+            "--help",
+            "-oraw",
+            "/etc/passwd",
+            "=15",
+            "",
+        ] {
+            let error = parse_raw_options(&names(&[option])).unwrap_err();
+            assert!(error.starts_with("Unsupported raw option"), "{:?}: {}", option, error);
+        }
+    }
+
+    #[test]
+    fn raw_options_cannot_override_job_options() {
+        for option in [
+            "copies=100",
+            "page-ranges=1-1000",
+            "media=Letter",
+            "PageSize=Custom.4x6in",
+            "orientation-requested=4",
+            "job-sheets=standard",
+            "number-up=4",
+            "outputorder=reverse",
+            "job-hold-until=indefinite",
+            "raw",
+            "fit-to-page",
+            "Darkness=30",
+            "COPIES=2",
+        ] {
+            let error = parse_raw_options(&names(&[option])).unwrap_err();
+            assert!(error.contains("is not allowed"), "{:?}: {}", option, error);
+        }
+        // One bad option refuses the lot
+        assert!(parse_raw_options(&names(&["MediaType=Thermal", "copies=5"])).is_err());
+    }
+
+    #[test]
+    fn raw_options_are_limited_in_number() {
+        let options: Vec<String> = (0..MAX_RAW_OPTIONS).map(|i| format!("Option{}=1", i)).collect();
+        assert_eq!(parse_raw_options(&options).unwrap().len(), MAX_RAW_OPTIONS);
+        let too_many: Vec<String> = (0..=MAX_RAW_OPTIONS).map(|i| format!("Option{}=1", i)).collect();
+        assert!(parse_raw_options(&too_many).unwrap_err().starts_with("At most"));
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn raw_options_are_forwarded_last() {
+        let options = JobOptions {
+            darkness: Some(20),
+            raw_options: parse_raw_options(&names(&["MediaType=Thermal", "zeMediaTracking=Continuous"])).unwrap(),
+            ..JobOptions::default()
+        };
+        assert_eq!(
+            lp_args(&options, false),
+            [
+                "-d",
+                "Zebra_ZP450",
+                "-n",
+                "1",
+                "-o",
+                "Darkness=20",
+                "-o",
+                "MediaType=Thermal",
+                "-o",
+                "zeMediaTracking=Continuous",
+                "/tmp/label"
+            ]
+        );
+        // Raw jobs keep them too, since they can still select the printer's media handling
+        let args = lp_args(&options, true);
+        assert_eq!(args[args.len() - 3..], ["-o", "zeMediaTracking=Continuous", "/tmp/label"]);
+    }
+
+    #[test]
+    fn tspl_is_sent_raw_and_untouched() {
+        assert!(is_raw_format("tspl"));
+        assert!(is_raw_format("TSPL"));
+
+        let commands = b"SIZE 50 mm, 30 mm\r\nCLS\r\nTEXT 10,20,\"3\",0,1,1,\"Hi\"\r\nPRINT 1,1\r\n".to_vec();
+        let data = LabelData::Bytes(commands.clone());
+        let label = prepare_label("TSC_TE200", &data, "TSPL", &JobOptions::default()).unwrap();
+        assert_eq!(label.format, "tspl");
+        assert!(label.raw);
+        assert_eq!(label.data.as_ref(), commands.as_slice());
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn tspl_goes_to_lp_without_filters() {
+        let options = JobOptions {
+            copies: 3,
+            ..JobOptions::default()
+        };
+        let path = std::path::Path::new("/tmp/label.tspl");
+        let args: Vec<_> = lp_command(path, "TSC_TE200", &options, true)
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(args, ["-d", "TSC_TE200", "-n", "3", "-o", "raw", "/tmp/label.tspl"]);
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn documents_are_not_sent_raw() {
+        assert_eq!(lp_args(&JobOptions::default(), false), ["-d", "Zebra_ZP450", "-n", "1", "/tmp/label"]);
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn pinned_printer_replaces_the_os_default() {
+        let available = names(&["Office_Laser", "Zebra_ZP450"]);
+        assert_eq!(pin_state("Zebra_ZP450", &available), PinState::Present);
+        assert!(is_listed_default(Some("Zebra_ZP450"), "Zebra_ZP450", false));
+        assert!(!is_listed_default(Some("Zebra_ZP450"), "Office_Laser", true));
+    }
+
+    #[test]
+    fn missing_pin_falls_back_to_the_os_default() {
+        assert_eq!(pin_state("Zebra_ZP450", &names(&["Office_Laser"])), PinState::Gone);
+        assert!(is_listed_default(None, "Office_Laser", true));
+        assert!(!is_listed_default(None, "Zebra_ZP450", false));
+    }
+
+    #[test]
+    fn pin_is_kept_while_nothing_is_listed() {
+        assert_eq!(pin_state("Zebra_ZP450", &[]), PinState::Unknown);
+    }
+
+    fn with_paper(name: &str) -> JobOptions {
+        JobOptions {
+            paper_size: Some(parse_paper_size(name).unwrap()),
+            ..JobOptions::default()
+        }
+    }
+
+    #[test]
+    fn paper_size_names_are_case_insensitive() {
+        assert_eq!(parse_paper_size(" Letter "), parse_paper_size("letter"));
+        assert_eq!(parse_paper_size("4X6").unwrap().cups_media(), "na_index-4x6_4x6in");
+    }
+
+    #[test]
+    fn unknown_paper_size_is_an_error() {
+        let error = parse_paper_size("b5").unwrap_err();
+        assert!(error.contains("'b5'"), "{}", error);
+        assert!(error.contains("4x6, a4, a5, a6, letter, legal"), "{}", error);
+    }
+
+    #[test]
+    fn custom_paper_size_in_inches() {
+        let size = parse_paper_size("custom:2.25x1.25in").unwrap();
+        assert!(size.is_custom());
+        assert_eq!(size.cups_media(), "Custom.2.25x1.25in");
+        assert_eq!((size.width_in, size.height_in), (2.25, 1.25));
+        assert_eq!(size.sumatra_paper, None);
+        assert_eq!(parse_paper_size(" CUSTOM:4X6IN "), parse_paper_size("custom:4x6in"));
+    }
+
+    #[test]
+    fn custom_paper_size_in_millimeters() {
+        let size = parse_paper_size("custom:57x32mm").unwrap();
+        assert!(size.is_custom());
+        // CUPS gets the numbers as sent, GDI gets inches
+        assert_eq!(size.cups_media(), "Custom.57x32mm");
+        assert!((size.width_in - 57.0 / 25.4).abs() < 1e-4, "{:?}", size);
+        assert!((size.height_in - 32.0 / 25.4).abs() < 1e-4, "{:?}", size);
+    }
+
+    #[test]
+    fn malformed_custom_paper_sizes_are_errors() {
+        for name in [
+            "custom:",
+            "custom:4x6",
+            "custom:4x6cm",
+            "custom:4in",
+            "custom:x6in",
+            "custom:4x6xin",
+            "custom:-4x6in",
+            "custom:1e1x6in",
+            "custom:infx6in",
+            "custom:4,5x6in",
+        ] {
+            let error = parse_paper_size(name).unwrap_err();
+            assert!(error.starts_with("Invalid custom paper size"), "{:?}: {}", name, error);
+        }
+    }
+
+    #[test]
+    fn custom_paper_size_must_fit_the_limits() {
+        for name in ["custom:0x6in", "custom:4x0.0mm", "custom:49x6in", "custom:57x1300mm"] {
+            let error = parse_paper_size(name).unwrap_err();
+            assert!(error.contains("at most 48 inches a side"), "{:?}: {}", name, error);
+        }
+        assert!(parse_paper_size("custom:48x1219mm").is_ok());
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn custom_paper_size_sets_a_custom_cups_media() {
+        let args = lp_args(&with_paper("custom:57x32mm"), false);
+        assert!(args.windows(2).any(|pair| pair == ["-o", "media=Custom.57x32mm"]), "{:?}", args);
+    }
+
+    #[cfg(target_os = "windows")]
+    #[test]
+    fn custom_paper_size_is_left_out_of_sumatra_settings() {
+        assert_eq!(sumatra_print_settings(&with_paper("custom:57x32mm"), true), "1x");
+        assert_eq!(sumatra_print_settings(&with_paper("custom:57x32mm"), false), "1x");
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn every_paper_size_sets_its_cups_media() {
+        for (name, cups_media, ..) in PAPER_SIZES {
+            let args = lp_args(&with_paper(name), false);
+            let media = format!("media={}", cups_media);
+            assert!(args.windows(2).any(|pair| pair == ["-o", media.as_str()]), "{}: {:?}", name, args);
+        }
+    }
+
+    #[cfg(target_os = "windows")]
+    #[test]
+    fn every_paper_size_sets_its_sumatra_paper() {
+        for (name, _, sumatra_paper, ..) in PAPER_SIZES {
+            let expected = match sumatra_paper {
+                Some(paper) => format!("1x,paper={}", paper),
+                // Label sizes are left to the driver
+                None => "1x".to_string(),
+            };
+            assert_eq!(sumatra_print_settings(&with_paper(name), false), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn rotation_is_one_of_the_four_turns() {
+        assert_eq!(parse_rotation(0), Ok(Rotation::None));
+        assert_eq!(parse_rotation(90), Ok(Rotation::Quarter));
+        assert_eq!(parse_rotation(180), Ok(Rotation::Half));
+        assert_eq!(parse_rotation(270), Ok(Rotation::ThreeQuarter));
+        for degrees in [45, 360, 450] {
+            let error = parse_rotation(degrees).unwrap_err();
+            assert!(error.contains(&format!("rotation {}", degrees)), "{}", error);
+        }
+    }
+
+    #[test]
+    fn scaling_is_fit_actual_or_a_percentage() {
+        assert_eq!(parse_scaling("fit"), Ok(Scaling::Fit));
+        assert_eq!(parse_scaling(" Actual "), Ok(Scaling::Actual));
+        assert_eq!(parse_scaling("75%"), Ok(Scaling::Percent(75)));
+        assert_eq!(parse_scaling("150"), Ok(Scaling::Percent(150)));
+        assert_eq!(parse_scaling("1000 %"), Ok(Scaling::Percent(1000)));
+        for value in ["0", "1001", "-5%", "half", ""] {
+            assert!(parse_scaling(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn thermal_printers_print_at_actual_size_by_default() {
+        for name in ["Zebra_ZP450", "DYMO_LabelWriter_450", "Rollo_Printer", "TSC TE200"] {
+            assert_eq!(default_scaling(name), Scaling::Actual, "{}", name);
+        }
+        for name in ["Office_Laser", "HP_LaserJet_Pro", "Canon_iP110"] {
+            assert_eq!(default_scaling(name), Scaling::Fit, "{}", name);
+        }
+    }
+
+    #[test]
+    fn requested_scaling_beats_the_default() {
+        let fit = JobOptions {
+            scaling: Some(Scaling::Fit),
+            ..JobOptions::default()
+        };
+        assert_eq!(with_default_scaling(&fit, "Zebra_ZP450").scaling, Some(Scaling::Fit));
+        let actual = JobOptions {
+            scaling: Some(Scaling::Actual),
+            ..JobOptions::default()
+        };
+        assert_eq!(with_default_scaling(&actual, "Office_Laser").scaling, Some(Scaling::Actual));
+        assert_eq!(with_default_scaling(&JobOptions::default(), "Zebra_ZP450").scaling, Some(Scaling::Actual));
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn only_standard_printers_fit_to_page_by_default() {
+        let thermal = lp_args(&with_default_scaling(&JobOptions::default(), "Zebra_ZP450"), false);
+        assert!(!thermal.iter().any(|arg| arg == "fit-to-page"), "{:?}", thermal);
+        assert!(thermal.iter().any(|arg| arg == "scaling=100"), "{:?}", thermal);
+
+        let standard = lp_args(&with_default_scaling(&JobOptions::default(), "Office_Laser"), false);
+        assert!(standard.iter().any(|arg| arg == "fit-to-page"), "{:?}", standard);
+
+        // Fitting a label is still available when asked for
+        let fit = JobOptions {
+            scaling: Some(Scaling::Fit),
+            ..JobOptions::default()
+        };
+        let thermal_fit = lp_args(&with_default_scaling(&fit, "Zebra_ZP450"), false);
+        assert!(thermal_fit.iter().any(|arg| arg == "fit-to-page"), "{:?}", thermal_fit);
+    }
+
+    #[cfg(target_os = "windows")]
+    #[test]
+    fn only_standard_printers_fit_to_page_by_default_with_sumatra() {
+        let thermal = with_default_scaling(&JobOptions::default(), "Zebra_ZP450");
+        assert_eq!(sumatra_print_settings(&thermal, false), "1x,noscale");
+        let standard = with_default_scaling(&JobOptions::default(), "Office_Laser");
+        assert_eq!(sumatra_print_settings(&standard, false), "1x,fit");
+    }
+
+    #[test]
+    fn quality_is_draft_normal_or_high() {
+        assert_eq!(parse_quality("draft"), Ok(Quality::Draft));
+        assert_eq!(parse_quality(" Normal "), Ok(Quality::Normal));
+        assert_eq!(parse_quality("HIGH"), Ok(Quality::High));
+        for value in ["best", "", "4"] {
+            let error = parse_quality(value).unwrap_err();
+            assert!(error.starts_with("Unsupported quality"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn darkness_is_zero_to_thirty() {
+        assert_eq!(parse_darkness(0), Ok(0));
+        assert_eq!(parse_darkness(15), Ok(15));
+        assert_eq!(parse_darkness(30), Ok(30));
+        assert_eq!(parse_darkness(31), Err("Darkness 31 is out of range (0 to 30)".to_string()));
+        assert!(parse_darkness(u32::MAX).is_err());
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn quality_and_darkness_set_cups_options() {
+        for (quality, ipp) in [(Quality::Draft, "3"), (Quality::Normal, "4"), (Quality::High, "5")] {
+            let options = JobOptions {
+                quality: Some(quality),
+                darkness: Some(25),
+                ..JobOptions::default()
+            };
+            let expected = names(&[
+                "-d",
+                "Zebra_ZP450",
+                "-n",
+                "1",
+                "-o",
+                &format!("print-quality={}", ipp),
+                "-o",
+                "Darkness=25",
+                "/tmp/label",
+            ]);
+            assert_eq!(lp_args(&options, false), expected, "{:?}", quality);
+        }
+        // Left to the driver when unset
+        let args = lp_args(&JobOptions::default(), false);
+        assert!(!args.iter().any(|arg| arg.starts_with("print-quality") || arg.starts_with("Darkness")));
+    }
+
+    #[cfg(target_os = "windows")]
+    #[test]
+    fn quality_and_darkness_are_left_to_the_windows_driver() {
+        let options = JobOptions {
+            quality: Some(Quality::High),
+            darkness: Some(25),
+            ..JobOptions::default()
+        };
+        assert_eq!(sumatra_print_settings(&options, false), "1x");
+    }
+
+    const ROTATIONS: [Rotation; 4] = [Rotation::None, Rotation::Quarter, Rotation::Half, Rotation::ThreeQuarter];
+    const SCALINGS: [Option<Scaling>; 4] = [None, Some(Scaling::Fit), Some(Scaling::Actual), Some(Scaling::Percent(75))];
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn rotation_and_scaling_set_cups_options() {
+        for rotation in ROTATIONS {
+            for scaling in SCALINGS {
+                let options = JobOptions {
+                    rotation,
+                    scaling,
+                    ..JobOptions::default()
+                };
+                let mut expected = names(&["-d", "Zebra_ZP450", "-n", "1"]);
+                match rotation {
+                    Rotation::None => {}
+                    Rotation::Quarter => expected.extend(names(&["-o", "orientation-requested=4"])),
+                    Rotation::Half => expected.extend(names(&["-o", "orientation-requested=6"])),
+                    Rotation::ThreeQuarter => expected.extend(names(&["-o", "orientation-requested=5"])),
+                }
+                match scaling {
+                    None => {}
+                    Some(Scaling::Fit) => expected.extend(names(&["-o", "fit-to-page"])),
+                    Some(Scaling::Actual) => expected.extend(names(&["-o", "scaling=100"])),
+                    Some(Scaling::Percent(percent)) => expected.extend(names(&["-o", &format!("scaling={}", percent)])),
+                }
+                expected.push("/tmp/label".to_string());
+                assert_eq!(lp_args(&options, false), expected, "{:?} {:?}", rotation, scaling);
+            }
+        }
+    }
+
+    #[test]
+    fn page_ranges_are_parsed() {
+        assert_eq!(parse_page_range("1"), Ok(PageRange(vec![(1, 1)])));
+        assert_eq!(parse_page_range("2-3"), Ok(PageRange(vec![(2, 3)])));
+        assert_eq!(parse_page_range(" 1, 4-6 ,9"), Ok(PageRange(vec![(1, 1), (4, 6), (9, 9)])));
+        assert_eq!(parse_page_range("1,4-6,9").unwrap().to_string(), "1,4-6,9");
+    }
+
+    #[test]
+    fn malformed_page_ranges_are_rejected() {
+        for value in ["", "0", "3-2", "1-", "-2", "1,,2", "a", "1-2-3", "1.5", "-1"] {
+            let err = parse_page_range(value).unwrap_err();
+            assert!(err.starts_with(&format!("Invalid page range '{}'", value)), "{}", err);
+        }
+    }
+
+    #[test]
+    fn page_range_contains_its_pages() {
+        let range = parse_page_range("1,4-6").unwrap();
+        let pages: Vec<u32> = (1..=8).filter(|&page| range.contains(page)).collect();
+        assert_eq!(pages, [1, 4, 5, 6]);
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn page_range_is_passed_to_lp() {
+        let options = JobOptions {
+            page_range: Some(parse_page_range("2-3,5").unwrap()),
+            ..JobOptions::default()
+        };
+        let args = lp_args(&options, false);
+        let at = args.iter().position(|arg| arg == "page-ranges=2-3,5").expect("no page-ranges option");
+        assert_eq!(args[at - 1], "-o");
+    }
+
+    #[cfg(target_os = "windows")]
+    #[test]
+    fn page_range_is_passed_to_sumatra() {
+        let options = JobOptions {
+            page_range: Some(parse_page_range("2-3,5").unwrap()),
+            ..JobOptions::default()
+        };
+        assert_eq!(sumatra_print_settings(&options, false), "1x,2-3,5");
+    }
+
+    #[cfg(target_os = "windows")]
+    #[test]
+    fn rotation_and_scaling_set_sumatra_settings() {
+        for rotation in ROTATIONS {
+            for scaling in SCALINGS {
+                let options = JobOptions {
+                    rotation,
+                    scaling,
+                    ..JobOptions::default()
+                };
+                let mut expected = "1x".to_string();
+                // SumatraPDF can't turn a page upside down, so 180 prints upright
+                if matches!(rotation, Rotation::Quarter | Rotation::ThreeQuarter) {
+                    expected.push_str(",landscape");
+                }
+                match scaling {
+                    None => {}
+                    Some(Scaling::Fit) => expected.push_str(",fit"),
+                    // No percentage scaling either, so that prints at actual size
+                    Some(Scaling::Actual | Scaling::Percent(_)) => expected.push_str(",noscale"),
+                }
+                assert_eq!(sumatra_print_settings(&options, false), expected, "{:?} {:?}", rotation, scaling);
+            }
+        }
+    }
+
+    const PDF: &[u8] = b"%PDF-1.4\n%\xfb\xff\xfe\n";
+
+    #[test]
+    fn data_uri_prefix_is_stripped() {
+        let uri = format!("data:application/pdf;base64,{}", STANDARD.encode(PDF));
+        assert_eq!(decode_payload(&uri, "pdf"), Ok((PDF.to_vec(), "pdf".to_string())));
+    }
+
+    #[test]
+    fn bare_base64_is_decoded() {
+        assert_eq!(
+            decode_payload(&STANDARD.encode(b"^XA^FDHi^FS^XZ"), "ZPL"),
+            Ok((b"^XA^FDHi^FS^XZ".to_vec(), "zpl".to_string()))
+        );
+    }
+
+    #[test]
+    fn url_safe_base64_is_decoded() {
+        let encoded = URL_SAFE_NO_PAD.encode(PDF);
+        assert!(encoded.contains(['-', '_']), "{}", encoded);
+        assert_eq!(decode_payload(&encoded, "pdf"), Ok((PDF.to_vec(), "pdf".to_string())));
+        assert_eq!(decode_payload(&URL_SAFE.encode(PDF), "pdf"), Ok((PDF.to_vec(), "pdf".to_string())));
+    }
+
+    #[test]
+    fn format_is_inferred_from_the_data_uri() {
+        let uri = format!("data:image/jpeg;base64,{}", STANDARD.encode(b"\xff\xd8\xff"));
+        assert_eq!(decode_payload(&uri, "auto").unwrap().1, "jpg");
+        assert_eq!(decode_payload(&uri, "").unwrap().1, "jpg");
+        assert_eq!(decode_payload(&uri, "jpeg").unwrap().1, "jpeg");
+    }
+
+    #[test]
+    fn data_uri_type_must_match_the_format() {
+        let uri = format!("data:application/pdf;base64,{}", STANDARD.encode(PDF));
+        assert!(matches!(decode_payload(&uri, "zpl"), Err(PayloadError::FormatMismatch(_))));
+    }
+
+    #[test]
+    fn format_cannot_be_inferred_from_bare_base64_or_unknown_types() {
+        assert!(matches!(decode_payload(&STANDARD.encode(PDF), "auto"), Err(PayloadError::Invalid(_))));
+        let uri = format!("data:text/plain;base64,{}", STANDARD.encode(b"hello"));
+        assert!(matches!(decode_payload(&uri, "auto"), Err(PayloadError::Invalid(e)) if e.contains("text/plain")));
+        assert!(matches!(
+            decode_payload("data:application/pdf,%PDF", "pdf"),
+            Err(PayloadError::Invalid(e)) if e.contains("base64")
+        ));
+    }
+
+    #[test]
+    fn empty_data_is_told_apart_from_bad_base64() {
+        assert_eq!(decode_payload("", "zpl"), Err(PayloadError::Empty));
+        assert_eq!(decode_payload(" \n", "zpl"), Err(PayloadError::Empty));
+        assert_eq!(decode_payload("data:application/pdf;base64,", "pdf"), Err(PayloadError::Empty));
+        assert!(matches!(decode_payload("not base64!", "zpl"), Err(PayloadError::InvalidBase64(_))));
+        // Truncated mid-quantum
+        assert!(matches!(decode_payload("XlhBXlo=X", "zpl"), Err(PayloadError::InvalidBase64(_))));
+    }
+
+    #[test]
+    fn pdf_bytes_must_look_like_a_pdf() {
+        let zpl = LabelData::Base64(STANDARD.encode(b"^XA^XZ"));
+        assert!(matches!(decode_label(zpl, "pdf"), Err(PayloadError::FormatMismatch(_))));
+        assert!(matches!(decode_label(LabelData::Bytes(b"^XA^XZ".to_vec()), "pdf"), Err(PayloadError::FormatMismatch(_))));
+
+        // The header may follow some leading junk
+        let padded = [&b"\r\n"[..], PDF].concat();
+        assert_eq!(decode_label(LabelData::Bytes(padded.clone()), "pdf"), Ok((padded, "pdf".to_string())));
+    }
+
+    #[test]
+    fn empty_binary_frame_is_empty_payload() {
+        assert_eq!(decode_label(LabelData::Bytes(Vec::new()), "zpl"), Err(PayloadError::Empty));
+    }
+
+    fn printer_list(printers: &[(&str, &str)]) -> Vec<(String, String)> {
+        printers.iter().map(|(system_name, name)| (system_name.to_string(), name.to_string())).collect()
+    }
+
+    fn config_for(ids: &[&str]) -> config::Config {
+        let mut config = config::Config {
+            default_printer_id: ids.first().map(|id| id.to_string()),
+            ..config::Config::default()
+        };
+        for id in ids {
+            config.printer_nicknames.insert(id.to_string(), format!("{} nickname", id));
+            let defaults = PrintOptions {
+                copies: Some(2),
+                ..PrintOptions::default()
+            };
+            config.printer_defaults.insert(id.to_string(), defaults);
+        }
+        config
+    }
+
+    #[test]
+    fn renamed_queues_get_their_settings_back() {
+        let before = printer_list(&[("Zebra_ZP450", "Zebra ZP450"), ("Office_Laser", "Office Laser")]);
+        let mut config = config_for(&["Zebra_ZP450", "Office_Laser"]);
+        config.ignores_copies.insert("Zebra_ZP450".to_string());
+        config.raw_devices.insert("Zebra_ZP450".to_string(), "/dev/usb/lp0".to_string());
+        config.label_printer_id = Some("Zebra_ZP450".to_string());
+        assert!(stale_id_remaps(&config, &before).is_empty());
+
+        // After a driver reinstall CUPS added the Zebra again under a new queue name
+        let after = printer_list(&[("Zebra_ZP450_1", "Zebra ZP450"), ("Office_Laser", "Office Laser")]);
+        let remaps = stale_id_remaps(&config, &after);
+        assert_eq!(remaps, BTreeMap::from([("Zebra_ZP450".to_string(), "Zebra_ZP450_1".to_string())]));
+
+        apply_remaps(&mut config, &remaps);
+        assert_eq!(config.default_printer_id.as_deref(), Some("Zebra_ZP450_1"));
+        assert_eq!(config.label_printer_id.as_deref(), Some("Zebra_ZP450_1"));
+        assert_eq!(config.printer_nicknames.get("Zebra_ZP450_1").map(String::as_str), Some("Zebra_ZP450 nickname"));
+        assert_eq!(config.printer_defaults.get("Zebra_ZP450_1").and_then(|d| d.copies), Some(2));
+        assert_eq!(config.raw_devices.get("Zebra_ZP450_1").map(String::as_str), Some("/dev/usb/lp0"));
+        assert!(config.ignores_copies.contains("Zebra_ZP450_1"));
+        assert!(stored_printer_ids(&config).iter().all(|id| id != "Zebra_ZP450"));
+        // Printers that kept their id are left alone
+        assert!(config.printer_nicknames.contains_key("Office_Laser"));
+
+        // Nothing is left to migrate on the next start
+        assert!(stale_id_remaps(&config, &after).is_empty());
+    }
+
+    #[test]
+    fn ambiguous_or_missing_printers_are_not_remapped() {
+        let config = config_for(&["Zebra_ZP450", "DYMO_LabelWriter"]);
+        // Two new queues could be the old Zebra, and the DYMO is gone entirely
+        let after = printer_list(&[("Zebra_ZP450_1", "Zebra ZP450"), ("Zebra_ZP450_2", "Zebra ZP450")]);
+        assert!(stale_id_remaps(&config, &after).is_empty());
+    }
+
+    #[test]
+    fn ids_match_by_display_name_or_sanitized_id() {
+        let after = printer_list(&[("_192_168_1_50", "Zebra_ZP450"), ("Rollo_X1038", "Rollo Printer")]);
+        assert_eq!(remap_target("Zebra_ZP450", &after).as_deref(), Some("_192_168_1_50"));
+        assert_eq!(remap_target("Rollo Printer", &after).as_deref(), Some("Rollo_X1038"));
+        assert_eq!(remap_target("Office_Laser", &after), None);
+    }
+
+    #[test]
+    fn virtual_printer_settings_are_never_remapped() {
+        let config = config_for(&[virtual_printer::ID]);
+        let after = printer_list(&[("virtual_printer", "Virtual Printer")]);
+        assert!(stale_id_remaps(&config, &after).is_empty());
+    }
+
+    #[test]
+    fn existing_settings_of_the_new_id_win() {
+        let mut config = config_for(&["Zebra_ZP450", "Zebra_ZP450_1"]);
+        config.printer_nicknames.insert("Zebra_ZP450_1".to_string(), "Shipping".to_string());
+        let remaps = BTreeMap::from([("Zebra_ZP450".to_string(), "Zebra_ZP450_1".to_string())]);
+        apply_remaps(&mut config, &remaps);
+        assert_eq!(config.printer_nicknames.get("Zebra_ZP450_1").map(String::as_str), Some("Shipping"));
+        // The old entry stays rather than being dropped
+        assert!(config.printer_nicknames.contains_key("Zebra_ZP450"));
+    }
+
+    #[test]
+    fn quotes_in_printer_names_are_refused() {
+        for name in ["Zebra'", "Zebra\" & calc", "Office 'Laser'", "\"Zebra\""] {
+            let error = validate_printer_name(name).unwrap_err();
+            assert!(error.contains("unsupported character"), "{}: {}", name, error);
+        }
+    }
+
+    #[test]
+    fn unsafe_printer_names_are_refused() {
+        for name in ["", "  ", "-oraw", "Zebra;rm", "Zebra$(id)", "Zebra`id`", "Zebra|x", "Zebra\nx", "Zeb\u{200b}ra"] {
+            assert!(validate_printer_name(name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn ordinary_printer_names_are_accepted() {
+        for name in ["Zebra_ZP450", "DYMO LabelWriter 450 (Copy 1)", "HP@office.local", "\\\\server\\Zebra", "Imprimante Bureau — Café"] {
+            assert_eq!(validate_printer_name(name), Ok(()), "{}", name);
+        }
+    }
+
+    const CAFE: &str = "Imprimante Bureau — Café";
+
+    #[test]
+    fn non_ascii_printer_names_are_found() {
+        // CUPS queue names can't have spaces, so the queue differs from the display name
+        let printers = [("Imprimante_Bureau_—_Café", CAFE), ("Zebra_ZP450", "Zebra ZP450")];
+        assert_eq!(
+            match_printer("Imprimante_Bureau_—_Café", &printers),
+            Some(("Imprimante_Bureau_—_Café", PrinterMatch::Exact))
+        );
+        assert_eq!(match_printer(CAFE, &printers), Some(("Imprimante_Bureau_—_Café", PrinterMatch::DisplayName)));
+        assert_eq!(
+            match_printer("imprimante bureau — café", &printers),
+            Some(("Imprimante_Bureau_—_Café", PrinterMatch::DisplayName))
+        );
+        // Sanitizing keeps the accented letter, so ids built from either name still resolve
+        assert_eq!(sanitize_printer_id(CAFE), "imprimante-bureau---café");
+        assert_eq!(
+            match_printer("imprimante-bureau---café", &printers),
+            Some(("Imprimante_Bureau_—_Café", PrinterMatch::SanitizedId))
+        );
+        assert_eq!(match_printer("Imprimante Bureau — Cafe", &printers), None);
+    }
+
+    #[test]
+    fn non_ascii_printer_names_are_listed_and_serialized_as_utf8() {
+        let nicknames = BTreeMap::from([("事務所".to_string(), "Étiquettes ✓".to_string())]);
+        assert_eq!(listed_name(&nicknames, CAFE, CAFE), CAFE);
+        assert_eq!(listed_name(&nicknames, "事務所", "事務所"), "Étiquettes ✓");
+
+        let info = PrinterInfo {
+            id: "Imprimante_Bureau_—_Café".to_string(),
+            name: CAFE.to_string(),
+            printer_type: "standard".to_string(),
+            status: PrinterStatus::Ready,
+            is_default: false,
+            capabilities: PrinterCapabilities {
+                formats: supported_formats(),
+                dpi: None,
+                color: None,
+                max_copies: None,
+                media_sizes: None,
+            },
+        };
+        let json = serde_json::to_string(&info).unwrap();
+        assert!(json.contains(r#""name":"Imprimante Bureau — Café""#), "{}", json);
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed["id"], "Imprimante_Bureau_—_Café");
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn non_ascii_printer_names_are_passed_to_lp_as_one_argument() {
+        let path = std::path::Path::new("/tmp/label");
+        let cmd = lp_command(path, "Imprimante_Bureau_—_Café", &JobOptions::default(), false);
+        let args: Vec<_> = cmd.get_args().map(|arg| arg.to_str().unwrap().to_string()).collect();
+        assert_eq!(args, ["-d", "Imprimante_Bureau_—_Café", "-n", "1", "/tmp/label"]);
+        // In a UTF-8 locale, or lpstat and lp would print the name back as '?'
+        let locale = cmd.get_envs().find(|(key, _)| *key == "LC_ALL").and_then(|(_, value)| value);
+        assert!(locale.is_some_and(|value| value.to_string_lossy().ends_with("UTF-8")));
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn clearing_a_queue_cancels_all_of_its_jobs() {
+        let cmd = cancel_all_command("Zebra_ZP450");
+        assert_eq!(cmd.get_program(), "cancel");
+        let args: Vec<_> = cmd.get_args().map(|arg| arg.to_string_lossy().into_owned()).collect();
+        assert_eq!(args, ["-a", "Zebra_ZP450"]);
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn queued_jobs_are_counted_for_their_own_queue() {
+        let output = "\
+Zebra_ZP450-41          alice             1024   Tue 14 Oct 2025 09:12:03 AM
+Zebra_ZP450-42          alice             2048   Tue 14 Oct 2025 09:12:04 AM
+Zebra_ZP450_1-7         bob               1024   Tue 14 Oct 2025 09:12:05 AM
+Office_Laser-3          carol            51200   Tue 14 Oct 2025 09:13:00 AM
+
+HP-Office-12            dave              4096   Tue 14 Oct 2025 09:14:00 AM
+";
+        assert_eq!(count_queued_jobs(output, "Zebra_ZP450"), 2);
+        assert_eq!(count_queued_jobs(output, "Zebra_ZP450_1"), 1);
+        assert_eq!(count_queued_jobs(output, "HP-Office"), 1);
+        assert_eq!(count_queued_jobs(output, "HP"), 0);
+        assert_eq!(count_queued_jobs(output, "Rollo"), 0);
+        assert_eq!(count_queued_jobs("", "Zebra_ZP450"), 0);
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn lines_that_are_not_jobs_are_not_counted() {
+        let output = "Zebra_ZP450-draft   alice   1024\nZebra_ZP450-   alice   1024\nlpstat: No destinations added.\n";
+        assert_eq!(count_queued_jobs(output, "Zebra_ZP450"), 0);
+    }
+
+    fn listed_printer(id: &str, status: PrinterStatus) -> PrinterInfo {
+        PrinterInfo {
+            id: id.to_string(),
+            name: id.replace('_', " "),
+            printer_type: "thermal".to_string(),
+            status,
+            is_default: false,
+            capabilities: PrinterCapabilities {
+                formats: vec!["zpl".to_string()],
+                dpi: Some(203),
+                color: Some(false),
+                max_copies: None,
+                media_sizes: None,
+            },
+        }
+    }
+
+    #[test]
+    fn single_printer_is_found_with_a_fresh_status() {
+        let printers = vec![
+            listed_printer("Office_Laser", PrinterStatus::Ready),
+            listed_printer("Zebra_ZP450", PrinterStatus::Ready),
+        ];
+        let mut asked = None;
+        let printer = printer_with_status("Zebra_ZP450", printers, |name| {
+            asked = Some(name.to_string());
+            Some(PrinterStatus::Paused)
+        })
+        .unwrap();
+        assert_eq!(printer.id, "Zebra_ZP450");
+        assert_eq!(printer.status, PrinterStatus::Paused);
+        assert_eq!(asked.as_deref(), Some("Zebra_ZP450"));
+    }
+
+    #[test]
+    fn listed_status_is_kept_when_no_fresh_one_is_known() {
+        let printers = vec![listed_printer("Zebra_ZP450", PrinterStatus::Offline)];
+        let printer = printer_with_status("Zebra_ZP450", printers, |_| None).unwrap();
+        assert_eq!(printer.status, PrinterStatus::Offline);
+    }
+
+    #[test]
+    fn unlisted_printer_is_not_found() {
+        let printers = vec![listed_printer("Zebra_ZP450", PrinterStatus::Ready)];
+        assert_eq!(printer_with_status("Rollo", printers, |_| panic!("status queried for a missing printer")), None);
+        assert_eq!(printer_with_status("Rollo", Vec::new(), |_| None), None);
+    }
+
+    #[test]
+    fn virtual_printer_status_is_not_queried() {
+        let printers = vec![listed_printer(virtual_printer::ID, PrinterStatus::Ready)];
+        let printer = printer_with_status(virtual_printer::ID, printers, |_| panic!("spooler queried")).unwrap();
+        assert_eq!(printer.status, PrinterStatus::Ready);
+    }
+
+    fn candidate(id: &str, thermal: bool, is_default: bool) -> PrinterInfo {
+        PrinterInfo {
+            printer_type: if thermal { "thermal" } else { "standard" }.to_string(),
+            is_default,
+            ..listed_printer(id, PrinterStatus::Ready)
+        }
+    }
+
+    fn chosen(printers: Vec<PrinterInfo>, pinned: Option<&str>) -> Option<String> {
+        choose_thermal_printer(printers, pinned).map(|p| p.id)
+    }
+
+    #[test]
+    fn pinned_label_printer_comes_first() {
+        let printers = || {
+            vec![
+                candidate("Office_Laser", false, false),
+                candidate("Rollo", true, false),
+                candidate("Zebra_ZP450", true, true),
+            ]
+        };
+        assert_eq!(chosen(printers(), Some("Rollo")).as_deref(), Some("Rollo"));
+        // Pinning is an explicit choice, so it holds even for a printer that doesn't look thermal
+        assert_eq!(chosen(printers(), Some("Office_Laser")).as_deref(), Some("Office_Laser"));
+        // A pin that's no longer listed falls through to the next rule
+        assert_eq!(chosen(printers(), Some("Gone")).as_deref(), Some("Zebra_ZP450"));
+    }
+
+    #[test]
+    fn thermal_default_beats_other_thermal_printers() {
+        let printers = vec![
+            candidate("Rollo", true, false),
+            candidate("Zebra_ZP450", true, true),
+            candidate("DYMO", true, false),
+        ];
+        assert_eq!(chosen(printers, None).as_deref(), Some("Zebra_ZP450"));
+    }
+
+    #[test]
+    fn first_thermal_printer_when_the_default_is_not_thermal() {
+        let printers = vec![
+            candidate("Office_Laser", false, true),
+            candidate("Rollo", true, false),
+            candidate("Zebra_ZP450", true, false),
+        ];
+        assert_eq!(chosen(printers, None).as_deref(), Some("Rollo"));
+    }
+
+    #[test]
+    fn no_thermal_printer_is_none() {
+        let printers = vec![candidate("Office_Laser", false, true), candidate("HP_Color", false, false)];
+        assert_eq!(chosen(printers, None), None);
+        assert_eq!(chosen(Vec::new(), Some("Zebra_ZP450")), None);
+    }
+
+    #[test]
+    fn configured_keywords_recognise_new_models() {
+        assert!(matches_thermal_keywords("Zebra ZD421", &[]));
+        assert!(matches_thermal_keywords("MUNBYN ITPP941", &[]));
+        assert!(!matches_thermal_keywords("Acme LP-9000", &[]));
+        let extra = vec![" acme lp ".to_string(), String::new()];
+        assert!(matches_thermal_keywords("Acme LP-9000", &extra));
+        assert!(!matches_thermal_keywords("Office_Laser", &extra));
+    }
+
+    #[test]
+    fn quoted_printer_name_fails_before_any_command_is_built() {
+        let label = LabelData::Bytes(b"^XA^XZ".to_vec());
+        let error = print_label("Zebra\"; calc; \"", &label, "zpl", &JobOptions::default()).unwrap_err();
+        assert!(error.contains("unsupported character '\"'"), "{}", error);
+        assert!(clear_queue("Zebra'").unwrap_err().contains("unsupported character '\\''"));
+    }
+
+    fn retries(max_retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            initial_backoff: Duration::from_millis(10),
+        }
+    }
+
+    /// A print that fails with each of `errors` in turn, then succeeds
+    fn failing_print(errors: &[&str]) -> impl FnMut() -> Result<Option<String>, String> {
+        let mut errors: Vec<String> = errors.iter().rev().map(|e| e.to_string()).collect();
+        move || match errors.pop() {
+            Some(error) => Err(error),
+            None => Ok(Some("Zebra-1".to_string())),
+        }
+    }
+
+    #[test]
+    fn transient_failures_are_retried_until_the_print_succeeds() {
+        let started = Instant::now();
+        let (result, attempts) = with_retries(retries(2), failing_print(&["Printer busy", "lp: Connection refused"]));
+        assert_eq!(result, Ok(Some("Zebra-1".to_string())));
+        assert_eq!(attempts, 3);
+        // 10ms, then doubled to 20ms
+        assert!(started.elapsed() >= Duration::from_millis(30));
+    }
+
+    #[test]
+    fn retries_run_out() {
+        let (result, attempts) = with_retries(retries(2), failing_print(&["busy", "busy", "busy", "busy"]));
+        assert_eq!(result, Err("busy".to_string()));
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn permanent_failures_fail_fast() {
+        let (result, attempts) = with_retries(
+            retries(2),
+            failing_print(&["lp: Error - The printer or class does not exist."]),
+        );
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn no_retries_means_one_attempt() {
+        let (result, attempts) = with_retries(retries(0), failing_print(&["Printer busy"]));
+        assert_eq!(result, Err("Printer busy".to_string()));
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn transient_errors_are_recognised_in_any_case() {
+        assert!(is_transient_error("lp: Printer BUSY"));
+        assert!(is_transient_error("Device not ready"));
+        assert!(is_transient_error("Resource temporarily unavailable"));
+        assert!(!is_transient_error("Unsupported document format"));
+        assert!(!is_transient_error("lp: Destination \"Zebra\" is not accepting jobs."));
+    }
+
+    #[test]
+    fn rejecting_and_full_queues_are_unavailable() {
+        assert_eq!(
+            printer_unavailable_reason("lp: Destination \"Zebra_ZP450\" is not accepting jobs.\n").as_deref(),
+            Some("The printer's queue is not accepting jobs (lp: Destination \"Zebra_ZP450\" is not accepting jobs.)")
+        );
+        assert_eq!(
+            printer_unavailable_reason("lp: Too many active jobs.").as_deref(),
+            Some("The printer's queue is full (lp: Too many active jobs.)")
+        );
+        assert!(printer_unavailable_reason("lpr: error - Too many jobs").unwrap().starts_with("The printer's queue is full"));
+        assert!(printer_unavailable_reason("The printer is paused").unwrap().starts_with("The printer is paused"));
+    }
+
+    #[test]
+    fn other_print_errors_are_not_unavailable() {
+        for error in [
+            "lp: The printer or class does not exist.",
+            "lp: Unsupported document-format \"application/vnd.zebra-zpl\".",
+            "lp: Printer busy",
+            "",
+        ] {
+            assert_eq!(printer_unavailable_reason(error), None, "{}", error);
+        }
+    }
+
+    const QUEUES: &[(&str, &str)] = &[
+        ("Zebra_Technologies_ZTC_ZP_450_200dpi", "Zebra ZP 450"),
+        ("DYMO_LabelWriter_450", "DYMO LabelWriter 450"),
+        ("Office_Laser", "Office Laser"),
+    ];
+
+    #[test]
+    fn exact_system_name_matches() {
+        assert_eq!(match_printer("Office_Laser", QUEUES), Some(("Office_Laser", PrinterMatch::Exact)));
+    }
+
+    #[test]
+    fn exact_match_wins_over_a_display_name() {
+        let queues = [("Zebra", "Office_Laser"), ("Office_Laser", "Office Laser")];
+        assert_eq!(match_printer("Office_Laser", &queues), Some(("Office_Laser", PrinterMatch::Exact)));
+    }
+
+    #[test]
+    fn display_name_matches_case_insensitively() {
+        assert_eq!(
+            match_printer("zebra zp 450", QUEUES),
+            Some(("Zebra_Technologies_ZTC_ZP_450_200dpi", PrinterMatch::DisplayName))
+        );
+    }
+
+    #[test]
+    fn sanitized_id_matches_either_name() {
+        assert_eq!(
+            match_printer("dymo-labelwriter-450", QUEUES),
+            Some(("DYMO_LabelWriter_450", PrinterMatch::SanitizedId))
+        );
+        assert_eq!(match_printer("zebra-zp-450", QUEUES), Some(("Zebra_Technologies_ZTC_ZP_450_200dpi", PrinterMatch::SanitizedId)));
+    }
+
+    #[test]
+    fn unknown_id_matches_nothing() {
+        assert_eq!(match_printer("Brother_QL_800", QUEUES), None);
+        assert_eq!(match_printer("Office_Laser", &[]), None);
+    }
+
+    #[test]
+    fn nickname_replaces_the_listed_name() {
+        let mut nicknames = BTreeMap::new();
+        set_nickname(&mut nicknames, "EPSON_TM_T88VI_Receipt_192_168_1_50", "  Front desk  ");
+        assert_eq!(listed_name(&nicknames, "EPSON_TM_T88VI_Receipt_192_168_1_50", "EPSON TM-T88VI"), "Front desk");
+        assert_eq!(listed_name(&nicknames, "Office_Laser", "Office Laser"), "Office Laser");
+    }
+
+    #[test]
+    fn empty_nickname_restores_the_system_name() {
+        let mut nicknames = BTreeMap::new();
+        set_nickname(&mut nicknames, "Office_Laser", "Back office");
+        set_nickname(&mut nicknames, "Office_Laser", "   ");
+        assert!(nicknames.is_empty());
+        assert_eq!(listed_name(&nicknames, "Office_Laser", "Office Laser"), "Office Laser");
+    }
+
+    #[test]
+    fn nicknamed_printer_is_still_found_by_its_id() {
+        let mut nicknames = BTreeMap::new();
+        set_nickname(&mut nicknames, "Zebra_Technologies_ZTC_ZP_450_200dpi", "Shipping");
+        assert_eq!(listed_name(&nicknames, "Zebra_Technologies_ZTC_ZP_450_200dpi", "Zebra ZP 450"), "Shipping");
+        // Matching only sees the system names, so the nickname doesn't change what an id resolves to
+        assert_eq!(
+            match_printer("Zebra_Technologies_ZTC_ZP_450_200dpi", QUEUES),
+            Some(("Zebra_Technologies_ZTC_ZP_450_200dpi", PrinterMatch::Exact))
+        );
+    }
+
+    fn listed(names: &[&str]) -> PrinterList {
+        PrinterList {
+            printers: names
+                .iter()
+                .map(|name| PrinterInfo {
+                    id: name.to_string(),
+                    name: name.to_string(),
+                    printer_type: "thermal".to_string(),
+                    status: PrinterStatus::Unknown,
+                    is_default: false,
+                    capabilities: PrinterCapabilities {
+                        formats: vec!["zpl".to_string()],
+                        dpi: None,
+                        color: None,
+                        max_copies: None,
+                        media_sizes: None,
+                    },
+                })
+                .collect(),
+            error: None,
+        }
+    }
+
+    #[test]
+    fn concurrent_callers_share_one_enumeration() {
+        let cache = Mutex::new(None);
+        let enumerations = AtomicU64::new(0);
+        let lists: Vec<PrinterList> = std::thread::scope(|scope| {
+            let callers: Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        cached_list_in(&cache, Duration::from_secs(60), 1, || {
+                            enumerations.fetch_add(1, Ordering::SeqCst);
+                            // Slow enough that every caller arrives while the first is still listing
+                            std::thread::sleep(Duration::from_millis(50));
+                            listed(&["Zebra_ZP450"])
+                        })
+                    })
+                })
+                .collect();
+            callers.into_iter().map(|caller| caller.join().unwrap()).collect()
+        });
+
+        assert_eq!(enumerations.load(Ordering::SeqCst), 1);
+        assert!(lists.iter().all(|list| *list == listed(&["Zebra_ZP450"])));
+    }
+
+    #[test]
+    fn expired_list_is_enumerated_again() {
+        let cache = Mutex::new(None);
+        cached_list_in(&cache, Duration::ZERO, 1, || listed(&["Zebra_ZP450"]));
+        let list = cached_list_in(&cache, Duration::ZERO, 1, || listed(&["Zebra_ZP450", "Office_Laser"]));
+        assert_eq!(list, listed(&["Zebra_ZP450", "Office_Laser"]));
+    }
+
+    #[test]
+    fn config_change_makes_the_list_stale() {
+        let cache = Mutex::new(None);
+        let ttl = Duration::from_secs(60);
+        cached_list_in(&cache, ttl, 1, || listed(&["Zebra_ZP450"]));
+        assert_eq!(cached_list_in(&cache, ttl, 1, || listed(&[])), listed(&["Zebra_ZP450"]));
+        assert_eq!(cached_list_in(&cache, ttl, 2, || listed(&["Shipping"])), listed(&["Shipping"]));
+    }
+
+    fn known(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn edit_distance_counts_character_edits() {
+        assert_eq!(edit_distance("zebra", "zebra"), 0);
+        assert_eq!(edit_distance("zebra", "zebre"), 1);
+        assert_eq!(edit_distance("zp450", "zp45"), 1);
+        assert_eq!(edit_distance("", "rollo"), 5);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn ids_containing_the_wanted_id_are_suggested_first() {
+        let known = known(&["Zebra_ZP350", "Office_Laser", "Zebra_ZP450_1"]);
+        assert_eq!(closest_ids("zebra_zp450", &known), ["Zebra_ZP450_1", "Zebra_ZP350"]);
+    }
+
+    #[test]
+    fn near_misses_are_suggested_by_edit_distance() {
+        let known = known(&["DYMO_LabelWriter_450", "Office_Laser", "Rollo_X1040"]);
+        assert_eq!(closest_ids("Offce-Lazer", &known), ["Office_Laser"]);
+        assert_eq!(closest_ids("rolo_x1040", &known), ["Rollo_X1040"]);
+    }
+
+    #[test]
+    fn distant_ids_are_not_suggested() {
+        let known = known(&["DYMO_LabelWriter_450", "Office_Laser"]);
+        assert!(closest_ids("Brother_QL_800", &known).is_empty());
+        assert!(closest_ids("Zebra", &[]).is_empty());
+    }
+
+    #[test]
+    fn suggestions_are_capped() {
+        let known = known(&["Zebra_1", "Zebra_2", "Zebra_3", "Zebra_4", "Zebra_5"]);
+        assert_eq!(closest_ids("zebra", &known).len(), MAX_SUGGESTIONS);
+    }
+
+    fn strategy(per_copy_jobs: Option<bool>, copies: u32, ignores_copies: bool, max_copies: Option<u32>) -> CopyStrategy {
+        choose_copy_strategy(per_copy_jobs, copies, ignores_copies, || max_copies).0
+    }
+
+    #[test]
+    fn printer_that_ignores_copies_gets_a_job_per_copy() {
+        assert_eq!(strategy(None, 3, true, None), CopyStrategy::PerCopyJobs);
+        assert_eq!(
+            choose_copy_strategy(None, 3, true, || None).1,
+            "printer is listed in ignoresCopies"
+        );
+        // A driver limited to one copy is treated the same
+        assert_eq!(strategy(None, 3, false, Some(1)), CopyStrategy::PerCopyJobs);
+    }
+
+    #[test]
+    fn other_printers_leave_copies_to_the_spooler() {
+        assert_eq!(strategy(None, 3, false, None), CopyStrategy::Spooler);
+        assert_eq!(strategy(None, 3, false, Some(999)), CopyStrategy::Spooler);
+    }
+
+    #[test]
+    fn explicit_per_copy_jobs_wins() {
+        assert_eq!(strategy(Some(false), 3, true, Some(1)), CopyStrategy::Spooler);
+        assert_eq!(strategy(Some(true), 3, false, None), CopyStrategy::PerCopyJobs);
+        assert_eq!(strategy(Some(true), 1, false, None), CopyStrategy::PerCopyJobs);
+    }
+
+    #[test]
+    fn single_copy_needs_no_driver_query() {
+        let (strategy, reason) = choose_copy_strategy(None, 1, true, || panic!("driver queried for one copy"));
+        assert_eq!(strategy, CopyStrategy::Spooler);
+        assert_eq!(reason, "single copy");
+    }
+
+    #[test]
+    fn empty_enumeration_is_retried_until_printers_appear() {
+        let calls = std::cell::Cell::new(0);
+        let printers = retry_while_empty(
+            || {
+                calls.set(calls.get() + 1);
+                if calls.get() < 3 { Vec::new() } else { vec!["Zebra_ZP450"] }
+            },
+            5,
+            Duration::ZERO,
+        );
+        assert_eq!(printers, ["Zebra_ZP450"]);
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    fn empty_enumeration_gives_up_after_its_retries() {
+        let calls = std::cell::Cell::new(0);
+        let printers: Vec<&str> = retry_while_empty(
+            || {
+                calls.set(calls.get() + 1);
+                Vec::new()
+            },
+            2,
+            Duration::ZERO,
+        );
+        assert!(printers.is_empty());
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    fn printers_found_at_once_are_not_enumerated_again() {
+        let calls = std::cell::Cell::new(0);
+        let printers = retry_while_empty(
+            || {
+                calls.set(calls.get() + 1);
+                vec!["Zebra_ZP450"]
+            },
+            5,
+            Duration::from_secs(60),
+        );
+        assert_eq!(printers, ["Zebra_ZP450"]);
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn concurrent_labels_get_their_own_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let labels: Vec<_> = (0..8)
+            .map(|i| {
+                let dir = dir.path().to_path_buf();
+                std::thread::spawn(move || write_temp_label_in(&dir, format!("^XA^FD{}^FS^XZ", i).as_bytes(), "zpl").unwrap())
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|writer| writer.join().unwrap())
+            .collect();
+
+        let paths: BTreeSet<_> = labels.iter().map(|path| path.to_path_buf()).collect();
+        assert_eq!(paths.len(), labels.len());
+        for (i, path) in labels.iter().enumerate() {
+            assert_eq!(std::fs::read(path).unwrap(), format!("^XA^FD{}^FS^XZ", i).as_bytes());
+            let name = path.file_name().unwrap().to_string_lossy();
+            assert!(name.starts_with("limestack_label_") && name.ends_with(".zpl"), "{}", name);
+        }
+    }
+
+    #[test]
+    fn label_file_is_removed_when_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let label = write_temp_label_in(dir.path(), PDF, "pdf").unwrap();
+        let path = label.to_path_buf();
+        assert!(path.exists());
+
+        drop(label);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn label_file_is_removed_when_printing_panics() {
+        let dir = tempfile::tempdir().unwrap();
+        let written = Mutex::new(None);
+        let result = std::panic::catch_unwind(|| {
+            let label = write_temp_label_in(dir.path(), PDF, "pdf").unwrap();
+            *written.lock().unwrap() = Some(label.to_path_buf());
+            panic!("print command blew up");
+        });
+
+        assert!(result.is_err());
+        let path = written.into_inner().unwrap().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn label_file_is_readable_only_by_its_owner() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let label = write_temp_label_in(dir.path(), PDF, "pdf").unwrap();
+        let mode = std::fs::metadata(&label).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn cancel_is_run_with_just_the_job_id() {
+        let cmd = cancel_command("Zebra_ZP450-42").unwrap();
+        assert_eq!(cmd.get_program(), "cancel");
+        let args: Vec<_> = cmd.get_args().map(|arg| arg.to_string_lossy().into_owned()).collect();
+        assert_eq!(args, ["Zebra_ZP450-42"]);
+        assert!(cmd.get_envs().any(|(key, value)| key == "LC_ALL" && value == Some(CUPS_LOCALE.as_ref())));
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn job_ids_that_could_be_options_are_not_passed_to_cancel() {
+        for job_id in ["", "-a", "--help", "Zebra 42", "42;rm", "../42"] {
+            assert!(
+                matches!(cancel_command(job_id), Err(CancelError::NotFound)),
+                "{:?} was passed to cancel",
+                job_id
+            );
+        }
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn finished_jobs_are_told_apart_from_cancel_failures() {
+        assert!(matches!(
+            cancel_failure("cancel: cancel-job failed: Job #42 does not exist!"),
+            CancelError::NotFound
+        ));
+        assert!(matches!(
+            cancel_failure("cancel: cancel-job failed: Job #42 is already completed - can't cancel."),
+            CancelError::NotFound
+        ));
+        match cancel_failure("cancel: cancel-job failed: Forbidden\n") {
+            CancelError::Failed(e) => assert_eq!(e, "cancel failed: cancel: cancel-job failed: Forbidden"),
+            other => panic!("expected a failure, got {:?}", other),
+        }
+    }
+
+    #[cfg(target_os = "windows")]
+    #[test]
+    fn sumatra_is_found_in_either_program_files() {
+        assert_eq!(
+            sumatra_path(|path| path.starts_with(r"C:\Program Files\")),
+            Some(r"C:\Program Files\SumatraPDF\SumatraPDF.exe")
+        );
+        assert_eq!(
+            sumatra_path(|path| path.starts_with(r"C:\Program Files (x86)\")),
+            Some(r"C:\Program Files (x86)\SumatraPDF\SumatraPDF.exe")
+        );
+        assert_eq!(sumatra_path(|_| false), None);
+    }
+
+    #[cfg(target_os = "windows")]
+    #[test]
+    fn either_pdf_backend_is_enough() {
+        // SumatraPDF alone, without asking about the renderer
+        assert_eq!(pdf_backend_problem(|_| true, || panic!("renderer checked although SumatraPDF is installed")), None);
+        // The Windows PDF renderer alone
+        assert_eq!(pdf_backend_problem(|_| false, || true), None);
+    }
+
+    #[cfg(target_os = "windows")]
+    #[test]
+    fn missing_pdf_backend_points_to_the_fix() {
+        let problem = pdf_backend_problem(|_| false, || false).unwrap();
+        assert!(problem.contains("sumatrapdfreader.org"), "{}", problem);
     }
 }
@@ -0,0 +1,237 @@
+use crate::protocol::PrinterStatus;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a status query is reused so chatty clients don't spawn a process per request
+const STATUS_CACHE_TTL: Duration = Duration::from_secs(2);
+
+static STATUS_CACHE: Mutex<Option<(Instant, HashMap<String, PrinterStatus>)>> = Mutex::new(None);
+
+/// Get the current status of each named printer, reusing a recent query when possible
+pub fn printer_statuses(names: &[String]) -> HashMap<String, PrinterStatus> {
+    let mut cache = STATUS_CACHE.lock().unwrap();
+
+    if let Some((fetched_at, statuses)) = cache.as_ref() {
+        let covers_all = names.iter().all(|n| statuses.contains_key(n));
+        if fetched_at.elapsed() < STATUS_CACHE_TTL && covers_all {
+            return statuses.clone();
+        }
+    }
+
+    let statuses = query_statuses(names);
+    *cache = Some((Instant::now(), statuses.clone()));
+    statuses
+}
+
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+fn query_statuses(_names: &[String]) -> HashMap<String, PrinterStatus> {
+    // One lpstat call covers every queue, in English so the output parses reliably
+    let output = crate::printer::cups_command("lpstat").arg("-p").output();
+
+    match output {
+        Ok(output) => parse_lpstat(&String::from_utf8_lossy(&output.stdout)),
+        Err(e) => {
+            log::warn!("Failed to execute lpstat: {}", e);
+            HashMap::new()
+        }
+    }
+}
+
+/// Parse `lpstat -p` output into a status per printer
+///
+/// Each printer starts with a line like "printer Zebra is idle.  enabled since ..."
+/// followed by optional indented reason lines such as "\tPaused".
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+fn parse_lpstat(output: &str) -> HashMap<String, PrinterStatus> {
+    let mut statuses = HashMap::new();
+    let mut current: Option<(String, PrinterStatus)> = None;
+
+    for line in output.lines() {
+        if let Some(rest) = line.strip_prefix("printer ") {
+            if let Some((name, status)) = current.take() {
+                statuses.insert(name, status);
+            }
+
+            let mut parts = rest.splitn(2, ' ');
+            let name = parts.next().unwrap_or_default().to_string();
+            let state = parts.next().unwrap_or_default();
+
+            let status = if state.starts_with("disabled") {
+                PrinterStatus::Paused
+            } else {
+                PrinterStatus::Ready
+            };
+            current = Some((name, status));
+        } else if let Some((_, status)) = current.as_mut() {
+            *status = apply_reason(*status, line.trim());
+        }
+    }
+
+    if let Some((name, status)) = current {
+        statuses.insert(name, status);
+    }
+
+    statuses
+}
+
+/// Refine a printer's status using one of lpstat's reason lines
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+fn apply_reason(status: PrinterStatus, reason: &str) -> PrinterStatus {
+    let reason = reason.to_lowercase();
+
+    let offline_reasons = ["offline", "not connected", "unplugged", "turned off", "not responding"];
+    let error_reasons = ["error", "jam", "out of paper", "media-empty", "media empty", "door open"];
+
+    if offline_reasons.iter().any(|r| reason.contains(r)) {
+        PrinterStatus::Offline
+    } else if status == PrinterStatus::Ready && error_reasons.iter().any(|r| reason.contains(r)) {
+        PrinterStatus::Error
+    } else {
+        status
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn query_statuses(names: &[String]) -> HashMap<String, PrinterStatus> {
+    names
+        .iter()
+        .map(|name| (name.clone(), spooler_status(name)))
+        .collect()
+}
+
+/// Read PRINTER_INFO_2 from the spooler and map its status flags
+#[cfg(target_os = "windows")]
+fn spooler_status(name: &str) -> PrinterStatus {
+    use windows::core::HSTRING;
+    use windows::Win32::Graphics::Printing::{ClosePrinter, GetPrinterW, OpenPrinterW, PRINTER_HANDLE, PRINTER_INFO_2W};
+
+    let printer = HSTRING::from(name);
+
+    unsafe {
+        let mut handle = PRINTER_HANDLE::default();
+        if let Err(e) = OpenPrinterW(&printer, &mut handle, None) {
+            log::warn!("Failed to open printer '{}' for status: {}", name, e);
+            return PrinterStatus::Unknown;
+        }
+
+        // First call reports the buffer size needed for PRINTER_INFO_2
+        let mut needed = 0u32;
+        let _ = GetPrinterW(handle, 2, None, &mut needed);
+        let mut buf = vec![0u8; needed as usize];
+        let ok = needed > 0 && GetPrinterW(handle, 2, Some(&mut buf), &mut needed).as_bool();
+        let _ = ClosePrinter(handle);
+
+        if !ok {
+            return PrinterStatus::Unknown;
+        }
+
+        let info = std::ptr::read_unaligned(buf.as_ptr() as *const PRINTER_INFO_2W);
+        status_from_spooler(info.Status, info.Attributes)
+    }
+}
+
+/// Map spooler PRINTER_STATUS_* flags and PRINTER_ATTRIBUTE_* bits to a status
+#[cfg(target_os = "windows")]
+fn status_from_spooler(status: u32, attributes: u32) -> PrinterStatus {
+    const PRINTER_STATUS_PAUSED: u32 = 0x0000_0001;
+    const PRINTER_STATUS_ERROR: u32 = 0x0000_0002;
+    const PRINTER_STATUS_PAPER_JAM: u32 = 0x0000_0008;
+    const PRINTER_STATUS_PAPER_OUT: u32 = 0x0000_0010;
+    const PRINTER_STATUS_PAPER_PROBLEM: u32 = 0x0000_0040;
+    const PRINTER_STATUS_OFFLINE: u32 = 0x0000_0080;
+    const PRINTER_STATUS_NOT_AVAILABLE: u32 = 0x0000_1000;
+    const PRINTER_STATUS_DOOR_OPEN: u32 = 0x0040_0000;
+    const PRINTER_ATTRIBUTE_WORK_OFFLINE: u32 = 0x0000_0400;
+
+    let error_flags = PRINTER_STATUS_ERROR
+        | PRINTER_STATUS_PAPER_JAM
+        | PRINTER_STATUS_PAPER_OUT
+        | PRINTER_STATUS_PAPER_PROBLEM
+        | PRINTER_STATUS_DOOR_OPEN;
+
+    if status & (PRINTER_STATUS_OFFLINE | PRINTER_STATUS_NOT_AVAILABLE) != 0
+        || attributes & PRINTER_ATTRIBUTE_WORK_OFFLINE != 0
+    {
+        PrinterStatus::Offline
+    } else if status & PRINTER_STATUS_PAUSED != 0 {
+        PrinterStatus::Paused
+    } else if status & error_flags != 0 {
+        PrinterStatus::Error
+    } else {
+        PrinterStatus::Ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn idle_printer_is_ready() {
+        let statuses = parse_lpstat("printer Zebra_ZP450 is idle.  enabled since Tue 14 Oct 2025 09:12:03 AM\n");
+        assert_eq!(statuses.get("Zebra_ZP450"), Some(&PrinterStatus::Ready));
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn disabled_printer_is_paused() {
+        let output = "printer DYMO_LabelWriter_450 disabled since Tue 14 Oct 2025 09:12:03 AM -\n\tPaused\n";
+        assert_eq!(parse_lpstat(output).get("DYMO_LabelWriter_450"), Some(&PrinterStatus::Paused));
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn printing_printer_is_ready() {
+        let output = "printer Office_Laser now printing Office_Laser-17.  enabled since Tue 14 Oct 2025 09:12:03 AM\n";
+        assert_eq!(parse_lpstat(output).get("Office_Laser"), Some(&PrinterStatus::Ready));
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn non_ascii_queue_names_are_kept_intact() {
+        let output = "printer Imprimante_Bureau_—_Café disabled since mar. 14 oct. 2025 09:12:03 -\n\tPaused\nprinter 事務所プリンタ is idle.  enabled since Tue 14 Oct 2025 09:12:03 AM\n";
+        let statuses = parse_lpstat(output);
+        assert_eq!(statuses.get("Imprimante_Bureau_—_Café"), Some(&PrinterStatus::Paused));
+        assert_eq!(statuses.get("事務所プリンタ"), Some(&PrinterStatus::Ready));
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn reason_lines_apply_to_their_own_printer() {
+        let output = "\
+printer Rollo is idle.  enabled since Tue 14 Oct 2025 09:12:03 AM
+\tThe printer is not connected.
+printer Zebra_ZP450 is idle.  enabled since Tue 14 Oct 2025 09:12:03 AM
+\tMedia empty or jammed
+printer Office_Laser is idle.  enabled since Tue 14 Oct 2025 09:12:03 AM
+";
+        let statuses = parse_lpstat(output);
+        assert_eq!(statuses.len(), 3);
+        assert_eq!(statuses["Rollo"], PrinterStatus::Offline);
+        assert_eq!(statuses["Zebra_ZP450"], PrinterStatus::Error);
+        assert_eq!(statuses["Office_Laser"], PrinterStatus::Ready);
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn stopped_queue_stays_paused_whatever_the_reason() {
+        let output = "\
+printer Zebra_ZP450 disabled since Tue 14 Oct 2025 09:12:03 AM -
+\tMedia empty or jammed
+printer Rollo disabled since Tue 14 Oct 2025 09:12:03 AM -
+\treason unknown
+";
+        let statuses = parse_lpstat(output);
+        // A jam on a stopped queue still needs the queue resumed, so it reports as paused
+        assert_eq!(statuses["Zebra_ZP450"], PrinterStatus::Paused);
+        assert_eq!(statuses["Rollo"], PrinterStatus::Paused);
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn no_printers_parses_to_nothing() {
+        assert!(parse_lpstat("").is_empty());
+    }
+}
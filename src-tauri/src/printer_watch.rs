@@ -0,0 +1,124 @@
+use crate::log_context;
+use crate::printer::{self, PrinterList};
+use crate::protocol::PrinterInfo;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// Poll for the whole server while any connection is listening, sending each change
+///
+/// The only printer poll: `printers_changed` and `subscribe_printers` both feed from it, so a
+/// change is detected once and each connection is sent one message for it.
+pub async fn broadcast_changes(interval: Duration, changes: broadcast::Sender<PrinterList>) {
+    let mut previous: Option<PrinterList> = None;
+    let mut ticker = tokio::time::interval(interval);
+
+    loop {
+        ticker.tick().await;
+        if changes.receiver_count() == 0 {
+            previous = None;
+            continue;
+        }
+
+        // Enumeration shells out to lpstat/the spooler, so keep it off the async threads
+        let current = match log_context::spawn_blocking(printer::list_printers).await {
+            Ok(printers) => printers,
+            Err(e) => {
+                log::error!("Printer poll failed: {}", e);
+                continue;
+            }
+        };
+
+        // The first poll is the baseline; connections already have that list from `welcome`
+        if previous.as_ref().is_some_and(|previous| list_changed(previous, &current)) {
+            log::info!("Printers changed ({} printers), notifying clients", current.printers.len());
+            let _ = changes.send(current.clone());
+        }
+        previous = Some(current);
+    }
+}
+
+fn list_changed(previous: &PrinterList, current: &PrinterList) -> bool {
+    previous.error != current.error || printers_changed(&previous.printers, &current.printers)
+}
+
+/// Whether printers were added, removed, or changed status (ignoring enumeration order)
+pub fn printers_changed(previous: &[PrinterInfo], current: &[PrinterInfo]) -> bool {
+    if previous.len() != current.len() {
+        return true;
+    }
+
+    current.iter().any(|printer| !previous.contains(printer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{PrinterCapabilities, PrinterStatus};
+
+    fn printer(name: &str, status: PrinterStatus) -> PrinterInfo {
+        PrinterInfo {
+            id: name.to_string(),
+            name: name.to_string(),
+            printer_type: "thermal".to_string(),
+            status,
+            is_default: false,
+            capabilities: PrinterCapabilities {
+                formats: vec!["zpl".to_string()],
+                dpi: Some(203),
+                color: Some(false),
+                max_copies: None,
+                media_sizes: None,
+            },
+        }
+    }
+
+    fn list(printers: Vec<PrinterInfo>) -> PrinterList {
+        PrinterList { printers, error: None }
+    }
+
+    #[test]
+    fn same_printers_in_another_order_are_unchanged() {
+        let zebra = printer("Zebra", PrinterStatus::Ready);
+        let dymo = printer("Dymo", PrinterStatus::Offline);
+        assert!(!printers_changed(&[zebra.clone(), dymo.clone()], &[dymo, zebra]));
+        assert!(!printers_changed(&[], &[]));
+    }
+
+    #[test]
+    fn added_printer_is_a_change() {
+        let zebra = printer("Zebra", PrinterStatus::Ready);
+        assert!(printers_changed(&[zebra.clone()], &[zebra, printer("Dymo", PrinterStatus::Ready)]));
+    }
+
+    #[test]
+    fn removed_printer_is_a_change() {
+        let zebra = printer("Zebra", PrinterStatus::Ready);
+        assert!(printers_changed(&[zebra.clone(), printer("Dymo", PrinterStatus::Ready)], &[zebra]));
+    }
+
+    #[test]
+    fn status_change_is_a_change() {
+        assert!(printers_changed(
+            &[printer("Zebra", PrinterStatus::Ready)],
+            &[printer("Zebra", PrinterStatus::Offline)]
+        ));
+    }
+
+    #[test]
+    fn swapped_printer_of_the_same_count_is_a_change() {
+        assert!(printers_changed(
+            &[printer("Zebra", PrinterStatus::Ready)],
+            &[printer("Dymo", PrinterStatus::Ready)]
+        ));
+    }
+
+    #[test]
+    fn enumeration_error_is_a_change() {
+        let unavailable = PrinterList {
+            printers: Vec::new(),
+            error: Some("CUPS is not running".to_string()),
+        };
+        assert!(list_changed(&list(Vec::new()), &unavailable));
+        assert!(!list_changed(&unavailable, &unavailable));
+    }
+}
@@ -1,71 +1,819 @@
+use crate::receipt::Receipt;
+use crate::tspl::TsplLabel;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+/// Protocol versions this connector speaks. Only breaking changes bump it; additions are
+/// advertised through `capabilities` instead.
+pub const SUPPORTED_PROTOCOL_RANGE: RangeInclusive<u32> = 1..=1;
+
+/// Pick the protocol version for a `hello`, from the major part of its `version` ("1", "1.4.0")
+pub fn negotiate_protocol_version(client_version: &str) -> Result<u32, String> {
+    let major = client_version
+        .trim()
+        .trim_start_matches('v')
+        .split('.')
+        .next()
+        .and_then(|major| major.parse::<u32>().ok())
+        .ok_or_else(|| format!("Unrecognized protocol version: {:?}", client_version))?;
+
+    if major > *SUPPORTED_PROTOCOL_RANGE.end() {
+        Err(format!(
+            "LimeStack needs protocol version {}, but this connector supports up to {}. Please update the LimeStack Connector",
+            major,
+            SUPPORTED_PROTOCOL_RANGE.end()
+        ))
+    } else if major < *SUPPORTED_PROTOCOL_RANGE.start() {
+        Err(format!(
+            "This page uses protocol version {}, but the connector needs at least {}. Please reload LimeStack",
+            major,
+            SUPPORTED_PROTOCOL_RANGE.start()
+        ))
+    } else {
+        Ok(major)
+    }
+}
 
 /// Messages from the browser to the connector
 #[derive(Debug, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ClientMessage {
     Hello {
+        /// Protocol version the client speaks, checked against `SUPPORTED_PROTOCOL_RANGE`
         version: String,
         origin: String,
+        /// Paired token, or the pairing code shown in the tray to pair this browser
+        #[serde(default)]
+        token: Option<String>,
     },
+    /// Liveness check that works before `hello`
+    Ping,
     GetPrinters,
+    /// Look up one printer, e.g. a kiosk's pinned printer, without listing them all
+    GetPrinter {
+        printer: String,
+    },
+    /// The best guess at the label printer, so UIs don't each pick one from the list
+    GetDefaultThermalPrinter,
+    /// Ask what this connector accepts before sending a print
+    GetCapabilities,
+    /// Reply with `printers`, then send changes as `printers` rather than `printers_changed`
+    SubscribePrinters,
+    UnsubscribePrinters,
+    /// Stream connector log records at `level` ("info" by default) or more severe
+    SubscribeLogs {
+        #[serde(default)]
+        level: Option<String>,
+    },
+    UnsubscribeLogs,
     Print {
         #[serde(rename = "requestId")]
         request_id: String,
         printer: String,
+        /// Empty or "auto" to take the format from a data URI
+        #[serde(default)]
+        format: String,
+        data: String, // Base64 or a base64 data URI
+        options: PrintOptions,
+    },
+    /// Build an ESC/POS receipt from structured lines and print it
+    PrintReceipt {
+        #[serde(rename = "requestId")]
+        request_id: String,
+        printer: String,
+        receipt: Receipt,
+    },
+    /// Fill in a built-in label template and print it as ZPL or PDF, whichever the printer takes
+    PrintTemplate {
+        #[serde(rename = "requestId")]
+        request_id: String,
+        printer: String,
+        template: String,
+        fields: BTreeMap<String, String>,
+        #[serde(default)]
+        options: PrintOptions,
+    },
+    /// Build a TSPL label from text and barcodes and print it on a TSC printer
+    PrintTspl {
+        #[serde(rename = "requestId")]
+        request_id: String,
+        printer: String,
+        label: TsplLabel,
+        #[serde(default)]
+        options: PrintOptions,
+    },
+    /// Print a label the connector downloads itself (HTTPS on LimeStack domains only)
+    PrintUrl {
+        #[serde(rename = "requestId")]
+        request_id: String,
+        printer: String,
+        url: String,
+        /// Empty or "auto" to take the format from the response Content-Type
+        #[serde(default)]
+        format: String,
+        options: PrintOptions,
+    },
+    /// Header for a print whose bytes follow in a binary frame tagged with the same request id
+    PrintBinary {
+        #[serde(rename = "requestId")]
+        request_id: String,
+        printer: String,
+        format: String,
+        options: PrintOptions,
+    },
+    /// Several prints, possibly to different printers, answered with one `print_batch_result`
+    PrintBatch {
+        #[serde(rename = "requestId")]
+        request_id: String,
+        jobs: Vec<BatchJob>,
+        /// Skip the remaining jobs after the first failure instead of printing them anyway
+        #[serde(rename = "stopOnFailure", default)]
+        stop_on_failure: bool,
+    },
+    /// List connected scales and the configured serial scale
+    GetScales,
+    /// `scale` is an id from `scales`; without it the default scale is read
+    ReadScale {
+        #[serde(default)]
+        scale: Option<String>,
+    },
+    /// Tare the scale and reply with the reading after
+    TareScale {
+        #[serde(default)]
+        scale: Option<String>,
+    },
+    StartScaleStream {
+        #[serde(default)]
+        scale: Option<String>,
+    },
+    StopScaleStream,
+    /// Cancel a print still queued in the connector, or a spooled job by its `jobId`
+    CancelPrint {
+        #[serde(rename = "requestId")]
+        request_id: String,
+        #[serde(rename = "jobId", default)]
+        job_id: Option<String>,
+        /// Printer the job was sent to; needed to cancel spooled jobs on Windows
+        #[serde(default)]
+        printer: Option<String>,
+    },
+    SetDefaultPrinter {
+        printer: String,
+    },
+    /// Cancel every job waiting in a printer's spooler queue, e.g. after a jam
+    ClearPrintQueue {
+        printer: String,
+    },
+    /// Show a printer under a friendly name in `printers`; an empty nickname clears it
+    SetPrinterNickname {
+        printer: String,
+        nickname: String,
+    },
+    /// Open a page in the default browser; only URLs on allowed origins are opened
+    OpenUrl {
+        url: String,
+    },
+    /// Explain how a printer id resolves, with close matches when it doesn't
+    DiagnosePrinter {
+        printer: String,
+    },
+    GetPrintHistory,
+    /// Connection and print counters since the connector started
+    GetStats,
+    /// Rasterize a label to PNG without printing it
+    RenderPreview {
+        #[serde(rename = "requestId", default)]
+        request_id: Option<String>,
+        #[serde(default)]
         format: String,
-        data: String, // Base64 encoded
+        data: String,
         options: PrintOptions,
     },
-    ReadScale,
+    /// Any message type this connector doesn't know, e.g. from a newer LimeStack
+    #[serde(other)]
+    Unknown,
 }
 
+/// One print within a `print_batch`
 #[derive(Debug, Deserialize)]
+pub struct BatchJob {
+    pub printer: String,
+    #[serde(default)]
+    pub format: String,
+    pub data: String,
+    #[serde(default)]
+    pub options: PrintOptions,
+}
+
+/// Per-job options; also stored per printer as `printerDefaults` in `config.json`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct PrintOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub copies: Option<u32>,
-    #[serde(rename = "paperSize")]
+    #[serde(rename = "paperSize", default, skip_serializing_if = "Option::is_none")]
     pub paper_size: Option<String>,
+    /// Submit each copy as its own job so a failure can be retried individually
+    #[serde(rename = "perCopyJobs", default, skip_serializing_if = "Option::is_none")]
+    pub per_copy_jobs: Option<bool>,
+    /// Clockwise rotation in degrees: 0, 90, 180 or 270
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rotate: Option<u32>,
+    /// "fit", "actual" or a percentage such as "75%"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scale: Option<String>,
+    /// Pages of a PDF to print, e.g. "1", "2-3" or "1,4"
+    #[serde(rename = "pageRange", default, skip_serializing_if = "Option::is_none")]
+    pub page_range: Option<String>,
+    /// "draft", "normal" or "high"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quality: Option<String>,
+    /// Thermal print darkness from 0 (lightest) to 30
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub darkness: Option<u32>,
+    /// CUPS `-o` options passed to `lp` as they are, e.g. "MediaType=Thermal"; ignored on Windows
+    #[serde(rename = "rawOptions", default, skip_serializing_if = "Option::is_none")]
+    pub raw_options: Option<Vec<String>>,
+    /// Run every check and decode the data, but don't print
+    #[serde(rename = "dryRun", default, skip_serializing_if = "Option::is_none")]
+    pub dry_run: Option<bool>,
+    /// Reply once the spooler reports the job finished, not when it's accepted
+    #[serde(rename = "waitForCompletion", default, skip_serializing_if = "Option::is_none")]
+    pub wait_for_completion: Option<bool>,
+}
+
+impl PrintOptions {
+    /// Fill in options the request left unset from a printer's defaults
+    ///
+    /// `dryRun` is never inherited, so a stray default can't silently stop a printer printing,
+    /// and nor is `waitForCompletion`, since the print timeout is chosen before the printer
+    /// is looked up.
+    pub fn with_defaults(self, defaults: &PrintOptions) -> PrintOptions {
+        PrintOptions {
+            copies: self.copies.or(defaults.copies),
+            paper_size: self.paper_size.or_else(|| defaults.paper_size.clone()),
+            per_copy_jobs: self.per_copy_jobs.or(defaults.per_copy_jobs),
+            rotate: self.rotate.or(defaults.rotate),
+            scale: self.scale.or_else(|| defaults.scale.clone()),
+            page_range: self.page_range.or_else(|| defaults.page_range.clone()),
+            quality: self.quality.or_else(|| defaults.quality.clone()),
+            darkness: self.darkness.or(defaults.darkness),
+            raw_options: self.raw_options.or_else(|| defaults.raw_options.clone()),
+            dry_run: self.dry_run,
+            wait_for_completion: self.wait_for_completion,
+        }
+    }
 }
 
 /// Messages from the connector to the browser
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ServerMessage {
     Welcome {
         #[serde(rename = "connectorVersion")]
         connector_version: String,
+        /// Protocol version agreed from `hello`, the highest this connector supports
+        #[serde(rename = "protocolVersion")]
+        protocol_version: u32,
+        /// Whether this connection is using wss://
+        secure: bool,
+        /// Largest message the connector accepts, in bytes; bigger labels should use `print_url`
+        #[serde(rename = "maxMessageSize")]
+        max_message_size: usize,
         capabilities: Vec<String>,
         printers: Vec<PrinterInfo>,
+        /// Why `printers` is empty when the print system couldn't be queried
+        #[serde(rename = "enumerationError", skip_serializing_if = "Option::is_none")]
+        enumeration_error: Option<String>,
+        /// Issued when `hello` carried a pairing code; send it as `token` from now on
+        #[serde(skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+    },
+    Pong {
+        #[serde(rename = "connectorVersion")]
+        connector_version: String,
+        #[serde(rename = "uptimeSeconds")]
+        uptime_seconds: u64,
+    },
+    /// Sent unprompted to every authenticated client when printers are added, removed or
+    /// change status
+    PrintersChanged {
+        printers: Vec<PrinterInfo>,
+        #[serde(rename = "enumerationError", skip_serializing_if = "Option::is_none")]
+        enumeration_error: Option<String>,
+    },
+    /// Sent just before the connector closes every connection to stop, with how long to
+    /// wait before reconnecting
+    Goodbye {
+        reason: ShutdownReason,
+        #[serde(rename = "retryAfterMs")]
+        retry_after_ms: u64,
+    },
+    /// Reply to `get_capabilities`, taken from the running configuration
+    Capabilities {
+        formats: Vec<String>,
+        /// Largest message accepted, in bytes; base64 labels must fit within it
+        #[serde(rename = "maxPayloadBytes")]
+        max_payload_bytes: usize,
+        #[serde(rename = "maxCopies")]
+        max_copies: u32,
+        /// The same list as `capabilities` in `welcome`
+        features: Vec<String>,
     },
     Printers {
         printers: Vec<PrinterInfo>,
+        #[serde(rename = "enumerationError", skip_serializing_if = "Option::is_none")]
+        enumeration_error: Option<String>,
+    },
+    /// Reply to `get_printer`; null when no printer matches the id
+    Printer {
+        printer: Option<PrinterInfo>,
+    },
+    /// Reply to `get_default_thermal_printer`; null when no printer looks like a label printer
+    DefaultThermalPrinter {
+        printer: Option<PrinterInfo>,
     },
     PrintResult {
         #[serde(rename = "requestId")]
         request_id: String,
         success: bool,
+        #[serde(rename = "jobId", skip_serializing_if = "Option::is_none")]
+        job_id: Option<String>,
         #[serde(skip_serializing_if = "Option::is_none")]
         message: Option<String>,
         #[serde(skip_serializing_if = "Option::is_none")]
         error: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        code: Option<ErrorCode>,
+        #[serde(rename = "copyResults", skip_serializing_if = "Option::is_none")]
+        copy_results: Option<Vec<CopyResult>>,
+        /// How many times the job was submitted, including retries of transient failures
+        #[serde(skip_serializing_if = "Option::is_none")]
+        attempts: Option<u32>,
+        /// Seconds to wait before resubmitting a `rate_limited` request
+        #[serde(rename = "retryAfter", skip_serializing_if = "Option::is_none")]
+        retry_after: Option<u64>,
+    },
+    /// Outcome of every job in a `print_batch`, in request order
+    PrintBatchResult {
+        #[serde(rename = "requestId")]
+        request_id: String,
+        success: bool,
+        results: Vec<BatchJobResult>,
+    },
+    /// Sent after each copy is submitted in `perCopyJobs` mode, before the `print_result`
+    PrintProgress {
+        #[serde(rename = "requestId")]
+        request_id: String,
+        completed: u32,
+        total: u32,
+    },
+    CancelResult {
+        #[serde(rename = "requestId")]
+        request_id: String,
+        success: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        code: Option<ErrorCode>,
+    },
+    /// PNG rendering of a label, base64 encoded
+    Preview {
+        #[serde(rename = "requestId", skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+        image: String,
+        width: u32,
+        height: u32,
+    },
+    /// Reply to `clear_print_queue`
+    ClearQueueResult {
+        printer: String,
+        /// Jobs that were in the queue when it was cleared
+        removed: usize,
+    },
+    /// Reply to `open_url` once the browser has been asked to open it
+    UrlOpened {
+        url: String,
+    },
+    /// Reply to `diagnose_printer`
+    PrinterDiagnosis {
+        printer: String,
+        /// The queue `printer` resolves to; null when printing to it would fail with `printer_not_found`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        matched: Option<String>,
+        /// Known ids closest to `printer`, best first
+        suggestions: Vec<String>,
+        #[serde(rename = "knownPrinters")]
+        known_printers: Vec<String>,
+        #[serde(rename = "spoolerAvailable")]
+        spooler_available: bool,
+        #[serde(rename = "spoolerError", skip_serializing_if = "Option::is_none")]
+        spooler_error: Option<String>,
+    },
+    /// Recent print jobs, newest first
+    PrintHistory {
+        jobs: Vec<PrintRecord>,
+    },
+    /// Reply to `get_stats`; `totalPrints` counts finished print requests, failures included
+    Stats {
+        #[serde(rename = "totalConnections")]
+        total_connections: u64,
+        #[serde(rename = "activeConnections")]
+        active_connections: u64,
+        #[serde(rename = "totalPrints")]
+        total_prints: u64,
+        #[serde(rename = "failedPrints")]
+        failed_prints: u64,
+        #[serde(rename = "uptimeSeconds")]
+        uptime_seconds: u64,
+    },
+    Scales {
+        scales: Vec<ScaleInfo>,
     },
     ScaleReading {
         weight: f64,
         unit: String,
         stable: bool,
     },
+    /// A connector log record, for clients that sent `subscribe_logs`
+    Log {
+        timestamp: String,
+        level: String,
+        target: String,
+        message: String,
+        /// Records dropped before this one because the client fell behind
+        #[serde(skip_serializing_if = "is_zero")]
+        dropped: u64,
+    },
     Error {
+        code: ErrorCode,
         message: String,
     },
 }
 
+impl ServerMessage {
+    /// Build an error message with a machine-readable code
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        ServerMessage::Error {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// Build a failed print result with a machine-readable code
+    pub fn print_failure(request_id: String, code: ErrorCode, error: impl Into<String>) -> Self {
+        ServerMessage::PrintResult {
+            request_id,
+            success: false,
+            job_id: None,
+            message: None,
+            error: Some(error.into()),
+            code: Some(code),
+            copy_results: None,
+            attempts: None,
+            retry_after: None,
+        }
+    }
+
+    /// A print refused because the connection is submitting too fast
+    pub fn rate_limited(request_id: String, retry_after: Duration) -> Self {
+        // Round up so retrying at `retryAfter` never lands just short of a token
+        let retry_after = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        ServerMessage::PrintResult {
+            request_id,
+            success: false,
+            job_id: None,
+            message: None,
+            error: Some(format!("Too many print requests, retry in {}s", retry_after)),
+            code: Some(ErrorCode::RateLimited),
+            copy_results: None,
+            attempts: None,
+            retry_after: Some(retry_after),
+        }
+    }
+}
+
+/// Machine-readable error codes so the browser doesn't have to match on message text
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    OriginNotAllowed,
+    /// The token is unknown, or the pairing code expired
+    InvalidToken,
+    /// Pairing is required and `hello` had no token
+    PairingRequired,
+    /// Legacy: no longer sent, since commands before `hello` get `auth_required` instead
+    NotAuthenticated,
+    /// Something other than `hello` or `ping` was sent before authenticating
+    AuthRequired,
+    InvalidMessage,
+    PrinterNotFound,
+    PrintFailed,
+    ScaleNotFound,
+    ScaleError,
+    ConfigError,
+    InvalidPaperSize,
+    Timeout,
+    /// A print waited too long for other jobs to finish
+    QueueTimeout,
+    InvalidCopies,
+    InvalidRotation,
+    InvalidScale,
+    InvalidPageRange,
+    InvalidQuality,
+    /// `darkness` is above 30
+    InvalidDarkness,
+    /// A `rawOptions` entry isn't a plain `name=value`, or sets something the print options control
+    InvalidRawOptions,
+    /// A `print_tspl` label has a size, font or barcode TSPL can't take, or `print_template`
+    /// named an unknown template or had missing or unusable fields
+    InvalidLabel,
+    /// A print had no `data`
+    EmptyPayload,
+    InvalidBase64,
+    /// The printer's queue is stopped, rejecting jobs or full; retrying won't help until it's cleared
+    PrinterUnavailable,
+    /// `data` was valid base64 but decoded to nothing
+    EmptyDecoded,
+    /// With `waitForCompletion`, the spooler aborted or cancelled the job, or the printer stopped
+    JobFailed,
+    /// With `waitForCompletion`, the job was still queued when the wait ran out
+    CompletionTimeout,
+    /// The data isn't what `format` says, e.g. a "pdf" without a PDF header
+    FormatMismatch,
+    /// A `print_url` URL is not HTTPS on an allowed domain, or an `open_url` URL is not on an
+    /// allowed origin
+    UrlNotAllowed,
+    /// The default browser couldn't be launched
+    OpenUrlFailed,
+    /// Downloading a `print_url` label failed or it was too large
+    FetchFailed,
+    PreviewFailed,
+    /// The message type isn't supported by this connector version
+    UnsupportedMessage,
+    /// The connection sent prints faster than its rate limit allows
+    RateLimited,
+    /// The print was cancelled by `cancel_print` before it was spooled
+    Cancelled,
+    /// `cancel_print` named a job that already printed or never existed
+    JobNotFound,
+    /// A `print_batch` job wasn't attempted because an earlier one failed
+    Skipped,
+    /// The connected scale has no remote tare command
+    TareUnsupported,
+    /// The client's protocol version is outside the range this connector supports
+    IncompatibleVersion,
+    /// A message was over the connector's size limit
+    PayloadTooLarge,
+    /// Nothing on this machine can print the format, e.g. no CUPS or no PDF renderer
+    NoPrintBackend,
+}
+
+/// Outcome of one copy when copies are submitted as separate jobs
 #[derive(Debug, Serialize, Clone)]
+pub struct CopyResult {
+    pub copy: u32,
+    pub success: bool,
+    #[serde(rename = "jobId", skip_serializing_if = "Option::is_none")]
+    pub job_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub attempts: u32,
+}
+
+/// Outcome of one job in a `print_batch`
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchJobResult {
+    pub index: usize,
+    pub printer: String,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub job_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<ErrorCode>,
+}
+
+/// A finished print request, kept in the print history
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PrintRecord {
+    pub request_id: String,
+    pub printer: String,
+    pub format: String,
+    /// RFC 3339 time the request finished
+    pub timestamp: String,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
 pub struct PrinterInfo {
     pub id: String,
     pub name: String,
     #[serde(rename = "type")]
     pub printer_type: String,
-    pub status: String,
+    pub status: PrinterStatus,
+    #[serde(rename = "isDefault")]
+    pub is_default: bool,
+    pub capabilities: PrinterCapabilities,
+}
+
+/// A scale that can be read, identified for `read_scale` and the other scale messages
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct ScaleInfo {
+    pub id: String,
+    pub name: String,
+    /// "hid" for USB postal scales, "serial" for the configured serial scale
+    #[serde(rename = "type")]
+    pub scale_type: String,
+    /// The scale read when no id is given
     #[serde(rename = "isDefault")]
     pub is_default: bool,
 }
+
+/// What a printer's driver supports; fields are null when the driver doesn't say
+#[derive(Debug, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PrinterCapabilities {
+    pub formats: Vec<String>,
+    pub dpi: Option<u32>,
+    pub color: Option<bool>,
+    pub max_copies: Option<u32>,
+    pub media_sizes: Option<Vec<String>>,
+}
+
+/// Why the connector is stopping
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ShutdownReason {
+    /// Installing an update; it's back within seconds
+    Restart,
+    /// The user quit or the process was stopped; it may not come back until they restart it
+    Quit,
+}
+
+/// Current state of a printer as reported by the OS spooler
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PrinterStatus {
+    Ready,
+    Offline,
+    Paused,
+    Error,
+    Unknown,
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL_ERROR_CODES: &[ErrorCode] = &[
+        ErrorCode::OriginNotAllowed,
+        ErrorCode::InvalidToken,
+        ErrorCode::PairingRequired,
+        ErrorCode::NotAuthenticated,
+        ErrorCode::AuthRequired,
+        ErrorCode::InvalidMessage,
+        ErrorCode::PrinterNotFound,
+        ErrorCode::PrintFailed,
+        ErrorCode::ScaleNotFound,
+        ErrorCode::ScaleError,
+        ErrorCode::ConfigError,
+        ErrorCode::InvalidPaperSize,
+        ErrorCode::Timeout,
+        ErrorCode::QueueTimeout,
+        ErrorCode::InvalidCopies,
+        ErrorCode::InvalidRotation,
+        ErrorCode::InvalidScale,
+        ErrorCode::InvalidPageRange,
+        ErrorCode::InvalidQuality,
+        ErrorCode::InvalidDarkness,
+        ErrorCode::InvalidRawOptions,
+        ErrorCode::InvalidLabel,
+        ErrorCode::EmptyPayload,
+        ErrorCode::InvalidBase64,
+        ErrorCode::PrinterUnavailable,
+        ErrorCode::EmptyDecoded,
+        ErrorCode::JobFailed,
+        ErrorCode::CompletionTimeout,
+        ErrorCode::FormatMismatch,
+        ErrorCode::UrlNotAllowed,
+        ErrorCode::OpenUrlFailed,
+        ErrorCode::FetchFailed,
+        ErrorCode::PreviewFailed,
+        ErrorCode::UnsupportedMessage,
+        ErrorCode::RateLimited,
+        ErrorCode::Cancelled,
+        ErrorCode::JobNotFound,
+        ErrorCode::Skipped,
+        ErrorCode::TareUnsupported,
+        ErrorCode::IncompatibleVersion,
+        ErrorCode::PayloadTooLarge,
+        ErrorCode::NoPrintBackend,
+    ];
+
+    #[test]
+    fn error_codes_round_trip_as_snake_case() {
+        for code in ALL_ERROR_CODES {
+            let json = serde_json::to_string(code).unwrap();
+            let name = json.trim_matches('"');
+            assert!(
+                name.chars().all(|c| c.is_ascii_lowercase() || c == '_'),
+                "{:?} serialized as {}",
+                code,
+                json
+            );
+            assert_eq!(serde_json::from_str::<ErrorCode>(&json).unwrap(), *code);
+        }
+        assert_eq!(serde_json::to_string(&ErrorCode::OriginNotAllowed).unwrap(), r#""origin_not_allowed""#);
+    }
+
+    #[test]
+    fn error_message_carries_its_code() {
+        let json = serde_json::to_value(ServerMessage::error(ErrorCode::PrinterNotFound, "No such printer")).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"type": "error", "code": "printer_not_found", "message": "No such printer"})
+        );
+    }
+
+    #[test]
+    fn protocol_version_in_range_is_accepted() {
+        assert_eq!(negotiate_protocol_version("1"), Ok(1));
+        assert_eq!(negotiate_protocol_version("1.4.0"), Ok(1));
+        assert_eq!(negotiate_protocol_version(" v1.0 "), Ok(1));
+    }
+
+    #[test]
+    fn protocol_version_below_range_asks_for_a_reload() {
+        let error = negotiate_protocol_version("0.9").unwrap_err();
+        assert!(error.contains("reload"), "{}", error);
+    }
+
+    #[test]
+    fn protocol_version_above_range_asks_for_an_update() {
+        let error = negotiate_protocol_version("2.0.0").unwrap_err();
+        assert!(error.contains("update"), "{}", error);
+    }
+
+    #[test]
+    fn unparseable_protocol_version_is_refused() {
+        assert!(negotiate_protocol_version("").is_err());
+        assert!(negotiate_protocol_version("latest").is_err());
+    }
+
+    #[test]
+    fn unknown_message_types_parse_as_unknown() {
+        let message: ClientMessage = serde_json::from_str(r#"{"type": "teleport_label", "to": "mars"}"#).unwrap();
+        assert!(matches!(message, ClientMessage::Unknown), "{:?}", message);
+    }
+
+    #[test]
+    fn known_message_types_still_need_their_fields() {
+        // A malformed `hello` is an invalid message, not an unknown one
+        assert!(serde_json::from_str::<ClientMessage>(r#"{"type": "hello"}"#).is_err());
+        assert!(serde_json::from_str::<ClientMessage>(r#"{"to": "mars"}"#).is_err());
+    }
+
+    fn options(json: serde_json::Value) -> PrintOptions {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn request_options_override_printer_defaults() {
+        let defaults = options(serde_json::json!({ "paperSize": "4x6", "scale": "actual", "copies": 2, "darkness": 20 }));
+        let merged = options(serde_json::json!({ "paperSize": "letter", "copies": 1 })).with_defaults(&defaults);
+
+        assert_eq!(merged.paper_size.as_deref(), Some("letter"));
+        assert_eq!(merged.copies, Some(1));
+        // Left unset by the request, so the printer's defaults apply
+        assert_eq!(merged.scale.as_deref(), Some("actual"));
+        assert_eq!(merged.darkness, Some(20));
+        // Set by neither, so the built-in default applies later
+        assert_eq!(merged.rotate, None);
+        assert_eq!(merged.page_range, None);
+    }
+
+    #[test]
+    fn dry_run_and_wait_are_never_inherited() {
+        let defaults = options(serde_json::json!({ "dryRun": true, "waitForCompletion": true }));
+        let merged = PrintOptions::default().with_defaults(&defaults);
+        assert_eq!(merged.dry_run, None);
+        assert_eq!(merged.wait_for_completion, None);
+
+        let merged = options(serde_json::json!({ "dryRun": false, "waitForCompletion": true })).with_defaults(&defaults);
+        assert_eq!(merged.dry_run, Some(false));
+        assert_eq!(merged.wait_for_completion, Some(true));
+    }
+}
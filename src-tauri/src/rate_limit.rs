@@ -0,0 +1,135 @@
+use std::time::{Duration, Instant};
+
+/// How many prints a connection may submit: `burst` at once, refilled at `per_minute`
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    /// Zero turns the limit off
+    pub per_minute: u32,
+    pub burst: u32,
+}
+
+/// Token bucket enforcing a `RateLimit`, starting full
+pub struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take a token, or return how long until the next one is available
+    pub fn try_take(&mut self) -> Result<(), Duration> {
+        self.try_take_at(Instant::now())
+    }
+
+    fn try_take_at(&mut self, now: Instant) -> Result<(), Duration> {
+        if self.limit.per_minute == 0 {
+            return Ok(());
+        }
+
+        let per_second = self.limit.per_minute as f64 / 60.0;
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_second).min(self.limit.burst.max(1) as f64);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / per_second))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEN_PER_MINUTE: RateLimit = RateLimit {
+        per_minute: 10,
+        burst: 5,
+    };
+
+    fn secs(secs: f64) -> Duration {
+        Duration::from_secs_f64(secs)
+    }
+
+    fn assert_close(actual: Duration, expected: Duration) {
+        let diff = actual.abs_diff(expected);
+        assert!(diff < Duration::from_millis(1), "{:?} is not {:?}", actual, expected);
+    }
+
+    #[test]
+    fn burst_is_available_at_once_then_refused() {
+        let mut bucket = TokenBucket::new(TEN_PER_MINUTE);
+        let start = bucket.last_refill;
+        for _ in 0..5 {
+            assert_eq!(bucket.try_take_at(start), Ok(()));
+        }
+        // Ten a minute is one every six seconds
+        assert_close(bucket.try_take_at(start).unwrap_err(), secs(6.0));
+    }
+
+    #[test]
+    fn tokens_refill_at_the_configured_rate() {
+        let mut bucket = TokenBucket::new(TEN_PER_MINUTE);
+        let start = bucket.last_refill;
+        for _ in 0..5 {
+            bucket.try_take_at(start).unwrap();
+        }
+
+        // Half a token after three seconds isn't enough, and says how long is left
+        assert_close(bucket.try_take_at(start + secs(3.0)).unwrap_err(), secs(3.0));
+        assert_eq!(bucket.try_take_at(start + secs(6.0)), Ok(()));
+        assert!(bucket.try_take_at(start + secs(6.0)).is_err());
+
+        // Two more tokens twelve seconds later
+        assert_eq!(bucket.try_take_at(start + secs(18.0)), Ok(()));
+        assert_eq!(bucket.try_take_at(start + secs(18.0)), Ok(()));
+        assert!(bucket.try_take_at(start + secs(18.0)).is_err());
+    }
+
+    #[test]
+    fn refill_stops_at_the_burst() {
+        let mut bucket = TokenBucket::new(TEN_PER_MINUTE);
+        let start = bucket.last_refill;
+        bucket.try_take_at(start).unwrap();
+
+        let later = start + Duration::from_secs(3600);
+        for _ in 0..5 {
+            assert_eq!(bucket.try_take_at(later), Ok(()));
+        }
+        assert!(bucket.try_take_at(later).is_err());
+    }
+
+    #[test]
+    fn zero_burst_still_allows_one_print() {
+        let mut bucket = TokenBucket::new(RateLimit {
+            per_minute: 60,
+            burst: 0,
+        });
+        let start = bucket.last_refill;
+        assert!(bucket.try_take_at(start).is_err());
+        assert_eq!(bucket.try_take_at(start + secs(1.0)), Ok(()));
+        assert!(bucket.try_take_at(start + secs(10.0)).is_ok());
+        assert!(bucket.try_take_at(start + secs(10.0)).is_err());
+    }
+
+    #[test]
+    fn zero_rate_turns_the_limit_off() {
+        let mut bucket = TokenBucket::new(RateLimit {
+            per_minute: 0,
+            burst: 0,
+        });
+        for _ in 0..1000 {
+            assert_eq!(bucket.try_take(), Ok(()));
+        }
+    }
+}
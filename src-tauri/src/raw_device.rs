@@ -0,0 +1,164 @@
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::time::Duration;
+
+/// Default JetDirect port used when a socket target has no port
+const JETDIRECT_PORT: u16 = 9100;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where raw jobs for a mapped printer are written instead of going through the spooler
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RawTarget {
+    /// A device node such as `/dev/usb/lp0`
+    Device(String),
+    /// A JetDirect socket as `host:port`
+    Socket(String, u16),
+}
+
+/// Parse a configured target: `tcp://host[:port]` or `host:port` is a socket, anything else a path
+pub fn parse_target(target: &str) -> RawTarget {
+    let target = target.trim();
+    let (address, explicit_socket) = match target.strip_prefix("tcp://") {
+        Some(rest) => (rest, true),
+        None => (target, false),
+    };
+
+    if let Some((host, port)) = address.rsplit_once(':') {
+        if let Ok(port) = port.parse::<u16>() {
+            if !host.is_empty() && !host.starts_with('/') {
+                return RawTarget::Socket(host.to_string(), port);
+            }
+        }
+    }
+    if explicit_socket {
+        return RawTarget::Socket(address.to_string(), JETDIRECT_PORT);
+    }
+    RawTarget::Device(target.to_string())
+}
+
+/// Write a raw job straight to a device or socket, once per copy
+pub fn send(target: &RawTarget, data: &[u8], copies: u32) -> Result<(), String> {
+    match target {
+        RawTarget::Device(path) => send_to_device(path, data, copies),
+        RawTarget::Socket(host, port) => send_to_socket(host, *port, data, copies),
+    }
+}
+
+fn send_to_device(path: &str, data: &[u8], copies: u32) -> Result<(), String> {
+    if !Path::new(path).exists() {
+        return Err(format!("Raw device {} does not exist", path));
+    }
+
+    let mut device = std::fs::OpenOptions::new()
+        .write(true)
+        .open(path)
+        .map_err(|e| format!("Raw device {} is not writable: {}", path, e))?;
+
+    for _ in 0..copies {
+        device
+            .write_all(data)
+            .map_err(|e| format!("Failed to write to {}: {}", path, e))?;
+    }
+    device.flush().map_err(|e| format!("Failed to write to {}: {}", path, e))?;
+
+    log::info!("Sent {} bytes x{} to {}", data.len(), copies, path);
+    Ok(())
+}
+
+fn send_to_socket(host: &str, port: u16, data: &[u8], copies: u32) -> Result<(), String> {
+    let addr = (host, port)
+        .to_socket_addrs()
+        .map_err(|e| format!("Cannot resolve {}:{}: {}", host, port, e))?
+        .next()
+        .ok_or_else(|| format!("Cannot resolve {}:{}", host, port))?;
+
+    let mut stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)
+        .map_err(|e| format!("Printer at {} is unavailable: {}", addr, e))?;
+    stream
+        .set_write_timeout(Some(WRITE_TIMEOUT))
+        .map_err(|e| format!("Failed to configure socket: {}", e))?;
+
+    for _ in 0..copies {
+        stream
+            .write_all(data)
+            .map_err(|e| format!("Failed to send to {}: {}", addr, e))?;
+    }
+    stream.flush().map_err(|e| format!("Failed to send to {}: {}", addr, e))?;
+
+    log::info!("Sent {} bytes x{} to {}", data.len(), copies, addr);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    /// Accept one connection on an ephemeral port and return everything it sends
+    fn capture() -> (u16, std::thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let received = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).unwrap();
+            received
+        });
+        (port, received)
+    }
+
+    #[test]
+    fn socket_targets_are_parsed() {
+        assert_eq!(parse_target("192.168.1.50:9100"), RawTarget::Socket("192.168.1.50".to_string(), 9100));
+        assert_eq!(parse_target(" tcp://zebra.local "), RawTarget::Socket("zebra.local".to_string(), JETDIRECT_PORT));
+        assert_eq!(parse_target("tcp://zebra.local:6101"), RawTarget::Socket("zebra.local".to_string(), 6101));
+    }
+
+    #[test]
+    fn device_targets_are_parsed() {
+        assert_eq!(parse_target("/dev/usb/lp0"), RawTarget::Device("/dev/usb/lp0".to_string()));
+        // A path that happens to end in digits after a colon is still a path
+        assert_eq!(parse_target("/dev/serial:1"), RawTarget::Device("/dev/serial:1".to_string()));
+        assert_eq!(parse_target("COM3"), RawTarget::Device("COM3".to_string()));
+    }
+
+    #[test]
+    fn socket_receives_the_raw_bytes_once_per_copy() {
+        let (port, received) = capture();
+        let zpl = b"^XA^FO50,50^FDHello^FS^XZ";
+
+        send(&RawTarget::Socket("127.0.0.1".to_string(), port), zpl, 2).unwrap();
+        assert_eq!(received.join().unwrap(), [&zpl[..], &zpl[..]].concat());
+    }
+
+    #[test]
+    fn closed_socket_is_reported_unavailable() {
+        // Bind then drop, so nothing is listening on the port
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let err = send(&RawTarget::Socket("127.0.0.1".to_string(), port), b"^XA^XZ", 1).unwrap_err();
+        assert!(err.contains("is unavailable"), "{}", err);
+    }
+
+    #[test]
+    fn missing_device_is_refused_before_writing() {
+        let err = send(&RawTarget::Device("/nonexistent/lp9".to_string()), b"^XA^XZ", 1).unwrap_err();
+        assert_eq!(err, "Raw device /nonexistent/lp9 does not exist");
+    }
+
+    #[test]
+    fn device_receives_each_copy() {
+        let path = std::env::temp_dir().join(format!("limestack_raw_device_{}", std::process::id()));
+        std::fs::write(&path, b"").unwrap();
+
+        let result = send(&RawTarget::Device(path.to_string_lossy().into_owned()), b"N\nP1\n", 3);
+        let written = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        result.unwrap();
+        assert_eq!(written, b"N\nP1\nN\nP1\nN\nP1\n");
+    }
+}
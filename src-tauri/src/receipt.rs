@@ -0,0 +1,208 @@
+use serde::Deserialize;
+
+/// Characters per line for Font A on 80mm paper
+const DEFAULT_WIDTH: usize = 48;
+
+const ESC: u8 = 0x1b;
+const GS: u8 = 0x1d;
+
+/// A basic receipt the connector turns into ESC/POS, so the browser doesn't have to
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Receipt {
+    pub lines: Vec<ReceiptLine>,
+    /// Characters per line at normal size (48 for 80mm paper, 32 for 58mm)
+    #[serde(default)]
+    pub width: Option<usize>,
+    /// Feed and cut the paper after the last line
+    #[serde(default = "default_true")]
+    pub cut: bool,
+    /// Pulse the cash drawer connected to the printer
+    #[serde(default)]
+    pub open_drawer: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReceiptLine {
+    pub text: String,
+    #[serde(default)]
+    pub align: Align,
+    #[serde(default)]
+    pub bold: bool,
+    /// Double width and height
+    #[serde(default)]
+    pub large: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Align {
+    #[default]
+    Left,
+    Center,
+    Right,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Encode a receipt as ESC/POS commands
+///
+/// Text is word-wrapped to the line width; characters outside ASCII print as '?'.
+pub fn build_escpos(receipt: &Receipt) -> Vec<u8> {
+    let width = receipt.width.filter(|w| *w > 0).unwrap_or(DEFAULT_WIDTH);
+    let mut out = vec![ESC, b'@']; // Initialize
+
+    for line in &receipt.lines {
+        let align = match line.align {
+            Align::Left => 0,
+            Align::Center => 1,
+            Align::Right => 2,
+        };
+        out.extend_from_slice(&[ESC, b'a', align]);
+        out.extend_from_slice(&[ESC, b'E', line.bold as u8]);
+        out.extend_from_slice(&[GS, b'!', if line.large { 0x11 } else { 0x00 }]);
+
+        // Large text takes twice the width per character
+        let line_width = if line.large { (width / 2).max(1) } else { width };
+        for wrapped in wrap(&line.text, line_width) {
+            out.extend(wrapped.chars().map(|c| if c.is_ascii() && !c.is_ascii_control() { c as u8 } else { b'?' }));
+            out.push(b'\n');
+        }
+    }
+
+    // Back to defaults so the next job isn't affected
+    out.extend_from_slice(&[ESC, b'a', 0, ESC, b'E', 0, GS, b'!', 0]);
+
+    if receipt.open_drawer {
+        // Pulse pin 2 for 50ms on, 500ms off
+        out.extend_from_slice(&[ESC, b'p', 0, 25, 250]);
+    }
+    if receipt.cut {
+        // Feed past the cutter, then full cut
+        out.extend_from_slice(&[GS, b'V', 65, 3]);
+    }
+
+    out
+}
+
+/// Word-wrap text to `width` characters, splitting words longer than a line
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    for paragraph in text.split('\n') {
+        let mut current = String::new();
+        for word in paragraph.split_whitespace() {
+            let mut word: Vec<char> = word.chars().collect();
+            while word.len() > width {
+                if !current.is_empty() {
+                    lines.push(std::mem::take(&mut current));
+                }
+                lines.push(word.drain(..width).collect());
+            }
+            if word.is_empty() {
+                continue;
+            }
+
+            let current_len = current.chars().count();
+            if current_len > 0 && current_len + 1 + word.len() > width {
+                lines.push(std::mem::take(&mut current));
+            }
+            if !current.is_empty() {
+                current.push(' ');
+            }
+            current.extend(word);
+        }
+        lines.push(current);
+    }
+
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(text: &str) -> ReceiptLine {
+        ReceiptLine {
+            text: text.to_string(),
+            align: Align::Left,
+            bold: false,
+            large: false,
+        }
+    }
+
+    fn receipt(lines: Vec<ReceiptLine>) -> Receipt {
+        Receipt {
+            lines,
+            width: None,
+            cut: true,
+            open_drawer: false,
+        }
+    }
+
+    const CUT: &[u8] = &[GS, b'V', 65, 3];
+    const DRAWER_KICK: &[u8] = &[ESC, b'p', 0, 25, 250];
+
+    #[test]
+    fn words_wrap_at_the_line_width() {
+        assert_eq!(wrap("Thank you for shopping", 10), ["Thank you", "for", "shopping"]);
+        assert_eq!(wrap("exactly ten", 11), ["exactly ten"]);
+    }
+
+    #[test]
+    fn long_words_are_split() {
+        assert_eq!(wrap("Order #ABCDEFGHIJKLMN", 6), ["Order", "#ABCDE", "FGHIJK", "LMN"]);
+    }
+
+    #[test]
+    fn newlines_and_blank_lines_are_kept() {
+        assert_eq!(wrap("Subtotal\n\nTotal", 48), ["Subtotal", "", "Total"]);
+        assert_eq!(wrap("", 48), [""]);
+    }
+
+    #[test]
+    fn receipt_starts_with_init_and_ends_with_a_cut() {
+        let bytes = build_escpos(&receipt(vec![line("Hi")]));
+        assert_eq!(&bytes[..2], [ESC, b'@']);
+        assert!(bytes.ends_with(CUT));
+        assert!(!bytes.windows(DRAWER_KICK.len()).any(|w| w == DRAWER_KICK));
+        assert!(bytes.windows(3).any(|w| w == b"Hi\n"));
+    }
+
+    #[test]
+    fn cut_can_be_left_off_and_the_drawer_kicked() {
+        let bytes = build_escpos(&Receipt {
+            cut: false,
+            open_drawer: true,
+            ..receipt(vec![line("Hi")])
+        });
+        assert!(bytes.ends_with(DRAWER_KICK));
+        assert!(!bytes.windows(CUT.len()).any(|w| w == CUT));
+    }
+
+    #[test]
+    fn lines_wrap_at_the_receipt_width_and_half_that_when_large() {
+        let large = ReceiptLine {
+            large: true,
+            align: Align::Center,
+            bold: true,
+            ..line("TOTAL 12.50")
+        };
+        let bytes = build_escpos(&Receipt {
+            width: Some(10),
+            ..receipt(vec![line("one two three"), large])
+        });
+        assert!(bytes.windows(14).any(|w| w == b"one two\nthree\n"));
+        assert!(bytes.windows(9).any(|w| w == [ESC, b'a', 1, ESC, b'E', 1, GS, b'!', 0x11]));
+        assert!(bytes.windows(12).any(|w| w == b"TOTAL\n12.50\n"));
+    }
+
+    #[test]
+    fn non_ascii_prints_as_question_marks() {
+        let bytes = build_escpos(&receipt(vec![line("Café ✓")]));
+        assert!(bytes.windows(7).any(|w| w == b"Caf? ?\n"));
+    }
+}
@@ -0,0 +1,554 @@
+use crate::config;
+use crate::protocol::ScaleInfo;
+use crate::serial_scale::{SerialScale, SerialScaleConfig};
+use hidapi::{DeviceInfo, HidApi, HidDevice};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Known USB HID postal scales: (vendor id, product id, name)
+const KNOWN_SCALES: &[(u16, u16, &str)] = &[
+    (0x0922, 0x8003, "Dymo M10"),
+    (0x0922, 0x8004, "Dymo M25"),
+    (0x1446, 0x6a73, "Stamps.com Scale"),
+];
+
+/// How long to wait for a HID report before giving up
+const READ_TIMEOUT_MS: i32 = 1000;
+
+/// Minimum time between streamed readings (10 Hz)
+const STREAM_INTERVAL: Duration = Duration::from_millis(100);
+
+/// HID POS scale status byte values (USB HID Point of Sale usage tables)
+const STATUS_FAULT: u8 = 1;
+const STATUS_STABLE_ZERO: u8 = 2;
+const STATUS_STABLE: u8 = 4;
+const STATUS_UNDER_ZERO: u8 = 5;
+const STATUS_OVER_WEIGHT: u8 = 6;
+
+/// A decoded weight reading
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScaleReading {
+    pub weight: f64,
+    pub unit: String,
+    pub stable: bool,
+}
+
+#[derive(Debug)]
+pub enum ScaleError {
+    /// No supported scale is plugged in
+    NotConnected,
+    /// The scale was found but could not be read
+    Device(String),
+    /// The scale has no remote tare command
+    TareUnsupported,
+    /// No scale has the requested id
+    UnknownScale(String),
+}
+
+impl std::fmt::Display for ScaleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScaleError::NotConnected => write!(f, "No supported scale connected"),
+            ScaleError::Device(e) => write!(f, "{}", e),
+            ScaleError::TareUnsupported => write!(f, "This scale can't be tared remotely; use its tare button"),
+            ScaleError::UnknownScale(id) => write!(f, "Scale not found: {}", id),
+        }
+    }
+}
+
+/// An opened scale that can be read repeatedly without reopening the device
+pub struct Scale {
+    device: Device,
+}
+
+enum Device {
+    Hid(HidDevice),
+    Serial(SerialScale),
+}
+
+impl Scale {
+    /// Open the scale with the given id from `list_scales`, or with no id the serial scale
+    /// from the config if there is one, otherwise the first connected USB HID scale
+    pub fn open(id: Option<&str>) -> Result<Self, ScaleError> {
+        // HID is only initialized when the serial scale isn't the one asked for
+        let mut api = None;
+        let selected = select_scale(id, config::get().serial_scale, || {
+            let api = api.insert(HidApi::new().map_err(|e| ScaleError::Device(format!("Failed to initialize HID: {}", e)))?);
+            Ok(hid_scales(api).into_iter().map(hid_scale_id).collect())
+        })?;
+        let index = match selected {
+            SelectedScale::Serial(serial) => return Self::open_serial(&serial),
+            SelectedScale::Hid(index) => index,
+        };
+
+        let api = api.expect("HID is initialized to list its scales");
+        let info = hid_scales(&api)[index];
+        log::debug!(
+            "Opening scale: vid={:04x} pid={:04x} product={:?}",
+            info.vendor_id(),
+            info.product_id(),
+            info.product_string()
+        );
+
+        let device = info
+            .open_device(&api)
+            .map_err(|e| ScaleError::Device(format!("Failed to open scale: {}", e)))?;
+
+        Ok(Self {
+            device: Device::Hid(device),
+        })
+    }
+
+    fn open_serial(config: &SerialScaleConfig) -> Result<Self, ScaleError> {
+        Ok(Self {
+            device: Device::Serial(SerialScale::open(config)?),
+        })
+    }
+
+    /// Wait up to `timeout_ms` for the next reading; `None` if nothing arrived
+    pub fn read_timeout(&mut self, timeout_ms: i32) -> Result<Option<ScaleReading>, ScaleError> {
+        let device = match &mut self.device {
+            Device::Hid(device) => device,
+            Device::Serial(serial) => return serial.read_timeout(timeout_ms),
+        };
+
+        let mut report = [0u8; 6];
+        let read = device
+            .read_timeout(&mut report, timeout_ms)
+            .map_err(|e| ScaleError::Device(format!("Failed to read scale: {}", e)))?;
+
+        if read == 0 {
+            return Ok(None);
+        }
+
+        decode_report(&report[..read]).map(Some)
+    }
+}
+
+impl Scale {
+    /// Tare the scale; USB HID postal scales have no tare report, only a front-panel button
+    pub fn tare(&mut self) -> Result<(), ScaleError> {
+        match &mut self.device {
+            Device::Hid(_) => Err(ScaleError::TareUnsupported),
+            Device::Serial(serial) => serial.tare(),
+        }
+    }
+}
+
+/// Tare a scale (the default one without an id), then read the weight it settles on
+pub fn tare_scale(id: Option<&str>) -> Result<ScaleReading, ScaleError> {
+    let mut scale = Scale::open(id)?;
+    scale.tare()?;
+    scale
+        .read_timeout(READ_TIMEOUT_MS)?
+        .ok_or_else(|| ScaleError::Device("Timed out waiting for scale after tare".to_string()))
+}
+
+/// Read a single weight from a scale, or the default one without an id
+pub fn read_scale(id: Option<&str>) -> Result<ScaleReading, ScaleError> {
+    Scale::open(id)?
+        .read_timeout(READ_TIMEOUT_MS)?
+        .ok_or_else(|| ScaleError::Device("Timed out waiting for scale".to_string()))
+}
+
+/// Stream readings from `read` (usually an open scale's `read_timeout`) until `active` is
+/// cleared or `emit` returns false
+///
+/// Identical consecutive readings are coalesced and emits are capped at `STREAM_INTERVAL`.
+/// An error is emitted once and ends the stream.
+pub fn stream_readings(
+    mut read: impl FnMut(i32) -> Result<Option<ScaleReading>, ScaleError>,
+    active: &AtomicBool,
+    mut emit: impl FnMut(Result<ScaleReading, ScaleError>) -> bool,
+) {
+    let mut last: Option<ScaleReading> = None;
+    let mut last_emit = Instant::now().checked_sub(STREAM_INTERVAL).unwrap_or_else(Instant::now);
+
+    while active.load(Ordering::Relaxed) {
+        // A short timeout keeps the loop responsive to the stop flag
+        let reading = match read(STREAM_INTERVAL.as_millis() as i32) {
+            Ok(Some(reading)) => reading,
+            Ok(None) => continue,
+            Err(e) => {
+                emit(Err(e));
+                return;
+            }
+        };
+
+        if last.as_ref() == Some(&reading) {
+            continue;
+        }
+
+        let since_last = last_emit.elapsed();
+        if since_last < STREAM_INTERVAL {
+            std::thread::sleep(STREAM_INTERVAL - since_last);
+        }
+
+        if !emit(Ok(reading.clone())) {
+            return;
+        }
+        last = Some(reading);
+        last_emit = Instant::now();
+    }
+}
+
+/// Prefix of serial scale ids, followed by the port
+const SERIAL_ID_PREFIX: &str = "serial:";
+
+/// Every scale that can be read, the default (what `Scale::open(None)` picks) first
+pub fn list_scales() -> Vec<ScaleInfo> {
+    let hid = match HidApi::new() {
+        Ok(api) => hid_scales(&api).into_iter().map(|info| (hid_scale_id(info), scale_name(info))).collect(),
+        Err(e) => {
+            log::warn!("Failed to initialize HID, listing serial scales only: {}", e);
+            Vec::new()
+        }
+    };
+    scale_list(config::get().serial_scale.as_ref(), hid)
+}
+
+/// The configured serial scale, then the connected HID scales given as (id, name)
+fn scale_list(serial: Option<&SerialScaleConfig>, hid: Vec<(String, String)>) -> Vec<ScaleInfo> {
+    let mut scales = Vec::new();
+
+    if let Some(serial) = serial {
+        scales.push(ScaleInfo {
+            id: serial_scale_id(serial),
+            name: format!("Serial scale on {}", serial.port),
+            scale_type: "serial".to_string(),
+            is_default: true,
+        });
+    }
+    for (id, name) in hid {
+        let is_default = scales.is_empty();
+        scales.push(ScaleInfo {
+            id,
+            name,
+            scale_type: "hid".to_string(),
+            is_default,
+        });
+    }
+
+    scales
+}
+
+/// The scale `Scale::open` reads
+enum SelectedScale {
+    Serial(SerialScaleConfig),
+    /// Index into the HID scale ids
+    Hid(usize),
+}
+
+/// Pick the scale for `id`, listing HID scale ids with `hid_ids` only if the serial scale
+/// isn't the one wanted
+fn select_scale(
+    id: Option<&str>,
+    serial: Option<SerialScaleConfig>,
+    hid_ids: impl FnOnce() -> Result<Vec<String>, ScaleError>,
+) -> Result<SelectedScale, ScaleError> {
+    match (id, serial) {
+        (None, Some(serial)) => return Ok(SelectedScale::Serial(serial)),
+        (Some(id), serial) if id.starts_with(SERIAL_ID_PREFIX) => {
+            return serial
+                .filter(|s| serial_scale_id(s) == id)
+                .map(SelectedScale::Serial)
+                .ok_or_else(|| ScaleError::UnknownScale(id.to_string()));
+        }
+        _ => {}
+    }
+
+    let ids = hid_ids()?;
+    match id {
+        None if ids.is_empty() => Err(ScaleError::NotConnected),
+        None => Ok(SelectedScale::Hid(0)),
+        Some(id) => ids
+            .iter()
+            .position(|hid_id| hid_id == id)
+            .map(SelectedScale::Hid)
+            .ok_or_else(|| ScaleError::UnknownScale(id.to_string())),
+    }
+}
+
+fn serial_scale_id(config: &SerialScaleConfig) -> String {
+    format!("{}{}", SERIAL_ID_PREFIX, config.port)
+}
+
+/// Stable across reconnects when the scale reports a serial number, otherwise tied to the USB port
+fn hid_scale_id(info: &DeviceInfo) -> String {
+    match info.serial_number().filter(|s| !s.is_empty()) {
+        Some(serial) => format!("hid:{:04x}:{:04x}:{}", info.vendor_id(), info.product_id(), serial),
+        None => format!("hid:{}", info.path().to_string_lossy()),
+    }
+}
+
+fn scale_name(info: &DeviceInfo) -> String {
+    KNOWN_SCALES
+        .iter()
+        .find(|(vid, pid, _)| info.vendor_id() == *vid && info.product_id() == *pid)
+        .map(|(_, _, name)| name.to_string())
+        .or_else(|| info.product_string().map(str::to_string))
+        .unwrap_or_else(|| "USB scale".to_string())
+}
+
+/// Connected devices matching a known scale, once each even if they expose several interfaces
+fn hid_scales(api: &HidApi) -> Vec<&DeviceInfo> {
+    let mut seen = std::collections::HashSet::new();
+    api.device_list()
+        .filter(|d| {
+            KNOWN_SCALES
+                .iter()
+                .any(|(vid, pid, _)| d.vendor_id() == *vid && d.product_id() == *pid)
+        })
+        .filter(|d| seen.insert(hid_scale_id(d)))
+        .collect()
+}
+
+/// Decode a 6-byte HID scale report: [report id, status, unit, exponent, weight lsb, weight msb]
+fn decode_report(report: &[u8]) -> Result<ScaleReading, ScaleError> {
+    if report.len() < 6 {
+        return Err(ScaleError::Device(format!("Short scale report ({} bytes)", report.len())));
+    }
+
+    let status = report[1];
+    match status {
+        STATUS_FAULT => return Err(ScaleError::Device("Scale reported a fault".to_string())),
+        STATUS_OVER_WEIGHT => return Err(ScaleError::Device("Scale is over its weight limit".to_string())),
+        _ => {}
+    }
+
+    let unit = match report[2] {
+        2 => "g",
+        3 => "kg",
+        11 => "oz",
+        12 => "lb",
+        other => return Err(ScaleError::Device(format!("Unsupported scale unit: {}", other))),
+    };
+
+    let exponent = report[3] as i8;
+    let raw = u16::from_le_bytes([report[4], report[5]]) as f64;
+    let mut weight = raw * 10f64.powi(exponent as i32);
+    if status == STATUS_UNDER_ZERO {
+        weight = -weight;
+    }
+
+    Ok(ScaleReading {
+        weight,
+        unit: unit.to_string(),
+        stable: matches!(status, STATUS_STABLE_ZERO | STATUS_STABLE),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(report: &[u8]) -> ScaleReading {
+        decode_report(report).expect("report should decode")
+    }
+
+    #[test]
+    fn stable_grams_are_decoded() {
+        let reading = reading(&[3, STATUS_STABLE, 2, 0, 0xe8, 0x03]);
+        assert_eq!(reading.weight, 1000.0);
+        assert_eq!(reading.unit, "g");
+        assert!(reading.stable);
+    }
+
+    #[test]
+    fn negative_exponent_scales_the_weight() {
+        // Dymo scales in ounce mode report tenths of an ounce
+        let reading = reading(&[3, STATUS_STABLE, 11, 0xff, 0x34, 0x00]);
+        assert!((reading.weight - 5.2).abs() < 1e-9);
+        assert_eq!(reading.unit, "oz");
+    }
+
+    #[test]
+    fn zero_and_in_motion_readings() {
+        let zero = reading(&[3, STATUS_STABLE_ZERO, 12, 0, 0, 0]);
+        assert_eq!(zero.weight, 0.0);
+        assert_eq!(zero.unit, "lb");
+        assert!(zero.stable);
+
+        // Status 3 is "weighing": the value is still settling
+        let moving = reading(&[3, 3, 3, 0, 7, 0]);
+        assert_eq!(moving.unit, "kg");
+        assert!(!moving.stable);
+    }
+
+    #[test]
+    fn under_zero_is_negative() {
+        let reading = reading(&[3, STATUS_UNDER_ZERO, 2, 0, 10, 0]);
+        assert_eq!(reading.weight, -10.0);
+        assert!(!reading.stable);
+    }
+
+    #[test]
+    fn faults_and_bad_reports_are_errors() {
+        assert!(matches!(decode_report(&[3, 4, 2, 0]), Err(ScaleError::Device(e)) if e.contains("Short")));
+        assert!(matches!(decode_report(&[3, STATUS_FAULT, 2, 0, 0, 0]), Err(ScaleError::Device(e)) if e.contains("fault")));
+        assert!(matches!(
+            decode_report(&[3, STATUS_OVER_WEIGHT, 2, 0, 0, 0]),
+            Err(ScaleError::Device(e)) if e.contains("over its weight limit")
+        ));
+        assert!(matches!(decode_report(&[3, 4, 9, 0, 1, 0]), Err(ScaleError::Device(e)) if e.contains("unit: 9")));
+    }
+
+    fn serial(port: &str) -> SerialScaleConfig {
+        serde_json::from_value(serde_json::json!({ "port": port })).unwrap()
+    }
+
+    fn hid(ids: &[&str]) -> Vec<(String, String)> {
+        ids.iter().map(|id| (id.to_string(), format!("Scale {}", id))).collect()
+    }
+
+    fn ids(ids: &[&str]) -> impl FnOnce() -> Result<Vec<String>, ScaleError> {
+        let ids: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+        move || Ok(ids)
+    }
+
+    #[test]
+    fn every_scale_is_listed_with_the_default_first() {
+        let scales = scale_list(Some(&serial("/dev/ttyUSB0")), hid(&["hid:0922:8003:A1", "hid:0922:8004:B2"]));
+        let listed: Vec<_> = scales.iter().map(|s| (s.id.as_str(), s.scale_type.as_str(), s.is_default)).collect();
+        assert_eq!(
+            listed,
+            [
+                ("serial:/dev/ttyUSB0", "serial", true),
+                ("hid:0922:8003:A1", "hid", false),
+                ("hid:0922:8004:B2", "hid", false),
+            ]
+        );
+        assert_eq!(scales[0].name, "Serial scale on /dev/ttyUSB0");
+    }
+
+    #[test]
+    fn first_hid_scale_is_the_default_without_a_serial_scale() {
+        let scales = scale_list(None, hid(&["hid:0922:8003:A1", "hid:0922:8004:B2"]));
+        let defaults: Vec<bool> = scales.iter().map(|s| s.is_default).collect();
+        assert_eq!(defaults, [true, false]);
+        assert!(scale_list(None, Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn reads_go_to_the_scale_asked_for() {
+        let hid_ids = ["hid:0922:8003:A1", "hid:0922:8004:B2"];
+        assert!(matches!(select_scale(Some("hid:0922:8004:B2"), None, ids(&hid_ids)), Ok(SelectedScale::Hid(1))));
+        assert!(matches!(
+            select_scale(Some("serial:COM3"), Some(serial("COM3")), ids(&hid_ids)),
+            Ok(SelectedScale::Serial(config)) if config.port == "COM3"
+        ));
+        // An explicit HID id still reaches a HID scale when a serial scale is configured
+        assert!(matches!(
+            select_scale(Some("hid:0922:8003:A1"), Some(serial("COM3")), ids(&hid_ids)),
+            Ok(SelectedScale::Hid(0))
+        ));
+    }
+
+    #[test]
+    fn default_scale_is_serial_then_the_first_hid_scale() {
+        let unlisted = || -> Result<Vec<String>, ScaleError> { panic!("HID listed for the serial scale") };
+        assert!(matches!(select_scale(None, Some(serial("COM3")), unlisted), Ok(SelectedScale::Serial(_))));
+        assert!(matches!(select_scale(None, None, ids(&["hid:a", "hid:b"])), Ok(SelectedScale::Hid(0))));
+        assert!(matches!(select_scale(None, None, ids(&[])), Err(ScaleError::NotConnected)));
+    }
+
+    #[test]
+    fn unknown_scale_ids_are_refused() {
+        assert!(matches!(
+            select_scale(Some("hid:missing"), None, ids(&["hid:a"])),
+            Err(ScaleError::UnknownScale(id)) if id == "hid:missing"
+        ));
+        assert!(matches!(
+            select_scale(Some("serial:COM4"), Some(serial("COM3")), ids(&[])),
+            Err(ScaleError::UnknownScale(id)) if id == "serial:COM4"
+        ));
+        assert!(matches!(select_scale(Some("serial:COM3"), None, ids(&[])), Err(ScaleError::UnknownScale(_))));
+    }
+
+    fn grams(weight: f64, stable: bool) -> ScaleReading {
+        ScaleReading {
+            weight,
+            unit: "g".to_string(),
+            stable,
+        }
+    }
+
+    /// Stream a fixed sequence of reads, stopping once they run out, and collect what's emitted
+    fn stream(reads: Vec<Result<Option<ScaleReading>, ScaleError>>) -> Vec<(Result<ScaleReading, ScaleError>, Instant)> {
+        let active = AtomicBool::new(true);
+        let mut reads = reads.into_iter();
+        let mut emitted = Vec::new();
+        stream_readings(
+            |_| {
+                reads.next().unwrap_or_else(|| {
+                    active.store(false, Ordering::Relaxed);
+                    Ok(None)
+                })
+            },
+            &active,
+            |result| {
+                emitted.push((result, Instant::now()));
+                true
+            },
+        );
+        emitted
+    }
+
+    #[test]
+    fn stream_coalesces_identical_readings() {
+        let emitted = stream(vec![
+            Ok(Some(grams(10.0, false))),
+            Ok(Some(grams(10.0, false))),
+            Ok(None),
+            Ok(Some(grams(10.0, true))),
+            Ok(Some(grams(10.0, true))),
+            Ok(Some(grams(12.0, true))),
+        ]);
+        let weights: Vec<_> = emitted.into_iter().map(|(r, _)| r.unwrap()).collect();
+        assert_eq!(weights, [grams(10.0, false), grams(10.0, true), grams(12.0, true)]);
+    }
+
+    #[test]
+    fn stream_is_rate_limited() {
+        let emitted = stream(vec![
+            Ok(Some(grams(1.0, false))),
+            Ok(Some(grams(2.0, false))),
+            Ok(Some(grams(3.0, true))),
+        ]);
+        assert_eq!(emitted.len(), 3);
+        for pair in emitted.windows(2) {
+            assert!(pair[1].1 - pair[0].1 >= STREAM_INTERVAL);
+        }
+    }
+
+    #[test]
+    fn stream_ends_on_the_first_error() {
+        let emitted = stream(vec![
+            Ok(Some(grams(1.0, true))),
+            Err(ScaleError::Device("unplugged".to_string())),
+            Ok(Some(grams(2.0, true))),
+        ]);
+        assert_eq!(emitted.len(), 2);
+        assert!(matches!(&emitted[1].0, Err(ScaleError::Device(e)) if e == "unplugged"));
+    }
+
+    #[test]
+    fn stream_stops_when_emit_is_refused() {
+        let active = AtomicBool::new(true);
+        let mut weight = 0.0;
+        let mut emits = 0;
+        stream_readings(
+            |_| {
+                weight += 1.0;
+                Ok(Some(grams(weight, true)))
+            },
+            &active,
+            |_| {
+                emits += 1;
+                emits < 2
+            },
+        );
+        assert_eq!(emits, 2);
+        assert!(active.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,118 @@
+//! Optional checks at startup that the connector can actually print, enabled with
+//! `startupSelfTest: true` in `config.json`. Problems are logged and shown in the tray, so
+//! a broken setup is noticed before the first print fails.
+
+use crate::config;
+use crate::printer;
+
+/// A named check returning what's wrong, if anything
+type Check = (&'static str, fn() -> Option<String>);
+
+/// The checks that apply on this platform, in the order they run
+fn checks() -> Vec<Check> {
+    let mut checks: Vec<Check> = vec![("print system", spooler_problem)];
+    if cfg!(target_os = "windows") {
+        checks.push(("PDF printing", printer::missing_pdf_backend));
+    }
+    checks.push(("default printer", default_printer_problem));
+    checks
+}
+
+/// Run every check, logging each result, and return the problems found
+pub fn run() -> Vec<String> {
+    run_checks(checks())
+}
+
+fn run_checks(checks: Vec<Check>) -> Vec<String> {
+    let problems: Vec<String> = checks
+        .into_iter()
+        .filter_map(|(name, check)| match check() {
+            Some(problem) => {
+                log::warn!("Self-test: {} failed: {}", name, problem);
+                Some(problem)
+            }
+            None => {
+                log::info!("Self-test: {} ok", name);
+                None
+            }
+        })
+        .collect();
+
+    if problems.is_empty() {
+        log::info!("Self-test passed");
+    } else {
+        log::warn!("Self-test found {} problem(s)", problems.len());
+    }
+    problems
+}
+
+/// Whether CUPS or the Windows Print Spooler is running
+fn spooler_problem() -> Option<String> {
+    // Raw jobs need nothing beyond the spooler, so this leaves out the PDF backend
+    printer::print_backend_problem(true)
+}
+
+/// Whether the printer pinned as the LimeStack default is still installed
+fn default_printer_problem() -> Option<String> {
+    pinned_printer_problem(config::get().default_printer_id, printer::find_printer)
+}
+
+fn pinned_printer_problem(pinned: Option<String>, find: impl FnOnce(&str) -> Option<String>) -> Option<String> {
+    let pinned = pinned?;
+    match find(&pinned) {
+        Some(_) => None,
+        None => Some(format!("Default printer '{}' was not found", pinned)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(checks: &[Check]) -> Vec<&'static str> {
+        checks.iter().map(|(name, _)| *name).collect()
+    }
+
+    #[cfg(target_os = "windows")]
+    #[test]
+    fn windows_also_checks_for_a_pdf_backend() {
+        assert_eq!(names(&checks()), ["print system", "PDF printing", "default printer"]);
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn cups_checks_the_spooler_and_default_printer() {
+        assert_eq!(names(&checks()), ["print system", "default printer"]);
+    }
+
+    #[test]
+    fn passing_checks_report_no_problems() {
+        assert!(run_checks(vec![("a", || None), ("b", || None)]).is_empty());
+        assert!(run_checks(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn every_failure_is_reported_in_order() {
+        let checks: Vec<Check> = vec![
+            ("print system", || Some("The CUPS scheduler is not running".to_string())),
+            ("PDF printing", || None),
+            ("default printer", || Some("Default printer 'Zebra' was not found".to_string())),
+        ];
+        assert_eq!(
+            run_checks(checks),
+            ["The CUPS scheduler is not running", "Default printer 'Zebra' was not found"]
+        );
+    }
+
+    #[test]
+    fn missing_pinned_printer_is_a_problem() {
+        let problem = pinned_printer_problem(Some("Zebra_ZP450".to_string()), |_| None);
+        assert_eq!(problem.as_deref(), Some("Default printer 'Zebra_ZP450' was not found"));
+    }
+
+    #[test]
+    fn installed_or_unpinned_default_is_fine() {
+        assert_eq!(pinned_printer_problem(Some("Zebra".to_string()), |id| Some(id.to_string())), None);
+        assert_eq!(pinned_printer_problem(None, |_| panic!("nothing to look up")), None);
+    }
+}
@@ -0,0 +1,463 @@
+use crate::scale::{ScaleError, ScaleReading};
+use serde::{Deserialize, Serialize};
+use serialport::{ClearBuffer, DataBits, Parity, SerialPort, StopBits};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::time::Duration;
+
+/// A scale on an RS-232 or USB-serial port, configured as `serialScale` in `config.json`
+///
+/// Auto-detection is unreliable on serial ports, so the port and protocol are always explicit.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SerialScaleConfig {
+    /// e.g. "COM3" or "/dev/ttyUSB0"
+    pub port: String,
+    #[serde(default = "default_baud_rate")]
+    pub baud_rate: u32,
+    #[serde(default)]
+    pub parity: SerialParity,
+    /// 7 or 8
+    #[serde(default = "default_data_bits")]
+    pub data_bits: u8,
+    #[serde(default)]
+    pub protocol: SerialProtocol,
+    /// Sent to tare the scale, overriding the protocol's usual command
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tare_command: Option<String>,
+}
+
+fn default_baud_rate() -> u32 {
+    9600
+}
+
+fn default_data_bits() -> u8 {
+    8
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SerialParity {
+    #[default]
+    None,
+    Even,
+    Odd,
+}
+
+/// ASCII formats spoken by common industrial scales
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SerialProtocol {
+    /// Continuous "ST,GS,+  1.234kg" lines (A&D, CAS, Ohaus and many generic indicators)
+    #[default]
+    StGs,
+    /// Mettler Toledo MT-SICS, polled with "SI": "S S      1.234 kg"
+    Sics,
+}
+
+impl SerialProtocol {
+    /// The usual tare command for scales speaking this protocol
+    fn tare_command(self) -> &'static str {
+        // A&D and MT-SICS both tare on "T"; zero ("Z"/"R") would discard the box instead
+        match self {
+            SerialProtocol::StGs | SerialProtocol::Sics => "T\r\n",
+        }
+    }
+}
+
+/// How long a streaming scale takes to settle after a tare before its output is trusted
+const TARE_SETTLE: Duration = Duration::from_millis(500);
+
+/// An open serial scale
+pub struct SerialScale {
+    reader: BufReader<Box<dyn SerialPort>>,
+    protocol: SerialProtocol,
+    tare_command: String,
+    /// A streaming scale is joined partway through a line, after opening the port or clearing
+    /// its input, so the next line is a fragment
+    mid_line: bool,
+}
+
+impl SerialScale {
+    pub fn open(config: &SerialScaleConfig) -> Result<Self, ScaleError> {
+        let data_bits = match config.data_bits {
+            7 => DataBits::Seven,
+            8 => DataBits::Eight,
+            other => return Err(ScaleError::Device(format!("Unsupported serial data bits: {}", other))),
+        };
+        let parity = match config.parity {
+            SerialParity::None => Parity::None,
+            SerialParity::Even => Parity::Even,
+            SerialParity::Odd => Parity::Odd,
+        };
+
+        log::debug!("Opening serial scale on {} at {} baud", config.port, config.baud_rate);
+        let port = serialport::new(&config.port, config.baud_rate)
+            .data_bits(data_bits)
+            .parity(parity)
+            .stop_bits(StopBits::One)
+            .open()
+            .map_err(|e| match e.kind() {
+                serialport::ErrorKind::NoDevice => ScaleError::NotConnected,
+                serialport::ErrorKind::Io(ErrorKind::NotFound) => ScaleError::NotConnected,
+                _ => ScaleError::Device(format!(
+                    "Failed to open serial port {}: {} (available: {})",
+                    config.port,
+                    e,
+                    available_ports().join(", ")
+                )),
+            })?;
+
+        Ok(Self {
+            reader: BufReader::new(port),
+            protocol: config.protocol,
+            tare_command: tare_command(config),
+            mid_line: matches!(config.protocol, SerialProtocol::StGs),
+        })
+    }
+
+    /// Tare the scale so later readings are net of whatever is on it
+    pub fn tare(&mut self) -> Result<(), ScaleError> {
+        send_tare(self.reader.get_mut(), &self.tare_command)?;
+
+        match self.protocol {
+            // SICS answers the command itself before any weight
+            SerialProtocol::Sics => {
+                self.reader
+                    .get_mut()
+                    .set_timeout(TARE_SETTLE * 4)
+                    .map_err(|e| ScaleError::Device(format!("Failed to configure serial port: {}", e)))?;
+                let mut line = String::new();
+                self.reader
+                    .read_line(&mut line)
+                    .map_err(|e| ScaleError::Device(format!("No reply to tare command: {}", e)))?;
+                parse_sics_tare(&line)
+            }
+            // Streaming scales just carry on; drop the readings from before the tare
+            SerialProtocol::StGs => {
+                std::thread::sleep(TARE_SETTLE);
+                let buffered = self.reader.buffer().len();
+                self.reader.consume(buffered);
+                self.mid_line = true;
+                self.reader
+                    .get_mut()
+                    .clear(ClearBuffer::Input)
+                    .map_err(|e| ScaleError::Device(format!("Failed to clear serial input: {}", e)))
+            }
+        }
+    }
+
+    /// Wait up to `timeout_ms` for the next weight line; `None` if nothing arrived
+    pub fn read_timeout(&mut self, timeout_ms: i32) -> Result<Option<ScaleReading>, ScaleError> {
+        let port = self.reader.get_mut();
+        port.set_timeout(Duration::from_millis(timeout_ms.max(1) as u64))
+            .map_err(|e| ScaleError::Device(format!("Failed to configure serial port: {}", e)))?;
+
+        // SICS scales only answer when asked
+        if matches!(self.protocol, SerialProtocol::Sics) {
+            port.write_all(b"SI\r\n")
+                .map_err(|e| ScaleError::Device(format!("Failed to poll scale: {}", e)))?;
+        }
+
+        next_reading(&mut self.reader, self.protocol, &mut self.mid_line)
+    }
+}
+
+/// Read the next weight line, first skipping the rest of a line joined partway through
+fn next_reading(
+    reader: &mut impl BufRead,
+    protocol: SerialProtocol,
+    mid_line: &mut bool,
+) -> Result<Option<ScaleReading>, ScaleError> {
+    if *mid_line && read_line(reader, mid_line)?.is_none() {
+        return Ok(None);
+    }
+    match read_line(reader, mid_line)? {
+        Some(line) => parse_line(protocol, &line),
+        None => Ok(None),
+    }
+}
+
+/// One line, or `None` if the port had nothing more before its timeout
+///
+/// A line cut short by the timeout leaves the next read partway through it.
+fn read_line(reader: &mut impl BufRead, mid_line: &mut bool) -> Result<Option<String>, ScaleError> {
+    let mut line = String::new();
+    match reader.read_line(&mut line) {
+        Ok(0) => Ok(None),
+        Ok(_) => {
+            *mid_line = false;
+            Ok(Some(line))
+        }
+        Err(e) if e.kind() == ErrorKind::TimedOut => {
+            *mid_line |= !line.is_empty();
+            Ok(None)
+        }
+        Err(e) => Err(ScaleError::Device(format!("Failed to read serial scale: {}", e))),
+    }
+}
+
+/// The configured tare command, or the protocol's usual one
+fn tare_command(config: &SerialScaleConfig) -> String {
+    config
+        .tare_command
+        .clone()
+        .unwrap_or_else(|| config.protocol.tare_command().to_string())
+}
+
+fn send_tare(port: &mut impl Write, command: &str) -> Result<(), ScaleError> {
+    port.write_all(command.as_bytes())
+        .and_then(|_| port.flush())
+        .map_err(|e| ScaleError::Device(format!("Failed to send tare command: {}", e)))
+}
+
+/// Serial ports on this machine, for pointing users at the right `port`
+pub fn available_ports() -> Vec<String> {
+    serialport::available_ports()
+        .map(|ports| ports.into_iter().map(|p| p.port_name).collect())
+        .unwrap_or_default()
+}
+
+/// Parse one line of scale output; `None` for lines that carry no weight
+pub fn parse_line(protocol: SerialProtocol, line: &str) -> Result<Option<ScaleReading>, ScaleError> {
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    match protocol {
+        SerialProtocol::StGs => parse_st_gs(line),
+        SerialProtocol::Sics => parse_sics(line),
+    }
+}
+
+/// "ST,GS,+  1.234kg": stability (ST stable, US unstable, OL overload), then gross (GS),
+/// net (NT, tare active) or tare (TR), then the signed weight and unit
+fn parse_st_gs(line: &str) -> Result<Option<ScaleReading>, ScaleError> {
+    let mut fields = line.splitn(3, ',');
+    let (Some(status), Some(_kind), Some(value)) = (fields.next(), fields.next(), fields.next()) else {
+        return Err(unrecognized(line));
+    };
+
+    let stable = match status.trim() {
+        "ST" => true,
+        "US" => false,
+        "OL" => return Err(ScaleError::Device("Scale is over its weight limit".to_string())),
+        _ => return Err(unrecognized(line)),
+    };
+
+    parse_weight(value).map(|(weight, unit)| Some(ScaleReading { weight, unit, stable })).ok_or_else(|| unrecognized(line))
+}
+
+/// MT-SICS weight responses: "S S <weight> <unit>" when stable, "S D ..." while moving
+fn parse_sics(line: &str) -> Result<Option<ScaleReading>, ScaleError> {
+    let mut fields = line.split_whitespace();
+    if fields.next() != Some("S") {
+        return Err(unrecognized(line));
+    }
+
+    let stable = match fields.next() {
+        Some("S") => true,
+        Some("D") => false,
+        // Busy; the next poll will have a weight
+        Some("I") => return Ok(None),
+        Some("+") => return Err(ScaleError::Device("Scale is over its weight limit".to_string())),
+        Some("-") => return Err(ScaleError::Device("Scale is under its weight limit".to_string())),
+        _ => return Err(unrecognized(line)),
+    };
+
+    let rest: String = fields.collect::<Vec<_>>().join(" ");
+    parse_weight(&rest).map(|(weight, unit)| Some(ScaleReading { weight, unit, stable })).ok_or_else(|| unrecognized(line))
+}
+
+/// Split "+  1.234kg" or "-0.50 lb" into a signed weight and a normalized unit
+fn parse_weight(value: &str) -> Option<(f64, String)> {
+    let compact: String = value.chars().filter(|c| !c.is_whitespace()).collect();
+    let split = compact.find(|c: char| c.is_ascii_alphabetic())?;
+    let (number, unit) = compact.split_at(split);
+
+    let weight = number.parse::<f64>().ok()?;
+    let unit = match unit.to_ascii_lowercase().as_str() {
+        "g" => "g",
+        "kg" => "kg",
+        "oz" => "oz",
+        "lb" | "lbs" => "lb",
+        _ => return None,
+    };
+    Some((weight, unit.to_string()))
+}
+
+/// MT-SICS tare replies: "T S <tare> <unit>" on success, "T I" busy, "T +"/"T -" out of range,
+/// "ES" when the scale doesn't know the command
+fn parse_sics_tare(line: &str) -> Result<(), ScaleError> {
+    let mut fields = line.split_whitespace();
+    match (fields.next(), fields.next()) {
+        (Some("T"), Some("S")) => Ok(()),
+        (Some("T"), Some("I")) => Err(ScaleError::Device("Scale is busy, try the tare again".to_string())),
+        (Some("T"), Some("+" | "-")) => Err(ScaleError::Device("Scale can't tare outside its weight range".to_string())),
+        (Some("ES"), None) => Err(ScaleError::TareUnsupported),
+        _ => Err(unrecognized(line)),
+    }
+}
+
+fn unrecognized(line: &str) -> ScaleError {
+    ScaleError::Device(format!("Unrecognized scale output: {:?}", line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(weight: f64, unit: &str, stable: bool) -> Option<ScaleReading> {
+        Some(ScaleReading {
+            weight,
+            unit: unit.to_string(),
+            stable,
+        })
+    }
+
+    fn device_error(result: Result<Option<ScaleReading>, ScaleError>) -> String {
+        match result {
+            Err(ScaleError::Device(e)) => e,
+            other => panic!("expected a device error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn st_gs_stable_and_moving_weights() {
+        assert_eq!(parse_line(SerialProtocol::StGs, "ST,GS,+  1.234kg\r\n").unwrap(), reading(1.234, "kg", true));
+        assert_eq!(parse_line(SerialProtocol::StGs, "US,GS,+  0.980kg").unwrap(), reading(0.98, "kg", false));
+        assert_eq!(parse_line(SerialProtocol::StGs, "ST,GS,+0002.50 lb").unwrap(), reading(2.5, "lb", true));
+        assert_eq!(parse_line(SerialProtocol::StGs, "ST,GS,+   125.0  g").unwrap(), reading(125.0, "g", true));
+    }
+
+    #[test]
+    fn st_gs_net_and_negative_weights() {
+        // With a tare active the indicator reports net weight, negative once the box is lifted
+        assert_eq!(parse_line(SerialProtocol::StGs, "ST,NT,+  0.750kg").unwrap(), reading(0.75, "kg", true));
+        assert_eq!(parse_line(SerialProtocol::StGs, "ST,NT,-  0.250kg").unwrap(), reading(-0.25, "kg", true));
+        assert_eq!(parse_line(SerialProtocol::StGs, "US,GS,- 12.5oz").unwrap(), reading(-12.5, "oz", false));
+    }
+
+    #[test]
+    fn st_gs_overload_and_garbage_are_errors() {
+        assert_eq!(device_error(parse_line(SerialProtocol::StGs, "OL,GS,+  9.999kg")), "Scale is over its weight limit");
+        assert!(device_error(parse_line(SerialProtocol::StGs, "ST,GS")).starts_with("Unrecognized scale output"));
+        assert!(device_error(parse_line(SerialProtocol::StGs, "XX,GS,+1.0kg")).starts_with("Unrecognized scale output"));
+        assert!(device_error(parse_line(SerialProtocol::StGs, "ST,GS,+1.0 stone")).starts_with("Unrecognized scale output"));
+    }
+
+    #[test]
+    fn sics_weights() {
+        assert_eq!(parse_line(SerialProtocol::Sics, "S S      1.234 kg\r\n").unwrap(), reading(1.234, "kg", true));
+        assert_eq!(parse_line(SerialProtocol::Sics, "S D      0.512 kg").unwrap(), reading(0.512, "kg", false));
+        assert_eq!(parse_line(SerialProtocol::Sics, "S S     -0.020 kg").unwrap(), reading(-0.02, "kg", true));
+        // Busy: no weight this time, but not an error either
+        assert_eq!(parse_line(SerialProtocol::Sics, "S I").unwrap(), None);
+    }
+
+    #[test]
+    fn sics_range_errors() {
+        assert_eq!(device_error(parse_line(SerialProtocol::Sics, "S +")), "Scale is over its weight limit");
+        assert_eq!(device_error(parse_line(SerialProtocol::Sics, "S -")), "Scale is under its weight limit");
+        assert!(device_error(parse_line(SerialProtocol::Sics, "ES")).starts_with("Unrecognized scale output"));
+    }
+
+    #[test]
+    fn blank_lines_carry_no_weight() {
+        assert_eq!(parse_line(SerialProtocol::StGs, "\r\n").unwrap(), None);
+        assert_eq!(parse_line(SerialProtocol::Sics, "   ").unwrap(), None);
+    }
+
+    #[test]
+    fn fragment_of_a_streamed_line_is_skipped() {
+        // Opened partway through "ST,GS,+  0.980kg"
+        let mut stream = std::io::Cursor::new(&b"80kg\r\nST,GS,+  1.234kg\r\nST,GS,+  1.240kg\r\n"[..]);
+        let mut mid_line = true;
+        assert_eq!(next_reading(&mut stream, SerialProtocol::StGs, &mut mid_line).unwrap(), reading(1.234, "kg", true));
+        assert!(!mid_line);
+        // Later lines are whole
+        assert_eq!(next_reading(&mut stream, SerialProtocol::StGs, &mut mid_line).unwrap(), reading(1.24, "kg", true));
+        assert_eq!(next_reading(&mut stream, SerialProtocol::StGs, &mut mid_line).unwrap(), None);
+    }
+
+    #[test]
+    fn whole_lines_are_read_from_the_start() {
+        let mut replies = std::io::Cursor::new(&b"S S      1.234 kg\r\n"[..]);
+        let mut mid_line = false;
+        assert_eq!(next_reading(&mut replies, SerialProtocol::Sics, &mut mid_line).unwrap(), reading(1.234, "kg", true));
+    }
+
+    /// Hands out its bytes, then times out like a quiet serial port
+    struct QuietAfter(std::io::Cursor<&'static [u8]>);
+
+    impl std::io::Read for QuietAfter {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match std::io::Read::read(&mut self.0, buf)? {
+                0 => Err(ErrorKind::TimedOut.into()),
+                n => Ok(n),
+            }
+        }
+    }
+
+    #[test]
+    fn line_cut_short_by_the_timeout_is_skipped_next_time() {
+        let mut port = BufReader::new(QuietAfter(std::io::Cursor::new(&b"ST,GS,+  1.2"[..])));
+        let mut mid_line = false;
+        assert_eq!(next_reading(&mut port, SerialProtocol::StGs, &mut mid_line).unwrap(), None);
+        assert!(mid_line);
+    }
+
+    fn config(protocol: SerialProtocol, tare_command: Option<&str>) -> SerialScaleConfig {
+        SerialScaleConfig {
+            port: "/dev/ttyUSB0".to_string(),
+            baud_rate: 9600,
+            parity: SerialParity::None,
+            data_bits: 8,
+            protocol,
+            tare_command: tare_command.map(str::to_string),
+        }
+    }
+
+    /// The bytes a tare sends to the port for a config
+    fn tare_bytes(config: &SerialScaleConfig) -> Vec<u8> {
+        let mut port = Vec::new();
+        send_tare(&mut port, &tare_command(config)).unwrap();
+        port
+    }
+
+    #[test]
+    fn each_protocol_tares_with_its_own_command() {
+        assert_eq!(tare_bytes(&config(SerialProtocol::StGs, None)), b"T\r\n");
+        assert_eq!(tare_bytes(&config(SerialProtocol::Sics, None)), b"T\r\n");
+    }
+
+    #[test]
+    fn configured_tare_command_is_sent_as_is() {
+        assert_eq!(tare_bytes(&config(SerialProtocol::StGs, Some("Z\r\n"))), b"Z\r\n");
+        assert_eq!(tare_bytes(&config(SerialProtocol::Sics, Some("TI\r\n"))), b"TI\r\n");
+    }
+
+    #[test]
+    fn sics_tare_replies() {
+        assert!(parse_sics_tare("T S      0.125 kg\r\n").is_ok());
+        assert!(matches!(parse_sics_tare("ES\r\n"), Err(ScaleError::TareUnsupported)));
+        assert!(matches!(parse_sics_tare("T I"), Err(ScaleError::Device(e)) if e.contains("busy")));
+        assert!(matches!(parse_sics_tare("T +"), Err(ScaleError::Device(e)) if e.contains("weight range")));
+        assert!(matches!(parse_sics_tare("S S 1.0 kg"), Err(ScaleError::Device(e)) if e.starts_with("Unrecognized")));
+    }
+
+    #[test]
+    fn config_defaults_to_9600_8n1_st_gs() {
+        let config: SerialScaleConfig = serde_json::from_str(r#"{ "port": "/dev/ttyUSB0" }"#).unwrap();
+        assert_eq!(config.baud_rate, 9600);
+        assert_eq!(config.data_bits, 8);
+        assert!(matches!(config.parity, SerialParity::None));
+        assert!(matches!(config.protocol, SerialProtocol::StGs));
+
+        let config: SerialScaleConfig =
+            serde_json::from_str(r#"{ "port": "COM3", "baudRate": 2400, "parity": "even", "dataBits": 7, "protocol": "sics" }"#)
+                .unwrap();
+        assert_eq!(config.baud_rate, 2400);
+        assert!(matches!(config.parity, SerialParity::Even));
+        assert!(matches!(config.protocol, SerialProtocol::Sics));
+    }
+}
@@ -1,43 +1,680 @@
-use crate::printer;
-use crate::protocol::{ClientMessage, ServerMessage};
-use futures_util::{SinkExt, StreamExt};
+use crate::config;
+use crate::discovery::Advertisement;
+use crate::health::{self, HealthReport, Listeners};
+use crate::job_state::{self, JobOutcome};
+use crate::label_fetch::{self, FetchError};
+use crate::label_template;
+use crate::log_context;
+use crate::log_stream::{self, LogSubscription};
+use crate::notifications;
+use crate::origins;
+use crate::preview;
+use crate::pairing::{self, TokenCheck};
+use crate::print_dedupe::{ClientPrints, EarlierResult, RecentPrints};
+use crate::print_history;
+use crate::printer::{self, CopyStrategy, JobOptions, LabelData, PayloadError, PrinterList, RetryPolicy};
+use crate::printer_watch;
+use crate::protocol::{
+    self, BatchJob, BatchJobResult, ClientMessage, CopyResult, ErrorCode, PrintOptions, ServerMessage, ShutdownReason,
+};
+use crate::rate_limit::{RateLimit, TokenBucket};
+use crate::receipt;
+use crate::scale::{self, Scale, ScaleError, ScaleReading};
+use crate::self_test;
+use crate::stats;
+use crate::tls;
+use crate::tspl;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures_util::{Sink, SinkExt, StreamExt};
+use std::collections::{BTreeMap, HashMap};
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream};
-use tokio_tungstenite::{accept_async, tungstenite::Message};
+use tokio::sync::{broadcast, mpsc, oneshot, watch, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame, WebSocketConfig};
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::{accept_hdr_async_with_config, tungstenite::Message};
 
 const CONNECTOR_VERSION: &str = env!("CARGO_PKG_VERSION");
-const SERVER_PORT: u16 = 9632;
-
-/// Allowed origins for WebSocket connections
-const ALLOWED_ORIGINS: &[&str] = &[
-    "https://app.limestack.io",
-    "https://limestack.io",
-    "http://localhost:5173", // Local dev
-    "http://localhost:4173", // Local preview
-];
+pub const SERVER_PORT: u16 = 9632;
+
+/// Runtime settings for the WebSocket server
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// How often to ping clients to detect dead connections
+    pub ping_interval: Duration,
+    /// How long to wait for a pong before dropping the client
+    pub pong_timeout: Duration,
+    /// Close connections that send no messages for this long
+    pub idle_timeout: Duration,
+    /// Longest a print command may run before the request fails with a timeout
+    pub print_timeout: Duration,
+    /// How long `waitForCompletion` follows a spooled job, on top of the print timeout
+    pub completion_timeout: Duration,
+    /// Most copies a single print request may ask for
+    pub max_copies: u32,
+    /// Print commands allowed to run at once across all clients; others queue
+    pub max_concurrent_prints: usize,
+    /// WebSocket connections open at once; more are closed straight after the handshake
+    pub max_connections: usize,
+    /// Longest a queued print may wait for its turn before failing
+    pub print_queue_timeout: Duration,
+    /// Automatic retries of prints that fail with a transient error
+    pub print_retry: RetryPolicy,
+    /// Prints each connection may submit before being told to back off
+    pub print_rate_limit: RateLimit,
+    /// How long a successful print's result answers a resend of the same `requestId`
+    pub print_dedupe_ttl: Duration,
+    /// Largest WebSocket message (and frame) a client may send, in bytes
+    pub max_message_size: usize,
+    /// How often printer subscriptions poll for changes
+    pub printer_poll_interval: Duration,
+    /// How long one printer enumeration answers every request; zero enumerates each time
+    pub printer_cache_ttl: Duration,
+    /// Origins (or `*.` subdomain patterns) allowed to authenticate
+    pub allowed_origins: Vec<String>,
+    /// Whether wss:// is being served alongside ws://
+    pub tls_enabled: bool,
+    /// Only browsers paired through the tray may authenticate
+    pub require_pairing: bool,
+    /// Messages other than `hello` and `ping` a client may send before authenticating,
+    /// after which it's closed as probing; zero never closes
+    pub max_unauthenticated_messages: u32,
+    /// Advertise the server over mDNS
+    pub advertise_mdns: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: Duration::from_secs(30),
+            pong_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(10 * 60),
+            print_timeout: Duration::from_secs(30),
+            completion_timeout: Duration::from_secs(120),
+            max_copies: 100,
+            max_concurrent_prints: 4,
+            max_connections: 16,
+            print_queue_timeout: Duration::from_secs(60),
+            print_retry: RetryPolicy {
+                max_retries: 2,
+                initial_backoff: Duration::from_millis(500),
+            },
+            print_rate_limit: RateLimit {
+                per_minute: 10,
+                burst: 5,
+            },
+            print_dedupe_ttl: Duration::from_secs(10 * 60),
+            // Room for large multi-page PDFs once base64 encoded
+            max_message_size: 32 * 1024 * 1024,
+            printer_poll_interval: Duration::from_secs(5),
+            printer_cache_ttl: Duration::from_secs(2),
+            allowed_origins: origins::DEFAULT_ALLOWED_ORIGINS.iter().map(|o| o.to_string()).collect(),
+            tls_enabled: false,
+            require_pairing: false,
+            max_unauthenticated_messages: 5,
+            advertise_mdns: false,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Build the config from defaults, the config file, and `LIMESTACK_*` environment variables
+    pub fn load() -> Self {
+        let mut config = Self::from_file(&config::get());
+        config.allowed_origins = origins::configured_origins();
+
+        if let Some(secs) = env_secs("LIMESTACK_PING_INTERVAL_SECS") {
+            config.ping_interval = secs;
+        }
+        if let Some(secs) = env_secs("LIMESTACK_PONG_TIMEOUT_SECS") {
+            config.pong_timeout = secs;
+        }
+        if let Some(secs) = env_secs("LIMESTACK_IDLE_TIMEOUT_SECS") {
+            config.idle_timeout = secs;
+        }
+        if let Some(secs) = env_secs("LIMESTACK_PRINT_TIMEOUT_SECS") {
+            config.print_timeout = secs;
+        }
+        if let Some(secs) = env_secs("LIMESTACK_COMPLETION_TIMEOUT_SECS") {
+            config.completion_timeout = secs;
+        }
+        if let Some(secs) = env_secs("LIMESTACK_PRINT_DEDUPE_SECS") {
+            config.print_dedupe_ttl = secs;
+        }
+        if let Some(secs) = env_secs("LIMESTACK_PRINTER_POLL_SECS") {
+            config.printer_poll_interval = secs;
+        }
+        // Zero turns the cache off
+        if let Ok(value) = std::env::var("LIMESTACK_PRINTER_CACHE_MS") {
+            match value.trim().parse::<u64>() {
+                Ok(ms) => config.printer_cache_ttl = Duration::from_millis(ms),
+                Err(_) => log::warn!("Ignoring invalid LIMESTACK_PRINTER_CACHE_MS: {}", value),
+            }
+        }
+        if let Some(max) = env_number("LIMESTACK_MAX_COPIES") {
+            config.max_copies = max.min(u32::MAX as u64) as u32;
+        }
+        if let Some(max) = env_number("LIMESTACK_MAX_CONCURRENT_PRINTS") {
+            config.max_concurrent_prints = max.min(Semaphore::MAX_PERMITS as u64) as usize;
+        }
+        if let Some(max) = env_number("LIMESTACK_MAX_CONNECTIONS") {
+            config.max_connections = max.min(Semaphore::MAX_PERMITS as u64) as usize;
+        }
+        if let Some(secs) = env_secs("LIMESTACK_PRINT_QUEUE_TIMEOUT_SECS") {
+            config.print_queue_timeout = secs;
+        }
+        // Zero is meaningful here: it turns retries off
+        if let Ok(value) = std::env::var("LIMESTACK_PRINT_RETRIES") {
+            match value.trim().parse::<u32>() {
+                Ok(retries) => config.print_retry.max_retries = retries,
+                Err(_) => log::warn!("Ignoring invalid LIMESTACK_PRINT_RETRIES: {}", value),
+            }
+        }
+        // Zero per minute turns rate limiting off
+        if let Ok(value) = std::env::var("LIMESTACK_PRINT_RATE_PER_MINUTE") {
+            match value.trim().parse::<u32>() {
+                Ok(rate) => config.print_rate_limit.per_minute = rate,
+                Err(_) => log::warn!("Ignoring invalid LIMESTACK_PRINT_RATE_PER_MINUTE: {}", value),
+            }
+        }
+        if let Some(max) = env_number("LIMESTACK_MAX_MESSAGE_BYTES") {
+            config.max_message_size = max.min(usize::MAX as u64) as usize;
+        }
+        if let Some(burst) = env_number("LIMESTACK_PRINT_BURST") {
+            config.print_rate_limit.burst = burst.min(u32::MAX as u64) as u32;
+        }
+        // Zero keeps unauthenticated clients open however much they send
+        if let Ok(value) = std::env::var("LIMESTACK_MAX_UNAUTHENTICATED_MESSAGES") {
+            match value.trim().parse::<u32>() {
+                Ok(max) => config.max_unauthenticated_messages = max,
+                Err(_) => log::warn!("Ignoring invalid LIMESTACK_MAX_UNAUTHENTICATED_MESSAGES: {}", value),
+            }
+        }
+        config
+    }
+
+    /// Defaults with the settings `config.json` overrides; environment variables win over both
+    fn from_file(file: &config::Config) -> Self {
+        let mut config = Self::default();
+        config.require_pairing = file.require_pairing;
+        config.advertise_mdns = file.advertise_mdns;
+
+        match file.print_timeout_secs {
+            Some(0) => log::warn!("Ignoring printTimeoutSecs of 0"),
+            Some(secs) => config.print_timeout = Duration::from_secs(secs),
+            None => {}
+        }
+        match file.idle_timeout_secs {
+            Some(0) => log::warn!("Ignoring idleTimeoutSecs of 0"),
+            Some(secs) => config.idle_timeout = Duration::from_secs(secs),
+            None => {}
+        }
+        if let Some(rate) = file.print_rate_per_minute {
+            config.print_rate_limit.per_minute = rate;
+        }
+        if let Some(burst) = file.print_burst {
+            config.print_rate_limit.burst = burst;
+        }
+        config
+    }
+}
+
+/// Read a positive number of seconds from an environment variable
+fn env_secs(name: &str) -> Option<Duration> {
+    env_number(name).map(Duration::from_secs)
+}
+
+/// Read a positive integer from an environment variable
+fn env_number(name: &str) -> Option<u64> {
+    let value = std::env::var(name).ok()?;
+    match value.trim().parse::<u64>() {
+        Ok(n) if n > 0 => Some(n),
+        _ => {
+            log::warn!("Ignoring invalid {}: {}", name, value);
+            None
+        }
+    }
+}
+
+/// Whether new connections are accepted; toggled from the tray
+static ACCEPTING_CONNECTIONS: AtomicBool = AtomicBool::new(true);
+
+/// Pause or resume accepting new connections; existing connections stay open
+pub fn set_accepting_connections(accepting: bool) {
+    ACCEPTING_CONNECTIONS.store(accepting, Ordering::SeqCst);
+    log::info!("{} new connections", if accepting { "Accepting" } else { "Refusing" });
+}
+
+pub fn accepting_connections() -> bool {
+    ACCEPTING_CONNECTIONS.load(Ordering::SeqCst)
+}
+
+/// Live server status, published to the tray
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ServerStatus {
+    /// Number of currently connected, authenticated clients
+    pub connected_clients: usize,
+    /// Why the server isn't running, if it failed to start
+    pub error: Option<String>,
+    /// Problems the startup self-test found; printing may fail until they're fixed
+    pub warnings: Vec<String>,
+}
+
+/// Counts an authenticated client for as long as the guard is alive
+struct ClientGuard(Arc<watch::Sender<ServerStatus>>);
+
+impl ClientGuard {
+    fn new(status: Arc<watch::Sender<ServerStatus>>) -> Self {
+        status.send_modify(|s| s.connected_clients += 1);
+        Self(status)
+    }
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        self.0.send_modify(|s| s.connected_clients = s.connected_clients.saturating_sub(1));
+    }
+}
+
+/// Keeps a scale stream running; dropping it stops the reader thread
+struct ScaleStream(Arc<AtomicBool>);
+
+impl Drop for ScaleStream {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Aborts a background task when dropped, tying it to the connection's lifetime
+struct TaskGuard(tokio::task::JoinHandle<()>);
 
-pub async fn start_server(_app_handle: tauri::AppHandle) {
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Printer changes a connection may fall behind by; older ones are superseded anyway
+const PRINTER_CHANGE_BUFFER: usize = 8;
+
+/// Messages queued for a client before it counts as a slow consumer and is disconnected
+const WRITE_BUFFER: usize = 64;
+
+/// How long a closing connection waits for the client to take its queued messages
+const WRITER_FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// First byte of a TLS ClientHello record, used to tell wss:// from ws:// on the same port
+const TLS_HANDSHAKE_BYTE: u8 = 0x16;
+
+/// How long connections get to finish in-flight print jobs once shutdown starts
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long quitting waits for the server to stop before exiting anyway
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(8);
+
+/// `retryAfterMs` in `goodbye`: soon after an update restart, backing off after a quit
+const RESTART_RETRY_AFTER: Duration = Duration::from_secs(3);
+const QUIT_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Lets the app stop the server gracefully before exiting
+pub struct ServerShutdown {
+    signal: watch::Sender<Option<ShutdownReason>>,
+    stopped: std::sync::Mutex<Option<oneshot::Receiver<()>>>,
+}
+
+impl ServerShutdown {
+    /// Create the handle plus the halves given to the server: the shutdown signal and
+    /// a sender to drop once the server has stopped
+    pub fn new() -> (Self, watch::Receiver<Option<ShutdownReason>>, oneshot::Sender<()>) {
+        let (signal, signal_rx) = watch::channel(None);
+        let (stopped_tx, stopped_rx) = oneshot::channel();
+        let handle = Self {
+            signal,
+            stopped: std::sync::Mutex::new(Some(stopped_rx)),
+        };
+        (handle, signal_rx, stopped_tx)
+    }
+
+    /// Signal the server to stop and wait (bounded) for it to finish; clients are told
+    /// `reason` so they know how soon to reconnect
+    pub async fn shutdown(&self, reason: ShutdownReason) {
+        log::info!("Shutting down server ({:?})...", reason);
+        let _ = self.signal.send(Some(reason));
+
+        let stopped = self.stopped.lock().unwrap().take();
+        if let Some(stopped) = stopped {
+            if tokio::time::timeout(SHUTDOWN_TIMEOUT, stopped).await.is_err() {
+                log::warn!("Server did not stop within {:?}", SHUTDOWN_TIMEOUT);
+            }
+        }
+    }
+}
+
+/// Resolve with the reason once shutdown has been signalled
+async fn wait_for_shutdown(shutdown: &mut watch::Receiver<Option<ShutdownReason>>) -> ShutdownReason {
+    loop {
+        if let Some(reason) = *shutdown.borrow_and_update() {
+            return reason;
+        }
+        if shutdown.changed().await.is_err() {
+            // The handle is gone, so shutdown can never be signalled
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Shared handles passed to every connection
+#[derive(Clone)]
+struct ConnectionContext {
+    config: Arc<ServerConfig>,
+    status: Arc<watch::Sender<ServerStatus>>,
+    shutdown: watch::Receiver<Option<ShutdownReason>>,
+    /// When the server started, reported as uptime in `pong`
+    started_at: Instant,
+    /// Shared by all connections so the spooler sees a bounded number of jobs
+    print_permits: Arc<Semaphore>,
+    /// One per open WebSocket connection, sized when the server starts
+    connection_slots: Arc<Semaphore>,
+    /// Recent print results by client, shared so they outlive a reconnect
+    recent_prints: Arc<RecentPrints>,
+    /// Loopback addresses bound, reported by `/health`
+    listeners: Listeners,
+    /// `config::generation()` that `config` was built from
+    config_generation: u64,
+    /// Printer list changes, announced to every authenticated connection
+    printer_changes: broadcast::Sender<PrinterList>,
+    /// Whether new connections are accepted, normally `ACCEPTING_CONNECTIONS`
+    accepting: &'static AtomicBool,
+}
+
+impl ConnectionContext {
+    /// Pick up edits to `config.json` for connections accepted from now on
+    fn refresh_config(&mut self) {
+        let generation = config::generation();
+        if generation == self.config_generation {
+            return;
+        }
+
+        let mut reloaded = ServerConfig::load();
+        reloaded.tls_enabled = self.config.tls_enabled;
+        log::info!("Server settings reloaded, allowed origins: {}", reloaded.allowed_origins.join(", "));
+        self.config = Arc::new(reloaded);
+        self.config_generation = generation;
+    }
+}
+
+pub async fn start_server(
+    data_dir: Option<PathBuf>,
+    status: watch::Sender<ServerStatus>,
+    shutdown: watch::Receiver<Option<ShutdownReason>>,
+) {
+    let started_at = Instant::now();
+    let status = Arc::new(status);
+    let mut config = ServerConfig::load();
+    log::info!("Allowed origins: {}", config.allowed_origins.join(", "));
     let addr = SocketAddr::from(([127, 0, 0, 1], SERVER_PORT));
+    let addr_v6 = SocketAddr::from((std::net::Ipv6Addr::LOCALHOST, SERVER_PORT));
+
+    // TLS is optional: plain ws:// keeps working if the certificate can't be set up
+    let tls_acceptor = match &data_dir {
+        Some(dir) => tls::load_or_create_acceptor(dir)
+            .map_err(|e| log::warn!("TLS unavailable, serving ws:// only: {}", e))
+            .ok(),
+        None => {
+            log::warn!("TLS unavailable, no app data dir");
+            None
+        }
+    };
+    config.tls_enabled = tls_acceptor.is_some();
+    printer::set_list_cache_ttl(config.printer_cache_ttl);
+    // Before anything lists printers, which would drop a pinned default that was renamed
+    if let Err(e) = tokio::task::spawn_blocking(printer::migrate_printer_ids).await {
+        log::error!("Printer id migration failed: {}", e);
+    }
+    if config::get().startup_self_test {
+        // In the background, since a stalled spooler shouldn't hold up listening
+        let status = status.clone();
+        tokio::spawn(async move {
+            match tokio::task::spawn_blocking(self_test::run).await {
+                Ok(warnings) => status.send_modify(|s| s.warnings = warnings),
+                Err(e) => log::error!("Self-test failed to run: {}", e),
+            }
+        });
+    }
+    let config = Arc::new(config);
+
+    let Some(listener) = bind_or_report(addr, &status).await else {
+        return;
+    };
+    log::info!(
+        "WebSocket server listening on ws://127.0.0.1:{}{}",
+        SERVER_PORT,
+        if tls_acceptor.is_some() { " (wss:// also available)" } else { "" }
+    );
 
-    let listener = match TcpListener::bind(&addr).await {
+    // Browsers may resolve `localhost` to ::1 first. IPv4 stays the one that must work,
+    // since some machines have IPv6 disabled entirely.
+    let listener_v6 = match TcpListener::bind(&addr_v6).await {
         Ok(l) => {
-            log::info!("WebSocket server listening on ws://127.0.0.1:{}", SERVER_PORT);
-            l
+            log::info!("Also listening on ws://[::1]:{}", SERVER_PORT);
+            Some(l)
         }
         Err(e) => {
-            log::error!("Failed to bind to port {}: {}", SERVER_PORT, e);
-            return;
+            log::warn!("IPv6 loopback unavailable, serving 127.0.0.1 only: {}", e);
+            None
         }
     };
 
-    while let Ok((stream, peer_addr)) = listener.accept().await {
-        log::info!("New connection from: {}", peer_addr);
-        tokio::spawn(handle_connection(stream));
+    // Discovery is a convenience; the server works the same without it. It's opt-in, as it
+    // tells the whole LAN about a server only this machine can reach. Dropping the
+    // advertisement when the server stops withdraws it.
+    let _advertisement = if config.advertise_mdns {
+        Advertisement::start(SERVER_PORT, config.tls_enabled, CONNECTOR_VERSION)
+            .map_err(|e| log::warn!("mDNS advertisement unavailable: {}", e))
+            .ok()
+    } else {
+        None
+    };
+
+    let print_permits = Arc::new(Semaphore::new(config.max_concurrent_prints));
+    let connection_slots = Arc::new(Semaphore::new(config.max_connections));
+    let recent_prints = RecentPrints::new(config.print_dedupe_ttl);
+    let (printer_changes, _) = broadcast::channel(PRINTER_CHANGE_BUFFER);
+    let _printer_broadcast = TaskGuard(tokio::spawn(printer_watch::broadcast_changes(
+        config.printer_poll_interval,
+        printer_changes.clone(),
+    )));
+    let ctx = ConnectionContext {
+        config_generation: config::generation(),
+        config,
+        status,
+        shutdown,
+        started_at,
+        print_permits,
+        connection_slots,
+        recent_prints,
+        listeners: Listeners {
+            ipv4: true,
+            ipv6: listener_v6.is_some(),
+        },
+        printer_changes,
+        accepting: &ACCEPTING_CONNECTIONS,
+    };
+    serve(listener, listener_v6, ctx, tls_acceptor).await;
+}
+
+/// Accept connections until shutdown is signalled, then stop listening and give open
+/// connections a moment to finish their print jobs
+async fn serve(
+    listener: TcpListener,
+    listener_v6: Option<TcpListener>,
+    mut ctx: ConnectionContext,
+    tls_acceptor: Option<TlsAcceptor>,
+) {
+    let mut shutdown = ctx.shutdown.clone();
+    let mut connections = JoinSet::new();
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer_addr)) => {
+                    log::info!("New connection from: {}", peer_addr);
+                    ctx.refresh_config();
+                    connections.spawn(log_context::scope(
+                        log_context::next_id(),
+                        accept_connection(stream, ctx.clone(), tls_acceptor.clone()),
+                    ));
+                }
+                Err(e) => {
+                    log::error!("Failed to accept connection: {}", e);
+                    break;
+                }
+            },
+            accepted = accept_optional(&listener_v6) => match accepted {
+                Ok((stream, peer_addr)) => {
+                    log::info!("New connection from: {}", peer_addr);
+                    ctx.refresh_config();
+                    connections.spawn(log_context::scope(
+                        log_context::next_id(),
+                        accept_connection(stream, ctx.clone(), tls_acceptor.clone()),
+                    ));
+                }
+                Err(e) => {
+                    log::error!("Failed to accept connection: {}", e);
+                    break;
+                }
+            },
+            // Reap finished connections so the set doesn't grow forever
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            _ = wait_for_shutdown(&mut shutdown) => break,
+        }
+    }
+
+    drop(listener);
+    drop(listener_v6);
+    log::info!("Server stopping, waiting for {} connection(s)", connections.len());
+    let drained = tokio::time::timeout(DRAIN_TIMEOUT, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+
+    if drained.is_err() {
+        log::warn!("Connections did not close within {:?}, aborting", DRAIN_TIMEOUT);
+        connections.shutdown().await;
+    }
+    log::info!("Server stopped");
+}
+
+/// Bind the listener the server can't run without, reporting a failure to the tray and
+/// as a notification
+async fn bind_or_report(addr: SocketAddr, status: &watch::Sender<ServerStatus>) -> Option<TcpListener> {
+    match TcpListener::bind(&addr).await {
+        Ok(listener) => Some(listener),
+        Err(e) => {
+            log::error!("Failed to bind to port {}: {}", addr.port(), e);
+            let reason = if e.kind() == std::io::ErrorKind::AddrInUse {
+                "port in use".to_string()
+            } else {
+                e.to_string()
+            };
+            status.send_modify(|s| s.error = Some(reason));
+            notifications::alert(
+                "LimeStack Connector can't start",
+                &format!(
+                    "Port {} is unavailable, so LimeStack can't reach printers. Close other copies of the connector and restart it.",
+                    addr.port()
+                ),
+            );
+            None
+        }
+    }
+}
+
+/// Accept on a listener that may not exist; never completes without one
+async fn accept_optional(listener: &Option<TcpListener>) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Serve a connection as wss:// if it opens with a TLS handshake, otherwise as ws://
+async fn accept_connection(stream: TcpStream, ctx: ConnectionContext, tls_acceptor: Option<TlsAcceptor>) {
+    let mut first_byte = [0u8; 1];
+    let is_tls = matches!(stream.peek(&mut first_byte).await, Ok(1) if first_byte[0] == TLS_HANDSHAKE_BYTE);
+    let is_health = !is_tls && health::is_health_request(&stream).await;
+
+    match tls_acceptor {
+        Some(acceptor) if is_tls => match acceptor.accept(stream).await {
+            Ok(tls_stream) => handle_connection(tls_stream, ctx, true).await,
+            Err(e) => log::error!("TLS handshake failed: {}", e),
+        },
+        _ if is_health => health::respond(stream, health_report(&ctx).await).await,
+        _ => handle_connection(stream, ctx, false).await,
+    }
+}
+
+/// Snapshot for `/health`; printers are listed once, without the startup retries, so probes stay quick
+async fn health_report(ctx: &ConnectionContext) -> HealthReport {
+    let printers = log_context::spawn_blocking(printer::list_printers)
+        .await
+        .unwrap_or_else(|e| PrinterList {
+            printers: Vec::new(),
+            error: Some(format!("Printer enumeration failed: {}", e)),
+        });
+    HealthReport {
+        status: "ok",
+        connector_version: CONNECTOR_VERSION,
+        uptime_seconds: ctx.started_at.elapsed().as_secs(),
+        connected_clients: ctx.status.borrow().connected_clients,
+        printer_count: printers.printers.len(),
+        enumeration_error: printers.error,
+        accepting_connections: ctx.accepting.load(Ordering::SeqCst),
+        listeners: ctx.listeners,
     }
 }
 
-async fn handle_connection(stream: TcpStream) {
-    let ws_stream = match accept_async(stream).await {
+async fn handle_connection<S>(stream: S, ctx: ConnectionContext, secure: bool)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let ConnectionContext {
+        config,
+        status,
+        mut shutdown,
+        started_at,
+        print_permits,
+        connection_slots,
+        recent_prints,
+        printer_changes: printer_changes_tx,
+        accepting,
+        ..
+    } = ctx;
+
+    // The Origin header is set by the browser, unlike the origin reported in `hello`
+    let mut handshake_origin = String::new();
+    let check_origin = |request: &Request, response: Response| -> Result<Response, ErrorResponse> {
+        handshake_origin = check_handshake_origin(request, &config.allowed_origins)?;
+        log_declined_extensions(request);
+        Ok(response)
+    };
+
+    let ws_config = WebSocketConfig {
+        max_message_size: Some(config.max_message_size),
+        max_frame_size: Some(config.max_message_size),
+        ..Default::default()
+    };
+    let ws_stream = match accept_hdr_async_with_config(stream, check_origin, Some(ws_config)).await {
         Ok(ws) => ws,
         Err(e) => {
             log::error!("WebSocket handshake failed: {}", e);
@@ -46,19 +683,203 @@ async fn handle_connection(stream: TcpStream) {
     };
 
     let (mut write, mut read) = ws_stream.split();
+
+    // Finish the handshake first so the browser gets a reason rather than a reset
+    if !accepting.load(Ordering::SeqCst) {
+        log::info!("Refusing connection while paused");
+        stats::log_event("refused", &[("origin", &handshake_origin), ("reason", &"paused")]);
+        let _ = write.send(paused_close_frame()).await;
+        return;
+    }
+    // Held until this connection ends, freeing the slot for another
+    let Ok(_connection_slot) = connection_slots.try_acquire_owned() else {
+        log::warn!("Refusing connection, already at the limit of {}", config.max_connections);
+        stats::log_event("refused", &[("origin", &handshake_origin), ("reason", &"too_many_connections")]);
+        let _ = write.send(too_many_connections_close_frame()).await;
+        return;
+    };
+    let _active_connection = stats::ActiveConnection::open();
+    let connected_at = Instant::now();
+    stats::log_event("connected", &[("origin", &handshake_origin), ("secure", &secure)]);
+
+    // Everything after this is written by its own task, so a client that stops reading
+    // fills a bounded queue instead of stalling this loop
+    let (outbox_tx, outbox_rx) = mpsc::channel(WRITE_BUFFER);
+    let outbox = Outbox(outbox_tx);
+    let mut writer = TaskGuard(log_context::spawn(write_messages(write, outbox_rx)));
+
     let mut authenticated = false;
+    // Messages refused with `auth_required` so far
+    let mut unauthenticated_messages = 0u32;
+    // Held while authenticated so the tray's client count drops when this task ends
+    let mut client_guard: Option<ClientGuard> = None;
+
+    // Ping periodically; if the pong doesn't arrive by the deadline the client is gone
+    let mut ping_timer = tokio::time::interval_at(Instant::now() + config.ping_interval, config.ping_interval);
+    let mut pong_deadline: Option<Instant> = None;
+    // Pushed back by every client message; pongs alone don't count as activity
+    let mut idle_deadline = Instant::now() + config.idle_timeout;
+
+    // Responses from print jobs running in the background
+    let (response_tx, mut response_rx) = mpsc::unbounded_channel::<ServerMessage>();
+    let mut prints_in_flight = 0usize;
+    let mut shutting_down: Option<ShutdownReason> = None;
+    // Live scale readings for this client, stopped when the connection ends
+    let mut scale_stream: Option<ScaleStream> = None;
+    // Whether printer changes go out as `printers` (subscribed) rather than `printers_changed`
+    let mut printers_subscribed = false;
+    // Log records for support tooling, pulled from their bounded buffer as the outbox takes them
+    let mut log_subscription: Option<LogSubscription> = None;
+    // `print_binary` headers waiting for their binary frame, by request id
+    let mut pending_binary: HashMap<String, PrintRequest> = HashMap::new();
+    // Shared print slots, plus this connection's prints still waiting for one
+    let print_queue = PrintQueue::new(print_permits);
+    // Keyed by origin rather than connection so a resend after reconnecting is recognized
+    let client_prints = recent_prints.for_client(handshake_origin.clone());
+    // Per connection, so one runaway tab can't use up another's allowance
+    let mut print_rate = TokenBucket::new(config.print_rate_limit);
+    // Holding the sender keeps the channel open for as long as this connection runs
+    let mut printer_changes = printer_changes_tx.subscribe();
+
+    let close_reason = loop {
+        let msg = tokio::select! {
+            msg = read.next(), if shutting_down.is_none() => match msg {
+                Some(msg) => msg,
+                None => break "connection_lost",
+            },
+            Some(response) = response_rx.recv() => {
+                if let Err(e) = outbox.send_json(&response) {
+                    log::error!("Failed to send response: {}", e);
+                    break e.close_reason();
+                }
+                if matches!(response, ServerMessage::PrintResult { .. } | ServerMessage::PrintBatchResult { .. }) {
+                    prints_in_flight -= 1;
+                }
+                if let Some(reason) = shutting_down.filter(|_| prints_in_flight == 0) {
+                    for frame in shutdown_frames(reason) {
+                        let _ = outbox.send(frame);
+                    }
+                    break "shutdown";
+                }
+                continue;
+            }
+            changed = printer_changes.recv(), if authenticated => {
+                // A change missed while lagging is covered by the list that follows it
+                let Ok(list) = changed else { continue };
+                // One message per change: subscribers asked for `printers`, so they get only that
+                let message = if printers_subscribed {
+                    printers_message(list)
+                } else {
+                    ServerMessage::PrintersChanged {
+                        printers: list.printers,
+                        enumeration_error: list.error,
+                    }
+                };
+                if let Err(e) = outbox.send_json(&message) {
+                    log::error!("Failed to send printer change: {}", e);
+                    break e.close_reason();
+                }
+                continue;
+            }
+            record = next_log(&log_subscription), if log_subscription.is_some() => {
+                if let Err(e) = outbox.send_json(&record) {
+                    log::error!("Failed to send log record: {}", e);
+                    break e.close_reason();
+                }
+                continue;
+            }
+            reason = wait_for_shutdown(&mut shutdown), if shutting_down.is_none() => {
+                // Stop taking new messages, but let in-flight prints report back first
+                shutting_down = Some(reason);
+                if prints_in_flight == 0 {
+                    for frame in shutdown_frames(reason) {
+                        let _ = outbox.send(frame);
+                    }
+                    break "shutdown";
+                }
+                log::info!("Waiting for {} print job(s) before closing", prints_in_flight);
+                continue;
+            }
+            _ = ping_timer.tick() => {
+                if pong_deadline.is_none() {
+                    if let Err(e) = outbox.send(Message::Ping(Vec::new())) {
+                        log::error!("Failed to send ping: {}", e);
+                        break e.close_reason();
+                    }
+                    pong_deadline = Some(Instant::now() + config.pong_timeout);
+                }
+                continue;
+            }
+            _ = tokio::time::sleep_until(pong_deadline.unwrap_or_else(Instant::now)), if pong_deadline.is_some() => {
+                log::warn!("No pong within {:?}, closing connection", config.pong_timeout);
+                let _ = outbox.send(Message::Close(None));
+                break "pong_timeout";
+            }
+            _ = tokio::time::sleep_until(idle_deadline) => {
+                // A print or scale stream in progress keeps the connection busy
+                if prints_in_flight > 0 || scale_stream.is_some() {
+                    idle_deadline = Instant::now() + config.idle_timeout;
+                    continue;
+                }
+                log::info!("No messages for {:?}, closing idle connection", config.idle_timeout);
+                let _ = outbox.send(idle_close_frame());
+                break "idle";
+            }
+        };
+
+        if matches!(msg, Ok(Message::Text(_)) | Ok(Message::Binary(_))) {
+            idle_deadline = Instant::now() + config.idle_timeout;
+        }
 
-    while let Some(msg) = read.next().await {
         let msg = match msg {
             Ok(Message::Text(text)) => text,
-            Ok(Message::Close(_)) => {
-                log::info!("Client disconnected");
-                break;
+            Ok(Message::Close(_)) => break "client_closed",
+            Ok(Message::Pong(_)) => {
+                pong_deadline = None;
+                continue;
+            }
+            Ok(Message::Binary(frame)) => {
+                let refused = match take_binary_print(&mut pending_binary, frame) {
+                    Ok(request) => match claim_print(&client_prints, &request.request_id, is_dry_run(&request.options)) {
+                        Some(earlier) => {
+                            prints_in_flight += 1;
+                            forward_earlier_result(earlier, request.request_id, response_tx.clone());
+                            None
+                        }
+                        None => match print_rate.try_take() {
+                            Ok(()) => {
+                                prints_in_flight += 1;
+                                spawn_print(request, config.clone(), print_queue.clone(), client_prints.clone(), response_tx.clone());
+                                None
+                            }
+                            Err(retry_after) => Some(release_rate_limited(&client_prints, request.request_id, is_dry_run(&request.options), retry_after)),
+                        },
+                    },
+                    Err(error) => Some(error),
+                };
+                if let Some(error) = refused {
+                    if let Err(e) = outbox.send_json(&error) {
+                        log::error!("Failed to send response: {}", e);
+                        break e.close_reason();
+                    }
+                }
+                continue;
             }
             Ok(_) => continue,
+            Err(WsError::Capacity(e)) => {
+                // The frame was dropped unread; explain before closing rather than just vanishing
+                log::warn!("Closing connection after oversized message: {}", e);
+                let error = ServerMessage::error(
+                    ErrorCode::PayloadTooLarge,
+                    format!("Message exceeds the {} byte limit", config.max_message_size),
+                );
+                let _ = outbox.send_json(&error);
+                let _ = outbox.send(too_large_close_frame());
+                break "payload_too_large";
+            }
             Err(e) => {
                 log::error!("WebSocket error: {}", e);
-                break;
+                break "websocket_error";
             }
         };
 
@@ -66,45 +887,132 @@ async fn handle_connection(stream: TcpStream) {
             Ok(m) => m,
             Err(e) => {
                 log::warn!("Invalid message: {}", e);
-                let error = ServerMessage::Error {
-                    message: format!("Invalid message format: {}", e),
-                };
-                let _ = write.send(Message::Text(serde_json::to_string(&error).unwrap())).await;
+                let error = ServerMessage::error(
+                    ErrorCode::InvalidMessage,
+                    format!("Invalid message format: {}", e),
+                );
+                if let Err(e) = outbox.send_json(&error) {
+                    log::error!("Failed to send response: {}", e);
+                    break e.close_reason();
+                }
                 continue;
             }
         };
 
+        if handshake_origin.is_empty() && !matches!(client_msg, ClientMessage::Ping) {
+            log::warn!("Closing connection without an Origin header that sent more than ping");
+            let error = ServerMessage::error(ErrorCode::OriginNotAllowed, "Connections without an Origin header may only ping");
+            let _ = outbox.send_json(&error);
+            let _ = outbox.send(origin_required_close_frame());
+            break "origin_required";
+        }
+
+        // Tell a client that skipped `hello` how to authenticate, and close one that keeps
+        // sending commands anyway, since that's more likely probing than a confused page
+        if !authenticated && !matches!(client_msg, ClientMessage::Hello { .. } | ClientMessage::Ping) {
+            unauthenticated_messages += 1;
+            if let Err(e) = outbox.send_json(&auth_required_error()) {
+                log::error!("Failed to send response: {}", e);
+                break e.close_reason();
+            }
+            let max = config.max_unauthenticated_messages;
+            if max > 0 && unauthenticated_messages >= max {
+                log::warn!("Closing connection after {} messages without authenticating", unauthenticated_messages);
+                let _ = outbox.send(auth_required_close_frame());
+                break "auth_required";
+            }
+            continue;
+        }
+
         let response = match client_msg {
-            ClientMessage::Hello { version: _, origin } => {
-                // Validate origin
-                if !ALLOWED_ORIGINS.iter().any(|o| origin.starts_with(o)) {
-                    log::warn!("Rejected connection from origin: {}", origin);
-                    ServerMessage::Error {
-                        message: "Origin not allowed".to_string(),
-                    }
+            // Cheap liveness and version check, allowed before authentication
+            ClientMessage::Ping => ServerMessage::Pong {
+                connector_version: CONNECTOR_VERSION.to_string(),
+                uptime_seconds: started_at.elapsed().as_secs(),
+            },
+
+            ClientMessage::Hello {
+                version,
+                origin,
+                token,
+            } => {
+                let negotiated = protocol::negotiate_protocol_version(&version);
+                // The handshake already checked the real origin; `hello` must agree with it
+                if origin != handshake_origin {
+                    log::warn!("Hello origin {} does not match handshake origin {}", origin, handshake_origin);
+                    stats::log_event("auth_failed", &[("origin", &origin), ("reason", &"origin_mismatch")]);
+                    ServerMessage::error(ErrorCode::OriginNotAllowed, "Origin does not match connection")
+                } else if let Err(e) = &negotiated {
+                    log::warn!("Rejected hello with protocol version {:?}: {}", version, e);
+                    stats::log_event("auth_failed", &[("origin", &origin), ("reason", &"incompatible_version")]);
+                    ServerMessage::error(ErrorCode::IncompatibleVersion, e.clone())
                 } else {
-                    authenticated = true;
-                    log::info!("Client authenticated from origin: {}", origin);
-                    ServerMessage::Welcome {
-                        connector_version: CONNECTOR_VERSION.to_string(),
-                        capabilities: vec!["print".to_string()],
-                        printers: printer::get_printers(),
+                    match pairing::check_token(&origin, token.as_deref()) {
+                        TokenCheck::Invalid => {
+                            log::warn!("Rejected invalid token from origin: {}", origin);
+                            stats::log_event("auth_failed", &[("origin", &origin), ("reason", &"invalid_token")]);
+                            ServerMessage::error(ErrorCode::InvalidToken, "Invalid or expired pairing token")
+                        }
+                        TokenCheck::Missing if config.require_pairing => {
+                            stats::log_event("auth_failed", &[("origin", &origin), ("reason", &"pairing_required")]);
+                            ServerMessage::error(
+                                ErrorCode::PairingRequired,
+                                "Pair this browser using the code from the connector's tray menu",
+                            )
+                        }
+                        check => {
+                            authenticated = true;
+                            if client_guard.is_none() {
+                                client_guard = Some(ClientGuard::new(status.clone()));
+                            }
+                            stats::log_event("authenticated", &[("origin", &origin)]);
+                            let list = current_printers(started_at).await;
+                            ServerMessage::Welcome {
+                                connector_version: CONNECTOR_VERSION.to_string(),
+                                protocol_version: negotiated.unwrap_or(*protocol::SUPPORTED_PROTOCOL_RANGE.end()),
+                                secure,
+                                max_message_size: config.max_message_size,
+                                capabilities: capabilities(&config),
+                                printers: list.printers,
+                                enumeration_error: list.error,
+                                token: match check {
+                                    TokenCheck::NewlyPaired(token) => Some(token),
+                                    _ => None,
+                                },
+                            }
+                        }
                     }
                 }
             }
 
             ClientMessage::GetPrinters => {
-                if !authenticated {
-                    ServerMessage::Error {
-                        message: "Not authenticated".to_string(),
-                    }
-                } else {
-                    ServerMessage::Printers {
-                        printers: printer::get_printers(),
-                    }
+                printers_message(current_printers(started_at).await)
+            }
+
+            ClientMessage::GetPrinter { printer: printer_id } => {
+                handle_get_printer(printer_id).await
+            }
+
+            ClientMessage::GetDefaultThermalPrinter => {
+                handle_get_default_thermal_printer().await
+            }
+
+            ClientMessage::GetCapabilities => {
+                ServerMessage::Capabilities {
+                    formats: printer::supported_formats(),
+                    max_payload_bytes: config.max_message_size,
+                    max_copies: config.max_copies,
+                    features: capabilities(&config),
                 }
             }
 
+            ClientMessage::SubscribePrinters => {
+                // Send the current list now; changes follow from the server-wide printer watch
+                printers_subscribed = true;
+                log::info!("Client subscribed to printer changes");
+                printers_message(current_printers(started_at).await)
+            }
+
             ClientMessage::Print {
                 request_id,
                 printer: printer_id,
@@ -112,72 +1020,3340 @@ async fn handle_connection(stream: TcpStream) {
                 data,
                 options,
             } => {
-                if !authenticated {
-                    ServerMessage::Error {
-                        message: "Not authenticated".to_string(),
-                    }
+                if let Some(earlier) = claim_print(&client_prints, &request_id, is_dry_run(&options)) {
+                    prints_in_flight += 1;
+                    forward_earlier_result(earlier, request_id, response_tx.clone());
+                    continue;
+                } else if let Err(retry_after) = print_rate.try_take() {
+                    release_rate_limited(&client_prints, request_id, is_dry_run(&options), retry_after)
                 } else {
-                    handle_print_request(request_id, printer_id, data, format, options.copies.unwrap_or(1))
+                    let request = PrintRequest {
+                        request_id,
+                        printer_id,
+                        format,
+                        data: LabelData::Base64(data),
+                        options,
+                    };
+                    prints_in_flight += 1;
+                    spawn_print(request, config.clone(), print_queue.clone(), client_prints.clone(), response_tx.clone());
+                    continue;
                 }
             }
 
-            ClientMessage::ReadScale => {
-                // TODO: Implement scale reading
-                ServerMessage::Error {
-                    message: "Scale reading not yet implemented".to_string(),
+            ClientMessage::PrintReceipt {
+                request_id,
+                printer: printer_id,
+                receipt,
+            } => {
+                if let Some(earlier) = client_prints.earlier_result(&request_id) {
+                    prints_in_flight += 1;
+                    forward_earlier_result(earlier, request_id, response_tx.clone());
+                    continue;
+                } else if let Err(retry_after) = print_rate.try_take() {
+                    release_rate_limited(&client_prints, request_id, false, retry_after)
+                } else {
+                    let request = PrintRequest {
+                        request_id,
+                        printer_id,
+                        format: "escpos".to_string(),
+                        data: LabelData::Bytes(receipt::build_escpos(&receipt)),
+                        options: PrintOptions::default(),
+                    };
+                    prints_in_flight += 1;
+                    spawn_print(request, config.clone(), print_queue.clone(), client_prints.clone(), response_tx.clone());
+                    continue;
                 }
             }
-        };
-
-        let response_json = serde_json::to_string(&response).unwrap();
-        if let Err(e) = write.send(Message::Text(response_json)).await {
-            log::error!("Failed to send response: {}", e);
-            break;
-        }
-    }
-}
-
-fn handle_print_request(
-    request_id: String,
-    printer_id: String,
-    data: String,
-    format: String,
-    copies: u32,
-) -> ServerMessage {
-    log::info!("Print request for printer: {} (format: {})", printer_id, format);
 
-    // Find the printer
-    let printer_name = match printer::find_printer(&printer_id) {
-        Some(name) => name,
-        None => {
-            return ServerMessage::PrintResult {
+            ClientMessage::PrintTemplate {
                 request_id,
-                success: false,
-                message: None,
-                error: Some(format!("Printer not found: {}", printer_id)),
-            };
-        }
-    };
+                printer: printer_id,
+                template,
+                fields,
+                options,
+            } => {
+                if let Some(earlier) = claim_print(&client_prints, &request_id, is_dry_run(&options)) {
+                    prints_in_flight += 1;
+                    forward_earlier_result(earlier, request_id, response_tx.clone());
+                    continue;
+                } else if let Err(retry_after) = print_rate.try_take() {
+                    release_rate_limited(&client_prints, request_id, is_dry_run(&options), retry_after)
+                } else {
+                    match render_template(&printer_id, &template, fields).await {
+                        Err(e) => {
+                            log::warn!("Refused print_template {}: {}", request_id, e);
+                            let response = ServerMessage::print_failure(request_id.clone(), ErrorCode::InvalidLabel, e);
+                            release_claim(&client_prints, &request_id, is_dry_run(&options), &response);
+                            response
+                        }
+                        Ok((format, label)) => {
+                            let request = PrintRequest {
+                                request_id,
+                                printer_id,
+                                format: format.to_string(),
+                                data: LabelData::Bytes(label),
+                                options,
+                            };
+                            prints_in_flight += 1;
+                            spawn_print(request, config.clone(), print_queue.clone(), client_prints.clone(), response_tx.clone());
+                            continue;
+                        }
+                    }
+                }
+            }
 
-    // Print the label
-    match printer::print_label(&printer_name, &data, &format, copies) {
-        Ok(_) => {
-            log::info!("Print job sent successfully to {}", printer_name);
-            ServerMessage::PrintResult {
+            ClientMessage::PrintTspl {
                 request_id,
-                success: true,
-                message: Some(format!("Label sent to {}", printer_name)),
-                error: None,
+                printer: printer_id,
+                label,
+                options,
+            } => {
+                if let Some(earlier) = claim_print(&client_prints, &request_id, is_dry_run(&options)) {
+                    prints_in_flight += 1;
+                    forward_earlier_result(earlier, request_id, response_tx.clone());
+                    continue;
+                } else if let Err(retry_after) = print_rate.try_take() {
+                    release_rate_limited(&client_prints, request_id, is_dry_run(&options), retry_after)
+                } else {
+                    match tspl::build_tspl(&label) {
+                        Err(e) => {
+                            log::warn!("Refused print_tspl {}: {}", request_id, e);
+                            let response = ServerMessage::print_failure(request_id.clone(), ErrorCode::InvalidLabel, e);
+                            release_claim(&client_prints, &request_id, is_dry_run(&options), &response);
+                            response
+                        }
+                        Ok(commands) => {
+                            let request = PrintRequest {
+                                request_id,
+                                printer_id,
+                                format: "tspl".to_string(),
+                                data: LabelData::Bytes(commands),
+                                options,
+                            };
+                            prints_in_flight += 1;
+                            spawn_print(request, config.clone(), print_queue.clone(), client_prints.clone(), response_tx.clone());
+                            continue;
+                        }
+                    }
+                }
             }
-        }
+
+            ClientMessage::PrintUrl {
+                request_id,
+                printer: printer_id,
+                url,
+                format,
+                options,
+            } => {
+                if let Err(e) = label_fetch::check_url(&url) {
+                    log::warn!("Refused print_url {}: {}", request_id, e);
+                    ServerMessage::print_failure(request_id, ErrorCode::UrlNotAllowed, e.to_string())
+                } else if let Some(earlier) = claim_print(&client_prints, &request_id, is_dry_run(&options)) {
+                    prints_in_flight += 1;
+                    forward_earlier_result(earlier, request_id, response_tx.clone());
+                    continue;
+                } else if let Err(retry_after) = print_rate.try_take() {
+                    release_rate_limited(&client_prints, request_id, is_dry_run(&options), retry_after)
+                } else {
+                    // The data is filled in once the download finishes
+                    let request = PrintRequest {
+                        request_id,
+                        printer_id,
+                        format,
+                        data: LabelData::Bytes(Vec::new()),
+                        options,
+                    };
+                    prints_in_flight += 1;
+                    spawn_url_print(url, request, config.clone(), print_queue.clone(), client_prints.clone(), response_tx.clone());
+                    continue;
+                }
+            }
+
+            ClientMessage::PrintBinary {
+                request_id,
+                printer: printer_id,
+                format,
+                options,
+            } => {
+                if pending_binary.len() >= MAX_PENDING_BINARY {
+                    ServerMessage::print_failure(
+                        request_id,
+                        ErrorCode::InvalidMessage,
+                        "Too many print_binary headers waiting for data",
+                    )
+                } else {
+                    // Nothing to reply until the matching binary frame arrives and fills in the data;
+                    // the rate limit is charged then, once it's known not to be a resend
+                    let request = PrintRequest {
+                        request_id: request_id.clone(),
+                        printer_id,
+                        format,
+                        data: LabelData::Bytes(Vec::new()),
+                        options,
+                    };
+                    pending_binary.insert(request_id, request);
+                    continue;
+                }
+            }
+
+            ClientMessage::PrintBatch {
+                request_id,
+                jobs,
+                stop_on_failure,
+            } => {
+                if jobs.is_empty() || jobs.len() > MAX_BATCH_JOBS {
+                    ServerMessage::error(
+                        ErrorCode::InvalidMessage,
+                        format!("print_batch needs 1 to {} jobs", MAX_BATCH_JOBS),
+                    )
+                } else if let Some(earlier) = client_prints.earlier_result(&request_id) {
+                    prints_in_flight += 1;
+                    forward_earlier_result(earlier, request_id, response_tx.clone());
+                    continue;
+                } else if let Err(retry_after) = print_rate.try_take() {
+                    release_rate_limited(&client_prints, request_id, false, retry_after)
+                } else {
+                    prints_in_flight += 1;
+                    spawn_batch_print(
+                        request_id,
+                        jobs,
+                        stop_on_failure,
+                        config.clone(),
+                        print_queue.clone(),
+                        client_prints.clone(),
+                        response_tx.clone(),
+                    );
+                    continue;
+                }
+            }
+
+            ClientMessage::CancelPrint {
+                request_id,
+                job_id,
+                printer: printer_id,
+            } => {
+                if pending_binary.remove(&request_id).is_some() {
+                    // The header never got its data, so nothing was queued or will report back
+                    log::info!("Cancelled print_binary {} before its data arrived", request_id);
+                    cancel_result(request_id, Ok("Cancelled before printing".to_string()))
+                } else if print_queue.cancel(&request_id) {
+                    cancel_result(request_id, Ok("Cancelled before printing".to_string()))
+                } else if let Some(job_id) = job_id {
+                    handle_cancel_job(request_id, printer_id, job_id).await
+                } else {
+                    cancel_result(request_id, Err((ErrorCode::JobNotFound, "No queued print with this requestId".to_string())))
+                }
+            }
+
+            ClientMessage::ClearPrintQueue { printer: printer_id } => {
+                handle_clear_print_queue(printer_id).await
+            }
+
+            ClientMessage::GetScales => {
+                handle_get_scales().await
+            }
+
+            ClientMessage::ReadScale { scale } => {
+                handle_read_scale(scale).await
+            }
+
+            ClientMessage::TareScale { scale } => {
+                handle_tare_scale(scale).await
+            }
+
+            ClientMessage::StartScaleStream { scale } => {
+                log::info!("Starting scale stream");
+                scale_stream = Some(start_scale_stream(scale, response_tx.clone()));
+                continue;
+            }
+
+            ClientMessage::StopScaleStream => {
+                if scale_stream.take().is_some() {
+                    log::info!("Stopped scale stream");
+                }
+                continue;
+            }
+
+            ClientMessage::UnsubscribePrinters => {
+                if std::mem::take(&mut printers_subscribed) {
+                    log::info!("Client unsubscribed from printer changes");
+                }
+                continue;
+            }
+
+            ClientMessage::SubscribeLogs { level } => {
+                let level = level.as_deref().unwrap_or("info");
+                match level.parse::<log::LevelFilter>() {
+                    Ok(level) => {
+                        log::info!("Client subscribed to logs at {}", level);
+                        log_subscription = Some(log_stream::subscribe(level));
+                        continue;
+                    }
+                    Err(_) => {
+                        ServerMessage::error(ErrorCode::InvalidMessage, format!("Invalid log level: {}", level))
+                    }
+                }
+            }
+
+            ClientMessage::UnsubscribeLogs => {
+                if log_subscription.take().is_some() {
+                    log::info!("Client unsubscribed from logs");
+                }
+                continue;
+            }
+
+            ClientMessage::GetStats => {
+                stats::snapshot(started_at.elapsed().as_secs())
+            }
+
+            ClientMessage::GetPrintHistory => {
+                ServerMessage::PrintHistory {
+                    jobs: print_history::recent(),
+                }
+            }
+
+            ClientMessage::RenderPreview {
+                request_id,
+                format,
+                data,
+                options,
+            } => {
+                let response_tx = response_tx.clone();
+                log_context::spawn(async move {
+                    let response = handle_render_preview(request_id, format, data, options).await;
+                    let _ = response_tx.send(response);
+                });
+                continue;
+            }
+
+            ClientMessage::Unknown => {
+                let message_type = serde_json::from_str::<serde_json::Value>(&msg)
+                    .ok()
+                    .and_then(|value| value.get("type")?.as_str().map(str::to_string))
+                    .unwrap_or_default();
+                log::warn!("Unsupported message type: {}", message_type);
+                ServerMessage::error(
+                    ErrorCode::UnsupportedMessage,
+                    format!(
+                        "Unsupported message type '{}' (connector version {})",
+                        message_type, CONNECTOR_VERSION
+                    ),
+                )
+            }
+
+            ClientMessage::SetDefaultPrinter { printer: printer_id } => {
+                handle_set_default_printer(printer_id).await
+            }
+
+            ClientMessage::SetPrinterNickname { printer: printer_id, nickname } => {
+                handle_set_printer_nickname(printer_id, nickname).await
+            }
+
+            ClientMessage::OpenUrl { url } => {
+                handle_open_url(url, &config.allowed_origins)
+            }
+
+            ClientMessage::DiagnosePrinter { printer: printer_id } => {
+                handle_diagnose_printer(printer_id).await
+            }
+        };
+
+        if let Err(e) = outbox.send_json(&response) {
+            log::error!("Failed to send response: {}", e);
+            break e.close_reason();
+        }
+    };
+    stats::log_event(
+        "disconnected",
+        &[
+            ("origin", &handshake_origin),
+            ("reason", &close_reason),
+            ("authenticated", &authenticated),
+            ("duration_secs", &connected_at.elapsed().as_secs()),
+        ],
+    );
+
+    // Let the writer flush what's queued, close frame included, unless the client has
+    // stopped reading altogether
+    drop(outbox);
+    if tokio::time::timeout(WRITER_FLUSH_TIMEOUT, &mut writer.0).await.is_err() {
+        log::warn!("Client did not take queued messages within {:?}, dropping them", WRITER_FLUSH_TIMEOUT);
+    }
+}
+
+/// Messages a connection has queued for its writer task
+///
+/// The last slot is kept for a close frame, so a client that has fallen a whole buffer
+/// behind can still be told why it's being disconnected.
+struct Outbox(mpsc::Sender<Message>);
+
+/// Why a message couldn't be queued for the client
+#[derive(Debug)]
+enum OutboxError {
+    /// The client stopped reading; a `slow_consumer` close frame has been queued
+    SlowConsumer,
+    /// The writer has stopped, after a write error or a close frame
+    Closed,
+}
+
+impl OutboxError {
+    /// Reason given for the disconnect in the `disconnected` event
+    fn close_reason(&self) -> &'static str {
+        match self {
+            OutboxError::SlowConsumer => "slow_consumer",
+            OutboxError::Closed => "connection_lost",
+        }
+    }
+}
+
+impl std::fmt::Display for OutboxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutboxError::SlowConsumer => write!(f, "client is not reading, closing with slow_consumer"),
+            OutboxError::Closed => write!(f, "connection closed"),
+        }
+    }
+}
+
+impl Outbox {
+    fn send(&self, message: Message) -> Result<(), OutboxError> {
+        if self.0.is_closed() {
+            return Err(OutboxError::Closed);
+        }
+        if self.0.capacity() <= 1 && !matches!(message, Message::Close(_)) {
+            let _ = self.0.try_send(slow_consumer_close_frame());
+            return Err(OutboxError::SlowConsumer);
+        }
+        self.0.try_send(message).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => OutboxError::SlowConsumer,
+            mpsc::error::TrySendError::Closed(_) => OutboxError::Closed,
+        })
+    }
+
+    fn send_json(&self, message: &ServerMessage) -> Result<(), OutboxError> {
+        self.send(Message::Text(serde_json::to_string(message).unwrap()))
+    }
+}
+
+/// Write queued messages to the client until the queue closes or a close frame is sent
+async fn write_messages<W>(mut write: W, mut outbox: mpsc::Receiver<Message>)
+where
+    W: Sink<Message, Error = WsError> + Unpin,
+{
+    while let Some(message) = outbox.recv().await {
+        let closing = matches!(message, Message::Close(_));
+        if let Err(e) = write.send(message).await {
+            // The client may well be gone already by the time it's told to close
+            if !closing {
+                log::error!("Failed to write to client: {}", e);
+            }
+            return;
+        }
+        if closing {
+            return;
+        }
+    }
+}
+
+/// Refuse the WebSocket upgrade unless the Origin header is allowed, returning the origin
+///
+/// Upgrades without an Origin header don't come from a page, so they're let through for
+/// health checks with an empty origin and may only `ping`.
+fn check_handshake_origin(request: &Request, allowed: &[String]) -> Result<String, ErrorResponse> {
+    let origin = request
+        .headers()
+        .get("origin")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+
+    if origin.is_empty() {
+        log::debug!("Handshake without an Origin header, allowing ping only");
+        return Ok(String::new());
+    }
+    if !origins::origin_allowed(origin, allowed) {
+        log::warn!("Refused handshake from origin: {}", origin);
+        return Err(forbidden("Origin not allowed"));
+    }
+
+    Ok(origin.to_string())
+}
+
+/// Browsers offer `permessage-deflate`, but tungstenite 0.21 can't decompress frames (it
+/// rejects the RSV1 bit), so the response never accepts it and the client falls back to
+/// uncompressed frames. Large labels are cheaper sent with `print_binary`.
+fn log_declined_extensions(request: &Request) {
+    let offered = request
+        .headers()
+        .get_all("sec-websocket-extensions")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(", ");
+    if !offered.is_empty() {
+        log::debug!("Declined WebSocket extensions: {}", offered);
+    }
+}
+
+fn forbidden(reason: &str) -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some(reason.to_string()));
+    *response.status_mut() = StatusCode::FORBIDDEN;
+    response
+}
+
+/// `goodbye` with when to reconnect, then the close frame, sent to each client on shutdown
+fn shutdown_frames(reason: ShutdownReason) -> [Message; 2] {
+    let retry_after = match reason {
+        ShutdownReason::Restart => RESTART_RETRY_AFTER,
+        ShutdownReason::Quit => QUIT_RETRY_AFTER,
+    };
+    let goodbye = ServerMessage::Goodbye {
+        reason,
+        retry_after_ms: retry_after.as_millis() as u64,
+    };
+    [Message::Text(serde_json::to_string(&goodbye).unwrap()), shutdown_close_frame()]
+}
+
+/// Close frame telling the client the connector is going away
+fn shutdown_close_frame() -> Message {
+    Message::Close(Some(CloseFrame {
+        code: CloseCode::Away,
+        reason: "Connector shutting down".into(),
+    }))
+}
+
+fn idle_close_frame() -> Message {
+    Message::Close(Some(CloseFrame {
+        code: CloseCode::Normal,
+        reason: "Idle timeout".into(),
+    }))
+}
+
+fn too_large_close_frame() -> Message {
+    Message::Close(Some(CloseFrame {
+        code: CloseCode::Size,
+        reason: "Message too large".into(),
+    }))
+}
+
+fn slow_consumer_close_frame() -> Message {
+    Message::Close(Some(CloseFrame {
+        code: CloseCode::Policy,
+        reason: "slow_consumer".into(),
+    }))
+}
+
+/// `auth_required` with the `hello` the client should have sent
+fn auth_required_error() -> ServerMessage {
+    ServerMessage::error(
+        ErrorCode::AuthRequired,
+        format!(
+            r#"Send hello before anything else: {{"type":"hello","version":"{}","origin":"<page origin>","token":"<paired token, optional>"}}"#,
+            protocol::SUPPORTED_PROTOCOL_RANGE.end()
+        ),
+    )
+}
+
+fn auth_required_close_frame() -> Message {
+    Message::Close(Some(CloseFrame {
+        code: CloseCode::Policy,
+        reason: "auth_required".into(),
+    }))
+}
+
+fn origin_required_close_frame() -> Message {
+    Message::Close(Some(CloseFrame {
+        code: CloseCode::Policy,
+        reason: "origin_required".into(),
+    }))
+}
+
+fn too_many_connections_close_frame() -> Message {
+    Message::Close(Some(CloseFrame {
+        code: CloseCode::Again,
+        reason: "too_many_connections".into(),
+    }))
+}
+
+fn paused_close_frame() -> Message {
+    Message::Close(Some(CloseFrame {
+        code: CloseCode::Again,
+        reason: "Connector is not accepting connections".into(),
+    }))
+}
+
+/// Features advertised to the browser in the Welcome message
+fn capabilities(config: &ServerConfig) -> Vec<String> {
+    let mut capabilities: Vec<String> = CAPABILITIES.iter().map(|c| c.to_string()).collect();
+    if config.tls_enabled {
+        capabilities.push("tls".to_string());
+    }
+    capabilities
+}
+
+/// Features this build supports; the browser should check these before using newer messages
+const CAPABILITIES: &[&str] = &[
+    "print",
+    "print_binary",
+    "print_url",
+    "print_receipt",
+    "print_tspl",
+    "print_template",
+    "clear_print_queue",
+    "get_printer",
+    "get_default_thermal_printer",
+    "print_progress",
+    "print_history",
+    "stats",
+    "preview",
+    "printer_subscription",
+    "printers_changed",
+    "scale",
+    "scale_stream",
+    "scale_tare",
+    "scale_selection",
+    "pairing",
+    "log_stream",
+    "cancel_print",
+    "print_batch",
+    "printer_nicknames",
+    "diagnose_printer",
+    "get_capabilities",
+    "wait_for_completion",
+    "open_url",
+];
+
+/// A print job as received from the client, not yet validated
+struct PrintRequest {
+    request_id: String,
+    printer_id: String,
+    format: String,
+    data: LabelData,
+    options: PrintOptions,
+}
+
+/// Print in the background so a hung driver doesn't stall the connection
+fn spawn_print(
+    request: PrintRequest,
+    config: Arc<ServerConfig>,
+    queue: PrintQueue,
+    prints: ClientPrints,
+    response_tx: mpsc::UnboundedSender<ServerMessage>,
+) {
+    let cancelled = queue.enqueue(&request.request_id);
+    log_context::spawn(print_and_record(request, config, queue, cancelled, prints, response_tx));
+}
+
+async fn print_and_record(
+    request: PrintRequest,
+    config: Arc<ServerConfig>,
+    queue: PrintQueue,
+    cancelled: oneshot::Receiver<()>,
+    prints: ClientPrints,
+    response_tx: mpsc::UnboundedSender<ServerMessage>,
+) {
+    let request_id = request.request_id.clone();
+    let printer_id = request.printer_id.clone();
+    let format = request.format.clone();
+    // Dry runs print nothing, so they stay out of the history
+    let dry_run = is_dry_run(&request.options);
+    let response = run_print_request(request, config, queue, cancelled, response_tx.clone()).await;
+    if !dry_run {
+        record_outcome(&printer_id, &format, &response);
+    }
+    release_claim(&prints, &request_id, dry_run, &response);
+    let _ = response_tx.send(response);
+}
+
+/// Answer a resent print with the first attempt's result, once it has one
+fn forward_earlier_result(earlier: EarlierResult, request_id: String, response_tx: mpsc::UnboundedSender<ServerMessage>) {
+    log::info!("Print request {} was already received, not printing it again", request_id);
+    match earlier {
+        EarlierResult::Finished(response) => {
+            let _ = response_tx.send(response);
+        }
+        EarlierResult::Running(result) => {
+            log_context::spawn(async move {
+                let response = result.await.unwrap_or_else(|_| {
+                    ServerMessage::print_failure(request_id, ErrorCode::PrintFailed, "The earlier attempt ended without a result")
+                });
+                let _ = response_tx.send(response);
+            });
+        }
+    }
+}
+
+/// Keep a finished print in the history and let the operator know about it
+fn record_outcome(printer_id: &str, format: &str, response: &ServerMessage) {
+    print_history::record(printer_id, format, response);
+    stats::record_print(response);
+    notifications::print_outcome(printer_id, response);
+}
+
+/// Download a `print_url` label, then print it like any other request
+fn spawn_url_print(
+    url: String,
+    mut request: PrintRequest,
+    config: Arc<ServerConfig>,
+    queue: PrintQueue,
+    prints: ClientPrints,
+    response_tx: mpsc::UnboundedSender<ServerMessage>,
+) {
+    // Cancellable from the start, including while the label downloads
+    let mut cancelled = queue.enqueue(&request.request_id);
+    log_context::spawn(async move {
+        let fetched = tokio::select! {
+            fetched = label_fetch::fetch_label(&url) => fetched,
+            Ok(()) = &mut cancelled => {
+                log::info!("Print request {} cancelled while downloading", request.request_id);
+                let response = cancelled_print(request.request_id.clone());
+                let dry_run = is_dry_run(&request.options);
+                if !dry_run {
+                    record_outcome(&request.printer_id, &request.format, &response);
+                }
+                release_claim(&prints, &request.request_id, dry_run, &response);
+                let _ = response_tx.send(response);
+                return;
+            }
+        };
+
+        match fetched {
+            Ok((bytes, content_type)) => {
+                let format = request.format.trim();
+                if format.is_empty() || format.eq_ignore_ascii_case("auto") {
+                    if let Some(format) = content_type.as_deref().and_then(printer::format_for_mime) {
+                        request.format = format.to_string();
+                    }
+                }
+                request.data = LabelData::Bytes(bytes);
+                print_and_record(request, config, queue, cancelled, prints, response_tx).await;
+            }
+            Err(e) => {
+                queue.remove(&request.request_id);
+                log::error!("Failed to fetch label for {}: {}", request.request_id, e);
+                let code = match e {
+                    FetchError::NotAllowed(_) => ErrorCode::UrlNotAllowed,
+                    FetchError::Failed(_) => ErrorCode::FetchFailed,
+                };
+                let response = ServerMessage::print_failure(request.request_id.clone(), code, e.to_string());
+                let dry_run = is_dry_run(&request.options);
+                if !dry_run {
+                    record_outcome(&request.printer_id, &request.format, &response);
+                }
+                release_claim(&prints, &request.request_id, dry_run, &response);
+                let _ = response_tx.send(response);
+            }
+        }
+    });
+}
+
+/// Most jobs a single `print_batch` may contain
+const MAX_BATCH_JOBS: usize = 20;
+
+/// Print a batch's jobs in order and answer with one result covering all of them
+///
+/// Each job gets the request id `<requestId>:<index>`, which is what its progress messages,
+/// the print history and `cancel_print` use.
+fn spawn_batch_print(
+    request_id: String,
+    jobs: Vec<BatchJob>,
+    stop_on_failure: bool,
+    config: Arc<ServerConfig>,
+    queue: PrintQueue,
+    prints: ClientPrints,
+    response_tx: mpsc::UnboundedSender<ServerMessage>,
+) {
+    log_context::spawn(async move {
+        let (success, results) = print_batch(&request_id, jobs, stop_on_failure, |request| {
+            let (config, queue, response_tx) = (config.clone(), queue.clone(), response_tx.clone());
+            async move {
+                let (printer_id, format) = (request.printer_id.clone(), request.format.clone());
+                let cancelled = queue.enqueue(&request.request_id);
+                let response = run_print_request(request, config, queue, cancelled, response_tx).await;
+                record_outcome(&printer_id, &format, &response);
+                response
+            }
+        })
+        .await;
+
+        log::info!(
+            "Batch {}: {} of {} jobs printed",
+            request_id,
+            results.iter().filter(|r| r.success).count(),
+            results.len()
+        );
+        let response = ServerMessage::PrintBatchResult {
+            request_id: request_id.clone(),
+            success,
+            results,
+        };
+        prints.finish(&request_id, &response);
+        let _ = response_tx.send(response);
+    });
+}
+
+/// Run each job through `print` in order, returning whether all succeeded and each job's result
+///
+/// With `stop_on_failure`, jobs after the first failure are skipped rather than printed.
+async fn print_batch<F, Fut>(
+    request_id: &str,
+    jobs: Vec<BatchJob>,
+    stop_on_failure: bool,
+    mut print: F,
+) -> (bool, Vec<BatchJobResult>)
+where
+    F: FnMut(PrintRequest) -> Fut,
+    Fut: std::future::Future<Output = ServerMessage>,
+{
+    let mut results = Vec::with_capacity(jobs.len());
+    let mut failed = false;
+
+    for (index, job) in jobs.into_iter().enumerate() {
+        if failed && stop_on_failure {
+            results.push(BatchJobResult {
+                index,
+                printer: job.printer,
+                success: false,
+                job_id: None,
+                error: Some("Skipped after an earlier job failed".to_string()),
+                code: Some(ErrorCode::Skipped),
+            });
+            continue;
+        }
+
+        let response = print(PrintRequest {
+            request_id: format!("{}:{}", request_id, index),
+            printer_id: job.printer.clone(),
+            format: job.format,
+            data: LabelData::Base64(job.data),
+            options: job.options,
+        })
+        .await;
+
+        let result = match response {
+            ServerMessage::PrintResult {
+                success,
+                job_id,
+                error,
+                code,
+                ..
+            } => BatchJobResult {
+                index,
+                printer: job.printer,
+                success,
+                job_id,
+                error,
+                code,
+            },
+            _ => BatchJobResult {
+                index,
+                printer: job.printer,
+                success: false,
+                job_id: None,
+                error: Some("Unexpected print response".to_string()),
+                code: Some(ErrorCode::PrintFailed),
+            },
+        };
+        failed |= !result.success;
+        results.push(result);
+    }
+
+    (!failed, results)
+}
+
+/// The shared print permits, plus this connection's prints that haven't got one yet
+///
+/// Waiting prints can be cancelled before they ever reach the spooler.
+#[derive(Clone)]
+struct PrintQueue {
+    permits: Arc<Semaphore>,
+    waiting: Arc<std::sync::Mutex<HashMap<String, oneshot::Sender<()>>>>,
+}
+
+impl PrintQueue {
+    fn new(permits: Arc<Semaphore>) -> Self {
+        Self {
+            permits,
+            waiting: Arc::default(),
+        }
+    }
+
+    /// Mark a print as waiting; the receiver fires if it's cancelled
+    fn enqueue(&self, request_id: &str) -> oneshot::Receiver<()> {
+        let (cancel_tx, cancel_rx) = oneshot::channel();
+        self.waiting.lock().unwrap().insert(request_id.to_string(), cancel_tx);
+        cancel_rx
+    }
+
+    /// Stop waiting on a print's behalf, once it runs or fails
+    fn remove(&self, request_id: &str) {
+        self.waiting.lock().unwrap().remove(request_id);
+    }
+
+    /// Cancel a waiting print; false if it already started or isn't known
+    fn cancel(&self, request_id: &str) -> bool {
+        match self.waiting.lock().unwrap().remove(request_id) {
+            Some(cancel_tx) => cancel_tx.send(()).is_ok(),
+            None => false,
+        }
+    }
+}
+
+fn cancelled_print(request_id: String) -> ServerMessage {
+    ServerMessage::print_failure(request_id, ErrorCode::Cancelled, "Print cancelled before it was sent to the printer")
+}
+
+/// Most `print_binary` headers a connection may leave waiting for their data
+const MAX_PENDING_BINARY: usize = 16;
+
+/// Pair a binary frame with its `print_binary` header
+///
+/// Frames start with a big-endian u16 length and that many bytes of UTF-8 request id;
+/// the rest is the label data.
+fn take_binary_print(pending: &mut HashMap<String, PrintRequest>, mut frame: Vec<u8>) -> Result<PrintRequest, ServerMessage> {
+    let malformed = || ServerMessage::error(ErrorCode::InvalidMessage, "Malformed binary frame");
+
+    let id_len = match frame.get(..2) {
+        Some(prefix) => u16::from_be_bytes([prefix[0], prefix[1]]) as usize,
+        None => return Err(malformed()),
+    };
+    let request_id = frame
+        .get(2..2 + id_len)
+        .and_then(|id| std::str::from_utf8(id).ok())
+        .ok_or_else(malformed)?
+        .to_string();
+
+    let mut request = pending.remove(&request_id).ok_or_else(|| {
+        log::warn!("Binary frame for unknown request {}", request_id);
+        ServerMessage::print_failure(
+            request_id.clone(),
+            ErrorCode::InvalidMessage,
+            "No print_binary header for this request",
+        )
+    })?;
+
+    request.data = LabelData::Bytes(frame.split_off(2 + id_len));
+    Ok(request)
+}
+
+/// Run a print request on the blocking pool, failing with a timeout if the OS command hangs
+async fn run_print_request(
+    request: PrintRequest,
+    config: Arc<ServerConfig>,
+    queue: PrintQueue,
+    cancelled: oneshot::Receiver<()>,
+    progress_tx: mpsc::UnboundedSender<ServerMessage>,
+) -> ServerMessage {
+    let timeout = if request.options.wait_for_completion.unwrap_or(false) {
+        config.print_timeout + config.completion_timeout
+    } else {
+        config.print_timeout
+    };
+    let request_id = request.request_id.clone();
+    let queue_timeout = config.print_queue_timeout;
+    let print = move |on_progress: &mut dyn FnMut(u32, u32)| handle_print_request(request, &config, on_progress);
+    run_queued(request_id, timeout, queue_timeout, queue, cancelled, progress_tx, print).await
+}
+
+/// Wait for a print slot, then run `print` on the blocking pool for at most `timeout`
+///
+/// Jobs wait (first come, first served) for one of the shared print permits before running.
+/// The blocking task can't be cancelled, so a hung command keeps its thread and permit
+/// until it exits.
+async fn run_queued(
+    request_id: String,
+    timeout: Duration,
+    queue_timeout: Duration,
+    queue: PrintQueue,
+    mut cancelled: oneshot::Receiver<()>,
+    progress_tx: mpsc::UnboundedSender<ServerMessage>,
+    print: impl FnOnce(&mut dyn FnMut(u32, u32)) -> ServerMessage + Send + 'static,
+) -> ServerMessage {
+    let acquired = tokio::select! {
+        acquired = tokio::time::timeout(queue_timeout, queue.permits.clone().acquire_owned()) => Some(acquired),
+        Ok(()) = &mut cancelled => None,
+    };
+    // Past this point the print can only be cancelled through the spooler
+    queue.remove(&request_id);
+
+    let permit = match acquired {
+        None => {
+            log::info!("Print request {} cancelled while queued", request_id);
+            return cancelled_print(request_id);
+        }
+        Some(Ok(Ok(permit))) => permit,
+        Some(Ok(Err(_))) => {
+            return ServerMessage::print_failure(request_id, ErrorCode::PrintFailed, "Print queue closed");
+        }
+        Some(Err(_)) => {
+            log::warn!("Print request {} waited too long for a free print slot", request_id);
+            return ServerMessage::print_failure(
+                request_id,
+                ErrorCode::QueueTimeout,
+                format!("Too many print jobs in progress, gave up after {} seconds", queue_timeout.as_secs()),
+            );
+        }
+    };
+
+    // Progress goes through the same channel as the result, so it always arrives first
+    let progress_request_id = request_id.clone();
+    let mut on_progress = move |completed, total| {
+        let _ = progress_tx.send(ServerMessage::PrintProgress {
+            request_id: progress_request_id.clone(),
+            completed,
+            total,
+        });
+    };
+
+    let task = log_context::spawn_blocking(move || {
+        let _permit = permit;
+        print(&mut on_progress)
+    });
+
+    match tokio::time::timeout(timeout, task).await {
+        Ok(Ok(response)) => response,
+        Ok(Err(e)) => {
+            log::error!("Print task for {} failed: {}", request_id, e);
+            ServerMessage::print_failure(request_id, ErrorCode::PrintFailed, "Print task failed")
+        }
+        Err(_) => {
+            log::error!("Print request {} timed out after {:?}", request_id, timeout);
+            ServerMessage::print_failure(
+                request_id,
+                ErrorCode::Timeout,
+                format!("Print did not finish within {} seconds", timeout.as_secs()),
+            )
+        }
+    }
+}
+
+fn handle_print_request(request: PrintRequest, config: &ServerConfig, on_progress: impl FnMut(u32, u32)) -> ServerMessage {
+    handle_print_request_with(request, config, on_progress, printer::find_printer, printer::print_label)
+}
+
+/// `handle_print_request` with the printer lookup and the spooler submission passed in,
+/// so everything up to the print command can run without a printer
+fn handle_print_request_with(
+    request: PrintRequest,
+    config: &ServerConfig,
+    on_progress: impl FnMut(u32, u32),
+    find_printer: impl Fn(&str) -> Option<String>,
+    print_label: impl Fn(&str, &LabelData, &str, &JobOptions) -> Result<Option<String>, String>,
+) -> ServerMessage {
+    let PrintRequest {
+        request_id,
+        printer_id,
+        format,
+        data,
+        options,
+    } = request;
+
+    log::info!("Print request for printer: {} (format: {})", printer_id, format);
+
+    // Find the printer
+    let printer_name = match find_printer(&printer_id) {
+        Some(name) => name,
+        None => {
+            return ServerMessage::print_failure(
+                request_id,
+                ErrorCode::PrinterNotFound,
+                format!("Printer not found: {}", printer_id),
+            );
+        }
+    };
+
+    // Options the request leaves unset come from the printer's configured defaults
+    let options = match config::get().printer_defaults.get(&printer_name) {
+        Some(defaults) => options.with_defaults(defaults),
+        None => options,
+    };
+
+    // Bound copies before anything is spooled; a bad value could waste a whole roll of labels
+    let copies = match validate_copies(options.copies, config.max_copies) {
+        Ok(copies) => copies,
+        Err(e) => {
+            log::warn!("Rejected print request {}: {}", request_id, e);
+            return ServerMessage::print_failure(request_id, ErrorCode::InvalidCopies, e);
+        }
+    };
+
+    let paper_size = match options.paper_size.as_deref().map(printer::parse_paper_size).transpose() {
+        Ok(paper_size) => paper_size,
+        Err(e) => return ServerMessage::print_failure(request_id, ErrorCode::InvalidPaperSize, e),
+    };
+
+    let rotation = match options.rotate.map(printer::parse_rotation).transpose() {
+        Ok(rotation) => rotation.unwrap_or_default(),
+        Err(e) => return ServerMessage::print_failure(request_id, ErrorCode::InvalidRotation, e),
+    };
+
+    let scaling = match options.scale.as_deref().map(printer::parse_scaling).transpose() {
+        Ok(scaling) => scaling,
+        Err(e) => return ServerMessage::print_failure(request_id, ErrorCode::InvalidScale, e),
+    };
+
+    let page_range = match options.page_range.as_deref().map(printer::parse_page_range).transpose() {
+        Ok(page_range) => page_range,
+        Err(e) => return ServerMessage::print_failure(request_id, ErrorCode::InvalidPageRange, e),
+    };
+
+    let quality = match options.quality.as_deref().map(printer::parse_quality).transpose() {
+        Ok(quality) => quality,
+        Err(e) => return ServerMessage::print_failure(request_id, ErrorCode::InvalidQuality, e),
+    };
+
+    let darkness = match options.darkness.map(printer::parse_darkness).transpose() {
+        Ok(darkness) => darkness,
+        Err(e) => return ServerMessage::print_failure(request_id, ErrorCode::InvalidDarkness, e),
+    };
+
+    let raw_options = match options.raw_options.as_deref().map(printer::parse_raw_options).transpose() {
+        Ok(raw_options) => raw_options.unwrap_or_default(),
+        Err(e) => return ServerMessage::print_failure(request_id, ErrorCode::InvalidRawOptions, e),
+    };
+
+    let job = JobOptions {
+        copies,
+        paper_size,
+        rotation,
+        scaling,
+        page_range,
+        quality,
+        darkness,
+        raw_options,
+    };
+
+    // Decode once up front so each kind of bad payload gets its own error code
+    let (data, format) = match printer::decode_label(data, &format) {
+        Ok((bytes, format)) => (LabelData::Bytes(bytes), format),
+        Err(e) => {
+            log::warn!("Rejected print request {}: {}", request_id, e);
+            return ServerMessage::print_failure(request_id, payload_error_code(&e), e.to_string());
+        }
+    };
+
+    if is_dry_run(&options) {
+        return validate_print(request_id, &printer_name, &data, &format, &job);
+    }
+
+    let wait_for_completion = options.wait_for_completion.unwrap_or(false);
+    let (strategy, reason) = printer::copy_strategy(&printer_name, options.per_copy_jobs, job.copies);
+    if job.copies > 1 {
+        log::info!("Printing {} copies to {} as {:?} ({})", job.copies, printer_name, strategy, reason);
+    }
+    if strategy == CopyStrategy::PerCopyJobs {
+        let single = JobOptions { copies: 1, ..job.clone() };
+        let submit = || print_label(&printer_name, &data, &format, &single);
+        return print_per_copy(request_id, &printer_name, job.copies, config.print_retry, submit, on_progress);
+    }
+
+    // Print the label
+    let (result, attempts) =
+        printer::with_retries(config.print_retry, || print_label(&printer_name, &data, &format, &job));
+    match result {
+        Ok(job_id) if wait_for_completion => {
+            log::info!("Print job sent to {} (job: {:?}), waiting for it to finish", printer_name, job_id);
+            confirm_completion(request_id, &printer_name, job_id, attempts, config.completion_timeout)
+        }
+        Ok(job_id) => {
+            log::info!("Print job sent successfully to {} (job: {:?})", printer_name, job_id);
+            ServerMessage::PrintResult {
+                request_id,
+                success: true,
+                job_id,
+                message: Some(format!("Label sent to {}", printer_name)),
+                error: None,
+                code: None,
+                copy_results: None,
+                attempts: Some(attempts),
+                retry_after: None,
+            }
+        }
+        Err(e) => {
+            log::error!("Print failed after {} attempt(s): {}", attempts, e);
+            let (code, error) = print_failure_reason(&format, e);
+            ServerMessage::PrintResult {
+                request_id,
+                success: false,
+                job_id: None,
+                message: None,
+                error: Some(error),
+                code: Some(code),
+                copy_results: None,
+                attempts: Some(attempts),
+                retry_after: None,
+            }
+        }
+    }
+}
+
+/// Follow a spooled job until it leaves the queue and report how it ended
+fn confirm_completion(
+    request_id: String,
+    printer_name: &str,
+    job_id: Option<String>,
+    attempts: u32,
+    timeout: Duration,
+) -> ServerMessage {
+    let outcome = match &job_id {
+        Some(id) => job_state::wait_for_completion(printer_name, id, timeout),
+        None => JobOutcome::Unconfirmed,
+    };
+    log::info!("Job {:?} on {} ended as {:?}", job_id, printer_name, outcome);
+
+    let (success, message, error, code) = match outcome {
+        JobOutcome::Completed => (true, Some(format!("Label printed on {}", printer_name)), None, None),
+        JobOutcome::Unconfirmed => (
+            true,
+            Some(format!("Label sent to {}; the spooler can't confirm it printed", printer_name)),
+            None,
+            None,
+        ),
+        JobOutcome::Failed(reason) => (false, None, Some(reason), Some(ErrorCode::JobFailed)),
+        JobOutcome::TimedOut => (
+            false,
+            None,
+            Some(format!("Job was still queued after {} seconds", timeout.as_secs())),
+            Some(ErrorCode::CompletionTimeout),
+        ),
+    };
+    ServerMessage::PrintResult {
+        request_id,
+        success,
+        job_id,
+        message,
+        error,
+        code,
+        copy_results: None,
+        attempts: Some(attempts),
+        retry_after: None,
+    }
+}
+
+/// Cancel a job the spooler already has
+async fn handle_cancel_job(request_id: String, printer_id: Option<String>, job_id: String) -> ServerMessage {
+    // Finding the printer enumerates them, so it runs off the async threads with the cancel
+    let cancelled = log_context::spawn_blocking({
+        let job_id = job_id.clone();
+        move || -> Result<_, String> {
+            let printer_name = match printer_id {
+                Some(printer_id) => printer::find_printer(&printer_id).ok_or(printer_id)?,
+                None => String::new(),
+            };
+            Ok(printer::cancel_job(&printer_name, &job_id))
+        }
+    })
+    .await
+    .unwrap_or_else(|e| Ok(Err(printer::CancelError::Failed(format!("Cancel task failed: {}", e)))));
+
+    let cancelled = match cancelled {
+        Ok(cancelled) => cancelled,
+        Err(printer_id) => {
+            return cancel_result(
+                request_id,
+                Err((ErrorCode::PrinterNotFound, format!("Printer not found: {}", printer_id))),
+            );
+        }
+    };
+
+    match cancelled {
+        Ok(()) => {
+            log::info!("Cancelled job {} for {}", job_id, request_id);
+            cancel_result(request_id, Ok(format!("Cancelled job {}", job_id)))
+        }
+        Err(e @ printer::CancelError::NotFound) => {
+            log::info!("Job {} for {} not found to cancel", job_id, request_id);
+            cancel_result(request_id, Err((ErrorCode::JobNotFound, e.to_string())))
+        }
+        Err(e) => {
+            log::error!("Failed to cancel job {}: {}", job_id, e);
+            cancel_result(request_id, Err((ErrorCode::PrintFailed, e.to_string())))
+        }
+    }
+}
+
+/// Fill in a label template off the async threads, since choosing its format queries the driver
+async fn render_template(
+    printer_id: &str,
+    template: &str,
+    fields: BTreeMap<String, String>,
+) -> Result<(&'static str, Vec<u8>), String> {
+    let template = label_template::parse_template(template)?;
+    let printer_id = printer_id.to_string();
+    log_context::spawn_blocking(move || label_template::render_for_printer(&printer_id, template, &fields))
+        .await
+        .unwrap_or_else(|e| Err(format!("Template rendering failed: {}", e)))
+}
+
+/// Cancel everything in a printer's spooler queue
+async fn handle_clear_print_queue(printer_id: String) -> ServerMessage {
+    let cleared = log_context::spawn_blocking({
+        let printer_id = printer_id.clone();
+        move || {
+            let printer_name = printer::find_printer(&printer_id)?;
+            let cleared = printer::clear_queue(&printer_name);
+            Some((printer_name, cleared))
+        }
+    })
+    .await
+    .unwrap_or_else(|e| Some((printer_id.clone(), Err(format!("Clear queue task failed: {}", e)))));
+
+    match cleared {
+        None => ServerMessage::error(ErrorCode::PrinterNotFound, format!("Printer not found: {}", printer_id)),
+        Some((printer_name, Ok(removed))) => {
+            log::info!("Cleared {} job(s) from the queue of {}", removed, printer_name);
+            ServerMessage::ClearQueueResult {
+                printer: printer_id,
+                removed,
+            }
+        }
+        Some((printer_name, Err(e))) => {
+            log::error!("Failed to clear the queue of {}: {}", printer_name, e);
+            ServerMessage::error(ErrorCode::PrintFailed, e)
+        }
+    }
+}
+
+fn cancel_result(request_id: String, result: Result<String, (ErrorCode, String)>) -> ServerMessage {
+    match result {
+        Ok(message) => ServerMessage::CancelResult {
+            request_id,
+            success: true,
+            message: Some(message),
+            error: None,
+            code: None,
+        },
+        Err((code, error)) => ServerMessage::CancelResult {
+            request_id,
+            success: false,
+            message: None,
+            error: Some(error),
+            code: Some(code),
+        },
+    }
+}
+
+fn payload_error_code(error: &PayloadError) -> ErrorCode {
+    match error {
+        PayloadError::Empty => ErrorCode::EmptyPayload,
+        PayloadError::InvalidBase64(_) => ErrorCode::InvalidBase64,
+        PayloadError::EmptyDecoded => ErrorCode::EmptyDecoded,
+        PayloadError::FormatMismatch(_) => ErrorCode::FormatMismatch,
+        PayloadError::Invalid(_) => ErrorCode::InvalidMessage,
+    }
+}
+
+fn rate_limited(request_id: String, retry_after: Duration) -> ServerMessage {
+    log::warn!("Rate limited print {}, retry in {:?}", request_id, retry_after);
+    ServerMessage::rate_limited(request_id, retry_after)
+}
+
+/// Refuse a claimed print over the rate limit, releasing its id so the retry prints
+fn release_rate_limited(prints: &ClientPrints, request_id: String, dry_run: bool, retry_after: Duration) -> ServerMessage {
+    let response = rate_limited(request_id.clone(), retry_after);
+    release_claim(prints, &request_id, dry_run, &response);
+    response
+}
+
+fn is_dry_run(options: &PrintOptions) -> bool {
+    options.dry_run.unwrap_or(false)
+}
+
+/// A resent print's earlier result, or `None` after claiming its id for a new print
+///
+/// Dry runs print nothing, so they neither claim an id nor get a real print's result.
+fn claim_print(prints: &ClientPrints, request_id: &str, dry_run: bool) -> Option<EarlierResult> {
+    if dry_run {
+        None
+    } else {
+        prints.earlier_result(request_id)
+    }
+}
+
+/// Record the result of a print claimed with `claim_print`
+fn release_claim(prints: &ClientPrints, request_id: &str, dry_run: bool, response: &ServerMessage) {
+    if !dry_run {
+        prints.finish(request_id, response);
+    }
+}
+
+/// Answer a `dryRun` print: everything up to the print command, with the same error codes
+fn validate_print(request_id: String, printer_name: &str, data: &LabelData, format: &str, job: &JobOptions) -> ServerMessage {
+    match printer::prepare_label(printer_name, data, format, job) {
+        Ok(label) => {
+            log::info!("Dry run {} validated for {}", request_id, printer_name);
+            ServerMessage::PrintResult {
+                request_id,
+                success: true,
+                job_id: None,
+                message: Some(format!(
+                    "Dry run: would print {} cop{} of {} ({} bytes) to {}",
+                    job.copies,
+                    if job.copies == 1 { "y" } else { "ies" },
+                    label.format,
+                    label.data.len(),
+                    printer_name
+                )),
+                error: None,
+                code: None,
+                copy_results: None,
+                attempts: None,
+                retry_after: None,
+            }
+        }
+        Err(e) => {
+            log::warn!("Dry run {} failed validation: {}", request_id, e);
+            let (code, error) = print_failure_reason(format, e);
+            ServerMessage::print_failure(request_id, code, error)
+        }
+    }
+}
+
+/// The code and message for a failed print
+fn print_failure_reason(format: &str, e: String) -> (ErrorCode, String) {
+    // A missing backend explains the failure better than whatever the command said
+    match printer::print_backend_problem(printer::is_raw_format(format)) {
+        Some(problem) => (ErrorCode::NoPrintBackend, problem),
+        None => match printer::printer_unavailable_reason(&e) {
+            Some(reason) => (ErrorCode::PrinterUnavailable, reason),
+            None => (ErrorCode::PrintFailed, e),
+        },
+    }
+}
+
+/// Resolve the requested copy count: missing or 0 means 1, above `max` is rejected
+fn validate_copies(copies: Option<u32>, max: u32) -> Result<u32, String> {
+    match copies.unwrap_or(1) {
+        0 => Ok(1),
+        n if n > max => Err(format!("Too many copies: {} (maximum {})", n, max)),
+        n => Ok(n),
+    }
+}
+
+/// Submit each copy as its own job and report the outcome of every copy
+///
+/// `submit` sends one copy to the spooler; `on_progress(completed, total)` is called after
+/// each copy is submitted.
+fn print_per_copy(
+    request_id: String,
+    printer_name: &str,
+    copies: u32,
+    retry: RetryPolicy,
+    mut submit: impl FnMut() -> Result<Option<String>, String>,
+    mut on_progress: impl FnMut(u32, u32),
+) -> ServerMessage {
+    let results: Vec<CopyResult> = (1..=copies)
+        .map(|copy| {
+            let result = print_copy(&mut submit, retry, copy, copies);
+            on_progress(copy, copies);
+            result
+        })
+        .collect();
+
+    let failed = results.iter().filter(|r| !r.success).count();
+    log::info!("Sent {} of {} copies to {}", results.len() - failed, copies, printer_name);
+
+    ServerMessage::PrintResult {
+        request_id,
+        success: failed == 0,
+        job_id: None,
+        message: Some(format!("{} of {} copies sent to {}", results.len() - failed, copies, printer_name)),
+        error: (failed > 0).then(|| format!("{} of {} copies failed", failed, copies)),
+        code: (failed > 0).then_some(ErrorCode::PrintFailed),
+        copy_results: Some(results),
+        attempts: None,
+        retry_after: None,
+    }
+}
+
+/// Submit one copy of a per-copy job
+fn print_copy(
+    submit: &mut impl FnMut() -> Result<Option<String>, String>,
+    retry: RetryPolicy,
+    copy: u32,
+    copies: u32,
+) -> CopyResult {
+    let (result, attempts) = printer::with_retries(retry, submit);
+    match result {
+        Ok(job_id) => CopyResult {
+            copy,
+            success: true,
+            job_id,
+            error: None,
+            attempts,
+        },
+        Err(e) => {
+            log::error!("Copy {} of {} failed: {}", copy, copies, e);
+            CopyResult {
+                copy,
+                success: false,
+                job_id: None,
+                error: Some(e),
+                attempts,
+            }
+        }
+    }
+}
+
+async fn handle_render_preview(
+    request_id: Option<String>,
+    format: String,
+    data: String,
+    options: PrintOptions,
+) -> ServerMessage {
+    let rotation = match options.rotate.map(printer::parse_rotation).transpose() {
+        Ok(rotation) => rotation.unwrap_or_default(),
+        Err(e) => return ServerMessage::error(ErrorCode::InvalidRotation, e),
+    };
+    let scaling = match options.scale.as_deref().map(printer::parse_scaling).transpose() {
+        Ok(scaling) => scaling,
+        Err(e) => return ServerMessage::error(ErrorCode::InvalidScale, e),
+    };
+    let paper_size = match options.paper_size.as_deref().map(printer::parse_paper_size).transpose() {
+        Ok(paper_size) => paper_size,
+        Err(e) => return ServerMessage::error(ErrorCode::InvalidPaperSize, e),
+    };
+    let (data, format) = match printer::decode_payload(&data, &format) {
+        Ok(decoded) => decoded,
+        Err(e) => return ServerMessage::error(ErrorCode::PreviewFailed, e),
+    };
+
+    match preview::render_preview(&format, data, rotation, scaling, paper_size).await {
+        Ok(preview) => ServerMessage::Preview {
+            request_id,
+            image: STANDARD.encode(&preview.png),
+            width: preview.width,
+            height: preview.height,
+        },
+        Err(e) => {
+            log::warn!("Preview failed: {}", e);
+            ServerMessage::error(ErrorCode::PreviewFailed, e)
+        }
+    }
+}
+
+/// Next record for a log subscriber; only polled while one exists
+async fn next_log(subscription: &Option<LogSubscription>) -> ServerMessage {
+    match subscription {
+        Some(subscription) => subscription.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Enumerate printers off the async threads; the startup retry can sleep for a few seconds
+async fn current_printers(started_at: Instant) -> PrinterList {
+    let uptime = started_at.elapsed();
+    log_context::spawn_blocking(move || printer::list_printers_at_startup(uptime))
+        .await
+        .unwrap_or_else(|e| PrinterList {
+            printers: Vec::new(),
+            error: Some(format!("Printer enumeration failed: {}", e)),
+        })
+}
+
+async fn handle_get_printer(printer_id: String) -> ServerMessage {
+    let printer = log_context::spawn_blocking({
+        let printer_id = printer_id.clone();
+        move || printer::get_printer(&printer_id)
+    })
+    .await
+    .unwrap_or_else(|e| {
+        log::error!("Printer lookup failed: {}", e);
+        None
+    });
+    if printer.is_none() {
+        log::info!("get_printer: no printer matches '{}'", printer_id);
+    }
+    ServerMessage::Printer { printer }
+}
+
+async fn handle_get_default_thermal_printer() -> ServerMessage {
+    let printer = log_context::spawn_blocking(printer::default_thermal_printer)
+        .await
+        .unwrap_or_else(|e| {
+            log::error!("Thermal printer lookup failed: {}", e);
+            None
+        });
+    ServerMessage::DefaultThermalPrinter { printer }
+}
+
+fn printers_message(list: PrinterList) -> ServerMessage {
+    ServerMessage::Printers {
+        printers: list.printers,
+        enumeration_error: list.error,
+    }
+}
+
+/// Pin the default printer off the async threads, since finding it and relisting query the spooler
+async fn handle_set_default_printer(printer_id: String) -> ServerMessage {
+    log_context::spawn_blocking(move || pin_default_printer(printer_id))
+        .await
+        .unwrap_or_else(|e| ServerMessage::error(ErrorCode::ConfigError, format!("Setting the default printer failed: {}", e)))
+}
+
+fn pin_default_printer(printer_id: String) -> ServerMessage {
+    let Some(printer_name) = printer::find_printer(&printer_id) else {
+        return ServerMessage::error(ErrorCode::PrinterNotFound, format!("Printer not found: {}", printer_id));
+    };
+
+    match printer::set_default_printer(&printer_name) {
+        Ok(()) => {
+            log::info!("Pinned default printer: {}", printer_name);
+            printers_message(printer::list_printers())
+        }
+        Err(e) => {
+            log::error!("Failed to save default printer: {}", e);
+            ServerMessage::error(ErrorCode::ConfigError, e)
+        }
+    }
+}
+
+/// Save a nickname off the async threads, since finding the printer and relisting query the spooler
+async fn handle_set_printer_nickname(printer_id: String, nickname: String) -> ServerMessage {
+    log_context::spawn_blocking(move || save_printer_nickname(printer_id, nickname))
+        .await
+        .unwrap_or_else(|e| ServerMessage::error(ErrorCode::ConfigError, format!("Setting the nickname failed: {}", e)))
+}
+
+fn save_printer_nickname(printer_id: String, nickname: String) -> ServerMessage {
+    let Some(printer_name) = printer::find_printer(&printer_id) else {
+        return ServerMessage::error(ErrorCode::PrinterNotFound, format!("Printer not found: {}", printer_id));
+    };
+
+    match printer::set_printer_nickname(&printer_name, &nickname) {
+        Ok(()) => {
+            log::info!("Set nickname for {}: {:?}", printer_name, nickname.trim());
+            printers_message(printer::list_printers())
+        }
+        Err(e) => {
+            log::error!("Failed to save printer nickname: {}", e);
+            ServerMessage::error(ErrorCode::ConfigError, e)
+        }
+    }
+}
+
+fn handle_open_url(url: String, allowed_origins: &[String]) -> ServerMessage {
+    let parsed = match origins::url_allowed(&url, allowed_origins) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            log::warn!("Refused to open {}: {}", url, e);
+            return ServerMessage::error(ErrorCode::UrlNotAllowed, e);
+        }
+    };
+
+    match open::that(parsed.as_str()) {
+        Ok(()) => {
+            log::info!("Opened {} in the browser", parsed);
+            ServerMessage::UrlOpened { url: parsed.into() }
+        }
+        Err(e) => {
+            log::error!("Failed to open {}: {}", parsed, e);
+            ServerMessage::error(ErrorCode::OpenUrlFailed, format!("Failed to open the browser: {}", e))
+        }
+    }
+}
+
+/// Diagnose off the async threads, since it enumerates printers and queries the spooler
+async fn handle_diagnose_printer(printer_id: String) -> ServerMessage {
+    let diagnosis = log_context::spawn_blocking({
+        let printer_id = printer_id.clone();
+        move || printer::diagnose_printer(&printer_id)
+    })
+    .await
+    .unwrap_or_else(|e| printer::PrinterDiagnosis {
+        matched: None,
+        suggestions: Vec::new(),
+        known_ids: Vec::new(),
+        spooler_error: Some(format!("Diagnosis failed: {}", e)),
+    });
+    log::info!(
+        "Diagnosed printer id '{}': matched {:?}, suggested {:?}",
+        printer_id,
+        diagnosis.matched,
+        diagnosis.suggestions
+    );
+    ServerMessage::PrinterDiagnosis {
+        printer: printer_id,
+        matched: diagnosis.matched,
+        suggestions: diagnosis.suggestions,
+        known_printers: diagnosis.known_ids,
+        spooler_available: diagnosis.spooler_error.is_none(),
+        spooler_error: diagnosis.spooler_error,
+    }
+}
+
+/// List scales off the async threads, since enumerating HID devices can block
+async fn handle_get_scales() -> ServerMessage {
+    let scales = log_context::spawn_blocking(scale::list_scales).await.unwrap_or_else(|e| {
+        log::error!("Listing scales failed: {}", e);
+        Vec::new()
+    });
+    ServerMessage::Scales { scales }
+}
+
+/// Read off the async threads, since opening a scale and waiting for its report block
+async fn handle_read_scale(id: Option<String>) -> ServerMessage {
+    let reading = log_context::spawn_blocking(move || scale::read_scale(id.as_deref())).await;
+    scale_message(reading.unwrap_or_else(|e| Err(ScaleError::Device(format!("Scale read failed: {}", e)))))
+}
+
+async fn handle_tare_scale(id: Option<String>) -> ServerMessage {
+    log::info!("Taring scale {}", id.as_deref().unwrap_or("(default)"));
+    let reading = log_context::spawn_blocking(move || scale::tare_scale(id.as_deref())).await;
+    scale_message(reading.unwrap_or_else(|e| Err(ScaleError::Device(format!("Scale tare failed: {}", e)))))
+}
+
+/// Open the scale on a blocking thread and forward readings until the stream is dropped
+fn start_scale_stream(id: Option<String>, response_tx: mpsc::UnboundedSender<ServerMessage>) -> ScaleStream {
+    let active = Arc::new(AtomicBool::new(true));
+    let stream = ScaleStream(active.clone());
+
+    log_context::spawn_blocking(move || {
+        let mut scale = match Scale::open(id.as_deref()) {
+            Ok(scale) => scale,
+            Err(e) => {
+                let _ = response_tx.send(scale_message(Err(e)));
+                return;
+            }
+        };
+
+        // Sending fails once the connection is gone, which also ends the stream
+        scale::stream_readings(|timeout_ms| scale.read_timeout(timeout_ms), &active, |result| response_tx.send(scale_message(result)).is_ok());
+        log::debug!("Scale stream ended");
+    });
+
+    stream
+}
+
+/// Convert a scale read result into the message sent to the browser
+fn scale_message(result: Result<ScaleReading, ScaleError>) -> ServerMessage {
+    match result {
+        Ok(reading) => {
+            log::debug!("Scale reading: {} {} (stable: {})", reading.weight, reading.unit, reading.stable);
+            ServerMessage::ScaleReading {
+                weight: reading.weight,
+                unit: reading.unit,
+                stable: reading.stable,
+            }
+        }
+        Err(ScaleError::NotConnected) => {
+            log::warn!("Scale read requested but no scale is connected");
+            ServerMessage::error(ErrorCode::ScaleNotFound, ScaleError::NotConnected.to_string())
+        }
+        Err(e @ ScaleError::UnknownScale(_)) => {
+            log::warn!("Scale requested by an unknown id: {}", e);
+            ServerMessage::error(ErrorCode::ScaleNotFound, e.to_string())
+        }
+        Err(ScaleError::TareUnsupported) => {
+            log::warn!("Tare requested but the scale doesn't support it");
+            ServerMessage::error(ErrorCode::TareUnsupported, ScaleError::TareUnsupported.to_string())
+        }
         Err(e) => {
-            log::error!("Print failed: {}", e);
+            log::error!("Scale read failed: {}", e);
+            ServerMessage::error(ErrorCode::ScaleError, e.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{PrinterCapabilities, PrinterInfo, PrinterStatus};
+    use tokio::io::DuplexStream;
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::WebSocketStream;
+
+    const TEST_ORIGIN: &str = "https://app.limestack.io";
+
+    /// A context like `start_server` builds, long enough after startup that listing printers
+    /// doesn't wait out the startup retries
+    fn test_context(config: ServerConfig) -> ConnectionContext {
+        let (status, _) = watch::channel(ServerStatus::default());
+        let (_, shutdown) = watch::channel(None);
+        let (printer_changes, _) = broadcast::channel(PRINTER_CHANGE_BUFFER);
+        ConnectionContext {
+            print_permits: Arc::new(Semaphore::new(config.max_concurrent_prints)),
+            connection_slots: Arc::new(Semaphore::new(config.max_connections)),
+            recent_prints: RecentPrints::new(config.print_dedupe_ttl),
+            config: Arc::new(config),
+            status: Arc::new(status),
+            shutdown,
+            started_at: Instant::now().checked_sub(Duration::from_secs(3600)).unwrap_or_else(Instant::now),
+            listeners: Listeners {
+                ipv4: true,
+                ipv6: false,
+            },
+            config_generation: 0,
+            printer_changes,
+            accepting: Box::leak(Box::new(AtomicBool::new(true))),
+        }
+    }
+
+    /// Serve one connection over an in-memory stream and open it the way a page at `origin` would
+    async fn connect(
+        ctx: ConnectionContext,
+        origin: Option<&str>,
+    ) -> Result<(WebSocketStream<DuplexStream>, tokio::task::JoinHandle<()>), WsError> {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let connection = tokio::spawn(handle_connection(server, ctx, false));
+        let mut request = "ws://127.0.0.1:9632/".into_client_request()?;
+        if let Some(origin) = origin {
+            request.headers_mut().insert("origin", origin.parse().unwrap());
+        }
+        let (ws, _) = tokio_tungstenite::client_async(request, client).await?;
+        Ok((ws, connection))
+    }
+
+    fn heartbeat_config() -> ServerConfig {
+        ServerConfig {
+            ping_interval: Duration::from_millis(50),
+            pong_timeout: Duration::from_millis(50),
+            ..ServerConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn silent_peer_is_dropped_after_pong_timeout() {
+        // Connected, but never reads, so the ping is never answered
+        let (_client, connection) = connect(test_context(heartbeat_config()), Some(TEST_ORIGIN)).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(2), connection)
+            .await
+            .expect("connection should close without a pong")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn answering_peer_stays_connected() {
+        let (mut client, connection) = connect(test_context(heartbeat_config()), Some(TEST_ORIGIN)).await.unwrap();
+        // Reading is enough: tungstenite answers each ping as it's read
+        tokio::spawn(async move { while let Some(Ok(_)) = client.next().await {} });
+
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert!(!connection.is_finished(), "connection closed although every ping was answered");
+        connection.abort();
+    }
+
+    fn idle_config() -> ServerConfig {
+        ServerConfig {
+            idle_timeout: Duration::from_millis(150),
+            ..ServerConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn idle_connection_is_closed_with_a_reason() {
+        let (mut ws, connection) = connect(test_context(idle_config()), Some(TEST_ORIGIN)).await.unwrap();
+        authenticate(&mut ws).await;
+
+        // Nothing is sent after authenticating
+        let started = Instant::now();
+        let frame = recv_close(&mut ws).await.expect("close frame without a reason");
+        assert!(started.elapsed() >= Duration::from_millis(150), "closed before the idle timeout");
+        assert_eq!(frame.code, CloseCode::Normal);
+        assert_eq!(frame.reason, "Idle timeout");
+        tokio::time::timeout(Duration::from_secs(1), connection).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn client_messages_reset_the_idle_timer() {
+        let (mut ws, connection) = connect(test_context(idle_config()), Some(TEST_ORIGIN)).await.unwrap();
+        for _ in 0..6 {
+            tokio::time::sleep(Duration::from_millis(75)).await;
+            send_json(&mut ws, serde_json::json!({ "type": "ping" })).await;
+            assert_eq!(recv_json(&mut ws).await["type"], "pong");
+        }
+        assert!(!connection.is_finished(), "closed although messages kept arriving");
+    }
+
+    #[tokio::test]
+    async fn print_in_flight_keeps_an_idle_connection_open() {
+        let ctx = test_context(ServerConfig {
+            max_concurrent_prints: 1,
+            ..idle_config()
+        });
+        // The print waits for this permit, well past the idle timeout
+        let busy = ctx.print_permits.clone().acquire_owned().await.unwrap();
+        let (mut ws, connection) = connect(ctx, Some(TEST_ORIGIN)).await.unwrap();
+        authenticate(&mut ws).await;
+        send_json(
+            &mut ws,
+            serde_json::json!({
+                "type": "print_binary",
+                "requestId": "label-1",
+                "printer": "Zebra_ZP450",
+                "format": "zpl",
+                "options": { "dryRun": true },
+            }),
+        )
+        .await;
+        ws.send(Message::Binary(binary_frame("label-1", b"^XA^XZ"))).await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert!(!connection.is_finished(), "closed while a print was in flight");
+
+        drop(busy);
+        while recv_json(&mut ws).await["type"] != "print_result" {}
+        // Once the print reports back the connection is idle again
+        let frame = recv_close(&mut ws).await.expect("close frame without a reason");
+        assert_eq!(frame.reason, "Idle timeout");
+    }
+
+    const NO_RETRIES: RetryPolicy = RetryPolicy {
+        max_retries: 0,
+        initial_backoff: Duration::ZERO,
+    };
+
+    fn copy_results(response: ServerMessage) -> (bool, Option<ErrorCode>, Vec<CopyResult>) {
+        match response {
+            ServerMessage::PrintResult {
+                success,
+                code,
+                copy_results: Some(results),
+                ..
+            } => (success, code, results),
+            other => panic!("expected a print result with copy results, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn each_copy_is_submitted_as_its_own_job() {
+        let mut submitted = 0;
+        let mut progress = Vec::new();
+        let response = print_per_copy(
+            "job-1".to_string(),
+            "Zebra",
+            3,
+            NO_RETRIES,
+            || {
+                submitted += 1;
+                Ok(Some(format!("Zebra-{}", submitted)))
+            },
+            |completed, total| progress.push((completed, total)),
+        );
+
+        let (success, code, results) = copy_results(response);
+        assert!(success);
+        assert_eq!(code, None);
+        assert_eq!(progress, [(1, 3), (2, 3), (3, 3)]);
+        let job_ids: Vec<_> = results.iter().map(|r| (r.copy, r.job_id.as_deref())).collect();
+        assert_eq!(job_ids, [(1, Some("Zebra-1")), (2, Some("Zebra-2")), (3, Some("Zebra-3"))]);
+    }
+
+    #[test]
+    fn a_failed_copy_fails_the_request_but_not_the_other_copies() {
+        let mut submitted = 0;
+        let response = print_per_copy(
+            "job-1".to_string(),
+            "Zebra",
+            5,
+            NO_RETRIES,
+            || {
+                submitted += 1;
+                match submitted {
+                    3 => Err("lp: Error - paper jam".to_string()),
+                    n => Ok(Some(format!("Zebra-{}", n))),
+                }
+            },
+            |_, _| {},
+        );
+
+        let (success, code, results) = copy_results(response);
+        assert!(!success);
+        assert_eq!(code, Some(ErrorCode::PrintFailed));
+        assert_eq!(results.len(), 5);
+        let failed: Vec<_> = results.iter().filter(|r| !r.success).map(|r| r.copy).collect();
+        assert_eq!(failed, [3]);
+        assert_eq!(results[2].error.as_deref(), Some("lp: Error - paper jam"));
+        assert_eq!(results[3].job_id.as_deref(), Some("Zebra-4"));
+    }
+
+    #[test]
+    fn copies_retry_on_their_own() {
+        let mut submitted = 0;
+        let retry = RetryPolicy {
+            max_retries: 1,
+            initial_backoff: Duration::ZERO,
+        };
+        let response = print_per_copy(
+            "job-1".to_string(),
+            "Zebra",
+            2,
+            retry,
+            || {
+                submitted += 1;
+                match submitted {
+                    2 => Err("Printer is busy".to_string()),
+                    n => Ok(Some(format!("Zebra-{}", n))),
+                }
+            },
+            |_, _| {},
+        );
+
+        let (success, _, results) = copy_results(response);
+        assert!(success);
+        let attempts: Vec<_> = results.iter().map(|r| r.attempts).collect();
+        assert_eq!(attempts, [1, 2]);
+    }
+
+    #[test]
+    fn client_count_survives_concurrent_connects_and_drops() {
+        let (status, _) = watch::channel(ServerStatus::default());
+        let status = Arc::new(status);
+
+        let held: Vec<ClientGuard> = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        for _ in 0..100 {
+                            drop(ClientGuard::new(status.clone()));
+                        }
+                        ClientGuard::new(status.clone())
+                    })
+                })
+                .collect();
+            workers.into_iter().map(|worker| worker.join().unwrap()).collect()
+        });
+        assert_eq!(status.borrow().connected_clients, 8);
+
+        drop(held);
+        assert_eq!(status.borrow().connected_clients, 0);
+    }
+
+    #[test]
+    fn client_count_never_goes_below_zero() {
+        let (status, _) = watch::channel(ServerStatus::default());
+        let status = Arc::new(status);
+        let guard = ClientGuard::new(status.clone());
+        status.send_modify(|s| s.connected_clients = 0);
+        drop(guard);
+        assert_eq!(status.borrow().connected_clients, 0);
+    }
+
+    fn print_code(response: &ServerMessage) -> Option<ErrorCode> {
+        match response {
+            ServerMessage::PrintResult { code, .. } => *code,
+            other => panic!("expected a print result, got {:?}", other),
+        }
+    }
+
+    fn sent(request_id: &str) -> ServerMessage {
+        ServerMessage::PrintResult {
+            request_id: request_id.to_string(),
+            success: true,
+            job_id: None,
+            message: None,
+            error: None,
+            code: None,
+            copy_results: None,
+            attempts: Some(1),
+            retry_after: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn hung_print_times_out() {
+        let queue = PrintQueue::new(Arc::new(Semaphore::new(1)));
+        let cancelled = queue.enqueue("job-1");
+        let (progress_tx, _progress_rx) = mpsc::unbounded_channel();
+
+        let started = std::time::Instant::now();
+        let response = run_queued(
+            "job-1".to_string(),
+            Duration::from_millis(50),
+            Duration::from_secs(5),
+            queue,
+            cancelled,
+            progress_tx,
+            |_| {
+                std::thread::sleep(Duration::from_millis(500));
+                sent("job-1")
+            },
+        )
+        .await;
+
+        assert_eq!(print_code(&response), Some(ErrorCode::Timeout));
+        assert!(started.elapsed() < Duration::from_millis(400), "waited for the hung print");
+    }
+
+    #[tokio::test]
+    async fn quick_print_reports_its_result_and_progress() {
+        let queue = PrintQueue::new(Arc::new(Semaphore::new(1)));
+        let cancelled = queue.enqueue("job-1");
+        let (progress_tx, mut progress_rx) = mpsc::unbounded_channel();
+
+        let response = run_queued(
+            "job-1".to_string(),
+            Duration::from_secs(5),
+            Duration::from_secs(5),
+            queue,
+            cancelled,
+            progress_tx,
+            |on_progress| {
+                on_progress(1, 1);
+                sent("job-1")
+            },
+        )
+        .await;
+
+        assert_eq!(print_code(&response), None);
+        assert!(matches!(
+            progress_rx.recv().await,
+            Some(ServerMessage::PrintProgress {
+                completed: 1,
+                total: 1,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn print_waiting_for_a_slot_gives_up_after_the_queue_timeout() {
+        let permits = Arc::new(Semaphore::new(1));
+        let _busy = permits.clone().acquire_owned().await.unwrap();
+        let queue = PrintQueue::new(permits);
+        let cancelled = queue.enqueue("job-1");
+        let (progress_tx, _progress_rx) = mpsc::unbounded_channel();
+
+        let response = run_queued(
+            "job-1".to_string(),
+            Duration::from_secs(5),
+            Duration::from_millis(50),
+            queue,
+            cancelled,
+            progress_tx,
+            |_| sent("job-1"),
+        )
+        .await;
+        assert_eq!(print_code(&response), Some(ErrorCode::QueueTimeout));
+    }
+
+    fn batch_jobs(printers: &[&str]) -> Vec<BatchJob> {
+        printers
+            .iter()
+            .map(|printer| BatchJob {
+                printer: printer.to_string(),
+                format: "zpl".to_string(),
+                data: STANDARD.encode("^XA^XZ"),
+                options: PrintOptions::default(),
+            })
+            .collect()
+    }
+
+    /// Print a batch where every job for `failing_printer` fails, returning the job ids printed
+    async fn run_batch(
+        printers: &[&str],
+        failing_printer: &str,
+        stop_on_failure: bool,
+    ) -> (bool, Vec<BatchJobResult>, Vec<String>) {
+        let mut printed = Vec::new();
+        let (success, results) = print_batch("batch-1", batch_jobs(printers), stop_on_failure, |request| {
+            printed.push(request.request_id.clone());
+            let response = if request.printer_id == failing_printer {
+                ServerMessage::print_failure(request.request_id, ErrorCode::PrinterUnavailable, "Printer is offline")
+            } else {
+                sent(&request.request_id)
+            };
+            async move { response }
+        })
+        .await;
+        (success, results, printed)
+    }
+
+    fn batch_codes(results: &[BatchJobResult]) -> Vec<(usize, bool, Option<ErrorCode>)> {
+        results.iter().map(|r| (r.index, r.success, r.code)).collect()
+    }
+
+    #[tokio::test]
+    async fn batch_prints_every_job_in_order() {
+        let (success, results, printed) = run_batch(&["Zebra_ZP450", "HP_LaserJet"], "", true).await;
+        assert!(success);
+        assert_eq!(printed, ["batch-1:0", "batch-1:1"]);
+        assert_eq!(batch_codes(&results), [(0, true, None), (1, true, None)]);
+        assert_eq!(results[1].printer, "HP_LaserJet");
+    }
+
+    #[tokio::test]
+    async fn batch_carries_on_after_a_failure_by_default() {
+        let printers = ["Zebra_ZP450", "HP_LaserJet", "Zebra_ZP450"];
+        let (success, results, printed) = run_batch(&printers, "HP_LaserJet", false).await;
+        assert!(!success);
+        assert_eq!(printed, ["batch-1:0", "batch-1:1", "batch-1:2"]);
+        assert_eq!(
+            batch_codes(&results),
+            [(0, true, None), (1, false, Some(ErrorCode::PrinterUnavailable)), (2, true, None)]
+        );
+        assert_eq!(results[1].error.as_deref(), Some("Printer is offline"));
+    }
+
+    #[tokio::test]
+    async fn batch_skips_the_rest_after_a_failure_when_asked() {
+        let printers = ["Zebra_ZP450", "HP_LaserJet", "Zebra_ZP450"];
+        let (success, results, printed) = run_batch(&printers, "HP_LaserJet", true).await;
+        assert!(!success);
+        // The job after the failure never reached a printer
+        assert_eq!(printed, ["batch-1:0", "batch-1:1"]);
+        assert_eq!(
+            batch_codes(&results),
+            [
+                (0, true, None),
+                (1, false, Some(ErrorCode::PrinterUnavailable)),
+                (2, false, Some(ErrorCode::Skipped))
+            ]
+        );
+    }
+
+    fn zpl_request(request_id: &str, data: &str, options: serde_json::Value) -> PrintRequest {
+        PrintRequest {
+            request_id: request_id.to_string(),
+            printer_id: "Zebra_ZP450".to_string(),
+            format: "zpl".to_string(),
+            data: LabelData::Base64(data.to_string()),
+            options: serde_json::from_value(options).unwrap(),
+        }
+    }
+
+    /// Handle a request against one known printer, counting how many labels reach the spooler
+    fn print_counting(request: PrintRequest) -> (ServerMessage, usize) {
+        let printed = std::cell::Cell::new(0);
+        let response = handle_print_request_with(
+            request,
+            &ServerConfig::default(),
+            |_, _| {},
+            |printer_id| (printer_id == "Zebra_ZP450").then(|| printer_id.to_string()),
+            |_, _, _, _| {
+                printed.set(printed.get() + 1);
+                Ok(Some("Zebra_ZP450-1".to_string()))
+            },
+        );
+        (response, printed.get())
+    }
+
+    #[test]
+    fn out_of_range_quality_and_darkness_are_refused_before_printing() {
+        let zpl = STANDARD.encode("^XA^XZ");
+        for (options, code) in [
+            (serde_json::json!({ "quality": "best" }), ErrorCode::InvalidQuality),
+            (serde_json::json!({ "darkness": 31 }), ErrorCode::InvalidDarkness),
+        ] {
+            let (response, printed) = print_counting(zpl_request("label-1", &zpl, options.clone()));
+            assert_eq!(print_code(&response), Some(code), "{}", options);
+            assert_eq!(printed, 0);
+        }
+    }
+
+    #[test]
+    fn quality_and_darkness_reach_the_printer() {
+        let options = serde_json::json!({ "quality": "draft", "darkness": 12 });
+        let request = zpl_request("label-1", &STANDARD.encode("^XA^XZ"), options);
+        let seen = std::cell::Cell::new(None);
+        let response = handle_print_request_with(
+            request,
+            &ServerConfig::default(),
+            |_, _| {},
+            |printer_id| Some(printer_id.to_string()),
+            |_, _, _, options| {
+                seen.set(Some((options.quality, options.darkness)));
+                Ok(None)
+            },
+        );
+        assert_eq!(print_code(&response), None);
+        assert_eq!(seen.get(), Some((Some(printer::Quality::Draft), Some(12))));
+    }
+
+    #[test]
+    fn payload_problems_get_their_own_codes() {
+        let pdf_request = |data: &str| PrintRequest {
+            format: "pdf".to_string(),
+            ..zpl_request("label-1", data, serde_json::json!({}))
+        };
+        let cases = [
+            (zpl_request("label-1", "", serde_json::json!({})), ErrorCode::EmptyPayload),
+            (zpl_request("label-1", "not base64!", serde_json::json!({})), ErrorCode::InvalidBase64),
+            (pdf_request(&STANDARD.encode("^XA^XZ")), ErrorCode::FormatMismatch),
+        ];
+        for (request, expected) in cases {
+            let (response, printed) = print_counting(request);
+            assert_eq!(printed, 0);
+            assert_eq!(print_code(&response), Some(expected));
+        }
+    }
+
+    #[test]
+    fn payload_errors_map_to_codes() {
+        assert_eq!(payload_error_code(&PayloadError::Empty), ErrorCode::EmptyPayload);
+        assert_eq!(payload_error_code(&PayloadError::InvalidBase64("bad".to_string())), ErrorCode::InvalidBase64);
+        assert_eq!(payload_error_code(&PayloadError::EmptyDecoded), ErrorCode::EmptyDecoded);
+        assert_eq!(payload_error_code(&PayloadError::FormatMismatch("pdf".to_string())), ErrorCode::FormatMismatch);
+        assert_eq!(payload_error_code(&PayloadError::Invalid("auto".to_string())), ErrorCode::InvalidMessage);
+    }
+
+    #[test]
+    fn dry_run_validates_without_printing() {
+        let options = serde_json::json!({ "dryRun": true, "copies": 2 });
+        let (response, printed) = print_counting(zpl_request("label-1", &STANDARD.encode("^XA^XZ"), options));
+        assert_eq!(printed, 0);
+        match response {
             ServerMessage::PrintResult {
+                success: true,
+                message: Some(message),
+                job_id: None,
+                ..
+            } => assert_eq!(message, "Dry run: would print 2 copies of zpl (6 bytes) to Zebra_ZP450"),
+            other => panic!("expected a successful dry run, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn dry_run_reports_the_same_errors_as_printing() {
+        let label = STANDARD.encode("^XA^XZ");
+        let cases: [(fn(&str, serde_json::Value) -> PrintRequest, serde_json::Value, ErrorCode); 8] = [
+            (|_, options| zpl_request("label-1", "not base64!", options), serde_json::json!({}), ErrorCode::InvalidBase64),
+            (|label, options| zpl_request("label-2", label, options), serde_json::json!({ "copies": 1000 }), ErrorCode::InvalidCopies),
+            (|label, options| zpl_request("label-3", label, options), serde_json::json!({ "rotate": 45 }), ErrorCode::InvalidRotation),
+            (|label, options| zpl_request("label-4", label, options), serde_json::json!({ "paperSize": "huge" }), ErrorCode::InvalidPaperSize),
+            (
+                |label, options| PrintRequest {
+                    printer_id: "Missing_Printer".to_string(),
+                    ..zpl_request("label-5", label, options)
+                },
+                serde_json::json!({}),
+                ErrorCode::PrinterNotFound,
+            ),
+            (
+                |_, options| zpl_request("label-6", "data:application/zpl;base64", options),
+                serde_json::json!({}),
+                ErrorCode::InvalidMessage,
+            ),
+            (
+                |_, options| PrintRequest {
+                    format: "auto".to_string(),
+                    data: LabelData::Bytes(b"^XA^XZ".to_vec()),
+                    ..zpl_request("label-7", "", options)
+                },
+                serde_json::json!({}),
+                ErrorCode::InvalidMessage,
+            ),
+            (
+                |_, options| PrintRequest {
+                    format: "pdf".to_string(),
+                    ..zpl_request("label-8", &STANDARD.encode("^XA^XZ"), options)
+                },
+                serde_json::json!({}),
+                ErrorCode::FormatMismatch,
+            ),
+        ];
+
+        for (request, options, expected) in cases {
+            let mut dry_run = options.clone();
+            dry_run["dryRun"] = serde_json::json!(true);
+            let (checked, printed) = print_counting(request(&label, dry_run));
+            assert_eq!(printed, 0, "{:?} was printed", checked);
+            assert_eq!(print_code(&checked), Some(expected), "{:?}", checked);
+
+            // The real print fails the same way
+            let (response, printed) = print_counting(request(&label, options));
+            assert_eq!(printed, 0, "{:?} was printed", response);
+            assert_eq!(print_code(&response), Some(expected), "{:?}", response);
+        }
+    }
+
+    #[test]
+    fn same_request_without_dry_run_prints_once() {
+        let (response, printed) = print_counting(zpl_request("label-1", &STANDARD.encode("^XA^XZ"), serde_json::json!({})));
+        assert_eq!(printed, 1);
+        assert_eq!(print_code(&response), None, "{:?}", response);
+    }
+
+    /// Print the way the connection does: a resend gets the earlier result, anything else is
+    /// handled and its result kept for resends
+    fn print_deduplicated(prints: &ClientPrints, request: PrintRequest) -> (ServerMessage, usize) {
+        let request_id = request.request_id.clone();
+        let dry_run = is_dry_run(&request.options);
+        match claim_print(prints, &request_id, dry_run) {
+            Some(EarlierResult::Finished(response)) => (response, 0),
+            Some(EarlierResult::Running(_)) => panic!("{} is still printing", request_id),
+            None => {
+                let (response, printed) = print_counting(request);
+                release_claim(prints, &request_id, dry_run, &response);
+                (response, printed)
+            }
+        }
+    }
+
+    #[test]
+    fn dry_run_is_not_the_result_of_a_later_print() {
+        let prints = RecentPrints::new(Duration::from_secs(600)).for_client(TEST_ORIGIN.to_string());
+        let label = STANDARD.encode("^XA^XZ");
+
+        let (dry_run, printed) = print_deduplicated(&prints, zpl_request("label-1", &label, serde_json::json!({ "dryRun": true })));
+        assert_eq!(printed, 0);
+        assert_eq!(print_code(&dry_run), None, "{:?}", dry_run);
+
+        let (response, printed) = print_deduplicated(&prints, zpl_request("label-1", &label, serde_json::json!({})));
+        assert_eq!(printed, 1);
+        assert!(matches!(&response, ServerMessage::PrintResult { job_id: Some(_), .. }), "{:?}", response);
+
+        // The real print is what a resend gets
+        let (resent, printed) = print_deduplicated(&prints, zpl_request("label-1", &label, serde_json::json!({})));
+        assert_eq!(printed, 0);
+        assert_eq!(serde_json::to_value(&resent).unwrap(), serde_json::to_value(&response).unwrap());
+    }
+
+    #[test]
+    fn dry_run_leaves_a_running_print_claimed() {
+        let prints = RecentPrints::new(Duration::from_secs(600)).for_client(TEST_ORIGIN.to_string());
+        assert!(prints.earlier_result("label-1").is_none());
+
+        let label = STANDARD.encode("^XA^XZ");
+        let (_, printed) = print_deduplicated(&prints, zpl_request("label-1", &label, serde_json::json!({ "dryRun": true })));
+        assert_eq!(printed, 0);
+        assert!(matches!(prints.earlier_result("label-1"), Some(EarlierResult::Running(_))));
+    }
+
+    /// Run the accept loop on an ephemeral port, with a handle to signal shutdown
+    async fn serve_locally() -> (SocketAddr, watch::Sender<Option<ShutdownReason>>, tokio::task::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (signal, shutdown) = watch::channel(None);
+        let ctx = ConnectionContext {
+            shutdown,
+            ..test_context(ServerConfig::default())
+        };
+        (addr, signal, tokio::spawn(serve(listener, None, ctx, None)))
+    }
+
+    #[tokio::test]
+    async fn server_stops_promptly_on_shutdown() {
+        let (_, signal, server) = serve_locally().await;
+
+        signal.send(Some(ShutdownReason::Quit)).unwrap();
+        tokio::time::timeout(Duration::from_secs(1), server)
+            .await
+            .expect("server did not stop")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_open_connections_and_stops_listening() {
+        let (addr, signal, server) = serve_locally().await;
+        let mut request = format!("ws://{}/", addr).into_client_request().unwrap();
+        request.headers_mut().insert("origin", TEST_ORIGIN.parse().unwrap());
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut ws, _) = tokio_tungstenite::client_async(request, stream).await.unwrap();
+        let client = tokio::spawn(async move {
+            let mut closed = false;
+            while let Some(Ok(message)) = ws.next().await {
+                closed |= message.is_close();
+            }
+            closed
+        });
+
+        signal.send(Some(ShutdownReason::Quit)).unwrap();
+        tokio::time::timeout(Duration::from_secs(1), server)
+            .await
+            .expect("server did not stop")
+            .unwrap();
+        assert!(client.await.unwrap(), "client was not sent a close frame");
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn ipv6_loopback_connections_authenticate_like_ipv4() {
+        let Ok(listener_v6) = TcpListener::bind("[::1]:0").await else {
+            // IPv6 disabled on this machine, which the server tolerates too
+            return;
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addrs = [listener.local_addr().unwrap(), listener_v6.local_addr().unwrap()];
+        let (signal, shutdown) = watch::channel(None);
+        let ctx = ConnectionContext {
+            shutdown,
+            ..test_context(ServerConfig::default())
+        };
+        let server = tokio::spawn(serve(listener, Some(listener_v6), ctx, None));
+
+        for addr in addrs {
+            let mut request = format!("ws://{}/", addr).into_client_request().unwrap();
+            request.headers_mut().insert("origin", TEST_ORIGIN.parse().unwrap());
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let (mut ws, _) = tokio_tungstenite::client_async(request, stream).await.unwrap();
+            authenticate(&mut ws).await;
+        }
+
+        signal.send(Some(ShutdownReason::Quit)).unwrap();
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn rate_limits_and_timeouts_come_from_the_config_file() {
+        let file = config::Config {
+            print_timeout_secs: Some(90),
+            idle_timeout_secs: Some(0),
+            print_rate_per_minute: Some(0),
+            print_burst: Some(2),
+            require_pairing: true,
+            advertise_mdns: true,
+            ..config::Config::default()
+        };
+        let config = ServerConfig::from_file(&file);
+        assert_eq!(config.print_timeout, Duration::from_secs(90));
+        // A zero timeout would close every connection at once, so it's ignored
+        assert_eq!(config.idle_timeout, ServerConfig::default().idle_timeout);
+        assert_eq!(config.print_rate_limit.per_minute, 0);
+        assert_eq!(config.print_rate_limit.burst, 2);
+        assert!(config.require_pairing);
+        assert!(config.advertise_mdns);
+
+        let unset = ServerConfig::from_file(&config::Config::default());
+        assert!(!unset.advertise_mdns);
+        assert_eq!(unset.print_timeout, ServerConfig::default().print_timeout);
+        assert_eq!(unset.print_rate_limit.per_minute, ServerConfig::default().print_rate_limit.per_minute);
+    }
+
+    #[test]
+    fn copies_boundaries() {
+        assert_eq!(validate_copies(None, 100), Ok(1));
+        assert_eq!(validate_copies(Some(0), 100), Ok(1));
+        assert_eq!(validate_copies(Some(1), 100), Ok(1));
+        assert_eq!(validate_copies(Some(100), 100), Ok(100));
+        assert_eq!(validate_copies(Some(101), 100), Err("Too many copies: 101 (maximum 100)".to_string()));
+        assert!(validate_copies(Some(u32::MAX), 100).is_err());
+    }
+
+    async fn send_json<S: AsyncRead + AsyncWrite + Unpin>(ws: &mut WebSocketStream<S>, message: serde_json::Value) {
+        ws.send(Message::Text(message.to_string())).await.unwrap();
+    }
+
+    /// The next text frame as JSON, skipping control frames
+    async fn recv_json<S: AsyncRead + AsyncWrite + Unpin>(ws: &mut WebSocketStream<S>) -> serde_json::Value {
+        tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                match ws.next().await {
+                    Some(Ok(Message::Text(text))) => return serde_json::from_str(&text).unwrap(),
+                    Some(Ok(message)) if !message.is_close() => continue,
+                    other => panic!("connection ended: {:?}", other),
+                }
+            }
+        })
+        .await
+        .expect("no reply")
+    }
+
+    #[tokio::test]
+    async fn ping_is_answered_before_hello() {
+        let (mut ws, _) = connect(test_context(ServerConfig::default()), Some(TEST_ORIGIN)).await.unwrap();
+        send_json(&mut ws, serde_json::json!({ "type": "ping" })).await;
+
+        let pong = recv_json(&mut ws).await;
+        assert_eq!(pong["type"], "pong");
+        assert_eq!(pong["connectorVersion"], CONNECTOR_VERSION);
+        assert!(pong["uptimeSeconds"].as_u64().unwrap() >= 3600, "{}", pong);
+    }
+
+    #[tokio::test]
+    async fn pings_do_not_count_as_unauthenticated_messages() {
+        let config = ServerConfig {
+            max_unauthenticated_messages: 2,
+            ..ServerConfig::default()
+        };
+        let (mut ws, connection) = connect(test_context(config), Some(TEST_ORIGIN)).await.unwrap();
+        for _ in 0..5 {
+            send_json(&mut ws, serde_json::json!({ "type": "ping" })).await;
+            assert_eq!(recv_json(&mut ws).await["type"], "pong");
+        }
+        assert!(!connection.is_finished());
+    }
+
+    fn print_before_hello() -> serde_json::Value {
+        serde_json::json!({
+            "type": "print",
+            "requestId": "label-1",
+            "printer": "Zebra_ZP450",
+            "format": "zpl",
+            "data": STANDARD.encode("^XA^XZ"),
+            "options": { "dryRun": true },
+        })
+    }
+
+    #[tokio::test]
+    async fn print_before_hello_is_auth_required() {
+        let (mut ws, connection) = connect(test_context(ServerConfig::default()), Some(TEST_ORIGIN)).await.unwrap();
+        send_json(&mut ws, print_before_hello()).await;
+
+        let error = recv_json(&mut ws).await;
+        assert_eq!(error["type"], "error");
+        assert_eq!(error["code"], "auth_required", "{}", error);
+        let message = error["message"].as_str().unwrap();
+        assert!(message.contains(r#""type":"hello""#), "{}", message);
+        assert!(message.contains(&format!(r#""version":"{}""#, protocol::SUPPORTED_PROTOCOL_RANGE.end())), "{}", message);
+
+        // One stray message is forgiven, and hello still works
+        assert!(!connection.is_finished());
+        authenticate(&mut ws).await;
+    }
+
+    #[tokio::test]
+    async fn repeated_messages_before_hello_close_the_connection() {
+        let config = ServerConfig {
+            max_unauthenticated_messages: 3,
+            ..ServerConfig::default()
+        };
+        let (mut ws, connection) = connect(test_context(config), Some(TEST_ORIGIN)).await.unwrap();
+        for _ in 0..3 {
+            send_json(&mut ws, print_before_hello()).await;
+        }
+        for _ in 0..3 {
+            assert_eq!(recv_json(&mut ws).await["code"], "auth_required");
+        }
+
+        let frame = recv_close(&mut ws).await.expect("close frame without a reason");
+        assert_eq!(frame.code, CloseCode::Policy);
+        assert_eq!(frame.reason, "auth_required");
+        tokio::time::timeout(Duration::from_secs(1), connection).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn unknown_message_type_is_reported_as_unsupported() {
+        let (mut ws, connection) = connect(test_context(ServerConfig::default()), Some(TEST_ORIGIN)).await.unwrap();
+        authenticate(&mut ws).await;
+        send_json(&mut ws, serde_json::json!({ "type": "teleport_label", "to": "mars" })).await;
+
+        let error = recv_json(&mut ws).await;
+        assert_eq!(error["type"], "error");
+        assert_eq!(error["code"], "unsupported_message", "{}", error);
+        let message = error["message"].as_str().unwrap();
+        assert!(message.contains("'teleport_label'"), "{}", message);
+        assert!(message.contains(CONNECTOR_VERSION), "{}", message);
+
+        // The connection carries on
+        send_json(&mut ws, serde_json::json!({ "type": "ping" })).await;
+        assert_eq!(recv_json(&mut ws).await["type"], "pong");
+        assert!(!connection.is_finished());
+    }
+
+    #[test]
+    fn tls_is_advertised_only_when_served() {
+        let plain = capabilities(&ServerConfig::default());
+        assert!(plain.iter().any(|c| c == "print"));
+        assert!(!plain.iter().any(|c| c == "tls"));
+
+        let secure = capabilities(&ServerConfig {
+            tls_enabled: true,
+            ..ServerConfig::default()
+        });
+        assert_eq!(secure.last().map(String::as_str), Some("tls"));
+    }
+
+    #[tokio::test]
+    async fn prints_over_the_rate_limit_are_refused_with_a_retry_hint() {
+        let config = ServerConfig {
+            print_rate_limit: RateLimit {
+                per_minute: 1,
+                burst: 1,
+            },
+            ..ServerConfig::default()
+        };
+        let (mut ws, _) = connect(test_context(config), Some(TEST_ORIGIN)).await.unwrap();
+        authenticate(&mut ws).await;
+        for request_id in ["label-1", "label-2"] {
+            send_json(
+                &mut ws,
+                serde_json::json!({
+                    "type": "print",
+                    "requestId": request_id,
+                    "printer": "Zebra_ZP450",
+                    "format": "zpl",
+                    "data": STANDARD.encode("^XA^XZ"),
+                    "options": { "dryRun": true },
+                }),
+            )
+            .await;
+        }
+
+        let mut results = BTreeMap::new();
+        while results.len() < 2 {
+            let result = recv_json(&mut ws).await;
+            if result["type"] == "print_result" {
+                results.insert(result["requestId"].as_str().unwrap().to_string(), result);
+            }
+        }
+        assert_ne!(results["label-1"]["code"], "rate_limited", "{}", results["label-1"]);
+        let limited = &results["label-2"];
+        assert_eq!(limited["success"], false);
+        assert_eq!(limited["code"], "rate_limited", "{}", limited);
+        // One a minute, with the only token just taken
+        assert_eq!(limited["retryAfter"], 60, "{}", limited);
+    }
+
+    #[tokio::test]
+    async fn resent_prints_get_the_earlier_result_without_using_the_rate_limit() {
+        let config = ServerConfig {
+            print_rate_limit: RateLimit {
+                per_minute: 1,
+                burst: 1,
+            },
+            ..ServerConfig::default()
+        };
+        let ctx = test_context(config);
+        // label-1 was printed before the client reconnected
+        let earlier = ctx.recent_prints.for_client(TEST_ORIGIN.to_string());
+        assert!(earlier.earlier_result("label-1").is_none());
+        let printed = ServerMessage::PrintResult {
+            request_id: "label-1".to_string(),
+            success: true,
+            job_id: Some("Zebra_ZP450-7".to_string()),
+            message: None,
+            error: None,
+            code: None,
+            copy_results: None,
+            attempts: None,
+            retry_after: None,
+        };
+        earlier.finish("label-1", &printed);
+
+        let (mut ws, _) = connect(ctx, Some(TEST_ORIGIN)).await.unwrap();
+        authenticate(&mut ws).await;
+        for request_id in ["label-1", "label-1", "label-2"] {
+            send_json(
+                &mut ws,
+                serde_json::json!({
+                    "type": "print",
+                    "requestId": request_id,
+                    "printer": "Zebra_ZP450",
+                    "format": "zpl",
+                    "data": STANDARD.encode("^XA^XZ"),
+                    "options": { "dryRun": true },
+                }),
+            )
+            .await;
+        }
+
+        let mut results = Vec::new();
+        while results.len() < 3 {
+            let result = recv_json(&mut ws).await;
+            if result["type"] == "print_result" {
+                results.push(result);
+            }
+        }
+        let resent: Vec<_> = results.iter().filter(|r| r["requestId"] == "label-1").collect();
+        assert_eq!(resent.len(), 2, "{:?}", results);
+        for result in resent {
+            assert_eq!(result["jobId"], "Zebra_ZP450-7", "{}", result);
+        }
+        // The only token is still there for the new print
+        let new = results.iter().find(|r| r["requestId"] == "label-2").unwrap();
+        assert_ne!(new["code"], "rate_limited", "{}", new);
+    }
+
+    #[tokio::test]
+    async fn offered_compression_is_declined_and_large_messages_arrive_uncompressed() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(handle_connection(server, test_context(ServerConfig::default()), false));
+        let mut request = "ws://127.0.0.1:9632/".into_client_request().unwrap();
+        request.headers_mut().insert("origin", TEST_ORIGIN.parse().unwrap());
+        request
+            .headers_mut()
+            .insert("sec-websocket-extensions", "permessage-deflate; client_max_window_bits".parse().unwrap());
+        let (mut ws, response) = tokio_tungstenite::client_async(request, client).await.unwrap();
+        assert!(response.headers().get("sec-websocket-extensions").is_none(), "{:?}", response.headers());
+        authenticate(&mut ws).await;
+
+        // About 2 MB once base64 encoded, like a multi-page PDF
+        let label = "^XA^FO50,50^FDLimeStack^FS^XZ".repeat(50_000);
+        send_json(
+            &mut ws,
+            serde_json::json!({
+                "type": "print",
+                "requestId": "large-1",
+                "printer": "Zebra_ZP450",
+                "format": "zpl",
+                "data": STANDARD.encode(&label),
+                "options": { "dryRun": true },
+            }),
+        )
+        .await;
+        let result = loop {
+            let message = recv_json(&mut ws).await;
+            if message["type"] == "print_result" {
+                break message;
+            }
+        };
+        assert_eq!(result["requestId"], "large-1");
+        assert_ne!(result["code"], "payload_too_large", "{}", result);
+        assert_ne!(result["code"], "invalid_base64", "{}", result);
+    }
+
+    #[tokio::test]
+    async fn capabilities_report_the_configured_limits() {
+        let config = ServerConfig {
+            max_copies: 7,
+            max_message_size: 4096,
+            tls_enabled: true,
+            ..ServerConfig::default()
+        };
+        let (mut ws, _) = connect(test_context(config.clone()), Some(TEST_ORIGIN)).await.unwrap();
+        authenticate(&mut ws).await;
+        send_json(&mut ws, serde_json::json!({ "type": "get_capabilities" })).await;
+
+        let reply = recv_json(&mut ws).await;
+        assert_eq!(reply["type"], "capabilities", "{}", reply);
+        assert_eq!(reply["maxPayloadBytes"], 4096);
+        assert_eq!(reply["maxCopies"], 7);
+        assert_eq!(reply["formats"], serde_json::json!(printer::supported_formats()));
+        // The same features `welcome` lists, including those that depend on the config
+        assert_eq!(reply["features"], serde_json::json!(capabilities(&config)));
+        assert!(reply["features"].as_array().unwrap().iter().any(|f| f == "tls"));
+    }
+
+    #[tokio::test]
+    async fn open_url_refuses_urls_off_the_allowlist() {
+        let (mut ws, _) = connect(test_context(ServerConfig::default()), Some(TEST_ORIGIN)).await.unwrap();
+        authenticate(&mut ws).await;
+        for url in ["https://evil.example/settings", "file:///etc/passwd", "http://app.limestack.io/settings"] {
+            send_json(&mut ws, serde_json::json!({ "type": "open_url", "url": url })).await;
+            let error = recv_json(&mut ws).await;
+            assert_eq!(error["type"], "error", "{}", error);
+            assert_eq!(error["code"], "url_not_allowed", "{}", error);
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_printer_is_null() {
+        let (mut ws, _) = connect(test_context(ServerConfig::default()), Some(TEST_ORIGIN)).await.unwrap();
+        authenticate(&mut ws).await;
+        send_json(&mut ws, serde_json::json!({ "type": "get_printer", "printer": "No_Such_Printer_7f3a" })).await;
+        assert_eq!(recv_json(&mut ws).await, serde_json::json!({ "type": "printer", "printer": null }));
+    }
+
+    #[tokio::test]
+    async fn open_url_needs_authentication() {
+        let (mut ws, _) = connect(test_context(ServerConfig::default()), Some(TEST_ORIGIN)).await.unwrap();
+        send_json(&mut ws, serde_json::json!({ "type": "open_url", "url": "https://app.limestack.io/settings" })).await;
+        let error = recv_json(&mut ws).await;
+        assert_eq!(error["code"], "auth_required", "{}", error);
+    }
+
+    #[tokio::test]
+    async fn printer_changes_reach_every_authenticated_connection() {
+        let ctx = test_context(ServerConfig::default());
+        let changes = ctx.printer_changes.clone();
+        let (mut first, _) = connect(ctx.clone(), Some(TEST_ORIGIN)).await.unwrap();
+        let (mut second, _) = connect(ctx.clone(), Some(TEST_ORIGIN)).await.unwrap();
+        let (mut anonymous, _) = connect(ctx, Some(TEST_ORIGIN)).await.unwrap();
+        authenticate(&mut first).await;
+        authenticate(&mut second).await;
+        send_json(&mut anonymous, serde_json::json!({ "type": "ping" })).await;
+        assert_eq!(recv_json(&mut anonymous).await["type"], "pong");
+
+        // A USB printer was plugged in
+        let zebra = PrinterInfo {
+            id: "Zebra_ZP450".to_string(),
+            name: "Zebra ZP450".to_string(),
+            printer_type: "thermal".to_string(),
+            status: PrinterStatus::Ready,
+            is_default: false,
+            capabilities: PrinterCapabilities {
+                formats: vec!["zpl".to_string()],
+                dpi: Some(203),
+                color: Some(false),
+                max_copies: None,
+                media_sizes: None,
+            },
+        };
+        let list = PrinterList {
+            printers: vec![zebra],
+            error: None,
+        };
+        assert_eq!(changes.send(list).unwrap(), 3);
+
+        for ws in [&mut first, &mut second] {
+            let changed = recv_json(ws).await;
+            assert_eq!(changed["type"], "printers_changed", "{}", changed);
+            assert_eq!(changed["printers"][0]["id"], "Zebra_ZP450", "{}", changed);
+            assert!(changed.get("enumerationError").is_none(), "{}", changed);
+        }
+
+        // Connections that haven't said hello aren't told
+        send_json(&mut anonymous, serde_json::json!({ "type": "ping" })).await;
+        assert_eq!(recv_json(&mut anonymous).await["type"], "pong");
+    }
+
+    #[tokio::test]
+    async fn printer_enumeration_errors_are_broadcast() {
+        let ctx = test_context(ServerConfig::default());
+        let changes = ctx.printer_changes.clone();
+        let (mut ws, _) = connect(ctx, Some(TEST_ORIGIN)).await.unwrap();
+        authenticate(&mut ws).await;
+
+        changes
+            .send(PrinterList {
+                printers: Vec::new(),
+                error: Some("CUPS is not running".to_string()),
+            })
+            .unwrap();
+        let changed = recv_json(&mut ws).await;
+        assert_eq!(changed["type"], "printers_changed", "{}", changed);
+        assert_eq!(changed["printers"], serde_json::json!([]));
+        assert_eq!(changed["enumerationError"], "CUPS is not running");
+    }
+
+    #[tokio::test]
+    async fn subscribers_get_one_printers_message_per_change() {
+        let ctx = test_context(ServerConfig::default());
+        let changes = ctx.printer_changes.clone();
+        let (mut ws, _) = connect(ctx, Some(TEST_ORIGIN)).await.unwrap();
+        authenticate(&mut ws).await;
+
+        send_json(&mut ws, serde_json::json!({ "type": "subscribe_printers" })).await;
+        assert_eq!(recv_json(&mut ws).await["type"], "printers");
+
+        let unavailable = PrinterList {
+            printers: Vec::new(),
+            error: Some("CUPS is not running".to_string()),
+        };
+        changes.send(unavailable).unwrap();
+        let changed = recv_json(&mut ws).await;
+        assert_eq!(changed["type"], "printers", "{}", changed);
+        assert_eq!(changed["enumerationError"], "CUPS is not running");
+
+        // Nothing else was sent for that change, so the next message is the pong
+        send_json(&mut ws, serde_json::json!({ "type": "ping" })).await;
+        assert_eq!(recv_json(&mut ws).await["type"], "pong");
+
+        send_json(&mut ws, serde_json::json!({ "type": "unsubscribe_printers" })).await;
+        changes
+            .send(PrinterList {
+                printers: Vec::new(),
+                error: None,
+            })
+            .unwrap();
+        assert_eq!(recv_json(&mut ws).await["type"], "printers_changed");
+    }
+
+    #[test]
+    fn full_outbox_queues_a_slow_consumer_close_in_its_last_slot() {
+        let (tx, mut rx) = mpsc::channel(4);
+        let outbox = Outbox(tx);
+        for n in 0..3 {
+            outbox.send(Message::Text(n.to_string())).unwrap();
+        }
+        assert!(matches!(outbox.send(Message::Text("3".to_string())), Err(OutboxError::SlowConsumer)));
+
+        let queued: Vec<Message> = std::iter::from_fn(|| rx.try_recv().ok()).collect();
+        assert_eq!(queued[..3], [Message::Text("0".into()), Message::Text("1".into()), Message::Text("2".into())]);
+        assert_eq!(queued[3], slow_consumer_close_frame());
+        assert_eq!(queued.len(), 4);
+    }
+
+    #[test]
+    fn outbox_reports_a_stopped_writer() {
+        let (tx, rx) = mpsc::channel(4);
+        let outbox = Outbox(tx);
+        drop(rx);
+        assert!(matches!(outbox.send(Message::Text("late".to_string())), Err(OutboxError::Closed)));
+    }
+
+    #[tokio::test]
+    async fn peer_that_never_reads_is_disconnected_once_the_buffer_fills() {
+        // A small pipe so the writer blocks after a handful of replies
+        let (client, server) = tokio::io::duplex(1024);
+        let connection = tokio::spawn(handle_connection(server, test_context(ServerConfig::default()), false));
+        let mut request = "ws://127.0.0.1:9632/".into_client_request().unwrap();
+        request.headers_mut().insert("origin", TEST_ORIGIN.parse().unwrap());
+        let (mut ws, _) = tokio_tungstenite::client_async(request, client).await.unwrap();
+
+        // Keep asking for pongs without ever reading one
+        let ping = Message::Text(serde_json::json!({ "type": "ping" }).to_string());
+        for _ in 0..WRITE_BUFFER * 20 {
+            if connection.is_finished() || ws.send(ping.clone()).await.is_err() {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+
+        // The reader loop wasn't stuck behind the writer, so the connection ends by itself
+        // once the queued replies can't be flushed
+        tokio::time::timeout(WRITER_FLUSH_TIMEOUT + Duration::from_secs(3), connection)
+            .await
+            .expect("connection to a non-reading peer was never closed")
+            .unwrap();
+
+        // Only what fit in the pipe was written, less than the queue held; then the stream ends
+        let mut pongs = 0;
+        while let Some(Ok(message)) = ws.next().await {
+            if message.is_text() {
+                pongs += 1;
+            }
+        }
+        assert!(pongs < WRITE_BUFFER, "{} pongs", pongs);
+    }
+
+    #[tokio::test]
+    async fn oversized_message_is_refused_with_an_error_and_close() {
+        let config = ServerConfig {
+            max_message_size: 1024,
+            ..ServerConfig::default()
+        };
+        let (mut ws, connection) = connect(test_context(config), Some(TEST_ORIGIN)).await.unwrap();
+        // Just under the limit is read as usual
+        let ping = serde_json::json!({ "type": "ping", "padding": "x".repeat(900) });
+        send_json(&mut ws, ping).await;
+        assert_eq!(recv_json(&mut ws).await["type"], "pong");
+
+        ws.send(Message::Text("x".repeat(4096))).await.unwrap();
+        let error = recv_json(&mut ws).await;
+        assert_eq!(error["code"], "payload_too_large", "{}", error);
+        assert_eq!(error["message"], "Message exceeds the 1024 byte limit");
+        let frame = recv_close(&mut ws).await.expect("close frame without a reason");
+        assert_eq!(frame.code, CloseCode::Size);
+        tokio::time::timeout(Duration::from_secs(1), connection).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn welcome_advertises_the_message_size_limit() {
+        let config = ServerConfig {
+            max_message_size: 4096,
+            ..ServerConfig::default()
+        };
+        let (mut ws, _) = connect(test_context(config), Some(TEST_ORIGIN)).await.unwrap();
+        send_json(&mut ws, serde_json::json!({ "type": "hello", "version": "1.0", "origin": TEST_ORIGIN })).await;
+        let welcome = recv_json(&mut ws).await;
+        assert_eq!(welcome["maxMessageSize"], 4096, "{}", welcome);
+    }
+
+    /// Send `hello` from `TEST_ORIGIN` and wait for `welcome`
+    async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(ws: &mut WebSocketStream<S>) {
+        send_json(ws, serde_json::json!({ "type": "hello", "version": "1.0", "origin": TEST_ORIGIN })).await;
+        let welcome = recv_json(ws).await;
+        assert_eq!(welcome["type"], "welcome", "{}", welcome);
+    }
+
+    fn binary_frame(request_id: &str, data: &[u8]) -> Vec<u8> {
+        let mut frame = (request_id.len() as u16).to_be_bytes().to_vec();
+        frame.extend_from_slice(request_id.as_bytes());
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    fn binary_header(request_id: &str) -> PrintRequest {
+        PrintRequest {
+            request_id: request_id.to_string(),
+            printer_id: "Zebra_ZP450".to_string(),
+            format: "zpl".to_string(),
+            data: LabelData::Bytes(Vec::new()),
+            options: PrintOptions::default(),
+        }
+    }
+
+    #[test]
+    fn binary_frame_fills_in_its_header() {
+        let mut pending = HashMap::new();
+        pending.insert("label-1".to_string(), binary_header("label-1"));
+        pending.insert("label-2".to_string(), binary_header("label-2"));
+
+        let request = take_binary_print(&mut pending, binary_frame("label-2", b"^XA^XZ")).unwrap();
+        assert_eq!(request.request_id, "label-2");
+        assert!(matches!(request.data, LabelData::Bytes(bytes) if bytes == b"^XA^XZ"));
+        assert_eq!(pending.keys().collect::<Vec<_>>(), ["label-1"]);
+    }
+
+    #[test]
+    fn binary_frame_without_a_header_is_refused() {
+        let mut pending = HashMap::new();
+        pending.insert("label-1".to_string(), binary_header("label-1"));
+
+        let response = take_binary_print(&mut pending, binary_frame("label-9", b"^XA^XZ")).err().expect("frame was accepted");
+        assert!(matches!(
+            response,
+            ServerMessage::PrintResult { request_id, code: Some(ErrorCode::InvalidMessage), .. } if request_id == "label-9"
+        ));
+        assert!(pending.contains_key("label-1"));
+    }
+
+    #[test]
+    fn malformed_binary_frames_are_refused() {
+        let mut pending = HashMap::new();
+        pending.insert("label-1".to_string(), binary_header("label-1"));
+
+        for frame in [vec![], vec![0], vec![0, 7, b'l', b'a'], vec![0, 2, 0xff, 0xfe]] {
+            let response = take_binary_print(&mut pending, frame.clone()).err().expect("frame was accepted");
+            assert!(
+                matches!(response, ServerMessage::Error { code: ErrorCode::InvalidMessage, .. }),
+                "{:?}",
+                frame
+            );
+        }
+        assert!(pending.contains_key("label-1"));
+    }
+
+    #[tokio::test]
+    async fn binary_print_pairs_header_and_frame_over_the_connection() {
+        let (mut ws, _) = connect(test_context(ServerConfig::default()), Some(TEST_ORIGIN)).await.unwrap();
+        authenticate(&mut ws).await;
+
+        send_json(
+            &mut ws,
+            serde_json::json!({
+                "type": "print_binary",
+                "requestId": "label-1",
+                "printer": "Zebra_ZP450",
+                "format": "zpl",
+                "options": { "dryRun": true },
+            }),
+        )
+        .await;
+        // A frame for a request with no header is answered on its own
+        ws.send(Message::Binary(binary_frame("label-2", b"^XA^XZ"))).await.unwrap();
+        let stray = recv_json(&mut ws).await;
+        assert_eq!(stray["requestId"], "label-2");
+        assert_eq!(stray["error"], "No print_binary header for this request");
+
+        ws.send(Message::Binary(binary_frame("label-1", b"^XA^XZ"))).await.unwrap();
+        let result = recv_json(&mut ws).await;
+        assert_eq!(result["type"], "print_result", "{}", result);
+        assert_eq!(result["requestId"], "label-1");
+        assert_ne!(result["code"], "invalid_message", "{}", result);
+    }
+
+    #[tokio::test]
+    async fn no_more_prints_run_at_once_than_there_are_permits() {
+        let queue = PrintQueue::new(Arc::new(Semaphore::new(2)));
+        let running = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let most_running = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+        let prints = (0..8).map(|i| {
+            let request_id = format!("job-{}", i);
+            let cancelled = queue.enqueue(&request_id);
+            let (progress_tx, _) = mpsc::unbounded_channel();
+            let (running, most_running) = (running.clone(), most_running.clone());
+            let done = request_id.clone();
+            run_queued(
                 request_id,
-                success: false,
-                message: None,
-                error: Some(e),
+                Duration::from_secs(5),
+                Duration::from_secs(5),
+                queue.clone(),
+                cancelled,
+                progress_tx,
+                move |_| {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    most_running.fetch_max(now, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(30));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    sent(&done)
+                },
+            )
+        });
+        let responses = futures_util::future::join_all(prints).await;
+
+        assert!(responses.iter().all(|response| print_code(response).is_none()));
+        assert_eq!(most_running.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn queued_print_can_be_cancelled() {
+        let permits = Arc::new(Semaphore::new(1));
+        let busy = permits.clone().acquire_owned().await.unwrap();
+        let queue = PrintQueue::new(permits);
+        let cancelled = queue.enqueue("job-1");
+        let (progress_tx, _progress_rx) = mpsc::unbounded_channel();
+
+        let waiting = tokio::spawn(run_queued(
+            "job-1".to_string(),
+            Duration::from_secs(5),
+            Duration::from_secs(5),
+            queue.clone(),
+            cancelled,
+            progress_tx,
+            |_| panic!("a cancelled print must not run"),
+        ));
+        tokio::task::yield_now().await;
+        assert!(queue.cancel("job-1"));
+        assert_eq!(print_code(&waiting.await.unwrap()), Some(ErrorCode::Cancelled));
+
+        // Once it's gone it can't be cancelled again, and its slot was never taken
+        assert!(!queue.cancel("job-1"));
+        drop(busy);
+    }
+
+    #[tokio::test]
+    async fn three_copy_job_reports_progress_for_each_copy_in_order() {
+        let queue = PrintQueue::new(Arc::new(Semaphore::new(1)));
+        let cancelled = queue.enqueue("job-1");
+        let (progress_tx, mut progress_rx) = mpsc::unbounded_channel();
+
+        let response = run_queued(
+            "job-1".to_string(),
+            Duration::from_secs(5),
+            Duration::from_secs(5),
+            queue,
+            cancelled,
+            progress_tx,
+            |on_progress| print_per_copy("job-1".to_string(), "Zebra", 3, NO_RETRIES, || Ok(None), on_progress),
+        )
+        .await;
+        assert!(copy_results(response).0);
+
+        let mut frames = Vec::new();
+        while let Some(frame) = progress_rx.recv().await {
+            frames.push(serde_json::to_value(frame).unwrap());
+        }
+        assert_eq!(
+            frames,
+            [1, 2, 3].map(|completed| serde_json::json!({
+                "type": "print_progress",
+                "requestId": "job-1",
+                "completed": completed,
+                "total": 3,
+            }))
+        );
+    }
+
+    /// The close frame a connection ends with, skipping anything sent before it
+    async fn recv_close(ws: &mut WebSocketStream<DuplexStream>) -> Option<CloseFrame<'static>> {
+        tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                match ws.next().await {
+                    Some(Ok(Message::Close(frame))) => return frame,
+                    Some(Ok(_)) => continue,
+                    other => panic!("connection ended without a close frame: {:?}", other),
+                }
             }
+        })
+        .await
+        .expect("connection was not closed")
+    }
+
+    #[tokio::test]
+    async fn connections_are_refused_while_paused() {
+        let ctx = test_context(ServerConfig::default());
+        let (mut open, open_connection) = connect(ctx.clone(), Some(TEST_ORIGIN)).await.unwrap();
+        ctx.accepting.store(false, Ordering::SeqCst);
+
+        let (mut refused, _) = connect(ctx.clone(), Some(TEST_ORIGIN)).await.unwrap();
+        let frame = recv_close(&mut refused).await.expect("close frame without a reason");
+        assert_eq!(frame.code, CloseCode::Again);
+        assert_eq!(frame.reason, "Connector is not accepting connections");
+
+        // Connections made before pausing are left alone
+        send_json(&mut open, serde_json::json!({ "type": "ping" })).await;
+        assert_eq!(recv_json(&mut open).await["type"], "pong");
+        assert!(!open_connection.is_finished());
+
+        ctx.accepting.store(true, Ordering::SeqCst);
+        let (mut resumed, _) = connect(ctx, Some(TEST_ORIGIN)).await.unwrap();
+        send_json(&mut resumed, serde_json::json!({ "type": "ping" })).await;
+        assert_eq!(recv_json(&mut resumed).await["type"], "pong");
+    }
+
+    #[tokio::test]
+    async fn connections_past_the_limit_are_refused_until_one_closes() {
+        let config = ServerConfig {
+            max_connections: 2,
+            ..ServerConfig::default()
+        };
+        let ctx = test_context(config);
+        let (mut first, first_connection) = connect(ctx.clone(), Some(TEST_ORIGIN)).await.unwrap();
+        let (mut second, _) = connect(ctx.clone(), Some(TEST_ORIGIN)).await.unwrap();
+        // Both hold a slot once they answer
+        for ws in [&mut first, &mut second] {
+            send_json(ws, serde_json::json!({ "type": "ping" })).await;
+            assert_eq!(recv_json(ws).await["type"], "pong");
+        }
+
+        let (mut refused, refused_connection) = connect(ctx.clone(), Some(TEST_ORIGIN)).await.unwrap();
+        let frame = recv_close(&mut refused).await.expect("close frame without a reason");
+        assert_eq!(frame.code, CloseCode::Again);
+        assert_eq!(frame.reason, "too_many_connections");
+        tokio::time::timeout(Duration::from_secs(1), refused_connection).await.unwrap().unwrap();
+        // A refused connection doesn't take a slot
+        assert_eq!(ctx.connection_slots.available_permits(), 0);
+
+        first.close(None).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(2), first_connection).await.unwrap().unwrap();
+        assert_eq!(ctx.connection_slots.available_permits(), 1);
+
+        let (mut next, _) = connect(ctx, Some(TEST_ORIGIN)).await.unwrap();
+        send_json(&mut next, serde_json::json!({ "type": "ping" })).await;
+        assert_eq!(recv_json(&mut next).await["type"], "pong");
+        send_json(&mut second, serde_json::json!({ "type": "ping" })).await;
+        assert_eq!(recv_json(&mut second).await["type"], "pong");
+    }
+
+    /// Shut down a connected, authenticated client for `reason` and return what it was sent
+    async fn goodbye_for(reason: ShutdownReason) -> (serde_json::Value, CloseFrame<'static>) {
+        let (signal, shutdown) = watch::channel(None);
+        let ctx = ConnectionContext {
+            shutdown,
+            ..test_context(ServerConfig::default())
+        };
+        let (mut ws, connection) = connect(ctx, Some(TEST_ORIGIN)).await.unwrap();
+        authenticate(&mut ws).await;
+
+        signal.send(Some(reason)).unwrap();
+        // `recv_json` fails on a close frame, so goodbye has to come first
+        let goodbye = recv_json(&mut ws).await;
+        let frame = recv_close(&mut ws).await.expect("close frame without a reason");
+        tokio::time::timeout(Duration::from_secs(1), connection).await.unwrap().unwrap();
+        (goodbye, frame)
+    }
+
+    #[tokio::test]
+    async fn restart_says_goodbye_with_a_short_retry_before_closing() {
+        let (goodbye, frame) = goodbye_for(ShutdownReason::Restart).await;
+        assert_eq!(goodbye, serde_json::json!({ "type": "goodbye", "reason": "restart", "retryAfterMs": 3000 }));
+        assert_eq!(frame.code, CloseCode::Away);
+        assert_eq!(frame.reason, "Connector shutting down");
+    }
+
+    #[tokio::test]
+    async fn quit_says_goodbye_with_a_long_retry_before_closing() {
+        let (goodbye, frame) = goodbye_for(ShutdownReason::Quit).await;
+        assert_eq!(goodbye, serde_json::json!({ "type": "goodbye", "reason": "quit", "retryAfterMs": 60000 }));
+        assert_eq!(frame.code, CloseCode::Away);
+    }
+
+    #[tokio::test]
+    async fn disallowed_origin_is_refused_at_the_handshake() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let connection = tokio::spawn(handle_connection(server, test_context(ServerConfig::default()), false));
+        let mut request = "ws://127.0.0.1:9632/".into_client_request().unwrap();
+        request.headers_mut().insert("origin", "https://evil-limestack.io".parse().unwrap());
+
+        match tokio_tungstenite::client_async(request, client).await {
+            Err(WsError::Http(response)) => assert_eq!(response.status(), StatusCode::FORBIDDEN),
+            other => panic!("handshake was not refused: {:?}", other.map(|_| ())),
         }
+        // Nothing past the handshake ran, so the connection ends without reading a message
+        tokio::time::timeout(Duration::from_secs(1), connection).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn connection_without_an_origin_may_only_ping() {
+        let (mut ws, _) = connect(test_context(ServerConfig::default()), None).await.unwrap();
+        send_json(&mut ws, serde_json::json!({ "type": "ping" })).await;
+        assert_eq!(recv_json(&mut ws).await["type"], "pong");
+
+        send_json(&mut ws, serde_json::json!({ "type": "hello", "version": "1.0", "origin": "" })).await;
+        let error = recv_json(&mut ws).await;
+        assert_eq!(error["code"], "origin_not_allowed", "{}", error);
+        let frame = recv_close(&mut ws).await.expect("close frame without a reason");
+        assert_eq!(frame.code, CloseCode::Policy);
+        assert_eq!(frame.reason, "origin_required");
+    }
+
+    #[tokio::test]
+    async fn port_in_use_is_reported_on_the_status_channel() {
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (status, status_rx) = watch::channel(ServerStatus::default());
+
+        assert!(bind_or_report(taken.local_addr().unwrap(), &status).await.is_none());
+        assert_eq!(status_rx.borrow().error.as_deref(), Some("port in use"));
+    }
+
+    #[tokio::test]
+    async fn free_port_binds_without_an_error() {
+        let (status, status_rx) = watch::channel(ServerStatus::default());
+
+        assert!(bind_or_report(SocketAddr::from(([127, 0, 0, 1], 0)), &status).await.is_some());
+        assert_eq!(status_rx.borrow().error, None);
     }
 }
@@ -0,0 +1,321 @@
+//! Native Windows spooler printing for raw printer command languages (ZPL, EPL).
+//!
+//! Raw jobs go through `OpenPrinter`/`StartDocPrinter`/`WritePrinter` with the `RAW`
+//! datatype and need no external executable. PDF jobs go through SumatraPDF or
+//! `gdi_print` (see `printer::print_file`).
+
+use crate::printer::CancelError;
+use windows::core::{Error, HSTRING, PWSTR};
+use windows::Win32::Foundation::ERROR_INVALID_PARAMETER;
+use windows::Win32::Graphics::Printing::{
+    ClosePrinter, EndDocPrinter, EndPagePrinter, GetPrinterW, OpenPrinterW, SetJobW, SetPrinterW, StartDocPrinterW,
+    StartPagePrinter, WritePrinter, DOC_INFO_1W, JOB_CONTROL_DELETE, PRINTER_ACCESS_ADMINISTER, PRINTER_DEFAULTSW,
+    PRINTER_HANDLE, PRINTER_INFO_2W,
+};
+
+/// Document name shown in the Windows print queue
+const DOC_NAME: &str = "LimeStack Label";
+
+/// The spooler calls a raw job makes, so the job flow can run against a fake in tests
+pub trait RawSpooler {
+    fn open(&mut self, printer_name: &str) -> Result<(), String>;
+    fn start_doc(&mut self, doc_name: &str, datatype: &str) -> Result<(), String>;
+    fn start_page(&mut self) -> Result<(), String>;
+    /// Returns the number of bytes the spooler accepted
+    fn write(&mut self, data: &[u8]) -> Result<usize, String>;
+    fn end_page(&mut self);
+    fn end_doc(&mut self);
+    fn close(&mut self);
+}
+
+/// Send bytes straight to the Windows spooler using the RAW datatype
+pub fn print_raw(printer_name: &str, data: &[u8], copies: u32) -> Result<(), String> {
+    log::info!("Sending {} raw bytes to '{}' via spooler ({} copies)", data.len(), printer_name, copies);
+    send_raw_job(&mut WinSpooler::default(), printer_name, data, copies)
+}
+
+/// Run one raw document with a page per copy, always closing what was opened
+pub fn send_raw_job(spooler: &mut impl RawSpooler, printer_name: &str, data: &[u8], copies: u32) -> Result<(), String> {
+    spooler.open(printer_name)?;
+    let result = write_document(spooler, data, copies);
+    spooler.close();
+    result
+}
+
+fn write_document(spooler: &mut impl RawSpooler, data: &[u8], copies: u32) -> Result<(), String> {
+    spooler.start_doc(DOC_NAME, "RAW")?;
+
+    for _ in 0..copies {
+        if let Err(e) = spooler.start_page() {
+            spooler.end_doc();
+            return Err(e);
+        }
+
+        let written = spooler.write(data);
+        spooler.end_page();
+
+        match written {
+            Ok(n) if n == data.len() => {}
+            Ok(n) => {
+                spooler.end_doc();
+                return Err(format!("WritePrinter failed after {} of {} bytes", n, data.len()));
+            }
+            Err(e) => {
+                spooler.end_doc();
+                return Err(e);
+            }
+        }
+    }
+
+    spooler.end_doc();
+    Ok(())
+}
+
+/// Delete a queued or printing job; the spooler rejects ids it no longer has
+pub fn cancel_job(printer_name: &str, job_id: u32) -> Result<(), CancelError> {
+    let printer = HSTRING::from(printer_name);
+    let mut handle = PRINTER_HANDLE::default();
+    unsafe { OpenPrinterW(&printer, &mut handle, None) }
+        .map_err(|e| CancelError::Failed(format!("Failed to open printer: {}", e)))?;
+
+    let ok = unsafe { SetJobW(handle, job_id, 0, None, JOB_CONTROL_DELETE) }.as_bool();
+    let error = Error::from_win32();
+    let _ = unsafe { ClosePrinter(handle) };
+
+    if ok {
+        Ok(())
+    } else if error.code() == ERROR_INVALID_PARAMETER.to_hresult() {
+        Err(CancelError::NotFound)
+    } else {
+        Err(CancelError::Failed(format!("SetJob failed: {}", error)))
+    }
+}
+
+/// Delete every job in a printer's queue, returning how many there were
+///
+/// Purging needs administer access to the printer, which standard users have for their
+/// own printers but may not for shared ones.
+pub fn purge_queue(printer_name: &str) -> Result<usize, String> {
+    const PRINTER_CONTROL_PURGE: u32 = 3;
+
+    let printer = HSTRING::from(printer_name);
+    let defaults = PRINTER_DEFAULTSW {
+        pDatatype: PWSTR::null(),
+        pDevMode: std::ptr::null_mut(),
+        DesiredAccess: PRINTER_ACCESS_ADMINISTER,
+    };
+    let mut handle = PRINTER_HANDLE::default();
+    unsafe { OpenPrinterW(&printer, &mut handle, Some(&defaults as *const _)) }
+        .map_err(|e| format!("Failed to open printer to clear its queue: {}", e))?;
+
+    let jobs = queued_jobs(handle);
+    let ok = unsafe { SetPrinterW(handle, 0, None, PRINTER_CONTROL_PURGE) }.as_bool();
+    let error = Error::from_win32();
+    let _ = unsafe { ClosePrinter(handle) };
+
+    if ok {
+        Ok(jobs)
+    } else {
+        Err(format!("Clearing the queue failed: {}", error))
+    }
+}
+
+/// Jobs in the queue according to PRINTER_INFO_2, or 0 if it can't be read
+fn queued_jobs(handle: PRINTER_HANDLE) -> usize {
+    unsafe {
+        // First call reports the buffer size needed
+        let mut needed = 0u32;
+        let _ = GetPrinterW(handle, 2, None, &mut needed);
+        let mut buf = vec![0u8; needed as usize];
+        if needed == 0 || !GetPrinterW(handle, 2, Some(&mut buf), &mut needed).as_bool() {
+            return 0;
+        }
+        std::ptr::read_unaligned(buf.as_ptr() as *const PRINTER_INFO_2W).cJobs as usize
+    }
+}
+
+/// The real Win32 spooler
+#[derive(Default)]
+struct WinSpooler {
+    handle: Option<PRINTER_HANDLE>,
+}
+
+impl WinSpooler {
+    fn handle(&self) -> Result<PRINTER_HANDLE, String> {
+        self.handle.ok_or_else(|| "Printer not open".to_string())
+    }
+}
+
+impl RawSpooler for WinSpooler {
+    fn open(&mut self, printer_name: &str) -> Result<(), String> {
+        let printer = HSTRING::from(printer_name);
+        let mut handle = PRINTER_HANDLE::default();
+        unsafe { OpenPrinterW(&printer, &mut handle, None) }.map_err(|e| format!("Failed to open printer: {}", e))?;
+        self.handle = Some(handle);
+        Ok(())
+    }
+
+    fn start_doc(&mut self, doc_name: &str, datatype: &str) -> Result<(), String> {
+        let handle = self.handle()?;
+        let mut doc_name: Vec<u16> = doc_name.encode_utf16().chain(Some(0)).collect();
+        let mut datatype: Vec<u16> = datatype.encode_utf16().chain(Some(0)).collect();
+
+        let doc_info = DOC_INFO_1W {
+            pDocName: PWSTR(doc_name.as_mut_ptr()),
+            pOutputFile: PWSTR::null(),
+            pDatatype: PWSTR(datatype.as_mut_ptr()),
+        };
+
+        if unsafe { StartDocPrinterW(handle, 1, &doc_info) } == 0 {
+            return Err(format!("StartDocPrinter failed: {}", Error::from_win32()));
+        }
+        Ok(())
+    }
+
+    fn start_page(&mut self) -> Result<(), String> {
+        let handle = self.handle()?;
+        if !unsafe { StartPagePrinter(handle) }.as_bool() {
+            return Err(format!("StartPagePrinter failed: {}", Error::from_win32()));
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<usize, String> {
+        let handle = self.handle()?;
+        let mut written = 0u32;
+        let ok = unsafe { WritePrinter(handle, data.as_ptr() as *const _, data.len() as u32, &mut written) }.as_bool();
+        if !ok {
+            return Err(format!("WritePrinter failed: {}", Error::from_win32()));
+        }
+        Ok(written as usize)
+    }
+
+    fn end_page(&mut self) {
+        if let Some(handle) = self.handle {
+            let _ = unsafe { EndPagePrinter(handle) };
+        }
+    }
+
+    fn end_doc(&mut self) {
+        if let Some(handle) = self.handle {
+            let _ = unsafe { EndDocPrinter(handle) };
+        }
+    }
+
+    fn close(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            let _ = unsafe { ClosePrinter(handle) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records every spooler call, failing the ones it's told to
+    #[derive(Default)]
+    struct FakeSpooler {
+        calls: Vec<String>,
+        refuse_open: bool,
+        fail_page: Option<u32>,
+        short_write: bool,
+        pages: u32,
+    }
+
+    impl RawSpooler for FakeSpooler {
+        fn open(&mut self, printer_name: &str) -> Result<(), String> {
+            self.calls.push(format!("open {}", printer_name));
+            if self.refuse_open {
+                return Err("Failed to open printer".to_string());
+            }
+            Ok(())
+        }
+
+        fn start_doc(&mut self, doc_name: &str, datatype: &str) -> Result<(), String> {
+            self.calls.push(format!("start_doc {} {}", doc_name, datatype));
+            Ok(())
+        }
+
+        fn start_page(&mut self) -> Result<(), String> {
+            self.pages += 1;
+            self.calls.push("start_page".to_string());
+            if self.fail_page == Some(self.pages) {
+                return Err("StartPagePrinter failed".to_string());
+            }
+            Ok(())
+        }
+
+        fn write(&mut self, data: &[u8]) -> Result<usize, String> {
+            self.calls.push(format!("write {}", data.len()));
+            Ok(if self.short_write { data.len() - 1 } else { data.len() })
+        }
+
+        fn end_page(&mut self) {
+            self.calls.push("end_page".to_string());
+        }
+
+        fn end_doc(&mut self) {
+            self.calls.push("end_doc".to_string());
+        }
+
+        fn close(&mut self) {
+            self.calls.push("close".to_string());
+        }
+    }
+
+    const ZPL: &[u8] = b"^XA^FDHello^FS^XZ";
+
+    #[test]
+    fn raw_job_writes_a_page_per_copy() {
+        let mut spooler = FakeSpooler::default();
+        send_raw_job(&mut spooler, "Zebra ZP 450", ZPL, 2).unwrap();
+        assert_eq!(
+            spooler.calls,
+            [
+                "open Zebra ZP 450",
+                "start_doc LimeStack Label RAW",
+                "start_page",
+                "write 17",
+                "end_page",
+                "start_page",
+                "write 17",
+                "end_page",
+                "end_doc",
+                "close",
+            ]
+        );
+    }
+
+    #[test]
+    fn refused_printer_is_not_closed() {
+        let mut spooler = FakeSpooler {
+            refuse_open: true,
+            ..FakeSpooler::default()
+        };
+        assert!(send_raw_job(&mut spooler, "Missing", ZPL, 1).is_err());
+        assert_eq!(spooler.calls, ["open Missing"]);
+    }
+
+    #[test]
+    fn failed_page_ends_the_document_and_closes() {
+        let mut spooler = FakeSpooler {
+            fail_page: Some(2),
+            ..FakeSpooler::default()
+        };
+        assert_eq!(send_raw_job(&mut spooler, "Zebra", ZPL, 3), Err("StartPagePrinter failed".to_string()));
+        assert_eq!(&spooler.calls[spooler.calls.len() - 3..], ["start_page", "end_doc", "close"]);
+        assert_eq!(spooler.pages, 2);
+    }
+
+    #[test]
+    fn short_write_is_an_error() {
+        let mut spooler = FakeSpooler {
+            short_write: true,
+            ..FakeSpooler::default()
+        };
+        let error = send_raw_job(&mut spooler, "Zebra", ZPL, 1).unwrap_err();
+        assert_eq!(error, "WritePrinter failed after 16 of 17 bytes");
+        assert_eq!(&spooler.calls[spooler.calls.len() - 3..], ["end_page", "end_doc", "close"]);
+    }
+}
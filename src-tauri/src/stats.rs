@@ -0,0 +1,181 @@
+//! Connection and print counters for `get_stats`, plus the connection lifecycle events
+//! logged alongside them. Counts cover every connection since the connector started.
+
+use crate::protocol::ServerMessage;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Log target for lifecycle events, so log tooling can pick them out
+const EVENT_TARGET: &str = "connection";
+
+static COUNTERS: Counters = Counters::new();
+
+struct Counters {
+    total_connections: AtomicU64,
+    active_connections: AtomicU64,
+    total_prints: AtomicU64,
+    failed_prints: AtomicU64,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Self {
+            total_connections: AtomicU64::new(0),
+            active_connections: AtomicU64::new(0),
+            total_prints: AtomicU64::new(0),
+            failed_prints: AtomicU64::new(0),
+        }
+    }
+
+    fn record_print(&self, result: &ServerMessage) {
+        if let ServerMessage::PrintResult { success, .. } = result {
+            self.total_prints.fetch_add(1, Ordering::Relaxed);
+            if !success {
+                self.failed_prints.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn snapshot(&self, uptime_seconds: u64) -> ServerMessage {
+        ServerMessage::Stats {
+            total_connections: self.total_connections.load(Ordering::Relaxed),
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+            total_prints: self.total_prints.load(Ordering::Relaxed),
+            failed_prints: self.failed_prints.load(Ordering::Relaxed),
+            uptime_seconds,
+        }
+    }
+}
+
+/// Counts a WebSocket connection as active until dropped
+pub struct ActiveConnection(&'static Counters);
+
+impl ActiveConnection {
+    pub fn open() -> Self {
+        Self::open_on(&COUNTERS)
+    }
+
+    fn open_on(counters: &'static Counters) -> Self {
+        counters.total_connections.fetch_add(1, Ordering::Relaxed);
+        counters.active_connections.fetch_add(1, Ordering::Relaxed);
+        Self(counters)
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Count a finished print request; other messages are ignored
+pub fn record_print(result: &ServerMessage) {
+    COUNTERS.record_print(result);
+}
+
+/// The counters as a `stats` message
+pub fn snapshot(uptime_seconds: u64) -> ServerMessage {
+    COUNTERS.snapshot(uptime_seconds)
+}
+
+/// Log a lifecycle event as `event=<name>` followed by `key=value` fields, quoting values
+/// with spaces
+pub fn log_event(event: &str, fields: &[(&str, &dyn Display)]) {
+    log::info!(target: EVENT_TARGET, "{}", event_line(event, fields));
+}
+
+fn event_line(event: &str, fields: &[(&str, &dyn Display)]) -> String {
+    let mut line = format!("event={}", event);
+    for (key, value) in fields {
+        let value = value.to_string();
+        if value.is_empty() || value.contains(char::is_whitespace) || value.contains('"') {
+            line.push_str(&format!(" {}={:?}", key, value));
+        } else {
+            line.push_str(&format!(" {}={}", key, value));
+        }
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ErrorCode;
+
+    /// Counters of their own, so tests running in parallel don't see each other's counts
+    fn counters() -> &'static Counters {
+        Box::leak(Box::new(Counters::new()))
+    }
+
+    fn print_result(success: bool) -> ServerMessage {
+        ServerMessage::PrintResult {
+            request_id: "job-1".to_string(),
+            success,
+            job_id: None,
+            message: None,
+            error: None,
+            code: None,
+            copy_results: None,
+            attempts: None,
+            retry_after: None,
+        }
+    }
+
+    fn counts(counters: &Counters) -> (u64, u64, u64, u64) {
+        match counters.snapshot(0) {
+            ServerMessage::Stats {
+                total_connections,
+                active_connections,
+                total_prints,
+                failed_prints,
+                ..
+            } => (total_connections, active_connections, total_prints, failed_prints),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn connections_count_while_open_and_in_total() {
+        let counters = counters();
+        let first = ActiveConnection::open_on(counters);
+        let second = ActiveConnection::open_on(counters);
+        assert_eq!(counts(counters), (2, 2, 0, 0));
+
+        drop(first);
+        assert_eq!(counts(counters), (2, 1, 0, 0));
+        drop(second);
+        let _third = ActiveConnection::open_on(counters);
+        assert_eq!(counts(counters), (3, 1, 0, 0));
+    }
+
+    #[test]
+    fn prints_count_with_failures_separately() {
+        let counters = counters();
+        counters.record_print(&print_result(true));
+        counters.record_print(&print_result(false));
+        counters.record_print(&print_result(true));
+        assert_eq!(counts(counters), (0, 0, 3, 1));
+    }
+
+    #[test]
+    fn messages_other_than_print_results_are_not_prints() {
+        let counters = counters();
+        counters.record_print(&ServerMessage::error(ErrorCode::PrintFailed, "not a print result"));
+        assert_eq!(counts(counters), (0, 0, 0, 0));
+    }
+
+    #[test]
+    fn snapshot_reports_uptime() {
+        match counters().snapshot(42) {
+            ServerMessage::Stats { uptime_seconds, .. } => assert_eq!(uptime_seconds, 42),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn event_values_with_spaces_are_quoted() {
+        let line = event_line("disconnected", &[("origin", &"https://app.limestack.io"), ("reason", &"no reply")]);
+        assert_eq!(line, r#"event=disconnected origin=https://app.limestack.io reason="no reply""#);
+        assert_eq!(event_line("refused", &[("origin", &"")]), r#"event=refused origin="""#);
+    }
+}
@@ -0,0 +1,73 @@
+/// Page size of the test label in points (4x6 inches)
+const PAGE_WIDTH: u32 = 288;
+const PAGE_HEIGHT: u32 = 432;
+
+/// Build a one-page PDF identifying the printer and when the test was printed
+pub fn test_label_pdf(printer_name: &str) -> Vec<u8> {
+    let timestamp = chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    let lines = [
+        (20, "LimeStack Connector"),
+        (16, "Test Print"),
+        (12, printer_name),
+        (12, timestamp.as_str()),
+    ];
+
+    let mut content = String::from("BT\n");
+    let mut y = PAGE_HEIGHT - 60;
+    for (size, text) in lines {
+        content.push_str(&format!("/F1 {} Tf\n1 0 0 1 24 {} Tm\n({}) Tj\n", size, y, escape_pdf_text(text)));
+        y -= 32;
+    }
+    content.push_str("ET\n");
+
+    text_pdf(PAGE_WIDTH, PAGE_HEIGHT, &content)
+}
+
+/// Build a one-page PDF from a content stream that draws with Helvetica as `/F1`
+///
+/// Shared with label templates. `content` must be ASCII, e.g. text run through
+/// `escape_pdf_text`.
+pub fn text_pdf(page_width: u32, page_height: u32, content: &str) -> Vec<u8> {
+    let objects = [
+        "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+        "<< /Type /Pages /Kids [3 0 R] /Count 1 >>".to_string(),
+        format!(
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] /Resources << /Font << /F1 4 0 R >> >> /Contents 5 0 R >>",
+            page_width, page_height
+        ),
+        "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica >>".to_string(),
+        format!("<< /Length {} >>\nstream\n{}endstream", content.len(), content),
+    ];
+
+    // Objects are ASCII, so string lengths are byte offsets for the xref table
+    let mut pdf = String::from("%PDF-1.4\n");
+    let mut offsets = Vec::with_capacity(objects.len());
+    for (i, object) in objects.iter().enumerate() {
+        offsets.push(pdf.len());
+        pdf.push_str(&format!("{} 0 obj\n{}\nendobj\n", i + 1, object));
+    }
+
+    let xref_offset = pdf.len();
+    pdf.push_str(&format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1));
+    for offset in offsets {
+        pdf.push_str(&format!("{:010} 00000 n \n", offset));
+    }
+    pdf.push_str(&format!(
+        "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+        objects.len() + 1,
+        xref_offset
+    ));
+
+    pdf.into_bytes()
+}
+
+/// Escape a string for a PDF literal, replacing characters the base font can't show
+pub fn escape_pdf_text(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '(' | ')' | '\\' => format!("\\{}", c),
+            c if c.is_ascii() && !c.is_ascii_control() => c.to_string(),
+            _ => "?".to_string(),
+        })
+        .collect()
+}
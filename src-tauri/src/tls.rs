@@ -0,0 +1,150 @@
+use std::io::{BufReader, Write};
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+const CERT_FILE: &str = "localhost-cert.pem";
+const KEY_FILE: &str = "localhost-key.pem";
+
+/// Hostnames the self-signed certificate is valid for
+const CERT_HOSTS: &[&str] = &["127.0.0.1", "::1", "localhost"];
+
+/// Build a TLS acceptor from the stored certificate, generating one on first run
+pub fn load_or_create_acceptor(dir: &Path) -> Result<TlsAcceptor, String> {
+    let cert_path = dir.join(CERT_FILE);
+    let key_path = dir.join(KEY_FILE);
+
+    if !cert_path.exists() || !key_path.exists() {
+        generate_certificate(&cert_path, &key_path)?;
+    }
+
+    let certs = {
+        let file = std::fs::File::open(&cert_path).map_err(|e| format!("Failed to open certificate: {}", e))?;
+        rustls_pemfile::certs(&mut BufReader::new(file))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Failed to parse certificate: {}", e))?
+    };
+
+    let key = {
+        let file = std::fs::File::open(&key_path).map_err(|e| format!("Failed to open private key: {}", e))?;
+        rustls_pemfile::private_key(&mut BufReader::new(file))
+            .map_err(|e| format!("Failed to parse private key: {}", e))?
+            .ok_or("No private key found")?
+    };
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("Invalid certificate: {}", e))?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Generate and persist a self-signed certificate for the loopback address
+fn generate_certificate(cert_path: &Path, key_path: &Path) -> Result<(), String> {
+    log::info!("Generating self-signed certificate at {:?}", cert_path);
+
+    let hosts: Vec<String> = CERT_HOSTS.iter().map(|h| h.to_string()).collect();
+    let certified = rcgen::generate_simple_self_signed(hosts)
+        .map_err(|e| format!("Failed to generate certificate: {}", e))?;
+
+    if let Some(dir) = cert_path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| format!("Failed to create certificate dir: {}", e))?;
+    }
+
+    std::fs::write(cert_path, certified.cert.pem()).map_err(|e| format!("Failed to write certificate: {}", e))?;
+    write_private_key(key_path, &certified.key_pair.serialize_pem())
+        .map_err(|e| format!("Failed to write private key: {}", e))?;
+
+    Ok(())
+}
+
+/// Write the private key to a new file that only the current user can read
+///
+/// The mode is set as the file is created, so the key is never readable by others, even
+/// briefly. A leftover key is removed first, since an existing file keeps its permissions.
+fn write_private_key(path: &Path, pem: &str) -> std::io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(pem.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    use tokio_rustls::TlsConnector;
+
+    /// A client that trusts only the certificate generated in `dir`
+    fn connector_trusting(dir: &Path) -> TlsConnector {
+        let file = std::fs::File::open(dir.join(CERT_FILE)).unwrap();
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut BufReader::new(file)) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+        TlsConnector::from(Arc::new(config))
+    }
+
+    #[tokio::test]
+    async fn acceptor_wraps_a_connection() {
+        let dir = tempfile::tempdir().unwrap();
+        let acceptor = load_or_create_acceptor(dir.path()).unwrap();
+        let connector = connector_trusting(dir.path());
+
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let server = tokio::spawn(async move {
+            let mut tls = acceptor.accept(server).await.unwrap();
+            let mut buf = [0u8; 4];
+            tls.read_exact(&mut buf).await.unwrap();
+            buf
+        });
+
+        let mut tls = connector
+            .connect(ServerName::try_from("localhost").unwrap(), client)
+            .await
+            .unwrap();
+        tls.write_all(b"ping").await.unwrap();
+        tls.flush().await.unwrap();
+        assert_eq!(&server.await.unwrap(), b"ping");
+    }
+
+    #[test]
+    fn certificate_is_reused_once_generated() {
+        let dir = tempfile::tempdir().unwrap();
+        load_or_create_acceptor(dir.path()).unwrap();
+        let cert = std::fs::read(dir.path().join(CERT_FILE)).unwrap();
+
+        load_or_create_acceptor(dir.path()).unwrap();
+        assert_eq!(std::fs::read(dir.path().join(CERT_FILE)).unwrap(), cert);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn private_key_is_readable_by_the_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let key_path = dir.path().join(KEY_FILE);
+        // A leftover key with loose permissions must not keep them
+        std::fs::write(&key_path, "old key").unwrap();
+        std::fs::set_permissions(&key_path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        generate_certificate(&dir.path().join(CERT_FILE), &key_path).unwrap();
+        let mode = std::fs::metadata(&key_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,287 @@
+use crate::autostart;
+use crate::config;
+use crate::logging;
+use crate::notifications::notify;
+use crate::origins;
+use crate::pairing;
+use crate::printer::{self, JobOptions, LabelData};
+use crate::protocol::{PrinterInfo, ShutdownReason};
+use crate::server::{self, ServerShutdown, ServerStatus};
+use crate::test_label;
+use crate::updater::{self, UpdateChannel};
+use tauri::menu::{CheckMenuItem, Menu, MenuItem, PredefinedMenuItem, Submenu};
+use tauri::tray::{TrayIconBuilder, TrayIconEvent};
+use tauri::{App, AppHandle, Manager};
+use tokio::sync::watch;
+
+/// Menu id prefix for the per-printer "Test Print" entries
+const TEST_PRINT_PREFIX: &str = "test_print:";
+
+/// Where to get SumatraPDF when Windows can't print PDFs on its own
+const SUMATRA_DOWNLOAD_URL: &str = "https://www.sumatrapdfreader.org/download-free-pdf-viewer";
+
+/// Build the system tray and keep its status line in sync with the server
+pub fn create_tray(app: &App, mut status_rx: watch::Receiver<ServerStatus>) -> tauri::Result<()> {
+    let status = MenuItem::with_id(app, "status", status_text(&status_rx.borrow()), false, None::<&str>)?;
+    let separator1 = PredefinedMenuItem::separator(app)?;
+    let open_limestack = MenuItem::with_id(app, "open_limestack", "Open LimeStack", true, None::<&str>)?;
+    let test_print = Submenu::with_id(app, "test_print", "Test Print", true)?;
+    let accept_connections = CheckMenuItem::with_id(
+        app,
+        "accept_connections",
+        "Accept Connections",
+        true,
+        server::accepting_connections(),
+        None::<&str>,
+    )?;
+    let start_at_login = CheckMenuItem::with_id(
+        app,
+        "start_at_login",
+        "Start at Login",
+        true,
+        autostart::is_enabled(app.handle()),
+        None::<&str>,
+    )?;
+    let failure_notifications = CheckMenuItem::with_id(
+        app,
+        "failure_notifications",
+        "Notify on Print Failures",
+        true,
+        config::get().notify_print_failures.unwrap_or(true),
+        None::<&str>,
+    )?;
+    let pair_browser = MenuItem::with_id(app, "pair_browser", "Pair Browser…", true, None::<&str>)?;
+    let check_updates = MenuItem::with_id(app, "check_updates", "Check for Updates…", true, None::<&str>)?;
+    let channel = config::get().update_channel;
+    let stable_channel =
+        CheckMenuItem::with_id(app, "channel_stable", "Stable", true, channel == UpdateChannel::Stable, None::<&str>)?;
+    let beta_channel =
+        CheckMenuItem::with_id(app, "channel_beta", "Beta", true, channel == UpdateChannel::Beta, None::<&str>)?;
+    let update_channel =
+        Submenu::with_id_and_items(app, "update_channel", "Update Channel", true, &[&stable_channel, &beta_channel])?;
+    let open_logs = MenuItem::with_id(app, "open_logs", "Open Log Folder", true, None::<&str>)?;
+    let separator2 = PredefinedMenuItem::separator(app)?;
+    let quit = MenuItem::with_id(app, "quit", "Quit", true, None::<&str>)?;
+
+    refresh_test_print_menu(app.handle().clone(), test_print.clone());
+
+    let menu = Menu::with_items(app, &[
+        &status,
+        &separator1,
+        &open_limestack,
+        &test_print,
+        &accept_connections,
+        &start_at_login,
+        &failure_notifications,
+        &pair_browser,
+        &check_updates,
+        &update_channel,
+        &open_logs,
+        &separator2,
+        &quit,
+    ])?;
+
+    // Without a PDF backend every PDF print fails, so say so up front with a way to fix it
+    if let Some(problem) = printer::missing_pdf_backend() {
+        log::warn!("{}", problem);
+        let install = MenuItem::with_id(
+            app,
+            "install_pdf_backend",
+            "⚠ Install SumatraPDF for PDF Printing…",
+            true,
+            None::<&str>,
+        )?;
+        menu.insert(&install, 1)?;
+        notify(app.handle(), "PDF printing unavailable", &problem);
+    }
+
+    let menu_status = status.clone();
+    let menu_status_rx = status_rx.clone();
+    let tray = TrayIconBuilder::new()
+        .icon(app.default_window_icon().unwrap().clone())
+        .menu(&menu)
+        .menu_on_left_click(true)
+        .on_menu_event(move |app, event| match event.id.as_ref() {
+            "open_limestack" => {
+                if let Err(e) = open::that(origins::settings_url()) {
+                    log::warn!("Failed to open LimeStack: {}", e);
+                }
+            }
+            "accept_connections" => {
+                // The check mark has already toggled by the time the event arrives
+                let accepting = accept_connections.is_checked().unwrap_or(true);
+                server::set_accepting_connections(accepting);
+                if let Err(e) = menu_status.set_text(status_text(&menu_status_rx.borrow())) {
+                    log::warn!("Failed to update tray status: {}", e);
+                }
+            }
+            "start_at_login" => {
+                let enabled = start_at_login.is_checked().unwrap_or(false);
+                if let Err(e) = autostart::set_enabled(app, enabled) {
+                    log::error!("{}", e);
+                    notify(app, "Start at Login", &e);
+                }
+                // Show what the OS actually has registered, not what was requested
+                let _ = start_at_login.set_checked(autostart::is_enabled(app));
+            }
+            "failure_notifications" => {
+                let enabled = failure_notifications.is_checked().unwrap_or(true);
+                if let Err(e) = config::update(|c| c.notify_print_failures = Some(enabled)) {
+                    log::error!("Failed to save notification setting: {}", e);
+                }
+            }
+            "pair_browser" => start_pairing(app),
+            "install_pdf_backend" => {
+                let _ = open::that(SUMATRA_DOWNLOAD_URL);
+            }
+            "check_updates" => {
+                tauri::async_runtime::spawn(updater::check_for_updates(app.clone(), true));
+            }
+            "channel_stable" | "channel_beta" => {
+                let channel = if event.id.as_ref() == "channel_beta" {
+                    UpdateChannel::Beta
+                } else {
+                    UpdateChannel::Stable
+                };
+                if let Err(e) = updater::set_channel(channel) {
+                    log::error!("Failed to save update channel: {}", e);
+                }
+                // Behave like radio items, whatever the click did to the check marks
+                let current = config::get().update_channel;
+                let _ = stable_channel.set_checked(current == UpdateChannel::Stable);
+                let _ = beta_channel.set_checked(current == UpdateChannel::Beta);
+            }
+            "open_logs" => logging::open_log_folder(app),
+            "quit" => {
+                // Let the server close connections and finish print jobs before exiting
+                let app = app.clone();
+                tauri::async_runtime::spawn(async move {
+                    app.state::<ServerShutdown>().shutdown(ShutdownReason::Quit).await;
+                    app.exit(0);
+                });
+            }
+            id => {
+                if let Some(printer_id) = id.strip_prefix(TEST_PRINT_PREFIX) {
+                    run_test_print(app.clone(), printer_id.to_string());
+                }
+            }
+        })
+        .on_tray_icon_event(move |tray, event| {
+            // Re-list printers when the tray is clicked so new ones show up. Hovering doesn't
+            // count: it would enumerate every time the pointer crosses the icon.
+            if matches!(event, TrayIconEvent::Click { .. }) {
+                refresh_test_print_menu(tray.app_handle().clone(), test_print.clone());
+            }
+        })
+        .tooltip(tooltip_text(&status_rx.borrow()))
+        .build(app)?;
+
+    // Keep the status line and tooltip in sync with connected clients and self-test warnings
+    tauri::async_runtime::spawn(async move {
+        while status_rx.changed().await.is_ok() {
+            let current = status_rx.borrow_and_update().clone();
+            if let Err(e) = status.set_text(status_text(&current)) {
+                log::warn!("Failed to update tray status: {}", e);
+            }
+            if let Err(e) = tray.set_tooltip(Some(tooltip_text(&current))) {
+                log::warn!("Failed to update tray tooltip: {}", e);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Tray status line for the current server state
+fn status_text(status: &ServerStatus) -> String {
+    if let Some(error) = &status.error {
+        return format!("● Error: {}", error);
+    }
+    if let Some(warning) = status.warnings.first() {
+        return format!("⚠ {}", warning);
+    }
+
+    if !server::accepting_connections() {
+        return match status.connected_clients {
+            0 => "⏸ Paused".to_string(),
+            n => format!("⏸ Paused ({} connected)", n),
+        };
+    }
+
+    match status.connected_clients {
+        0 => "○ Waiting for connection".to_string(),
+        1 => "● 1 client connected".to_string(),
+        n => format!("● {} clients connected", n),
+    }
+}
+
+/// Tray tooltip, listing every self-test problem
+fn tooltip_text(status: &ServerStatus) -> String {
+    if status.warnings.is_empty() {
+        return "LimeStack Connector".to_string();
+    }
+    format!("LimeStack Connector\n{}", status.warnings.join("\n"))
+}
+
+/// Re-list printers on a blocking thread, since enumerating can take seconds and would
+/// freeze the tray on the UI thread, then rebuild the submenu with them
+fn refresh_test_print_menu(app: AppHandle, submenu: Submenu<tauri::Wry>) {
+    tauri::async_runtime::spawn_blocking(move || {
+        let printers = printer::get_printers();
+        if let Err(e) = rebuild_test_print_menu(&app, &submenu, printers) {
+            log::warn!("Failed to refresh Test Print menu: {}", e);
+        }
+    });
+}
+
+fn rebuild_test_print_menu(app: &AppHandle, submenu: &Submenu<tauri::Wry>, printers: Vec<PrinterInfo>) -> tauri::Result<()> {
+    while submenu.remove_at(0)?.is_some() {}
+
+    if printers.is_empty() {
+        let none = MenuItem::with_id(app, "test_print_none", "No printers found", false, None::<&str>)?;
+        submenu.append(&none)?;
+    }
+
+    for p in printers {
+        let item = MenuItem::with_id(app, format!("{}{}", TEST_PRINT_PREFIX, p.id), &p.name, true, None::<&str>)?;
+        submenu.append(&item)?;
+    }
+
+    Ok(())
+}
+
+/// Show a new pairing code for the browser to send in `hello`
+fn start_pairing(app: &AppHandle) {
+    let dir = match app.path().app_data_dir() {
+        Ok(dir) => dir,
+        Err(e) => {
+            log::error!("Cannot pair without an app data dir: {}", e);
+            return;
+        }
+    };
+
+    let code = pairing::start_pairing(&dir);
+    notify(app, "Pair Browser", &format!("Enter code {} in LimeStack within 5 minutes", code));
+}
+
+/// Print the built-in test label and report the outcome as a notification
+fn run_test_print(app: AppHandle, printer_id: String) {
+    tauri::async_runtime::spawn_blocking(move || {
+        log::info!("Test print requested for {}", printer_id);
+
+        let result = printer::find_printer(&printer_id)
+            .ok_or_else(|| format!("Printer not found: {}", printer_id))
+            .and_then(|name| {
+                let pdf = test_label::test_label_pdf(&name);
+                printer::print_label(&name, &LabelData::Bytes(pdf), "pdf", &JobOptions::default()).map(|_| name)
+            });
+
+        match result {
+            Ok(name) => notify(&app, "Test print sent", &format!("Sent a test label to {}", name)),
+            Err(e) => {
+                log::error!("Test print failed: {}", e);
+                notify(&app, "Test print failed", &e);
+            }
+        }
+    });
+}
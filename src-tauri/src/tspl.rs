@@ -0,0 +1,290 @@
+use serde::Deserialize;
+
+/// A basic label the connector turns into TSPL for TSC printers
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TsplLabel {
+    pub width_mm: f32,
+    pub height_mm: f32,
+    /// Gap between die-cut labels; 0 for continuous stock
+    #[serde(default = "default_gap")]
+    pub gap_mm: f32,
+    pub items: Vec<TsplItem>,
+}
+
+/// Something drawn on the label, positioned in dots from the top left
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TsplItem {
+    Text {
+        x: u32,
+        y: u32,
+        text: String,
+        /// Built-in font "1" to "8", or a downloaded font name
+        #[serde(default = "default_font")]
+        font: String,
+        /// Multiplier for both width and height, 1 to 10
+        #[serde(default = "default_scale")]
+        scale: u8,
+    },
+    Barcode {
+        x: u32,
+        y: u32,
+        data: String,
+        /// TSPL code type, e.g. "128", "39", "EAN13"
+        #[serde(default = "default_symbology")]
+        symbology: String,
+        /// Bar height in dots
+        #[serde(default = "default_barcode_height")]
+        height: u32,
+        /// Print the data under the bars
+        #[serde(default = "default_true")]
+        readable: bool,
+    },
+}
+
+fn default_gap() -> f32 {
+    2.0
+}
+
+fn default_font() -> String {
+    "3".to_string()
+}
+
+fn default_scale() -> u8 {
+    1
+}
+
+fn default_symbology() -> String {
+    "128".to_string()
+}
+
+fn default_barcode_height() -> u32 {
+    80
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Encode a label as TSPL commands for one copy
+///
+/// Copies are left to the spooler like any other raw job. Characters outside printable
+/// ASCII print as '?'.
+pub fn build_tspl(label: &TsplLabel) -> Result<Vec<u8>, String> {
+    for (name, mm) in [("widthMm", label.width_mm), ("heightMm", label.height_mm)] {
+        if !(mm.is_finite() && mm > 0.0) {
+            return Err(format!("{} must be a positive number", name));
+        }
+    }
+    if !(label.gap_mm.is_finite() && label.gap_mm >= 0.0) {
+        return Err("gapMm must not be negative".to_string());
+    }
+
+    let mut lines = vec![
+        format!("SIZE {} mm, {} mm", label.width_mm, label.height_mm),
+        format!("GAP {} mm, 0 mm", label.gap_mm),
+        "DIRECTION 1".to_string(),
+        "CLS".to_string(),
+    ];
+
+    for item in &label.items {
+        lines.push(match item {
+            TsplItem::Text { x, y, text, font, scale } => {
+                check_name("font", font)?;
+                if !(1..=10).contains(scale) {
+                    return Err(format!("Text scale must be 1 to 10, got {}", scale));
+                }
+                format!("TEXT {},{},\"{}\",0,{},{},\"{}\"", x, y, font, scale, scale, quote(text))
+            }
+            TsplItem::Barcode {
+                x,
+                y,
+                data,
+                symbology,
+                height,
+                readable,
+            } => {
+                check_name("symbology", symbology)?;
+                if data.is_empty() {
+                    return Err("Barcode data is empty".to_string());
+                }
+                format!(
+                    "BARCODE {},{},\"{}\",{},{},0,2,2,\"{}\"",
+                    x,
+                    y,
+                    symbology,
+                    height,
+                    *readable as u8,
+                    quote(data)
+                )
+            }
+        });
+    }
+    lines.push("PRINT 1,1".to_string());
+
+    let mut out = lines.join("\r\n").into_bytes();
+    out.extend_from_slice(b"\r\n");
+    Ok(out)
+}
+
+/// Font and code type names go inside quotes unescaped, so only allow plain names
+fn check_name(field: &str, value: &str) -> Result<(), String> {
+    if value.is_empty() || !value.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-')) {
+        return Err(format!("Invalid {} '{}'", field, value));
+    }
+    Ok(())
+}
+
+/// A string for inside a TSPL quoted argument, where `\["]` stands for a double quote
+fn quote(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '"' => "\\[\"]".to_string(),
+            c if c.is_ascii() && !c.is_ascii_control() => c.to_string(),
+            _ => "?".to_string(),
+        })
+        .collect()
+}
+
+/// Whether a printer name looks like a TSC, which understands TSPL
+pub fn is_tsc_printer(name: &str) -> bool {
+    name.to_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .any(|word| word == "tsc")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn label(items: Vec<TsplItem>) -> TsplLabel {
+        TsplLabel {
+            width_mm: 50.0,
+            height_mm: 30.0,
+            gap_mm: 2.0,
+            items,
+        }
+    }
+
+    fn text(text: &str) -> TsplItem {
+        TsplItem::Text {
+            x: 10,
+            y: 20,
+            text: text.to_string(),
+            font: default_font(),
+            scale: default_scale(),
+        }
+    }
+
+    fn barcode(data: &str, symbology: &str) -> TsplItem {
+        TsplItem::Barcode {
+            x: 10,
+            y: 80,
+            data: data.to_string(),
+            symbology: symbology.to_string(),
+            height: default_barcode_height(),
+            readable: default_true(),
+        }
+    }
+
+    fn built(label: &TsplLabel) -> String {
+        String::from_utf8(build_tspl(label).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn label_with_text_and_barcode_builds_tspl_commands() {
+        let commands = built(&label(vec![text("Order 1042"), barcode("1042", "128")]));
+        assert_eq!(
+            commands,
+            "SIZE 50 mm, 30 mm\r\n\
+             GAP 2 mm, 0 mm\r\n\
+             DIRECTION 1\r\n\
+             CLS\r\n\
+             TEXT 10,20,\"3\",0,1,1,\"Order 1042\"\r\n\
+             BARCODE 10,80,\"128\",80,1,0,2,2,\"1042\"\r\n\
+             PRINT 1,1\r\n"
+        );
+    }
+
+    #[test]
+    fn fractional_sizes_and_continuous_stock_are_kept() {
+        let mut label = label(Vec::new());
+        label.width_mm = 101.6;
+        label.height_mm = 152.4;
+        label.gap_mm = 0.0;
+        let commands = built(&label);
+        assert!(commands.starts_with("SIZE 101.6 mm, 152.4 mm\r\nGAP 0 mm, 0 mm\r\n"), "{}", commands);
+        assert!(commands.ends_with("CLS\r\nPRINT 1,1\r\n"), "{}", commands);
+    }
+
+    #[test]
+    fn text_is_scaled_and_hides_its_quotes() {
+        let item = TsplItem::Text {
+            x: 0,
+            y: 0,
+            text: "12\" ruler — ok".to_string(),
+            font: "TSS24.BF2".to_string(),
+            scale: 3,
+        };
+        let commands = built(&label(vec![item]));
+        assert!(commands.contains("TEXT 0,0,\"TSS24.BF2\",0,3,3,\"12\\[\"] ruler ? ok\"\r\n"), "{}", commands);
+    }
+
+    #[test]
+    fn barcode_without_readable_text() {
+        let item = TsplItem::Barcode {
+            x: 5,
+            y: 6,
+            data: "4006381333931".to_string(),
+            symbology: "EAN13".to_string(),
+            height: 120,
+            readable: false,
+        };
+        let commands = built(&label(vec![item]));
+        assert!(commands.contains("BARCODE 5,6,\"EAN13\",120,0,0,2,2,\"4006381333931\"\r\n"), "{}", commands);
+    }
+
+    #[test]
+    fn invalid_labels_are_refused() {
+        let mut zero_width = label(Vec::new());
+        zero_width.width_mm = 0.0;
+        assert_eq!(build_tspl(&zero_width).unwrap_err(), "widthMm must be a positive number");
+        let mut nan_height = label(Vec::new());
+        nan_height.height_mm = f32::NAN;
+        assert_eq!(build_tspl(&nan_height).unwrap_err(), "heightMm must be a positive number");
+        let mut negative_gap = label(Vec::new());
+        negative_gap.gap_mm = -1.0;
+        assert_eq!(build_tspl(&negative_gap).unwrap_err(), "gapMm must not be negative");
+
+        let oversized = TsplItem::Text {
+            x: 0,
+            y: 0,
+            text: "x".to_string(),
+            font: default_font(),
+            scale: 11,
+        };
+        assert_eq!(build_tspl(&label(vec![oversized])).unwrap_err(), "Text scale must be 1 to 10, got 11");
+        assert_eq!(build_tspl(&label(vec![barcode("", "128")])).unwrap_err(), "Barcode data is empty");
+    }
+
+    #[test]
+    fn names_cannot_break_out_of_their_quotes() {
+        let error = build_tspl(&label(vec![barcode("1", "128\",0,0\r\nPRINT 99")])).unwrap_err();
+        assert!(error.starts_with("Invalid symbology"), "{}", error);
+        let mut item = text("x");
+        if let TsplItem::Text { font, .. } = &mut item {
+            *font = String::new();
+        }
+        assert_eq!(build_tspl(&label(vec![item])).unwrap_err(), "Invalid font ''");
+    }
+
+    #[test]
+    fn tsc_printers_are_recognised_by_name() {
+        assert!(is_tsc_printer("TSC TE200"));
+        assert!(is_tsc_printer("TSC_DA210"));
+        assert!(is_tsc_printer("Warehouse (tsc)"));
+        assert!(!is_tsc_printer("Zebra_ZP450"));
+        assert!(!is_tsc_printer("Etsc_Office"));
+    }
+}
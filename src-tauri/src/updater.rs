@@ -0,0 +1,152 @@
+use crate::config;
+use crate::notifications::notify;
+use crate::protocol::ShutdownReason;
+use crate::server::ServerShutdown;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tauri::{AppHandle, Manager};
+use tauri_plugin_updater::{Updater, UpdaterExt};
+
+/// Update feed for beta builds; stable uses the endpoint in `tauri.conf.json`
+const BETA_ENDPOINT: &str = "https://app.limestack.io/api/connector/update/beta/{{target}}/{{arch}}/{{current_version}}";
+
+/// Which builds the updater installs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpdateChannel {
+    #[default]
+    Stable,
+    Beta,
+}
+
+impl UpdateChannel {
+    /// Endpoint overriding the configured one, if the channel has its own feed
+    fn endpoint(self) -> Option<&'static str> {
+        match self {
+            UpdateChannel::Stable => None,
+            UpdateChannel::Beta => Some(BETA_ENDPOINT),
+        }
+    }
+}
+
+/// Save the channel; the next update check uses it
+pub fn set_channel(channel: UpdateChannel) -> Result<(), String> {
+    log::info!("Update channel set to {:?}", channel);
+    config::update(|c| c.update_channel = channel)
+}
+
+/// Updater for the channel currently in the config
+fn channel_updater(app: &AppHandle) -> Result<Updater, Box<dyn std::error::Error>> {
+    let channel = config::get().update_channel;
+    let Some(endpoint) = channel.endpoint() else {
+        return Ok(app.updater()?);
+    };
+    log::info!("Using {:?} update channel", channel);
+    Ok(app.updater_builder().endpoints(vec![tauri::Url::parse(endpoint)?])?.build()?)
+}
+
+/// Set while a check is running so the startup check and the tray item can't overlap
+static CHECK_IN_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+/// Clears the in-progress flag when the check ends, however it ends
+struct CheckGuard;
+
+impl Drop for CheckGuard {
+    fn drop(&mut self) {
+        CHECK_IN_PROGRESS.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Check for, download, and install an update
+///
+/// With `interactive` set (the tray item), every outcome is shown as a notification;
+/// the startup check stays silent unless an update is actually installed.
+pub async fn check_for_updates(app: AppHandle, interactive: bool) {
+    if CHECK_IN_PROGRESS.swap(true, Ordering::SeqCst) {
+        log::info!("Update check already in progress");
+        if interactive {
+            notify(&app, "LimeStack Connector", "Already checking for updates");
+        }
+        return;
+    }
+    let _guard = CheckGuard;
+
+    if let Err(e) = run_update(&app, interactive).await {
+        log::warn!("Update check failed: {}", e);
+        if interactive {
+            notify(&app, "Update check failed", &e.to_string());
+        }
+    }
+}
+
+async fn run_update(app: &AppHandle, interactive: bool) -> Result<(), Box<dyn std::error::Error>> {
+    log::info!("Checking for updates...");
+
+    let updater = channel_updater(app)?;
+
+    let Some(update) = updater.check().await? else {
+        log::info!("No updates available");
+        if interactive {
+            notify(app, "LimeStack Connector", "Up to date");
+        }
+        return Ok(());
+    };
+
+    log::info!("Update available: {} -> {}", update.current_version, update.version);
+    if interactive {
+        notify(app, "LimeStack Connector", &format!("Downloading update {}…", update.version));
+    }
+
+    // Download and install the update
+    let mut downloaded = 0;
+    let bytes = update.download(
+        |chunk, total| {
+            downloaded += chunk;
+            log::debug!("Downloaded {} of {:?} bytes", downloaded, total);
+        },
+        || {
+            log::info!("Download completed, preparing to install...");
+        }
+    ).await?;
+
+    log::info!("Installing update...");
+    update.install(bytes)?;
+
+    log::info!("Update installed. Restarting...");
+    notify(app, "LimeStack Connector", "Update installed, restarting");
+
+    // Close connections cleanly before the process is replaced
+    app.state::<ServerShutdown>().shutdown(ShutdownReason::Restart).await;
+    app.restart();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stable_uses_the_bundled_endpoint() {
+        assert_eq!(UpdateChannel::default(), UpdateChannel::Stable);
+        assert_eq!(UpdateChannel::Stable.endpoint(), None);
+    }
+
+    #[test]
+    fn beta_has_its_own_feed() {
+        let endpoint = UpdateChannel::Beta.endpoint().unwrap();
+        let url = tauri::Url::parse(endpoint).unwrap();
+        assert_eq!(url.scheme(), "https");
+        assert_eq!(url.host_str(), Some("app.limestack.io"));
+        assert!(url.path().starts_with("/api/connector/update/beta/"), "{}", url);
+    }
+
+    #[test]
+    fn channel_is_saved_in_lowercase_and_defaults_to_stable() {
+        assert_eq!(serde_json::to_string(&UpdateChannel::Beta).unwrap(), r#""beta""#);
+        assert_eq!(serde_json::from_str::<UpdateChannel>(r#""stable""#).unwrap(), UpdateChannel::Stable);
+
+        let config: config::Config = serde_json::from_str("{}").unwrap();
+        assert_eq!(config.update_channel, UpdateChannel::Stable);
+        let config: config::Config = serde_json::from_str(r#"{ "updateChannel": "beta" }"#).unwrap();
+        assert_eq!(config.update_channel.endpoint(), Some(BETA_ENDPOINT));
+    }
+}
@@ -0,0 +1,133 @@
+//! A printer that exists only in the connector, for developing and testing against the
+//! protocol without hardware. Enabled with `virtualPrinter: true` in `config.json` or
+//! `LIMESTACK_VIRTUAL_PRINTER=1`; prints always succeed and are saved as files.
+
+use crate::config;
+use crate::printer::{self, PreparedLabel};
+use crate::protocol::{PrinterCapabilities, PrinterInfo, PrinterStatus};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub const ID: &str = "virtual-printer";
+
+static NEXT_JOB: AtomicU64 = AtomicU64::new(1);
+
+pub fn enabled() -> bool {
+    enabled_by(config::get().virtual_printer, std::env::var("LIMESTACK_VIRTUAL_PRINTER").ok())
+}
+
+fn enabled_by(configured: bool, env: Option<String>) -> bool {
+    configured || env.is_some_and(|value| matches!(value.trim().to_lowercase().as_str(), "1" | "true" | "yes"))
+}
+
+/// How the virtual printer appears in `printers`
+pub fn info() -> PrinterInfo {
+    PrinterInfo {
+        id: ID.to_string(),
+        name: config::get()
+            .printer_nicknames
+            .get(ID)
+            .cloned()
+            .unwrap_or_else(|| "Virtual Printer".to_string()),
+        printer_type: "thermal".to_string(),
+        status: PrinterStatus::Ready,
+        is_default: false,
+        capabilities: PrinterCapabilities {
+            formats: printer::supported_formats(),
+            dpi: Some(203),
+            color: Some(false),
+            max_copies: None,
+            media_sizes: None,
+        },
+    }
+}
+
+/// Where printed labels are saved: `virtual-printer` in the label temp dir
+fn output_dir() -> PathBuf {
+    config::get().temp_dir.unwrap_or_else(std::env::temp_dir).join(ID)
+}
+
+/// "Print" a label by saving it (once, whatever the copies), returning the job id
+pub fn print(label: &PreparedLabel, copies: u32) -> Result<Option<String>, String> {
+    save_in(&output_dir(), label, copies)
+}
+
+fn save_in(dir: &Path, label: &PreparedLabel, copies: u32) -> Result<Option<String>, String> {
+    std::fs::create_dir_all(dir).map_err(|e| format!("Failed to create {:?}: {}", dir, e))?;
+
+    let job = format!("virtual-{}-{}", std::process::id(), NEXT_JOB.fetch_add(1, Ordering::Relaxed));
+    let path = dir.join(format!("{}.{}", job, extension(&label.format)));
+    std::fs::write(&path, &label.data).map_err(|e| format!("Failed to write {:?}: {}", path, e))?;
+    log::info!("Virtual printer saved {} copies of a {} label to {:?}", copies, label.format, path);
+
+    Ok(Some(job))
+}
+
+fn extension(format: &str) -> &str {
+    match format {
+        "escpos" | printer::BROTHER_RASTER_FORMAT => "bin",
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+
+    fn label(format: &str, data: &[u8]) -> PreparedLabel<'static> {
+        PreparedLabel {
+            data: Cow::Owned(data.to_vec()),
+            format: format.to_string(),
+            raw: format != "pdf",
+        }
+    }
+
+    #[test]
+    fn enabled_by_config_or_environment() {
+        assert!(enabled_by(true, None));
+        assert!(enabled_by(false, Some("1".to_string())));
+        assert!(enabled_by(false, Some(" TRUE ".to_string())));
+        assert!(enabled_by(false, Some("yes".to_string())));
+        assert!(!enabled_by(false, None));
+        assert!(!enabled_by(false, Some("0".to_string())));
+        assert!(!enabled_by(false, Some(String::new())));
+    }
+
+    #[test]
+    fn appears_as_a_ready_thermal_printer() {
+        let info = info();
+        assert_eq!(info.id, ID);
+        assert_eq!(info.name, "Virtual Printer");
+        assert_eq!(info.printer_type, "thermal");
+        assert_eq!(info.status, PrinterStatus::Ready);
+        assert!(!info.is_default);
+        assert!(info.capabilities.formats.iter().any(|f| f == "zpl"));
+    }
+
+    #[test]
+    fn printing_saves_the_payload() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join(ID);
+        let zpl = b"^XA^FO50,50^FDHello^FS^XZ";
+
+        let job = save_in(&out, &label("zpl", zpl), 3).unwrap().expect("no job id");
+        assert!(job.starts_with("virtual-"), "{}", job);
+        assert_eq!(std::fs::read(out.join(format!("{}.zpl", job))).unwrap(), zpl);
+
+        // Every print is kept, under its own job id
+        let second = save_in(&out, &label("escpos", b"\x1b@"), 1).unwrap().unwrap();
+        assert_ne!(second, job);
+        assert_eq!(std::fs::read(out.join(format!("{}.bin", second))).unwrap(), b"\x1b@");
+        assert_eq!(std::fs::read_dir(&out).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn unwritable_output_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("not-a-dir");
+        std::fs::write(&file, b"").unwrap();
+        let error = save_in(&file, &label("zpl", b"^XA^XZ"), 1).unwrap_err();
+        assert!(error.starts_with("Failed to create"), "{}", error);
+    }
+}